@@ -0,0 +1,28 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+fn main() {
+    evcxr::runtime_hook();
+    let address = match std::env::args().nth(1) {
+        Some(address) => address,
+        None => {
+            eprintln!("Usage: evcxr_runtime_server <address>, e.g. 127.0.0.1:8765");
+            std::process::exit(1);
+        }
+    };
+    if let Err(error) = evcxr::run_runtime_server(&address) {
+        eprintln!("{}", error);
+        std::process::exit(1);
+    }
+}
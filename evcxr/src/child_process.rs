@@ -14,103 +14,313 @@
 
 use crate::errors::bail;
 use crate::errors::Error;
-use crate::runtime;
-use std::io::BufReader;
-use std::process;
+use crate::transport::Interrupter;
+use crate::transport::Message;
+use crate::transport::RuntimeOutput;
+use crate::transport::RuntimeProcess;
+use crate::transport::StdinWriter;
+use crate::transport::Transport;
+use crossbeam_channel::RecvTimeoutError;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
 
 pub(crate) struct ChildProcess {
-    process: std::process::Child,
-    stdout: std::io::Lines<BufReader<std::process::ChildStdout>>,
-    // Only none while in drop.
-    stdin: Option<std::process::ChildStdin>,
-    command: Arc<Mutex<process::Command>>,
+    /// How we start the subprocess. Shared with any subprocesses that we restart as.
+    transport: Arc<Mutex<Box<dyn Transport>>>,
+    process: Box<dyn RuntimeProcess>,
+    /// What the subprocess writes to stdout, together with the control messages that it sends, in
+    /// order. These are read on a separate thread so that we can stop waiting for them if a
+    /// deadline is reached.
+    output: crossbeam_channel::Receiver<ChildOutput>,
     stderr_sender: Arc<Mutex<crossbeam_channel::Sender<String>>>,
+    /// Interrupts the current subprocess. Shared with any `InterruptHandle`s and updated when we
+    /// restart.
+    interrupter: Arc<Mutex<Interrupter>>,
+    /// Writes to the current subprocess's stdin. Shared with any `StdinHandle`s and updated when
+    /// we restart.
+    stdin_writer: Arc<Mutex<StdinWriter>>,
+    resource_limits: ResourceLimits,
+    /// Set if our subprocess reported that a memory allocation failed.
+    allocation_failed: Arc<AtomicBool>,
+    /// Receives a value each time a checkpoint in the subprocess writes `runtime::STDERR_SYNC`.
+    stderr_synced: crossbeam_channel::Receiver<()>,
+}
+
+/// Limits on the resources that the subprocess may consume. Applied when the subprocess starts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(crate) struct ResourceLimits {
+    pub(crate) memory_limit_mb: Option<u64>,
+    pub(crate) cpu_limit_secs: Option<u64>,
+}
+
+impl ResourceLimits {
+    /// Applies these limits to the current process. Called in the subprocess between fork and
+    /// exec, so mustn't allocate or acquire locks.
+    #[cfg(unix)]
+    pub(crate) fn apply(self) -> std::io::Result<()> {
+        if let Some(memory_limit_mb) = self.memory_limit_mb {
+            let bytes = memory_limit_mb.saturating_mul(1024 * 1024) as libc::rlim_t;
+            let limit = libc::rlimit {
+                rlim_cur: bytes,
+                rlim_max: bytes,
+            };
+            if unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        if let Some(cpu_limit_secs) = self.cpu_limit_secs {
+            // We only set the soft limit, so that exceeding it gets us SIGXCPU, which tells us the
+            // cause, rather than SIGKILL. Leaving the hard limit alone also lets the runtime move
+            // the soft limit before each execution, since the limit is on the total CPU time used
+            // by the process. See runtime::restart_cpu_limit.
+            let mut limit = libc::rlimit {
+                rlim_cur: 0,
+                rlim_max: 0,
+            };
+            if unsafe { libc::getrlimit(libc::RLIMIT_CPU, &mut limit) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            limit.rlim_cur = std::cmp::min(cpu_limit_secs as libc::rlim_t, limit.rlim_max);
+            if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) } != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A point in time after which we stop waiting for user code to complete and kill the subprocess.
+pub(crate) struct Deadline {
+    timeout: Duration,
+    expires_at: Instant,
+}
+
+impl Deadline {
+    pub(crate) fn after(timeout: Duration) -> Deadline {
+        Deadline {
+            timeout,
+            expires_at: Instant::now() + timeout,
+        }
+    }
+
+    /// Pushes the deadline back. Used when we spend time waiting on something other than the user
+    /// code, e.g. for the user to provide input.
+    pub(crate) fn extend(&mut self, duration: Duration) {
+        self.expires_at += duration;
+    }
+}
+
+/// Formats a memory limit in the units that make it most readable.
+pub(crate) fn format_memory_limit(memory_limit_mb: u64) -> String {
+    if memory_limit_mb >= 1024 && memory_limit_mb % 1024 == 0 {
+        format!("{}GB", memory_limit_mb / 1024)
+    } else {
+        format!("{}MB", memory_limit_mb)
+    }
+}
+
+/// Allows user code that is running in the subprocess to be interrupted. Can be used from a
+/// different thread to the one that is waiting for the code to finish.
+#[derive(Clone)]
+pub struct InterruptHandle {
+    interrupter: Arc<Mutex<Interrupter>>,
+}
+
+impl InterruptHandle {
+    /// Interrupts whatever user code is currently running by terminating the process running it.
+    /// If a checkpoint was taken before the code ran, execution resumes from it, otherwise the
+    /// subprocess is restarted. If nothing is running, this does nothing.
+    pub fn interrupt(&self) -> Result<(), Error> {
+        (self.interrupter.lock().unwrap())()
+    }
+}
+
+/// Allows input to be provided to user code that reads from stdin. Can be used from a different
+/// thread to the one that is waiting for the code to finish.
+#[derive(Clone)]
+pub struct StdinHandle {
+    stdin_writer: Arc<Mutex<StdinWriter>>,
+}
+
+impl StdinHandle {
+    /// Writes `data` to the stdin of the subprocess in which user code runs. Anything that isn't
+    /// read by the code that's currently running is left for code that runs later.
+    pub fn write(&self, data: &[u8]) -> Result<(), Error> {
+        if let Err(error) = (self.stdin_writer.lock().unwrap())(Some(data)) {
+            bail!("Failed to write to subprocess stdin: {}", error);
+        }
+        Ok(())
+    }
+
+    /// Closes the stdin of the subprocess in which user code runs, so that the code that's
+    /// currently running sees the end of its input. Code that runs later sees the end of its input
+    /// too, until the subprocess is restarted.
+    pub fn close(&self) -> Result<(), Error> {
+        if let Err(error) = (self.stdin_writer.lock().unwrap())(None) {
+            bail!("Failed to close subprocess stdin: {}", error);
+        }
+        Ok(())
+    }
+}
+
+pub(crate) enum ChildOutput {
+    Stdout(String),
+    Message(Message),
 }
 
 impl ChildProcess {
     pub(crate) fn new(
-        mut command: std::process::Command,
+        transport: Box<dyn Transport>,
         stderr_sender: crossbeam_channel::Sender<String>,
     ) -> Result<ChildProcess, Error> {
-        // Avoid a fork bomb. We could call runtime_hook here but then all the work that we did up
-        // to this point would be wasted. Also, it's possible that we could already have started
-        // threads, which could get messy.
-        if std::env::var(runtime::EVCXR_IS_RUNTIME_VAR).is_ok() {
-            bail!("Our current binary doesn't call runtime_hook()");
-        }
-        command
-            .env(runtime::EVCXR_IS_RUNTIME_VAR, "1")
-            .env("RUST_BACKTRACE", "1")
-            .stdin(std::process::Stdio::piped())
-            .stdout(std::process::Stdio::piped())
-            .stderr(std::process::Stdio::piped());
+        // Replaced once the subprocess has started.
+        let interrupter: Interrupter = Box::new(|| Ok(()));
+        let stdin_writer: StdinWriter = Box::new(|_| Ok(()));
         ChildProcess::new_internal(
-            Arc::new(Mutex::new(command)),
+            Arc::new(Mutex::new(transport)),
             Arc::new(Mutex::new(stderr_sender)),
+            Arc::new(Mutex::new(interrupter)),
+            Arc::new(Mutex::new(stdin_writer)),
+            ResourceLimits::default(),
         )
     }
 
     fn new_internal(
-        command: Arc<Mutex<std::process::Command>>,
+        transport: Arc<Mutex<Box<dyn Transport>>>,
         stderr_sender: Arc<Mutex<crossbeam_channel::Sender<String>>>,
+        interrupter: Arc<Mutex<Interrupter>>,
+        stdin_writer: Arc<Mutex<StdinWriter>>,
+        resource_limits: ResourceLimits,
     ) -> Result<ChildProcess, Error> {
-        let process = command.lock().unwrap().spawn();
-        let mut process = match process {
-            Ok(c) => c,
-            Err(error) => bail!("Failed to run '{:?}': {:?}", command, error),
-        };
-
-        let stdout = std::io::BufRead::lines(BufReader::new(process.stdout.take().unwrap()));
+        let connection = transport.lock().unwrap().start(resource_limits)?;
+        *interrupter.lock().unwrap() = connection.process.interrupter();
+        *stdin_writer.lock().unwrap() = connection.process.stdin_writer();
 
-        // Handle stderr by patching it through to a channel in our output struct.
-        let mut child_stderr =
-            std::io::BufRead::lines(BufReader::new(process.stderr.take().unwrap()));
+        // Stderr is passed straight through to a channel in our output struct. Everything else is
+        // passed to whoever's waiting for the subprocess to finish what it's doing.
+        let (output_sender, output) = crossbeam_channel::unbounded();
+        let runtime_output = connection.output;
+        let allocation_failed = Arc::new(AtomicBool::new(false));
+        let (stderr_synced_sender, stderr_synced) = crossbeam_channel::unbounded();
         std::thread::spawn({
             let stderr_sender = Arc::clone(&stderr_sender);
+            let allocation_failed = Arc::clone(&allocation_failed);
             move || {
                 let stderr_sender = stderr_sender.lock().unwrap();
-                while let Some(Ok(line)) = child_stderr.next() {
-                    // Ignore errors, since it just means that the user of the library has dropped the receive end.
-                    let _ = stderr_sender.send(line);
+                for runtime_output in runtime_output {
+                    let output = match runtime_output {
+                        RuntimeOutput::Stderr(line) => {
+                            if line == crate::runtime::STDERR_SYNC {
+                                let _ = stderr_synced_sender.send(());
+                                continue;
+                            }
+                            // This is what the default allocation error handler prints before
+                            // aborting.
+                            if line.starts_with("memory allocation of ") {
+                                allocation_failed.store(true, Ordering::SeqCst);
+                            }
+                            // Ignore errors, since it just means that the user of the library has
+                            // dropped the receive end.
+                            let _ = stderr_sender.send(line);
+                            continue;
+                        }
+                        RuntimeOutput::Stdout(line) => ChildOutput::Stdout(line),
+                        RuntimeOutput::Message(message) => ChildOutput::Message(message),
+                    };
+                    let _ = output_sender.send(output);
                 }
             }
         });
 
-        let stdin = process.stdin.take();
         Ok(ChildProcess {
-            process,
-            stdout,
-            stdin,
-            command,
+            transport,
+            process: connection.process,
+            output,
             stderr_sender,
+            interrupter,
+            stdin_writer,
+            resource_limits,
+            allocation_failed,
+            stderr_synced,
         })
     }
 
+    pub(crate) fn resource_limits(&self) -> ResourceLimits {
+        self.resource_limits
+    }
+
+    /// Sets the resource limits for the subprocess. These only take effect when the subprocess is
+    /// next started.
+    pub(crate) fn set_resource_limits(&mut self, limits: ResourceLimits) {
+        self.resource_limits = limits;
+    }
+
+    pub(crate) fn interrupt_handle(&self) -> InterruptHandle {
+        InterruptHandle {
+            interrupter: Arc::clone(&self.interrupter),
+        }
+    }
+
     /// Terminates this process if it hasn't already, then restarts
     pub(crate) fn restart(&mut self) -> Result<ChildProcess, Error> {
-        // If the process hasn't already terminated for some reason, kill it.
-        if let Ok(None) = self.process.try_wait() {
-            let _ = self.process.kill();
-            let _ = self.process.wait();
-        }
-        ChildProcess::new_internal(Arc::clone(&self.command), Arc::clone(&self.stderr_sender))
+        self.process.kill();
+        ChildProcess::new_internal(
+            Arc::clone(&self.transport),
+            Arc::clone(&self.stderr_sender),
+            Arc::clone(&self.interrupter),
+            Arc::clone(&self.stdin_writer),
+            self.resource_limits,
+        )
     }
 
-    pub(crate) fn send(&mut self, command: &str) -> Result<(), Error> {
-        use std::io::Write;
-        writeln!(self.stdin.as_mut().unwrap(), "{}", command)
-            .map_err(|_| self.get_termination_error())?;
-        self.stdin.as_mut().unwrap().flush()?;
-        Ok(())
+    /// Makes the file at `path` available to the subprocess, which might not share our
+    /// filesystem. Returns the path by which the subprocess can access it.
+    pub(crate) fn provide_file(&mut self, path: &Path) -> Result<PathBuf, Error> {
+        self.process.provide_file(path)
+    }
+
+    /// Sends a control message made up of `fields` to the subprocess.
+    pub(crate) fn send(&mut self, fields: &[&[u8]]) -> Result<(), Error> {
+        self.process
+            .send_message(fields)
+            .map_err(|_| self.get_termination_error())
     }
 
-    pub(crate) fn recv_line(&mut self) -> Result<String, Error> {
-        Ok(self
-            .stdout
-            .next()
-            .ok_or_else(|| self.get_termination_error())??)
+    pub(crate) fn write_stdin(&mut self, data: &[u8]) -> Result<(), Error> {
+        let result = (self.stdin_writer.lock().unwrap())(Some(data));
+        result.map_err(|_| self.get_termination_error())
+    }
+
+    pub(crate) fn stdin_handle(&self) -> StdinHandle {
+        StdinHandle {
+            stdin_writer: Arc::clone(&self.stdin_writer),
+        }
+    }
+
+    /// Receives the next line of stdout or control message from the subprocess. If `deadline`
+    /// passes before we get one, the subprocess is killed.
+    pub(crate) fn recv(&mut self, deadline: Option<&Deadline>) -> Result<ChildOutput, Error> {
+        if let Some(deadline) = deadline {
+            match self.output.recv_deadline(deadline.expires_at) {
+                Ok(output) => Ok(output),
+                Err(RecvTimeoutError::Timeout) => {
+                    self.process.kill();
+                    Err(Error::SubprocessTerminated(format!(
+                        "Subprocess killed after {}s timeout",
+                        deadline.timeout.as_secs()
+                    )))
+                }
+                Err(RecvTimeoutError::Disconnected) => Err(self.get_termination_error()),
+            }
+        } else {
+            self.output.recv().map_err(|_| self.get_termination_error())
+        }
     }
 
     fn get_termination_error(&mut self) -> Error {
@@ -119,16 +329,28 @@ impl ChildProcess {
         // just wait until we can aquire it, then drop it straight away.
         std::mem::drop(self.stderr_sender.lock().unwrap());
         let mut content = String::new();
-        while let Some(Ok(line)) = self.stdout.next() {
-            content.push_str(&line);
-            content.push('\n');
+        while let Ok(output) = self.output.recv() {
+            if let ChildOutput::Stdout(line) = output {
+                content.push_str(&line);
+                content.push('\n');
+            }
         }
         Error::SubprocessTerminated(match self.process.wait() {
-            Ok(exit_status) => {
+            Ok(termination) => {
+                if let Some(reason) = self.limit_exceeded_reason(termination.signal) {
+                    return Error::SubprocessTerminated(format!("{}{}", content, reason));
+                }
+                #[cfg(unix)]
+                if termination.signal == Some(libc::SIGINT) {
+                    return Error::SubprocessTerminated(format!(
+                        "{}{}",
+                        content,
+                        crate::runtime::EXECUTION_INTERRUPTED
+                    ));
+                }
                 #[cfg(target_os = "macos")]
                 {
-                    use std::os::unix::process::ExitStatusExt;
-                    if Some(9) == exit_status.signal() {
+                    if Some(9) == termination.signal {
                         return Error::SubprocessTerminated(
                             "Subprocess terminated with signal 9. This is known \
                             to happen when evcxr is installed via a Homebrew shell \
@@ -140,19 +362,60 @@ impl ChildProcess {
                 }
                 format!(
                     "{}Subprocess terminated with status: {}",
-                    content, exit_status
+                    content, termination.description
                 )
             }
             Err(wait_error) => format!("Subprocess didn't start: {}", wait_error),
         })
     }
+
+    /// Called when a checkpoint in the subprocess has taken over from the process that was
+    /// running user code, which was killed by `signal`, if it was killed by a signal. If that was
+    /// due to it exceeding one of our resource limits, returns a description of which one.
+    pub(crate) fn checkpoint_limit_exceeded_reason(&self, signal: Option<i32>) -> Option<String> {
+        // Anything that the process wrote to stderr before it died, such as the message printed
+        // when a memory allocation fails, comes before the checkpoint's marker.
+        let _ = self.stderr_synced.recv_timeout(Duration::from_secs(1));
+        let reason = self.limit_exceeded_reason(signal);
+        // Unlike when the whole subprocess terminates, we carry on with the same one.
+        self.allocation_failed.store(false, Ordering::SeqCst);
+        reason
+    }
+
+    /// If the subprocess, or the process within it that was running user code, appears to have
+    /// been terminated by `signal` due to exceeding one of our resource limits, returns a
+    /// description of which one.
+    fn limit_exceeded_reason(&self, signal: Option<i32>) -> Option<String> {
+        let limits = self.resource_limits();
+        if let Some(memory_limit_mb) = limits.memory_limit_mb {
+            if self.allocation_failed.load(Ordering::SeqCst) {
+                return Some(format!(
+                    "Subprocess exceeded {} memory limit",
+                    format_memory_limit(memory_limit_mb)
+                ));
+            }
+        }
+        // The kernel only sends SIGXCPU once the CPU time limit has been reached.
+        #[cfg(unix)]
+        if let Some(cpu_limit_secs) = limits.cpu_limit_secs {
+            if signal == Some(libc::SIGXCPU) {
+                return Some(format!(
+                    "Subprocess exceeded {}s CPU time limit",
+                    cpu_limit_secs
+                ));
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = signal;
+        None
+    }
 }
 
 impl Drop for ChildProcess {
     fn drop(&mut self) {
-        // Drop child_stdin before we wait. Our subprocess uses stdin being
-        // closed to know that it's time to terminate.
-        self.stdin.take();
+        // Our subprocess uses its control channel being closed to know that it's time to
+        // terminate.
+        self.process.close();
         // Wait for our subprocess to terminate. Otherwise we'll be left with
         // zombie processes.
         let _ = self.process.wait();
@@ -13,7 +13,10 @@
 // limitations under the License.
 
 use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
 
+use crate::child_process::format_memory_limit;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
 use crate::code_block::CommandCall;
@@ -27,11 +30,14 @@ use crate::errors::Span;
 use crate::errors::SpannedMessage;
 use crate::eval_context::ContextState;
 use crate::eval_context::EvalCallbacks;
+use crate::inspect::VariableInspection;
 use crate::rust_analyzer::Completion;
 use crate::rust_analyzer::Completions;
 use crate::EvalContext;
 use crate::EvalContextOutputs;
 use crate::EvalOutputs;
+use crate::InterruptHandle;
+use crate::StdinHandle;
 use anyhow::Result;
 use once_cell::sync::OnceCell;
 
@@ -84,6 +90,18 @@ impl CommandContext {
         self.eval_context.variables_and_types()
     }
 
+    /// Returns the type, size and a preview of the value of each variable, sorted by name. This
+    /// requires compiling and running code in the subprocess.
+    pub fn inspect_variables(&mut self) -> Result<Vec<VariableInspection>, Error> {
+        self.eval_context.inspect_variables()
+    }
+
+    /// Returns a number that changes whenever variables may have been defined, removed or changed.
+    /// See `EvalContext::variables_version`.
+    pub fn variables_version(&self) -> u64 {
+        self.eval_context.variables_version()
+    }
+
     pub fn reset_config(&mut self) {
         self.eval_context.reset_config();
     }
@@ -169,6 +187,22 @@ Panic detected. Here's some useful information if you're filing a bug report.
         self.eval_context.last_source()
     }
 
+    /// Makes sure that all output written by the subprocess so far has been sent to our output
+    /// channels. See `EvalContext::flush_output`.
+    pub fn flush_output(&mut self) -> Result<(), Error> {
+        self.eval_context.flush_output()
+    }
+
+    /// Returns a handle that can be used to interrupt execution from another thread.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.eval_context.interrupt_handle()
+    }
+
+    /// Returns a handle that can be used to provide input to user code that reads from stdin.
+    pub fn stdin_handle(&self) -> StdinHandle {
+        self.eval_context.stdin_handle()
+    }
+
     /// Returns completions within `src` at `position`, which should be a byte offset. Note, this
     /// function requires &mut self because it mutates internal state in order to determine
     /// completions. It also assumes exclusive access to those resources. However there should be
@@ -361,14 +395,16 @@ Panic detected. Here's some useful information if you're filing a bug report.
             }),
             AvailableCommand::new(
                 ":vars",
-                "List bound variables and their types",
+                "List bound variables with their types and values",
                 |ctx, _state, _args| {
+                    let variables = ctx.eval_context.inspect_variables()?;
                     Ok(EvalOutputs::text_html(
-                        ctx.vars_as_text(),
-                        ctx.vars_as_html(),
+                        vars_as_text(&variables),
+                        vars_as_html(&variables),
                     ))
                 },
-            ),
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":preserve_vars_on_panic",
                 "Try to keep vars on panic (0/1)",
@@ -381,6 +417,69 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     ))
                 },
             ),
+            AvailableCommand::new(
+                ":checkpoint",
+                "Checkpoint before running code, so that crashes don't lose variables (0/1)",
+                |_ctx, state, args| {
+                    if let Some(arg) = args {
+                        state.set_checkpoint(arg == "1")?;
+                    }
+                    if state.checkpoint() && state.checkpoint_unavailable() {
+                        return text_output(
+                            "Checkpoint: true (unavailable, so only preserving variables on panic)",
+                        );
+                    }
+                    text_output(format!("Checkpoint: {}", state.checkpoint()))
+                },
+            ),
+            AvailableCommand::new(
+                ":promote_borrowed_vars",
+                "Leak variables that are borrowed by other variables, so the borrows persist (0/1)",
+                |_ctx, state, args| {
+                    state.set_promote_borrowed_vars(args.as_ref().map(String::as_str) == Some("1"));
+                    text_output(format!(
+                        "Promote borrowed vars: {}",
+                        state.promote_borrowed_vars()
+                    ))
+                },
+            ),
+            AvailableCommand::new(
+                ":warnings",
+                "Set or toggle whether compiler warnings are shown for evaluated code (0/1)",
+                |_ctx, state, args| {
+                    let show_warnings = match args.as_deref().map(str::trim) {
+                        None | Some("") => !state.show_warnings(),
+                        Some("0") => false,
+                        Some("1") => true,
+                        Some(arg) => bail!(":warnings expects 0 or 1, got '{}'", arg),
+                    };
+                    state.set_show_warnings(show_warnings);
+                    text_output(format!("Show warnings: {}", state.show_warnings()))
+                },
+            ),
+            AvailableCommand::new(
+                ":undo_steps",
+                "Set/print how many executions can be undone (default 0)",
+                |_ctx, state, args| {
+                    if let Some(arg) = args {
+                        state.set_undo_steps(parse_count(arg)?);
+                    }
+                    text_output(format!("Undo steps: {}", state.undo_steps()))
+                },
+            ),
+            AvailableCommand::new(
+                ":undo",
+                "Undo the last n executions (default 1)",
+                |ctx, state, args| {
+                    ctx.eval_context.undo(parse_undo_count(args)?)?;
+                    *state = ctx.eval_context.state();
+                    Ok(EvalOutputs::new())
+                },
+            )
+            .with_analysis_callback(|ctx, state, args| {
+                *state = ctx.eval_context.undone_state(parse_undo_count(args)?)?;
+                Ok(EvalOutputs::default())
+            }),
             AvailableCommand::new(
                 ":clear",
                 "Clear all state, keeping compilation cache",
@@ -395,11 +494,104 @@ Panic detected. Here's some useful information if you're filing a bug report.
                 *state = ctx.eval_context.cleared_state();
                 Ok(EvalOutputs::default())
             }),
+            AvailableCommand::new(
+                ":forget",
+                "Forget a variable, dropping its value. e.g. :forget x",
+                |ctx, state, args| {
+                    let name = required_arg(":forget", args)?;
+                    ctx.eval_context.forget_variable(name)?;
+                    state.forget_variable(name);
+                    Ok(EvalOutputs::new())
+                },
+            )
+            .with_analysis_callback(|_ctx, state, args| {
+                let name = required_arg(":forget", args)?;
+                if !state.forget_variable(name) {
+                    bail!("No variable named `{}`", name);
+                }
+                Ok(EvalOutputs::default())
+            }),
+            AvailableCommand::new(
+                ":remove_item",
+                "Remove a previously defined item. e.g. :remove_item foo",
+                |_ctx, state, args| {
+                    state.remove_item(required_arg(":remove_item", args)?)?;
+                    Ok(EvalOutputs::new())
+                },
+            ),
+            AvailableCommand::new(
+                ":undep",
+                "Remove a dependency. e.g. :undep regex",
+                |_ctx, state, args| {
+                    state.remove_dep(required_arg(":undep", args)?)?;
+                    Ok(EvalOutputs::new())
+                },
+            ),
             AvailableCommand::new(
                 ":dep",
                 "Add dependency. e.g. :dep regex = \"1.0\"",
                 |_ctx, state, args| process_dep_command(state, args),
             ),
+            AvailableCommand::new(
+                ":export_crate",
+                "Write the session as a crate in the specified directory",
+                |_ctx, state, args| {
+                    let dir = if let Some(dir) = args {
+                        dir
+                    } else {
+                        bail!(":export_crate requires a directory");
+                    };
+                    crate::export::export_crate(state, Path::new(dir))?;
+                    text_output(format!("Exported crate to {}", dir))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":save_session",
+                "Save items, deps, config and serializable variables to the specified file",
+                |ctx, _state, args| {
+                    let path = required_arg(":save_session", args)?;
+                    let skipped = ctx.eval_context.save_session(Path::new(path))?;
+                    if skipped.is_empty() {
+                        text_output(format!("Saved session to {}", path))
+                    } else {
+                        text_output(format!(
+                            "Saved session to {}. Skipped variables that don't implement \
+                             Serialize + DeserializeOwned: {}",
+                            path,
+                            skipped.join(", ")
+                        ))
+                    }
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":load_session",
+                "Load a session saved with :save_session",
+                |ctx, state, args| {
+                    let path = required_arg(":load_session", args)?;
+                    let outputs = ctx
+                        .eval_context
+                        .load_session(Path::new(path), state.clone())?;
+                    *state = ctx.eval_context.state();
+                    Ok(outputs)
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":export_test",
+                "Write the session as an integration test to the specified file",
+                |_ctx, state, args| {
+                    let path = if let Some(path) = args {
+                        path
+                    } else {
+                        bail!(":export_test requires a file name");
+                    };
+                    crate::export::export_test(state, Path::new(path))?;
+                    text_output(format!("Exported test to {}", path))
+                },
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":last_compile_dir",
                 "Print the directory in which we last compiled",
@@ -463,6 +655,59 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("Offline mode: {}", state.offline_mode()))
                 },
             ),
+            AvailableCommand::new(
+                ":timeout",
+                "Set/print how many seconds code may run before being killed (0 for no limit)",
+                |_ctx, state, args| {
+                    if let Some(arg) = args {
+                        state.set_timeout(parse_limit(arg)?.map(Duration::from_secs));
+                    }
+                    text_output(format!(
+                        "Timeout: {}",
+                        state
+                            .timeout()
+                            .map(|timeout| format!("{}s", timeout.as_secs()))
+                            .unwrap_or_else(|| "none".to_owned())
+                    ))
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":memory_limit",
+                "Set/print the memory limit in MB for evaluated code (0 for no limit)",
+                |ctx, state, args| {
+                    if let Some(arg) = args {
+                        state.set_memory_limit_mb(parse_limit(arg)?);
+                    }
+                    let message = format!(
+                        "Memory limit: {}",
+                        state
+                            .memory_limit_mb()
+                            .map(format_memory_limit)
+                            .unwrap_or_else(|| "none".to_owned())
+                    );
+                    apply_resource_limits(ctx, state, message)
+                },
+            )
+            .disable_in_analysis(),
+            AvailableCommand::new(
+                ":cpu_limit",
+                "Set/print the CPU time limit in seconds for evaluated code (0 for no limit)",
+                |ctx, state, args| {
+                    if let Some(arg) = args {
+                        state.set_cpu_limit_secs(parse_limit(arg)?);
+                    }
+                    let message = format!(
+                        "CPU limit: {}",
+                        state
+                            .cpu_limit_secs()
+                            .map(|secs| format!("{}s", secs))
+                            .unwrap_or_else(|| "none".to_owned())
+                    );
+                    apply_resource_limits(ctx, state, message)
+                },
+            )
+            .disable_in_analysis(),
             AvailableCommand::new(
                 ":quit",
                 "Quit evaluation and exit",
@@ -503,6 +748,16 @@ Panic detected. Here's some useful information if you're filing a bug report.
                     text_output(format!("linker: {}", state.linker()))
                 },
             ),
+            AvailableCommand::new(
+                ":async_runtime",
+                "Set/print async runtime. Supported: tokio, tokio-current-thread, async-std, smol",
+                |_ctx, state, args| {
+                    if let Some(runtime) = args {
+                        state.set_async_runtime(runtime)?;
+                    }
+                    text_output(format!("Async runtime: {}", state.async_runtime()))
+                },
+            ),
             AvailableCommand::new(
                 ":explain",
                 "Print explanation of last error",
@@ -555,31 +810,110 @@ Panic detected. Here's some useful information if you're filing a bug report.
             }),
         ]
     }
+}
+
+/// The maximum number of lines of a value's preview to include in text output.
+const MAX_TEXT_PREVIEW_LINES: usize = 20;
 
-    fn vars_as_text(&self) -> String {
-        let mut out = String::new();
-        for (var, ty) in self.eval_context.variables_and_types() {
-            out.push_str(var);
-            out.push_str(": ");
-            out.push_str(ty);
+fn vars_as_text(variables: &[VariableInspection]) -> String {
+    let mut out = String::new();
+    for variable in variables {
+        out.push_str(&format!(
+            "{}: {} ({})",
+            variable.name,
+            variable.type_name,
+            variable_details(variable)
+        ));
+        if let Some(preview) = &variable.preview {
+            out.push_str(" = ");
+            for (index, line) in preview.lines().enumerate() {
+                if index == MAX_TEXT_PREVIEW_LINES {
+                    out.push_str("    ...\n");
+                    break;
+                }
+                out.push_str(line);
+                out.push('\n');
+            }
+        } else {
             out.push('\n');
         }
-        out
-    }
-
-    fn vars_as_html(&self) -> String {
-        let mut out = String::new();
-        out.push_str("<table><tr><th>Variable</th><th>Type</th></tr>");
-        for (var, ty) in self.eval_context.variables_and_types() {
-            out.push_str("<tr><td>");
-            html_escape(var, &mut out);
-            out.push_str("</td><td>");
-            html_escape(ty, &mut out);
-            out.push_str("</td><tr>");
+    }
+    out
+}
+
+fn vars_as_html(variables: &[VariableInspection]) -> String {
+    let mut out = String::new();
+    out.push_str("<table><tr><th>Variable</th><th>Type</th><th>Details</th><th>Value</th></tr>");
+    for variable in variables {
+        out.push_str("<tr><td>");
+        html_escape(&variable.name, &mut out);
+        out.push_str("</td><td>");
+        html_escape(&variable.type_name, &mut out);
+        out.push_str("</td><td>");
+        html_escape(&variable_details(variable), &mut out);
+        out.push_str("</td><td style=\"text-align: left\">");
+        if let Some(preview) = &variable.preview {
+            debug_tree_html(preview, &mut out);
         }
-        out.push_str("</table>");
-        out
+        out.push_str("</td></tr>");
+    }
+    out.push_str("</table>");
+    out
+}
+
+/// Returns the size of `variable` followed by whether it's mutable, Copy and/or promoted.
+fn variable_details(variable: &VariableInspection) -> String {
+    let mut details = vec![format!(
+        "{} byte{}",
+        variable.size,
+        if variable.size == 1 { "" } else { "s" }
+    )];
+    if variable.is_mut {
+        details.push("mut".to_owned());
+    }
+    if variable.is_copy {
+        details.push("Copy".to_owned());
     }
+    if variable.is_promoted {
+        details.push("promoted".to_owned());
+    }
+    details.join(", ")
+}
+
+/// Renders the output of `{:#?}` as a tree in which each struct, tuple, list etc can be expanded
+/// and collapsed. Pretty-printed Debug output puts the contents of each of these on separate
+/// lines, between a line that ends with an opening bracket and one that starts with the matching
+/// closing bracket.
+fn debug_tree_html(preview: &str, out: &mut String) {
+    let mut depth = 0;
+    out.push_str("<pre style=\"margin: 0\">");
+    for line in preview.lines() {
+        let trimmed = line.trim();
+        if depth > 0 && trimmed.starts_with(|c| matches!(c, '}' | ']' | ')')) {
+            out.push_str("</div>");
+            html_escape(trimmed, out);
+            out.push_str("</details>");
+            depth -= 1;
+        } else if trimmed.ends_with(|c| matches!(c, '{' | '[' | '(')) {
+            // The top level starts out expanded.
+            out.push_str(if depth == 0 {
+                "<details open><summary>"
+            } else {
+                "<details><summary>"
+            });
+            html_escape(trimmed, out);
+            out.push_str("</summary><div style=\"margin-left: 2em\">");
+            depth += 1;
+        } else {
+            html_escape(trimmed, out);
+            out.push('\n');
+        }
+    }
+    // A truncated preview might not have closed everything.
+    for _ in 0..depth {
+        out.push_str("</div></details>");
+    }
+    out.push_str("</pre>");
 }
 
 fn process_dep_command(
@@ -605,6 +939,63 @@ fn process_dep_command(
     }
 }
 
+/// Returns the argument that `command` was given, which is required.
+fn required_arg<'a>(command: &str, args: &'a Option<String>) -> Result<&'a str, Error> {
+    match args.as_deref().map(str::trim) {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => bail!("{} requires an argument", command),
+    }
+}
+
+/// Parses the argument to `:undo`. Defaults to undoing a single execution.
+fn parse_undo_count(args: &Option<String>) -> Result<usize, Error> {
+    match args {
+        Some(arg) => parse_count(arg),
+        None => Ok(1),
+    }
+}
+
+fn parse_count(arg: &str) -> Result<usize, Error> {
+    match arg.trim().parse() {
+        Ok(count) => Ok(count),
+        Err(_) => bail!("Expected a whole number, got '{}'", arg),
+    }
+}
+
+/// Parses the argument to one of the limit commands. Zero means no limit.
+fn parse_limit(arg: &str) -> Result<Option<u64>, Error> {
+    match arg.trim().parse() {
+        Ok(0) => Ok(None),
+        Ok(limit) => Ok(Some(limit)),
+        Err(_) => bail!("Expected a whole number, got '{}'", arg),
+    }
+}
+
+#[cfg(unix)]
+fn apply_resource_limits(
+    ctx: &mut CommandContext,
+    state: &mut ContextState,
+    message: String,
+) -> Result<EvalOutputs, Error> {
+    if ctx.eval_context.apply_resource_limits(state)? {
+        text_output(format!(
+            "{}\nThe subprocess was restarted to apply the limit. Variables have been cleared.",
+            message
+        ))
+    } else {
+        text_output(message)
+    }
+}
+
+#[cfg(not(unix))]
+fn apply_resource_limits(
+    _ctx: &mut CommandContext,
+    _state: &mut ContextState,
+    _message: String,
+) -> Result<EvalOutputs, Error> {
+    bail!("Resource limits aren't supported on this platform");
+}
+
 type CallbackFn = dyn Fn(&mut CommandContext, &mut ContextState, &Option<String>) -> Result<EvalOutputs, Error>
     + 'static
     + Sync
@@ -63,8 +63,50 @@ impl ExternalCrate {
     }
 }
 
+/// Returns `config` with each of `features` enabled, in addition to whatever it already enables.
+pub(crate) fn add_features(config: &str, features: &[&str]) -> String {
+    let config = config.trim();
+    let mut quoted: Vec<String> = features.iter().map(|f| format!("\"{}\"", f)).collect();
+    if quoted.is_empty() {
+        return config.to_owned();
+    }
+    if config.starts_with('"') {
+        return format!(
+            "{{ version = {}, features = [{}] }}",
+            config,
+            quoted.join(", ")
+        );
+    }
+    static FEATURES_RE: OnceCell<Regex> = OnceCell::new();
+    let features_re =
+        FEATURES_RE.get_or_init(|| Regex::new("features *= *\\[([^\\]]*)\\]").unwrap());
+    if let Some(captures) = features_re.captures(config) {
+        let existing = captures[1].trim().trim_end_matches(',');
+        quoted.retain(|feature| !existing.contains(feature.as_str()));
+        if !existing.is_empty() {
+            quoted.insert(0, existing.to_owned());
+        }
+        let all_features = captures.get(0).unwrap();
+        return format!(
+            "{}features = [{}]{}",
+            &config[..all_features.start()],
+            quoted.join(", "),
+            &config[all_features.end()..]
+        );
+    }
+    if let Some(without_brace) = config.strip_suffix('}') {
+        return format!(
+            "{}, features = [{}] }}",
+            without_brace.trim_end(),
+            quoted.join(", ")
+        );
+    }
+    config.to_owned()
+}
+
 #[cfg(test)]
 mod tests {
+    use super::add_features;
     use super::ExternalCrate;
     use std::path::Path;
 
@@ -84,4 +126,27 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn test_add_features() {
+        assert_eq!(
+            add_features("\"1.2\"", &["rt"]),
+            "{ version = \"1.2\", features = [\"rt\"] }"
+        );
+        assert_eq!(
+            add_features("{ version = \"1\" }", &["rt"]),
+            "{ version = \"1\", features = [\"rt\"] }"
+        );
+        assert_eq!(
+            add_features(
+                "{ version = \"1\", features = [\"time\"] }",
+                &["rt", "time"]
+            ),
+            "{ version = \"1\", features = [\"time\", \"rt\"] }"
+        );
+        assert_eq!(
+            add_features("{ version = \"1\", features = [\"full\"] }", &[]),
+            "{ version = \"1\", features = [\"full\"] }"
+        );
+    }
 }
@@ -189,7 +189,7 @@ impl CompilationError {
             Some(match code {
                 "E0597" => {
                     "Values assigned to variables in Evcxr cannot contain references \
-                     (unless they're static)"
+                     (unless they're static). See `:promote_borrowed_vars`."
                 }
                 _ => return None,
             })
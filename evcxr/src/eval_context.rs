@@ -12,7 +12,12 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::child_process::ChildOutput;
 use crate::child_process::ChildProcess;
+use crate::child_process::Deadline;
+use crate::child_process::InterruptHandle;
+use crate::child_process::ResourceLimits;
+use crate::child_process::StdinHandle;
 use crate::code_block::CodeBlock;
 use crate::code_block::CodeKind;
 use crate::code_block::Segment;
@@ -24,13 +29,29 @@ use crate::errors::Error;
 use crate::errors::Span;
 use crate::errors::SpannedMessage;
 use crate::evcxr_internal_runtime;
+use crate::inspect;
+use crate::inspect::VariableInspection;
 use crate::item;
+use crate::item_layers::ItemsCode;
+use crate::migration;
+use crate::migration::ItemDefinitions;
+use crate::migration::Migration;
+use crate::migration::PREVIOUS_TYPES_MODULE;
 use crate::module::Module;
 use crate::module::SoFile;
+use crate::module::ITEMS_CRATE_NAME;
 use crate::runtime;
 use crate::rust_analyzer::Completions;
 use crate::rust_analyzer::RustAnalyzer;
 use crate::rust_analyzer::VariableInfo;
+use crate::session;
+use crate::session::SavedVariable;
+use crate::session::Session;
+use crate::transport::path_to_bytes;
+use crate::transport::LocalTransport;
+use crate::transport::Message;
+use crate::transport::TcpTransport;
+use crate::transport::Transport;
 use crate::use_trees::Import;
 use anyhow::Result;
 use once_cell::sync::OnceCell;
@@ -42,6 +63,7 @@ use ra_ap_syntax::SyntaxNode;
 use regex::Regex;
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
@@ -57,6 +79,11 @@ pub struct EvalContext {
     stdout_sender: crossbeam_channel::Sender<String>,
     analyzer: RustAnalyzer,
     initial_config: Config,
+    /// States from before each of the most recent executions, oldest first. Used by `undo`.
+    undo_states: VecDeque<ContextState>,
+    /// Incremented whenever variables may have been defined, removed or changed. See
+    /// `variables_version`.
+    variables_version: u64,
 }
 
 #[derive(Clone, Debug)]
@@ -86,8 +113,30 @@ pub(crate) struct Config {
     /// Whether to attempt to avoid network access.
     pub(crate) offline_mode: bool,
     pub(crate) toolchain: String,
+    /// How long user code is allowed to run before we kill the subprocess.
+    pub(crate) timeout: Option<Duration>,
+    /// Limits applied to the subprocess. Changing these requires restarting the subprocess.
+    pub(crate) resource_limits: ResourceLimits,
+    /// Whether to report compiler warnings for the code being evaluated when compilation succeeds.
+    show_warnings: bool,
+    /// The runtime used to execute code containing top-level `.await`.
+    async_runtime: &'static AsyncRuntime,
+    /// Whether variables that are borrowed by other variables should be moved to a leaked heap
+    /// allocation so that the borrows can be persisted.
+    promote_borrowed_vars: bool,
+    /// Whether to checkpoint the subprocess before running user code, so that if the code crashes
+    /// the subprocess, we can carry on from the checkpoint without losing variables. When the
+    /// subprocess can't checkpoint, e.g. because user code has started threads, we fall back to
+    /// preserving variables on panic.
+    checkpoint: bool,
+    /// The maximum number of executions that can be undone. Each one that can be undone keeps a
+    /// copy of the variables that it used, so this is off by default.
+    undo_steps: usize,
 }
 
+/// Whether the subprocess can checkpoint itself before running user code. See runtime.rs.
+const CHECKPOINT_SUPPORTED: bool = cfg!(target_os = "linux");
+
 fn create_initial_config(crate_dir: PathBuf) -> Config {
     let mut config = Config::new(crate_dir);
     // default the linker to mold, then lld, first checking if either are installed
@@ -117,6 +166,13 @@ impl Config {
             sccache: None,
             offline_mode: false,
             toolchain: String::new(),
+            timeout: None,
+            resource_limits: ResourceLimits::default(),
+            show_warnings: true,
+            async_runtime: &ASYNC_RUNTIMES[0],
+            promote_borrowed_vars: false,
+            checkpoint: false,
+            undo_steps: 0,
         }
     }
 
@@ -179,26 +235,81 @@ static ERROR_FORMATS: &[ErrorFormat] = &[
     },
 ];
 
-const SEND_TEXT_PLAIN_DEF: &str = stringify!(
-    fn evcxr_send_text_plain(text: &str) {
-        use std::io::Write;
-        use std::io::{self};
-        fn try_send_text(text: &str) -> io::Result<()> {
-            let stdout = io::stdout();
-            let mut output = stdout.lock();
-            output.write_all(b"EVCXR_BEGIN_CONTENT text/plain\n")?;
-            output.write_all(text.as_bytes())?;
-            output.write_all(b"\nEVCXR_END_CONTENT\n")?;
-            Ok(())
-        }
-        if let Err(error) = try_send_text(text) {
-            eprintln!("Failed to send content to parent: {:?}", error);
-            std::process::exit(1);
+#[derive(Debug)]
+struct AsyncRuntime {
+    /// The name by which the user selects this runtime.
+    name: &'static str,
+    /// The name of the crate that provides the runtime, as it would be passed to `:dep`.
+    crate_name: &'static str,
+    /// Dependency configuration used if the user hasn't already added the crate.
+    default_dep_config: &'static str,
+    /// Features that we need enabled if we're reusing a dependency that the user added.
+    required_features: &'static [&'static str],
+    /// Code to create a runtime object, if the runtime has one. The runtime object is kept
+    /// between executions, so that any tasks spawned onto it continue to run.
+    runtime: Option<&'static str>,
+    /// Function used to run a future to completion if the runtime has no runtime object.
+    block_on: &'static str,
+}
+
+static ASYNC_RUNTIMES: &[AsyncRuntime] = &[
+    AsyncRuntime {
+        name: "tokio",
+        crate_name: "tokio",
+        default_dep_config: "{ version = \"1\", features = [\"full\"] }",
+        required_features: &["rt-multi-thread"],
+        runtime: Some("tokio::runtime::Runtime::new().unwrap()"),
+        block_on: "",
+    },
+    AsyncRuntime {
+        name: "tokio-current-thread",
+        crate_name: "tokio",
+        default_dep_config: "{ version = \"1\", features = [\"full\"] }",
+        required_features: &["rt"],
+        runtime: Some(
+            "tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap()",
+        ),
+        block_on: "",
+    },
+    AsyncRuntime {
+        name: "async-std",
+        crate_name: "async-std",
+        default_dep_config: "\"1\"",
+        required_features: &[],
+        runtime: None,
+        block_on: "async_std::task::block_on",
+    },
+    AsyncRuntime {
+        name: "smol",
+        crate_name: "smol",
+        default_dep_config: "\"2\"",
+        required_features: &[],
+        runtime: None,
+        block_on: "smol::block_on",
+    },
+];
+
+impl AsyncRuntime {
+    /// Returns code that, when followed by an async block in parenthesis, runs that block to
+    /// completion. If `reuse_runtime` is true, then any runtime object is stored in
+    /// `evcxr_variable_store` and reused by later executions.
+    fn block_on_code(&self, reuse_runtime: bool) -> String {
+        match self.runtime {
+            Some(runtime) if reuse_runtime => format!(
+                "evcxr_variable_store.lazy_arc(\"evcxr_async_runtime_{}\", \
+                 || std::sync::Mutex::new({})).lock().unwrap().block_on",
+                self.name.replace('-', "_"),
+                runtime
+            ),
+            Some(runtime) => format!("{}.block_on", runtime),
+            None => self.block_on.to_owned(),
         }
     }
-);
+}
 
-const PANIC_NOTIFICATION: &str = "EVCXR_PANIC_NOTIFICATION";
+/// Lets the parent process know that user code panicked.
+const PANIC_NOTIFICATION_CODE: &str = "    evcxr_internal_runtime::send_control_message(\
+     &[evcxr_internal_runtime::PANIC_OCCURRED.as_bytes()]);";
 
 // Outputs from an EvalContext. This is a separate struct since users may want
 // destructure this and pass its components to separate threads.
@@ -210,28 +321,65 @@ pub struct EvalContextOutputs {
 //#[non_exhaustive]
 pub struct EvalCallbacks<'a> {
     pub input_reader: &'a dyn Fn(&str, bool) -> String,
+    /// Called with each display bundle as soon as it's received, while code is still running. The
+    /// bundles are also included in the returned `EvalOutputs`.
+    pub display_handler: &'a dyn Fn(&DisplayBundle),
 }
 
 fn default_input_reader(_: &str, _: bool) -> String {
     String::new()
 }
 
+fn default_display_handler(_: &DisplayBundle) {}
+
+/// Asks the user for input on behalf of user code. Time spent waiting for the user doesn't count
+/// towards `deadline`.
+fn read_input(
+    prompt: &str,
+    is_password: bool,
+    callbacks: &EvalCallbacks,
+    deadline: &mut Option<Deadline>,
+) -> String {
+    let input_start = Instant::now();
+    let input = (callbacks.input_reader)(prompt, is_password);
+    if let Some(deadline) = deadline {
+        deadline.extend(input_start.elapsed());
+    }
+    input
+}
+
 impl<'a> Default for EvalCallbacks<'a> {
     fn default() -> Self {
         EvalCallbacks {
             input_reader: &default_input_reader,
+            display_handler: &default_display_handler,
         }
     }
 }
 
 impl EvalContext {
+    /// Creates a context that runs code in a subprocess of the current process. If the
+    /// environment variable EVCXR_RUNTIME_SERVER is set, code is instead run by the runtime server
+    /// at that address. See `with_runtime_server`.
     pub fn new() -> Result<(EvalContext, EvalContextOutputs), Error> {
         fix_path();
 
+        if let Ok(address) = std::env::var("EVCXR_RUNTIME_SERVER") {
+            return Self::with_runtime_server(&address);
+        }
         let current_exe = std::env::current_exe()?;
         Self::with_subprocess_command(std::process::Command::new(&current_exe))
     }
 
+    /// Creates a context that compiles code locally, but runs it in a process started by the
+    /// runtime server listening at `address`. See `run_runtime_server`.
+    pub fn with_runtime_server(address: &str) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        let transport = TcpTransport::new(address);
+        Self::with_transport(shared_target_dir_from_env(), |_module| {
+            Ok(Box::new(transport))
+        })
+    }
+
     #[cfg(windows)]
     fn apply_platform_specific_vars(module: &Module, command: &mut std::process::Command) {
         // Windows doesn't support rpath, so we need to set PATH so that it
@@ -257,6 +405,19 @@ impl EvalContext {
 
     #[doc(hidden)]
     pub fn new_for_testing() -> (EvalContext, EvalContextOutputs) {
+        Self::new_for_testing_with_target_dir(None)
+    }
+
+    #[doc(hidden)]
+    pub fn new_for_testing_with_shared_target_dir(
+        target_dir: &Path,
+    ) -> (EvalContext, EvalContextOutputs) {
+        Self::new_for_testing_with_target_dir(Some(target_dir.to_owned()))
+    }
+
+    fn new_for_testing_with_target_dir(
+        shared_target_dir: Option<PathBuf>,
+    ) -> (EvalContext, EvalContextOutputs) {
         let testing_runtime_path = std::env::current_exe()
             .unwrap()
             .parent()
@@ -264,17 +425,55 @@ impl EvalContext {
             .parent()
             .unwrap()
             .join("testing_runtime");
-        let (mut context, outputs) =
-            EvalContext::with_subprocess_command(std::process::Command::new(&testing_runtime_path))
-                .unwrap();
+        let (mut context, outputs) = EvalContext::with_subprocess_command_and_target_dir(
+            std::process::Command::new(&testing_runtime_path),
+            shared_target_dir,
+        )
+        .unwrap();
         let mut state = context.state();
         state.set_offline_mode(true);
         context.commit_state(state);
         (context, outputs)
     }
 
+    /// Creates a context that runs code in a subprocess started with `subprocess_command`. If the
+    /// environment variable EVCXR_TARGET_DIR is set, it's used as a shared target directory. See
+    /// `with_shared_target_dir`.
     pub fn with_subprocess_command(
+        subprocess_command: std::process::Command,
+    ) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        Self::with_subprocess_command_and_target_dir(
+            subprocess_command,
+            shared_target_dir_from_env(),
+        )
+    }
+
+    /// Like `with_subprocess_command`, but builds into `target_dir`, which can be shared with other
+    /// contexts, including ones in other processes. External crates then only need to be compiled
+    /// once for all the contexts that use them with the same configuration.
+    pub fn with_shared_target_dir(
+        subprocess_command: std::process::Command,
+        target_dir: &Path,
+    ) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        Self::with_subprocess_command_and_target_dir(
+            subprocess_command,
+            Some(target_dir.to_owned()),
+        )
+    }
+
+    fn with_subprocess_command_and_target_dir(
         mut subprocess_command: std::process::Command,
+        shared_target_dir: Option<PathBuf>,
+    ) -> Result<(EvalContext, EvalContextOutputs), Error> {
+        Self::with_transport(shared_target_dir, |module| {
+            Self::apply_platform_specific_vars(module, &mut subprocess_command);
+            Ok(Box::new(LocalTransport::new(subprocess_command)?))
+        })
+    }
+
+    fn with_transport(
+        shared_target_dir: Option<PathBuf>,
+        create_transport: impl FnOnce(&Module) -> Result<Box<dyn Transport>, Error>,
     ) -> Result<(EvalContext, EvalContextOutputs), Error> {
         let mut opt_tmpdir = None;
         let tmpdir_path;
@@ -287,13 +486,12 @@ impl EvalContext {
         }
 
         let analyzer = RustAnalyzer::new(&tmpdir_path)?;
-        let module = Module::new(tmpdir_path)?;
-
-        Self::apply_platform_specific_vars(&module, &mut subprocess_command);
+        let module = Module::new(tmpdir_path, shared_target_dir)?;
+        let transport = create_transport(&module)?;
 
         let (stdout_sender, stdout_receiver) = crossbeam_channel::unbounded();
         let (stderr_sender, stderr_receiver) = crossbeam_channel::unbounded();
-        let child_process = ChildProcess::new(subprocess_command, stderr_sender)?;
+        let child_process = ChildProcess::new(transport, stderr_sender)?;
         let initial_config = create_initial_config(module.crate_dir().to_owned());
         let initial_state = ContextState::new(initial_config.clone());
         let mut context = EvalContext {
@@ -304,6 +502,8 @@ impl EvalContext {
             stdout_sender,
             analyzer,
             initial_config,
+            undo_states: VecDeque::new(),
+            variables_version: 0,
         };
         let outputs = EvalContextOutputs {
             stdout: stdout_receiver,
@@ -350,7 +550,7 @@ impl EvalContext {
         state.config.expand_use_statements = false;
         let user_code = state.apply(user_code, &code_info.nodes)?;
         let code = state.analysis_code(user_code.clone());
-        let errors = self.module.check(&code, &state.config)?;
+        let errors = self.module.check(&code, &state, &state.config)?;
         Ok(state.apply_custom_errors(errors, &user_code, code_info))
     }
 
@@ -393,6 +593,16 @@ impl EvalContext {
 
         // Once, we reach here, our code has successfully executed, so we
         // conclude that variable changes are now applied.
+        state.record_pending_cell(&outputs);
+        // Variable queries don't change anything that the user would want to undo.
+        if state.variable_query.is_none() {
+            if state.config.undo_steps > 0 {
+                self.undo_states.push_back(self.committed_state.clone());
+            }
+            while self.undo_states.len() > state.config.undo_steps {
+                self.undo_states.pop_front();
+            }
+        }
         self.commit_state(state);
 
         phases.phase_complete("Execution");
@@ -467,6 +677,14 @@ impl EvalContext {
             .map(|(v, t)| (v.as_str(), t.type_name.as_str()))
     }
 
+    /// Returns a number that changes whenever variables may have been defined, removed or changed,
+    /// so that things showing variables know when they need to inspect them again. Values changed
+    /// by code other than the code being executed, such as threads started by earlier code, aren't
+    /// noticed.
+    pub fn variables_version(&self) -> u64 {
+        self.variables_version
+    }
+
     pub fn defined_item_names(&self) -> impl Iterator<Item = &str> {
         self.committed_state
             .items_by_name
@@ -488,6 +706,182 @@ impl EvalContext {
         ContextState::new(self.committed_state.config.clone())
     }
 
+    /// Restores the state from before the last `count` executions, keeping the current
+    /// configuration. Variable values are restored the next time code is executed. Variables that
+    /// the undone code used, but which don't implement Clone, can't be restored so will be lost.
+    pub fn undo(&mut self, count: usize) -> Result<(), Error> {
+        let state = self.undone_state(count)?;
+        self.undo_states.truncate(self.undo_states.len() - count);
+        self.committed_state = state;
+        self.variables_version += 1;
+        Ok(())
+    }
+
+    /// Saves items, dependencies, config and the values of variables to `path`. Only variables
+    /// whose types implement `serde::Serialize` and `serde::de::DeserializeOwned` can be saved.
+    /// Returns the names of variables that were skipped.
+    pub fn save_session(&mut self, path: &Path) -> Result<Vec<String>, Error> {
+        let mut values = json::JsonValue::new_object();
+        if !self.committed_state.stored_variable_states.is_empty() {
+            let mut state = self.state();
+            state.add_serde_deps()?;
+            values = json::parse(&self.run_variable_query(state, VariableQuery::SaveValues)?)?;
+        }
+        let session = self.committed_state.to_session(&values);
+        session.write(path)?;
+        let mut skipped: Vec<String> = self
+            .committed_state
+            .stored_variable_states
+            .keys()
+            .filter(|name| !values.has_key(name))
+            .cloned()
+            .collect();
+        skipped.sort();
+        Ok(skipped)
+    }
+
+    /// Returns what we can find out about each variable, including a preview of its value.
+    pub(crate) fn inspect_variables(&mut self) -> Result<Vec<VariableInspection>, Error> {
+        if self.committed_state.stored_variable_states.is_empty() {
+            return Ok(Vec::new());
+        }
+        let output = self.run_variable_query(self.state(), VariableQuery::Inspect)?;
+        let variable_states = &self.committed_state.variable_states;
+        inspect::parse_inspection(&output, |name| {
+            variable_states
+                .get(name)
+                .map(|state| (state.type_name.as_str(), state.is_mut, state.is_promoted))
+        })
+    }
+
+    /// Runs code in the subprocess to answer `query`, then commits `state`. Returns what the code
+    /// sent in response.
+    fn run_variable_query(
+        &mut self,
+        mut state: ContextState,
+        query: VariableQuery,
+    ) -> Result<String, Error> {
+        let build_num = state.build_num;
+        state.variable_query = Some(query);
+        let (user_code, code_info) = CodeBlock::from_original_user_code("");
+        let mut outputs =
+            self.eval_with_callbacks(user_code, state, &code_info, &mut EvalCallbacks::default())?;
+        // Keeping the same build number means that our state stays in step with any copy of it that
+        // commands are being applied to.
+        self.committed_state.build_num = build_num;
+        match outputs
+            .content_by_mime_type
+            .remove(evcxr_internal_runtime::VARIABLE_QUERY_MIME_TYPE)
+        {
+            Some(output) => Ok(output),
+            None => bail!("Subprocess didn't respond to variable query"),
+        }
+    }
+
+    /// Loads a session previously saved by `save_session`. Its config and dependencies are
+    /// applied to `state`, then its items and variables are defined by evaluating code. Fails if
+    /// any of the variables couldn't be restored.
+    pub fn load_session(
+        &mut self,
+        path: &Path,
+        mut state: ContextState,
+    ) -> Result<EvalOutputs, Error> {
+        let session = Session::read(path)?;
+        state.apply_session(&session)?;
+        let outputs = self.eval_with_state(&session.restore_code(), state)?;
+        let variable_states = &self.committed_state.variable_states;
+        let missing: Vec<&str> = session
+            .variables
+            .iter()
+            .map(|variable| variable.name.as_str())
+            .filter(|name| !variable_states.contains_key(*name))
+            .collect();
+        if !missing.is_empty() {
+            bail!(
+                "Failed to restore variables: {}. Items, dependencies and config were loaded.",
+                missing.join(", ")
+            );
+        }
+        Ok(outputs)
+    }
+
+    /// Forgets the variable `variable_name`, dropping its value in the subprocess.
+    pub fn forget_variable(&mut self, variable_name: &str) -> Result<(), Error> {
+        if self
+            .committed_state
+            .variable_states
+            .remove(variable_name)
+            .is_none()
+        {
+            bail!("No variable named `{}`", variable_name);
+        }
+        self.committed_state
+            .stored_variable_states
+            .remove(variable_name);
+        self.variables_version += 1;
+        // The value won't exist to be restored, so earlier states mustn't refer to it either.
+        for state in &mut self.undo_states {
+            state.forget_variable(variable_name);
+        }
+        self.child_process
+            .send(&[runtime::DROP_VARIABLE.as_bytes(), variable_name.as_bytes()])?;
+        // Passes on anything printed by the variable's Drop implementation.
+        self.wait_for_completion()
+    }
+
+    /// Makes sure that everything the subprocess has written so far has been sent to our output
+    /// channels. Output written while code runs is always sent before that execution completes,
+    /// but output from threads that keep running afterwards is otherwise only picked up once the
+    /// subprocess is next asked to do something. Calling this before starting another execution
+    /// keeps what was written since the last one finished apart from the output of the next. Output
+    /// that threads write while the next execution runs can't be told apart from its own though.
+    pub fn flush_output(&mut self) -> Result<(), Error> {
+        self.child_process.send(&[runtime::FLUSH.as_bytes()])?;
+        self.wait_for_completion()
+    }
+
+    /// Waits for the subprocess to report that it has finished handling the message that we last
+    /// sent, passing on anything that it writes to stdout in the meantime.
+    fn wait_for_completion(&mut self) -> Result<(), Error> {
+        loop {
+            match self.child_process.recv(None)? {
+                ChildOutput::Message(message) if message.kind() == runtime::EXECUTION_COMPLETE => {
+                    return Ok(());
+                }
+                ChildOutput::Stdout(line) => {
+                    let _ = self.stdout_sender.send(line);
+                }
+                ChildOutput::Message(_) => {}
+            }
+        }
+    }
+
+    /// Returns the state that would result from undoing `count` executions. Nothing is done to
+    /// the subprocess.
+    pub(crate) fn undone_state(&self, count: usize) -> Result<ContextState, Error> {
+        if count == 0 {
+            return Ok(self.state());
+        }
+        if self.committed_state.config.undo_steps == 0 {
+            bail!("Undo is disabled. Use `:undo_steps n` to be able to undo up to n executions.");
+        }
+        if count > self.undo_states.len() {
+            bail!(
+                "Can't undo {} executions, only {} can be undone",
+                count,
+                self.undo_states.len()
+            );
+        }
+        let mut state = self.undo_states[self.undo_states.len() - count].clone();
+        // Snapshots of variable values are identified by the build number of the execution that
+        // they were taken before, which is the build number that the state had at the time.
+        state.restore_snapshot = Some(state.build_num);
+        // Build numbers need to keep increasing, since they're used to name the code we load.
+        state.build_num = self.committed_state.build_num;
+        state.config = self.committed_state.config.clone();
+        Ok(state)
+    }
+
     pub fn reset_config(&mut self) {
         self.committed_state.config = self.initial_config.clone();
     }
@@ -495,10 +889,52 @@ impl EvalContext {
     fn restart_child_process(&mut self) -> Result<(), Error> {
         self.committed_state.variable_states.clear();
         self.committed_state.stored_variable_states.clear();
+        self.committed_state.checkpoint_unavailable = false;
+        self.variables_version += 1;
+        // Earlier states refer to variables that only existed in the old process.
+        self.undo_states.clear();
         self.child_process = self.child_process.restart()?;
         Ok(())
     }
 
+    /// Interrupts any user code that is currently running. See `InterruptHandle::interrupt`. Since
+    /// execution requires exclusive access to the context, this will generally be called via a
+    /// handle obtained from `interrupt_handle`.
+    pub fn interrupt(&self) -> Result<(), Error> {
+        self.interrupt_handle().interrupt()
+    }
+
+    /// Returns a handle that can be used to interrupt execution from another thread. The handle
+    /// remains valid if the subprocess is restarted.
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.child_process.interrupt_handle()
+    }
+
+    /// Returns a handle that can be used from another thread to provide input to user code that
+    /// reads from stdin. The handle remains valid if the subprocess is restarted.
+    pub fn stdin_handle(&self) -> StdinHandle {
+        self.child_process.stdin_handle()
+    }
+
+    /// Applies the resource limits from `state` to the subprocess. Limits can only be applied when
+    /// the subprocess starts, so if they've changed, the subprocess is restarted. Returns whether
+    /// any variables were lost as a result.
+    pub(crate) fn apply_resource_limits(
+        &mut self,
+        state: &mut ContextState,
+    ) -> Result<bool, Error> {
+        if state.config.resource_limits == self.child_process.resource_limits() {
+            return Ok(false);
+        }
+        self.child_process
+            .set_resource_limits(state.config.resource_limits);
+        self.restart_child_process()?;
+        let had_variables = !state.variable_states.is_empty();
+        state.variable_states.clear();
+        state.stored_variable_states.clear();
+        Ok(had_variables)
+    }
+
     pub(crate) fn last_compile_dir(&self) -> &Path {
         self.module.crate_dir()
     }
@@ -508,7 +944,22 @@ impl EvalContext {
             // This span only makes sense when the variable is first defined.
             variable_state.definition_span = None;
         }
+        // Variables that the code used or redefined may have changed, as may ones that were restored
+        // by undo.
+        if !state.snapshot_variables.is_empty()
+            || state.restore_snapshot.is_some()
+            || !same_variables(
+                &state.variable_states,
+                &self.committed_state.stored_variable_states,
+            )
+        {
+            self.variables_version += 1;
+        }
         state.stored_variable_states = state.variable_states.clone();
+        state.snapshot_variables.clear();
+        state.restore_snapshot = None;
+        state.variable_query = None;
+        state.variable_migrations.clear();
         state.commit_old_user_code();
         self.committed_state = state;
     }
@@ -522,72 +973,70 @@ impl EvalContext {
     ) -> Result<EvalOutputs, Error> {
         self.write_cargo_toml(state)?;
         self.fix_variable_types(state, state.analysis_code(user_code.clone()))?;
-        // In some circumstances we may need a few tries before we get the code right. Note that
-        // we'll generally give up sooner than this if there's nothing left that we think we can
-        // fix. The limit is really to prevent retrying indefinitely in case our "fixing" of things
-        // somehow ends up flip-flopping back and forth. Not sure how that could happen, but best to
-        // avoid any infinite loops.
-        let mut remaining_retries = 5;
-        // TODO: Now that we have rust analyzer, we can probably with a bit of work obtain all the
-        // information we need without relying on compilation errors. See if we can get rid of this.
-        loop {
-            // Try to compile and run the code.
-            let result = self.try_run_statements(
+        self.apply_variable_usage(&mut user_code, state)?;
+        // Rust analyzer normally tells us everything we need in order to get the code right first
+        // time, so that we only need to compile once.
+        let mut result = self.try_run_statements(
+            user_code.clone(),
+            state,
+            state.compilation_mode(),
+            phases,
+            callbacks,
+        );
+        if let Err(Error::CompilationErrors(errors)) = &result {
+            // Rust analyzer can't see into code that it can't expand or infer types for, such as
+            // some macros, whereas rustc can. So if it missed the type of a new variable, or a
+            // move of an existing one, the compilation errors tell us what it should have been and
+            // we compile once more.
+            if fix_unanalysed_variables(errors, state)? {
+                phases.phase_complete("Unanalysed variables");
+                result = self.try_run_statements(
+                    user_code.clone(),
+                    state,
+                    state.compilation_mode(),
+                    phases,
+                    callbacks,
+                );
+            }
+        }
+        if let Err(Error::TypeRedefinedVariablesLost(variables)) = &result {
+            // The variables were checked before any of the user's code ran, so we can run it again
+            // without them.
+            for variable in variables {
+                state.variable_states.remove(variable);
+                state.stored_variable_states.remove(variable);
+                self.committed_state.variable_states.remove(variable);
+                self.committed_state.stored_variable_states.remove(variable);
+            }
+            self.variables_version += 1;
+            result = self.try_run_statements(
                 user_code.clone(),
                 state,
                 state.compilation_mode(),
                 phases,
                 callbacks,
             );
-            match result {
-                Ok(execution_artifacts) => {
-                    return Ok(execution_artifacts.output);
-                }
-
-                Err(Error::CompilationErrors(errors)) => {
-                    // If we failed to compile, attempt to deal with the first
-                    // round of compilation errors by adjusting variable types,
-                    // whether they've been moved into the catch_unwind block
-                    // etc.
-                    if remaining_retries > 0 {
-                        let mut fixed = HashSet::new();
-                        for error in &errors {
-                            self.attempt_to_fix_error(error, &mut user_code, state, &mut fixed)?;
-                        }
-                        if !fixed.is_empty() {
-                            remaining_retries -= 1;
-                            let fixed_sorted: Vec<_> = fixed.into_iter().collect();
-                            phases.phase_complete(&fixed_sorted.join("|"));
-                            continue;
-                        }
-                    }
-                    if !user_code.is_empty() {
-                        // We have user code and it appears to have an error, recompile without
-                        // catch_unwind to try and get a better error message. e.g. we don't want the
-                        // user to see messages like "cannot borrow immutable captured outer variable in
-                        // an `FnOnce` closure `a` as mutable".
-                        self.try_run_statements(
-                            user_code,
-                            state,
-                            CompilationMode::NoCatchExpectError,
-                            phases,
-                            callbacks,
-                        )?;
-                    }
-                    return Err(Error::CompilationErrors(errors));
-                }
-
-                Err(Error::TypeRedefinedVariablesLost(variables)) => {
-                    for variable in &variables {
-                        state.variable_states.remove(variable);
-                        state.stored_variable_states.remove(variable);
-                        self.committed_state.variable_states.remove(variable);
-                        self.committed_state.stored_variable_states.remove(variable);
-                    }
-                    remaining_retries -= 1;
+        }
+        match result {
+            Ok(execution_artifacts) => Ok(execution_artifacts.output),
+            Err(Error::CompilationErrors(errors)) => {
+                explain_compilation_errors(&errors, state)?;
+                if !user_code.is_empty() {
+                    // We have user code and it appears to have an error, recompile without
+                    // catch_unwind to try and get a better error message. e.g. we don't want the
+                    // user to see messages like "cannot borrow immutable captured outer variable in
+                    // an `FnOnce` closure `a` as mutable".
+                    self.try_run_statements(
+                        user_code,
+                        state,
+                        CompilationMode::NoCatchExpectError,
+                        phases,
+                        callbacks,
+                    )?;
                 }
-                Err(error) => return Err(error),
+                Err(Error::CompilationErrors(errors))
             }
+            Err(error) => Err(error),
         }
     }
 
@@ -599,8 +1048,14 @@ impl EvalContext {
         phases: &mut PhaseDetailsBuilder,
         callbacks: &mut EvalCallbacks,
     ) -> Result<ExecutionArtifacts, Error> {
+        state.update_variable_migrations();
+        // Without user code, we don't touch the variable store.
+        let uses_variable_store = !user_code.is_empty();
+        let items_code = state.items_crate_code();
         let code = state.code_to_compile(user_code, compilation_mode);
-        let so_file = self.module.compile(&code, &state.config)?;
+        let so_file = self
+            .module
+            .compile(&items_code, &code, state, &state.config)?;
 
         if compilation_mode == CompilationMode::NoCatchExpectError {
             // Uh-oh, caller was expecting an error, return OK and the caller can return the
@@ -611,7 +1066,14 @@ impl EvalContext {
         }
         phases.phase_complete("Final compile");
 
-        let output = self.run_and_capture_output(state, &so_file, callbacks)?;
+        let mut output = self.run_and_capture_output(state, &so_file, callbacks)?;
+        if uses_variable_store {
+            output.notes = state.variable_migration_notes();
+            state.stored_item_definitions = state.item_definitions();
+        }
+        if state.config.show_warnings {
+            output.warnings = so_file.warnings;
+        }
         Ok(ExecutionArtifacts { output })
     }
 
@@ -626,11 +1088,14 @@ impl EvalContext {
         code: CodeBlock,
     ) -> Result<(), Error> {
         self.analyzer.set_source(code.code_string())?;
+        let mut borrowed_variables = HashSet::new();
         for (
             variable_name,
             VariableInfo {
                 type_name,
                 is_mutable,
+                is_copy,
+                borrows,
             },
         ) in self.analyzer.top_level_variables("evcxr_analysis_wrapper")
         {
@@ -640,15 +1105,30 @@ impl EvalContext {
             // We don't want to try to store record evcxr_variable_store into itself, so we ignore
             // it. We also ignore any variables for which we were given an invalid type. Variables
             // with invalid types will then have their types determined by looking at compilation
-            // errors (although we may eventually drop the code that does that). At the time of
-            // writing, the test `int_array` fails if we don't reject invalid types here.
+            // errors.
             if variable_name == "evcxr_variable_store"
                 || !crate::rust_analyzer::is_type_valid(&type_name)
             {
                 continue;
             }
-            let preserve_vars_on_panic = state.config.preserve_vars_on_panic;
-            state
+            if state.stored_variable_states.contains_key(&variable_name) {
+                // The variable is being redefined, so we'll need its old value if this gets undone.
+                state.snapshot_variables.insert(variable_name.clone());
+            }
+            // Any references that we store must be static, so if we're allowing references to
+            // other variables, we make that explicit in the type.
+            let type_name = if state.config.promote_borrowed_vars {
+                crate::rust_analyzer::with_static_lifetimes(&type_name)
+            } else {
+                type_name
+            };
+            // A variable whose value contains references can only be persisted if what it borrows
+            // lives forever.
+            if state.config.promote_borrowed_vars && type_name.contains("'static") {
+                borrowed_variables.extend(borrows);
+            }
+            let preserve_vars_on_panic = state.catches_panics();
+            let variable_state = state
                 .variable_states
                 .entry(variable_name)
                 .or_insert_with(|| VariableState {
@@ -657,16 +1137,81 @@ impl EvalContext {
                     // All new locals will initially be defined only inside our catch_unwind
                     // block.
                     move_state: VariableMoveState::MovedIntoCatchUnwind,
-                    // If we're preserving copy types, then assume this variable
-                    // is copy until we find out it's not.
-                    is_copy_type: preserve_vars_on_panic,
+                    is_copy_type: false,
                     definition_span: None,
+                    leak_on_load: false,
+                    is_promoted: false,
+                });
+            variable_state.type_name = type_name;
+            // Copy types only need special handling if we're preserving them.
+            variable_state.is_copy_type = preserve_vars_on_panic && is_copy;
+        }
+        for variable_name in &borrowed_variables {
+            state.promote_variable(variable_name);
+        }
+        Ok(())
+    }
+
+    /// Uses rust analyzer to find out things about the user's code that we'd otherwise need to
+    /// discover from compilation errors. Must be called after `fix_variable_types`, since it
+    /// analyses the source that was set there.
+    fn apply_variable_usage(
+        &mut self,
+        user_code: &mut CodeBlock,
+        state: &mut ContextState,
+    ) -> Result<(), Error> {
+        let usage = self.analyzer.variable_usage("evcxr_analysis_wrapper");
+        if usage.uses_await && !state.async_mode {
+            self.enable_async_mode(state)?;
+        }
+        if usage.uses_question_mark {
+            state.allow_question_mark = true;
+        }
+        if usage.unresolved_methods.contains("evcxr_display") {
+            let fallbacks: Vec<CodeBlock> = user_code
+                .segments
+                .iter()
+                .filter_map(|segment| match &segment.kind {
+                    CodeKind::WithFallback(fallback) => Some(fallback.clone()),
+                    _ => None,
                 })
-                .type_name = type_name;
+                .collect();
+            for fallback in &fallbacks {
+                user_code.apply_fallback(fallback);
+            }
+        }
+        state.snapshot_variables.extend(
+            usage
+                .referenced
+                .iter()
+                .filter(|name| state.stored_variable_states.contains_key(*name))
+                .cloned(),
+        );
+        for variable_name in &usage.moved {
+            state.variable_states.remove(variable_name);
+        }
+        if state.compilation_mode() == CompilationMode::RunAndCatchPanics {
+            // Any non-copy variable that the user's code references will be moved into the
+            // closure that we run it in.
+            for variable_name in &usage.referenced {
+                if let Some(variable_state) = state.variable_states.get_mut(variable_name) {
+                    if variable_state.move_state == VariableMoveState::Available {
+                        variable_state.move_state = VariableMoveState::MovedIntoCatchUnwind;
+                    }
+                }
+            }
         }
         Ok(())
     }
 
+    fn enable_async_mode(&mut self, state: &mut ContextState) -> Result<(), Error> {
+        state.async_mode = true;
+        state.add_async_runtime_dep()?;
+        // Rewrite Cargo.toml, since the dependency will probably have been validated in the process
+        // of being added, which will have overwritten Cargo.toml
+        self.write_cargo_toml(state)
+    }
+
     fn run_and_capture_output(
         &mut self,
         state: &mut ContextState,
@@ -674,75 +1219,152 @@ impl EvalContext {
         callbacks: &mut EvalCallbacks,
     ) -> Result<EvalOutputs, Error> {
         let mut output = EvalOutputs::new();
-        // TODO: We should probably send an OsString not a String. Otherwise
-        // things won't work if the path isn't UTF-8 - apparently that's a thing
-        // on some platforms.
         let fn_name = state.current_user_fn_name();
-        self.child_process.send(&format!(
-            "LOAD_AND_RUN {} {}",
-            so_file.path.to_string_lossy(),
-            fn_name,
-        ))?;
+        // The items crates aren't loaded explicitly, but they need to be available for when the
+        // code that depends on them gets loaded.
+        for items_path in &so_file.items_paths {
+            self.child_process.provide_file(items_path)?;
+        }
+        let so_path = self.child_process.provide_file(&so_file.path)?;
+        self.child_process.send(&[
+            if state.config.checkpoint {
+                runtime::LOAD_AND_RUN_WITH_CHECKPOINT
+            } else if CHECKPOINT_SUPPORTED {
+                // So that interrupting the code doesn't lose variables.
+                runtime::LOAD_AND_RUN_WITH_INTERRUPT_CHECKPOINT
+            } else {
+                runtime::LOAD_AND_RUN
+            }
+            .as_bytes(),
+            &path_to_bytes(&so_path),
+            fn_name.as_bytes(),
+        ])?;
+        let mut deadline = state.config.timeout.map(Deadline::after);
 
         state.build_num += 1;
 
         let mut got_panic = false;
+        let mut restored_reason = None;
         let mut lost_variables = Vec::new();
-        static MIME_OUTPUT: OnceCell<Regex> = OnceCell::new();
-        let mime_output =
-            MIME_OUTPUT.get_or_init(|| Regex::new("EVCXR_BEGIN_CONTENT ([^ ]+)").unwrap());
+        // Whether content that we receive is the value of the final expression, rather than
+        // something to be displayed straight away.
+        let mut in_result = false;
+        // Content should be sent in control messages, but for compatibility with code that prints
+        // it to stdout between marker lines, such as some `evcxr_display` implementations, we still
+        // accept that. Holds the mime type and lines of any such content that we're part way
+        // through.
+        let mut printed_content: Option<(String, Vec<String>)> = None;
         loop {
-            let line = self.child_process.recv_line()?;
-            if line == runtime::EVCXR_EXECUTION_COMPLETE {
-                break;
-            }
-            if line == PANIC_NOTIFICATION {
-                got_panic = true;
-            } else if line.starts_with(evcxr_input::GET_CMD) {
-                let is_password = line.starts_with(evcxr_input::GET_CMD_PASSWORD);
-                let prompt = line.split(':').nth(1).unwrap_or_default();
-                self.child_process
-                    .send(&(callbacks.input_reader)(prompt, is_password))?;
-            } else if line == evcxr_internal_runtime::USER_ERROR_OCCURRED {
-                // A question mark operator in user code triggered an early
-                // return. Any variables moved into the block in which the code
-                // was running, including any newly defined variables will have
-                // been lost (or possibly never even defined).
-                state
-                    .variable_states
-                    .retain(|_variable_name, variable_state| {
-                        variable_state.move_state != VariableMoveState::MovedIntoCatchUnwind
-                    });
-            } else if let Some(variable_name) =
-                line.strip_prefix(evcxr_internal_runtime::VARIABLE_CHANGED_TYPE)
-            {
-                lost_variables.push(variable_name.to_owned());
-            } else if let Some(captures) = mime_output.captures(&line) {
-                let mime_type = captures[1].to_owned();
-                let mut content = String::new();
-                loop {
-                    let line = self.child_process.recv_line()?;
-                    if line == "EVCXR_END_CONTENT" {
-                        break;
-                    }
-                    if line == PANIC_NOTIFICATION {
-                        got_panic = true;
-                        break;
-                    }
-                    if !content.is_empty() {
-                        content.push('\n');
+            let message = match self.child_process.recv(deadline.as_ref())? {
+                ChildOutput::Message(message) => message,
+                ChildOutput::Stdout(line) => {
+                    if let Some((mime_type, lines)) = &mut printed_content {
+                        if line == "EVCXR_END_CONTENT" {
+                            output.add_content(
+                                vec![(std::mem::take(mime_type), lines.join("\n"))],
+                                in_result,
+                                callbacks,
+                            );
+                            printed_content = None;
+                        } else {
+                            lines.push(line);
+                        }
+                    } else if let Some(mime_type) = line.strip_prefix("EVCXR_BEGIN_CONTENT ") {
+                        printed_content = Some((mime_type.to_owned(), Vec::new()));
+                    } else if line.starts_with(evcxr_input::GET_CMD) {
+                        // Likewise, older versions of evcxr_input request input via stdout and
+                        // read it from stdin.
+                        let is_password = line.starts_with(evcxr_input::GET_CMD_PASSWORD);
+                        let prompt = line.split(':').nth(1).unwrap_or_default();
+                        let input = read_input(prompt, is_password, callbacks, &mut deadline);
+                        self.child_process
+                            .write_stdin(format!("{}\n", input).as_bytes())?;
+                    } else {
+                        // Note, errors sending are ignored, since it just means the
+                        // user of the library has dropped the Receiver.
+                        let _ = self.stdout_sender.send(line);
                     }
-                    content.push_str(&line);
+                    continue;
                 }
-                output.content_by_mime_type.insert(mime_type, content);
-            } else {
-                // Note, errors sending are ignored, since it just means the
-                // user of the library has dropped the Receiver.
-                // TODO: Clone might not be necessary with NLL.
-                let _ = self.stdout_sender.send(line.clone());
+            };
+            match message.kind() {
+                runtime::EXECUTION_COMPLETE => break,
+                runtime::CHECKPOINT_RESTORED => {
+                    restored_reason = Some(
+                        self.child_process
+                            .checkpoint_limit_exceeded_reason(message.arg(1).parse().ok())
+                            .unwrap_or_else(|| message.arg(0)),
+                    );
+                }
+                runtime::CHECKPOINT_UNAVAILABLE => state.checkpoint_unavailable = true,
+                evcxr_internal_runtime::PANIC_OCCURRED => got_panic = true,
+                evcxr_internal_runtime::USER_ERROR_OCCURRED => {
+                    // A question mark operator in user code triggered an early
+                    // return. Any variables moved into the block in which the code
+                    // was running, including any newly defined variables will have
+                    // been lost (or possibly never even defined).
+                    state.pending_cell = None;
+                    state
+                        .variable_states
+                        .retain(|_variable_name, variable_state| {
+                            variable_state.move_state != VariableMoveState::MovedIntoCatchUnwind
+                        });
+                }
+                evcxr_internal_runtime::VARIABLE_CHANGED_TYPE => {
+                    lost_variables.push(message.arg(0));
+                }
+                evcxr_internal_runtime::RESULT_STARTED => in_result = true,
+                evcxr_internal_runtime::CONTENT => {
+                    output.add_content(message_content(&message, 0), in_result, callbacks);
+                }
+                kind @ (evcxr_internal_runtime::DISPLAY
+                | evcxr_internal_runtime::UPDATE_DISPLAY) => {
+                    output.add_display(
+                        DisplayBundle {
+                            content_by_mime_type: message_content(&message, 1)
+                                .into_iter()
+                                .collect(),
+                            display_id: Some(message.arg(0)),
+                            is_update: kind == evcxr_internal_runtime::UPDATE_DISPLAY,
+                        },
+                        callbacks,
+                    );
+                }
+                evcxr_internal_runtime::INPUT_REQUEST => {
+                    let input = read_input(
+                        &message.arg(0),
+                        message.arg(1) == "1",
+                        callbacks,
+                        &mut deadline,
+                    );
+                    self.child_process.send(&[
+                        evcxr_internal_runtime::INPUT_RESPONSE.as_bytes(),
+                        input.as_bytes(),
+                    ])?;
+                }
+                _ => {}
             }
         }
+        if let Some((mime_type, lines)) = printed_content {
+            // The content was never ended, so presumably wasn't meant to be content.
+            let _ = self
+                .stdout_sender
+                .send(format!("EVCXR_BEGIN_CONTENT {}", mime_type));
+            for line in lines {
+                let _ = self.stdout_sender.send(line);
+            }
+        }
+        if let Some(reason) = restored_reason {
+            // The subprocess is back to how it was before this code ran, so the committed state,
+            // which we leave alone by returning an error, still matches it.
+            bail!(
+                "{}. Resumed from a checkpoint, so all variables are as they were before this code \
+                 ran.",
+                reason
+            );
+        }
         if got_panic {
+            state.pending_cell = None;
             let mut lost = Vec::new();
             state
                 .variable_states
@@ -768,121 +1390,118 @@ impl EvalContext {
         }
         Ok(output)
     }
+}
 
-    fn attempt_to_fix_error(
-        &mut self,
-        error: &CompilationError,
-        user_code: &mut CodeBlock,
-        state: &mut ContextState,
-        fixed_errors: &mut HashSet<&'static str>,
-    ) -> Result<(), Error> {
-        static DISALLOWED_TYPES: OnceCell<Regex> = OnceCell::new();
-        let disallowed_types =
-            DISALLOWED_TYPES.get_or_init(|| Regex::new("(impl .*|[.*@])").unwrap());
+/// Fixes the types and move states of variables that rust analyzer couldn't determine, based on
+/// compilation errors from storing them. Returns whether anything was fixed.
+fn fix_unanalysed_variables(
+    errors: &[CompilationError],
+    state: &mut ContextState,
+) -> Result<bool, Error> {
+    static DISALLOWED_TYPES: OnceCell<Regex> = OnceCell::new();
+    let disallowed_types = DISALLOWED_TYPES.get_or_init(|| Regex::new("(impl .*|[.*@])").unwrap());
+    let mut fixed = false;
+    for error in errors {
+        for code_origin in &error.code_origins {
+            let variable_name = match code_origin {
+                CodeKind::PackVariable { variable_name } => variable_name,
+                _ => continue,
+            };
+            if !state.variable_states.contains_key(variable_name) {
+                continue;
+            }
+            if error.code() == Some("E0308") {
+                // Mismatched types.
+                if let Some(mut actual_type) = error.get_actual_type() {
+                    // If the user hasn't given enough information for the compiler to
+                    // determine what type of integer or float, we default to i32 and f64
+                    // respectively.
+                    actual_type = actual_type
+                        .replace("{integer}", "i32")
+                        .replace("{float}", "f64");
+                    if actual_type == "integer" {
+                        actual_type = "i32".to_string();
+                    } else if actual_type == "float" {
+                        actual_type = "f64".to_string();
+                    }
+                    if disallowed_types.is_match(&actual_type) {
+                        bail!(
+                            "Sorry, the type {} cannot currently be persisted",
+                            actual_type
+                        );
+                    }
+                    actual_type = replace_reserved_words_in_type(&actual_type);
+                    state
+                        .variable_states
+                        .get_mut(variable_name)
+                        .unwrap()
+                        .type_name = actual_type;
+                    fixed = true;
+                } else {
+                    bail!("Got error E0308 but failed to parse actual type");
+                }
+            } else if error.code() == Some("E0382") {
+                // Use of moved value.
+                let old_move_state = std::mem::replace(
+                    &mut state
+                        .variable_states
+                        .get_mut(variable_name)
+                        .unwrap()
+                        .move_state,
+                    VariableMoveState::MovedIntoCatchUnwind,
+                );
+                if old_move_state == VariableMoveState::MovedIntoCatchUnwind {
+                    // Variable is truly moved, forget about it.
+                    state.variable_states.remove(variable_name);
+                }
+                fixed = true;
+            } else if error.code() == Some("E0425") {
+                // cannot find value in scope.
+                state.variable_states.remove(variable_name);
+                fixed = true;
+            }
+        }
+    }
+    Ok(fixed)
+}
+
+/// Returns an error that explains `errors` better than rustc would, if they're errors that we know
+/// about.
+fn explain_compilation_errors(
+    errors: &[CompilationError],
+    state: &ContextState,
+) -> Result<(), Error> {
+    for error in errors {
         for code_origin in &error.code_origins {
             match code_origin {
                 CodeKind::PackVariable { variable_name } => {
-                    if error.code() == Some("E0308") {
-                        // Handle mismatched types. We might eventually remove this code entirely
-                        // now that we use Rust analyzer for type inference. Keeping it for now as
-                        // there's still a handful of tests that fail without this code..
-                        if let Some(mut actual_type) = error.get_actual_type() {
-                            // If the user hasn't given enough information for the compiler to
-                            // determine what type of integer or float, we default to i32 and f64
-                            // respectively.
-                            actual_type = actual_type
-                                .replace("{integer}", "i32")
-                                .replace("{float}", "f64");
-                            if actual_type == "integer" {
-                                actual_type = "i32".to_string();
-                            } else if actual_type == "float" {
-                                actual_type = "f64".to_string();
-                            }
-                            if disallowed_types.is_match(&actual_type) {
-                                bail!(
-                                    "Sorry, the type {} cannot currently be persisted",
-                                    actual_type
-                                );
-                            }
-                            actual_type = replace_reserved_words_in_type(&actual_type);
-                            state
-                                .variable_states
-                                .get_mut(variable_name)
-                                .unwrap()
-                                .type_name = actual_type;
-                            fixed_errors.insert("Variable types");
-                        } else {
-                            bail!("Got error E0308 but failed to parse actual type");
-                        }
-                    } else if error.code() == Some("E0382") {
-                        // Use of moved value.
-                        let old_move_state = std::mem::replace(
-                            &mut state
-                                .variable_states
-                                .get_mut(variable_name)
-                                .unwrap()
-                                .move_state,
-                            VariableMoveState::MovedIntoCatchUnwind,
+                    let variable_state = match state.variable_states.get(variable_name) {
+                        Some(variable_state) => variable_state,
+                        None => continue,
+                    };
+                    if error.code() == Some("E0603") {
+                        bail!(
+                            "Failed to determine type of variable `{}`. rustc suggested type {}, \
+                             but that's private. Sometimes adding an extern crate will help rustc \
+                             suggest the correct public type name, or you can give an explicit \
+                             type.",
+                            variable_name,
+                            variable_state.type_name
                         );
-                        if old_move_state == VariableMoveState::MovedIntoCatchUnwind {
-                            // Variable is truly moved, forget about it.
-                            state.variable_states.remove(variable_name);
-                        }
-                        fixed_errors.insert("Captured value");
-                    } else if error.code() == Some("E0425") {
-                        // cannot find value in scope.
-                        state.variable_states.remove(variable_name);
-                        fixed_errors.insert("Variable moved");
-                    } else if error.code() == Some("E0603") {
-                        if let Some(variable_state) = state.variable_states.remove(variable_name) {
-                            bail!(
-                                "Failed to determine type of variable `{}`. rustc suggested type \
-                             {}, but that's private. Sometimes adding an extern crate will help \
-                             rustc suggest the correct public type name, or you can give an \
-                             explicit type.",
-                                variable_name,
-                                variable_state.type_name
-                            );
-                        }
                     } else if error.code() == Some("E0562")
                         || (error.code().is_none() && error.code_origins.len() == 1)
                     {
                         bail!(
                             "The variable `{}` has a type `{}` that can't be persisted. You can \
-                            try wrapping your code in braces so that the variable goes out of \
-                            scope before the end of the code to be executed.",
+                             try wrapping your code in braces so that the variable goes out of \
+                             scope before the end of the code to be executed.",
                             variable_name,
-                            state.variable_states[variable_name].type_name
+                            variable_state.type_name
                         );
                     }
                 }
-                CodeKind::AssertCopyType { variable_name } => {
-                    if error.code() == Some("E0277") {
-                        if let Some(variable_state) = state.variable_states.get_mut(variable_name) {
-                            variable_state.is_copy_type = false;
-                            fixed_errors.insert("Non-copy type");
-                        }
-                    }
-                }
-                CodeKind::WithFallback(fallback) => {
-                    user_code.apply_fallback(fallback);
-                    fixed_errors.insert("Fallback");
-                }
                 CodeKind::OriginalUserCode(_) | CodeKind::OtherUserCode => {
-                    if error.code() == Some("E0728") && !state.async_mode {
-                        state.async_mode = true;
-                        if !state.external_deps.contains_key("tokio") {
-                            state.add_dep("tokio", "\"0.2\"")?;
-                            // Rewrite Cargo.toml, since the dependency will probably have been
-                            // validated in the process of being added, which will have overwritten
-                            // Cargo.toml
-                            self.write_cargo_toml(state)?;
-                        }
-                        fixed_errors.insert("Enabled async mode");
-                    } else if error.code() == Some("E0277") && !state.allow_question_mark {
-                        state.allow_question_mark = true;
-                        fixed_errors.insert("Allow question mark");
-                    } else if error.code() == Some("E0658")
+                    if error.code() == Some("E0658")
                         && error
                             .message()
                             .contains("`let` expressions in this position are experimental")
@@ -900,8 +1519,27 @@ impl EvalContext {
                 _ => {}
             }
         }
-        Ok(())
     }
+    Ok(())
+}
+
+/// Returns a regex that matches `word`, but not when it's part of a longer identifier.
+fn word_pattern(word: &str) -> Regex {
+    Regex::new(&format!(r"\b{}\b", regex::escape(word))).unwrap()
+}
+
+/// Returns whether `a` and `b` have the same variables with the same types.
+fn same_variables(a: &HashMap<String, VariableState>, b: &HashMap<String, VariableState>) -> bool {
+    a.len() == b.len()
+        && a.iter().all(|(name, state)| {
+            b.get(name)
+                .map_or(false, |other| other.type_name == state.type_name)
+        })
+}
+
+/// Returns the target directory that all contexts should share, if one has been configured.
+fn shared_target_dir_from_env() -> Option<PathBuf> {
+    std::env::var_os("EVCXR_TARGET_DIR").map(PathBuf::from)
 }
 
 fn fix_path() {
@@ -962,19 +1600,47 @@ impl PhaseDetailsBuilder {
     }
 }
 
+/// Alternative representations of a single thing to be displayed, keyed by mime type.
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct DisplayBundle {
+    pub content_by_mime_type: HashMap<String, String>,
+    /// Identifies the display, if it was created via a display handle, which can later update it.
+    pub display_id: Option<String>,
+    /// Whether this replaces the content of the earlier display with the same `display_id`, rather
+    /// than being displayed separately.
+    pub is_update: bool,
+}
+
+impl DisplayBundle {
+    pub fn get(&self, mime_type: &str) -> Option<&str> {
+        self.content_by_mime_type.get(mime_type).map(String::as_str)
+    }
+}
+
 #[derive(Default, Debug)]
 pub struct EvalOutputs {
+    /// The value of the final expression, or the output of a command.
     pub content_by_mime_type: HashMap<String, String>,
+    /// What was displayed while code was running, in the order that it was displayed.
+    pub display_bundles: Vec<DisplayBundle>,
     pub timing: Option<Duration>,
     pub phases: Vec<PhaseDetails>,
+    /// Warnings emitted by the compiler for the code that was evaluated.
+    pub warnings: Vec<CompilationError>,
+    /// Things the user should know about that aren't errors or warnings, such as variables that
+    /// were converted to a new definition of their type.
+    pub notes: Vec<String>,
 }
 
 impl EvalOutputs {
     pub fn new() -> EvalOutputs {
         EvalOutputs {
             content_by_mime_type: HashMap::new(),
+            display_bundles: Vec::new(),
             timing: None,
             phases: Vec::new(),
+            warnings: Vec::new(),
+            notes: Vec::new(),
         }
     }
 
@@ -987,6 +1653,7 @@ impl EvalOutputs {
         out
     }
 
+    /// Returns whether there's no value or command output. Display bundles aren't considered.
     pub fn is_empty(&self) -> bool {
         self.content_by_mime_type.is_empty()
     }
@@ -1002,9 +1669,52 @@ impl EvalOutputs {
                 .or_default()
                 .push_str(&content);
         }
+        self.display_bundles.extend(other.display_bundles);
+        self.warnings.extend(other.warnings);
+        self.notes.extend(other.notes);
+    }
+
+    /// Adds content received from the subprocess. If `is_result` is false, the content is a
+    /// display bundle, which `callbacks` is told about straight away.
+    fn add_content(
+        &mut self,
+        content: Vec<(String, String)>,
+        is_result: bool,
+        callbacks: &EvalCallbacks,
+    ) {
+        // Answers to variable queries are picked up from the result, no matter when they're sent.
+        if is_result
+            || content
+                .iter()
+                .any(|(mime_type, _)| mime_type == evcxr_internal_runtime::VARIABLE_QUERY_MIME_TYPE)
+        {
+            self.content_by_mime_type.extend(content);
+        } else {
+            self.add_display(
+                DisplayBundle {
+                    content_by_mime_type: content.into_iter().collect(),
+                    ..DisplayBundle::default()
+                },
+                callbacks,
+            );
+        }
+    }
+
+    fn add_display(&mut self, bundle: DisplayBundle, callbacks: &EvalCallbacks) {
+        (callbacks.display_handler)(&bundle);
+        self.display_bundles.push(bundle);
     }
 }
 
+/// Returns the pairs of mime type and content in the arguments of `message`, starting at argument
+/// `first`.
+fn message_content(message: &Message, first: usize) -> Vec<(String, String)> {
+    (first..message.args().len())
+        .step_by(2)
+        .map(|i| (message.arg(i), message.arg(i + 1)))
+        .collect()
+}
+
 #[derive(Clone, Debug)]
 struct VariableState {
     type_name: String,
@@ -1016,6 +1726,12 @@ struct VariableState {
     // the block will be lost.
     is_copy_type: bool,
     definition_span: Option<UserCodeSpan>,
+    // Whether, when this variable is next loaded, its value should be moved to a leaked heap
+    // allocation, with the variable becoming a static reference to that allocation.
+    leak_on_load: bool,
+    // Whether this variable was promoted to a static reference, so that other variables could
+    // borrow from it.
+    is_promoted: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -1035,6 +1751,33 @@ struct ExecutionArtifacts {
     output: EvalOutputs,
 }
 
+/// The non-item code from a cell that executed successfully. Items, use statements etc are
+/// stored separately in `ContextState`, so aren't included here.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExecutedCell {
+    /// Statements in the order in which they were written.
+    pub(crate) statements: Vec<String>,
+    /// The final expression of the cell, if there was one. This is the value that got displayed.
+    pub(crate) final_expression: Option<String>,
+    /// The text/plain output produced when the cell was executed, if any.
+    pub(crate) text_output: Option<String>,
+}
+
+impl ExecutedCell {
+    fn is_empty(&self) -> bool {
+        self.statements.is_empty() && self.final_expression.is_none()
+    }
+}
+
+/// Something to find out about the values of stored variables, by running code in the subprocess.
+#[derive(Clone, Copy, Debug)]
+enum VariableQuery {
+    /// Serialize the values of variables whose types support it. See `session::save_values_code`.
+    SaveValues,
+    /// Preview the values of variables. See `inspect::inspect_code`.
+    Inspect,
+}
+
 #[derive(Eq, PartialEq, Copy, Clone)]
 enum CompilationMode {
     /// User code should be wrapped in catch_unwind and executed.
@@ -1064,9 +1807,32 @@ pub struct ContextState {
     /// execution completes.
     stored_variable_states: HashMap<String, VariableState>,
     attributes: HashMap<String, CodeBlock>,
+    /// Cells that have been successfully executed, in order. Used when exporting the session.
+    cell_history: Vec<ExecutedCell>,
+    /// The cell that is currently being executed. Only gets added to `cell_history` if it runs
+    /// without error or panic.
+    pending_cell: Option<ExecutedCell>,
     async_mode: bool,
     allow_question_mark: bool,
     build_num: i32,
+    /// Stored variables that the code being executed uses or redefines. Their values are
+    /// snapshotted before execution so that they can be restored by `:undo`.
+    snapshot_variables: HashSet<String>,
+    /// If set, variable values need to be restored from the snapshots taken before the execution
+    /// with this build number and later ones.
+    restore_snapshot: Option<i32>,
+    /// If set, code to answer this query about stored variables is run before the user's code. The
+    /// answer is sent back as content with the mime type `VARIABLE_QUERY_MIME_TYPE`.
+    variable_query: Option<VariableQuery>,
+    /// Stored variables whose types have been redefined and which we know how to convert to the
+    /// new definition, keyed by variable name.
+    variable_migrations: HashMap<String, Migration>,
+    /// Item definitions as of when the types of stored variables were last checked. Values of
+    /// types defined by items are those of the build of the items with these definitions.
+    stored_item_definitions: ItemDefinitions,
+    /// Whether the subprocess has told us that it can't checkpoint. Stays set until the subprocess
+    /// is restarted.
+    checkpoint_unavailable: bool,
     config: Config,
 }
 
@@ -1080,9 +1846,17 @@ impl ContextState {
             variable_states: HashMap::new(),
             stored_variable_states: HashMap::new(),
             attributes: HashMap::new(),
+            cell_history: Vec::new(),
+            pending_cell: None,
             async_mode: false,
             allow_question_mark: false,
             build_num: 0,
+            snapshot_variables: HashSet::new(),
+            restore_snapshot: None,
+            variable_query: None,
+            variable_migrations: HashMap::new(),
+            stored_item_definitions: ItemDefinitions::default(),
+            checkpoint_unavailable: false,
             config,
         }
     }
@@ -1144,6 +1918,52 @@ impl ContextState {
         self.config.preserve_vars_on_panic
     }
 
+    pub fn show_warnings(&self) -> bool {
+        self.config.show_warnings
+    }
+
+    pub fn set_show_warnings(&mut self, value: bool) {
+        self.config.show_warnings = value;
+    }
+
+    pub fn set_async_runtime(&mut self, name: &str) -> Result<(), Error> {
+        let runtime = if let Some(runtime) = ASYNC_RUNTIMES.iter().find(|r| r.name == name) {
+            runtime
+        } else {
+            bail!(
+                "Unsupported async runtime. Available options: {}",
+                ASYNC_RUNTIMES
+                    .iter()
+                    .map(|r| r.name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        };
+        self.config.async_runtime = runtime;
+        if self.async_mode {
+            self.add_async_runtime_dep()?;
+        }
+        Ok(())
+    }
+
+    pub fn async_runtime(&self) -> &str {
+        self.config.async_runtime.name
+    }
+
+    /// Adds a dependency on the crate for the selected async runtime. If the user has already
+    /// added that crate, we keep their version and just make sure the features we need are
+    /// enabled.
+    fn add_async_runtime_dep(&mut self) -> Result<(), Error> {
+        let runtime = self.config.async_runtime;
+        let dep_config = match self.external_deps.get(runtime.crate_name) {
+            Some(existing) => {
+                crate::crate_config::add_features(&existing.config, runtime.required_features)
+            }
+            None => runtime.default_dep_config.to_owned(),
+        };
+        self.add_dep(runtime.crate_name, &dep_config)
+    }
+
     pub fn offline_mode(&mut self) -> bool {
         self.config.offline_mode
     }
@@ -1152,6 +1972,322 @@ impl ContextState {
         self.config.preserve_vars_on_panic = value;
     }
 
+    /// Adds the dependencies needed to save and load variable values. If the user has already
+    /// added these crates, we use their versions.
+    fn add_serde_deps(&mut self) -> Result<(), Error> {
+        for dep in ["serde", "serde_json"] {
+            if !self.external_deps.contains_key(dep) {
+                self.add_dep(dep, "\"1\"")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns this state as a session that can be saved. `values` maps variable names to values
+    /// serialized by serde_json. Variables without a value are left out.
+    fn to_session(&self, values: &json::JsonValue) -> Session {
+        let mut deps: Vec<(String, String)> = self
+            .external_deps
+            .iter()
+            .map(|(name, krate)| (name.clone(), krate.config.clone()))
+            .collect();
+        deps.sort();
+        let mut variables: Vec<SavedVariable> = self
+            .stored_variable_states
+            .iter()
+            .filter_map(|(name, variable_state)| {
+                Some(SavedVariable {
+                    name: name.clone(),
+                    type_name: variable_state.type_name.clone(),
+                    is_mut: variable_state.is_mut,
+                    value: values[name.as_str()].as_str()?.to_owned(),
+                })
+            })
+            .collect();
+        variables.sort_by(|a, b| a.name.cmp(&b.name));
+        Session {
+            config: self.session_config(),
+            deps,
+            items: self.session_items_code(),
+            variables,
+        }
+    }
+
+    /// Applies the config and dependencies of `session` to this state.
+    fn apply_session(&mut self, session: &Session) -> Result<(), Error> {
+        self.apply_session_config(&session.config)?;
+        for (name, config) in &session.deps {
+            self.add_dep(name, config)?;
+        }
+        if !session.variables.is_empty() {
+            self.add_serde_deps()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the config options that the user can set with commands. Options that depend on
+    /// what's installed on the current machine, like the linker, aren't included.
+    fn session_config(&self) -> json::JsonValue {
+        let mut config = json::JsonValue::new_object();
+        config["opt_level"] = self.config.opt_level.as_str().into();
+        config["output_format"] = self.config.output_format.as_str().into();
+        config["error_format"] = self.config.error_fmt.format_str.into();
+        config["preserve_vars_on_panic"] = self.config.preserve_vars_on_panic.into();
+        config["show_warnings"] = self.config.show_warnings.into();
+        config["offline_mode"] = self.config.offline_mode.into();
+        config["time_passes"] = self.config.time_passes.into();
+        config["toolchain"] = self.config.toolchain.as_str().into();
+        config["async_runtime"] = self.config.async_runtime.name.into();
+        config["promote_borrowed_vars"] = self.config.promote_borrowed_vars.into();
+        config["undo_steps"] = self.config.undo_steps.into();
+        if let Some(timeout) = self.config.timeout {
+            config["timeout_secs"] = timeout.as_secs_f64().into();
+        }
+        config
+    }
+
+    fn apply_session_config(&mut self, config: &json::JsonValue) -> Result<(), Error> {
+        if let Some(value) = config["opt_level"].as_str() {
+            self.set_opt_level(value)?;
+        }
+        if let Some(value) = config["output_format"].as_str() {
+            self.set_output_format(value.to_owned());
+        }
+        if let Some(value) = config["error_format"].as_str() {
+            self.set_error_format(value)?;
+        }
+        if let Some(value) = config["preserve_vars_on_panic"].as_bool() {
+            self.set_preserve_vars_on_panic(value);
+        }
+        if let Some(value) = config["show_warnings"].as_bool() {
+            self.set_show_warnings(value);
+        }
+        if let Some(value) = config["offline_mode"].as_bool() {
+            self.set_offline_mode(value);
+        }
+        if let Some(value) = config["time_passes"].as_bool() {
+            self.set_time_passes(value);
+        }
+        if let Some(value) = config["toolchain"].as_str() {
+            self.set_toolchain(value);
+        }
+        if let Some(value) = config["async_runtime"].as_str() {
+            self.set_async_runtime(value)?;
+        }
+        if let Some(value) = config["promote_borrowed_vars"].as_bool() {
+            self.set_promote_borrowed_vars(value);
+        }
+        if let Some(value) = config["undo_steps"].as_usize() {
+            self.set_undo_steps(value);
+        }
+        if let Some(value) = config["timeout_secs"].as_f64() {
+            self.set_timeout(Some(Duration::from_secs_f64(value)));
+        }
+        Ok(())
+    }
+
+    /// Returns source code for all items defined so far. `macro_rules!` definitions come first,
+    /// since they need to be defined before they're used.
+    fn session_items_code(&self) -> String {
+        let mut items: Vec<(&String, &CodeBlock)> = self.items_by_name.iter().collect();
+        items.sort_by_key(|&(name, item)| {
+            let is_macro = item
+                .segments
+                .iter()
+                .any(|segment| item::is_macro_rules(&segment.code));
+            (!is_macro, name.as_str())
+        });
+        let mut code = self.attributes_code();
+        for stmt in self.extern_crate_stmts.values() {
+            code = code.other_user_code(stmt.clone());
+        }
+        for (_, item) in items {
+            code = code.add_all(item.clone());
+        }
+        for item in &self.unnamed_items {
+            code = code.add_all(item.clone());
+        }
+        let mut source = String::new();
+        for segment in &code.segments {
+            source.push_str(segment.code.trim_end());
+            source.push('\n');
+        }
+        source
+    }
+
+    /// Removes `variable_name` from this state. Returns false if there was no such variable.
+    pub fn forget_variable(&mut self, variable_name: &str) -> bool {
+        self.stored_variable_states.remove(variable_name);
+        self.variable_states.remove(variable_name).is_some()
+    }
+
+    /// Removes the item named `item_name`, together with any impl blocks for it or of it that
+    /// were defined separately. Fails if there's no such item, or if variables have types that
+    /// refer to it or other items use it, since those would then fail to compile.
+    pub fn remove_item(&mut self, item_name: &str) -> Result<(), Error> {
+        if !self.items_by_name.contains_key(item_name) {
+            bail!("No item named `{}`", item_name);
+        }
+        let name_pattern = word_pattern(item_name);
+        let is_impl_of_item = |code: &CodeBlock| {
+            item::impl_target(&code.code_string())
+                .map_or(false, |target| name_pattern.is_match(&target))
+        };
+        let dependent_variables = self.variables_using(&name_pattern);
+        if !dependent_variables.is_empty() {
+            bail!(
+                "Can't remove `{}` while the following variables have types that use it: {}. \
+                 Use :forget to remove them first.",
+                item_name,
+                dependent_variables.join(", ")
+            );
+        }
+        let dependent_items = self.items_using(&name_pattern, |name, code| {
+            name == Some(item_name) || (name.is_none() && is_impl_of_item(code))
+        });
+        if !dependent_items.is_empty() {
+            bail!(
+                "Can't remove `{}` while the following items use it: {}. \
+                 Remove them first.",
+                item_name,
+                dependent_items.join(", ")
+            );
+        }
+        self.items_by_name.remove(item_name);
+        self.unnamed_items.retain(|code| !is_impl_of_item(code));
+        Ok(())
+    }
+
+    /// Removes the dependency on the crate `dep`. Fails if there's no such dependency, or if
+    /// variables or items still use the crate.
+    pub fn remove_dep(&mut self, dep: &str) -> Result<(), Error> {
+        if !self.external_deps.contains_key(dep) {
+            bail!("No dependency on `{}`", dep);
+        }
+        let name_pattern = word_pattern(dep);
+        let dependent_variables = self.variables_using(&name_pattern);
+        if !dependent_variables.is_empty() {
+            bail!(
+                "Can't remove `{}` while the following variables have types that use it: {}. \
+                 Use :forget to remove them first.",
+                dep,
+                dependent_variables.join(", ")
+            );
+        }
+        let dependent_items = self.items_using(&name_pattern, |_, _| false);
+        if !dependent_items.is_empty() {
+            bail!(
+                "Can't remove `{}` while the following items use it: {}. \
+                 Remove them first.",
+                dep,
+                dependent_items.join(", ")
+            );
+        }
+        self.external_deps.remove(dep);
+        self.extern_crate_stmts.remove(dep);
+        Ok(())
+    }
+
+    /// Returns the sorted names of variables whose types match `pattern`.
+    fn variables_using(&self, pattern: &Regex) -> Vec<&str> {
+        let mut variables: Vec<&str> = self
+            .variable_states
+            .iter()
+            .filter(|(_, variable_state)| pattern.is_match(&variable_state.type_name))
+            .map(|(variable_name, _)| variable_name.as_str())
+            .collect();
+        variables.sort_unstable();
+        variables
+    }
+
+    /// Returns descriptions of the items whose code matches `pattern`, skipping those for which
+    /// `skip` returns true when given the item's name, if it has one, and its code. Named items are
+    /// described by their name and unnamed items by their first line.
+    fn items_using(
+        &self,
+        pattern: &Regex,
+        skip: impl Fn(Option<&str>, &CodeBlock) -> bool,
+    ) -> Vec<String> {
+        let mut named: Vec<String> = self
+            .items_by_name
+            .iter()
+            .filter(|(name, code)| {
+                !skip(Some(name.as_str()), code) && pattern.is_match(&code.code_string())
+            })
+            .map(|(name, _)| format!("`{}`", name))
+            .collect();
+        named.sort_unstable();
+        let unnamed = self.unnamed_items.iter().filter_map(|code| {
+            let code_string = code.code_string();
+            if skip(None, code) || !pattern.is_match(&code_string) {
+                return None;
+            }
+            Some(format!(
+                "`{}`",
+                code_string.trim().lines().next().unwrap_or_default().trim()
+            ))
+        });
+        named.into_iter().chain(unnamed).collect()
+    }
+
+    pub fn checkpoint(&self) -> bool {
+        self.config.checkpoint
+    }
+
+    /// Whether checkpointing is on, but the subprocess has told us that it can't checkpoint.
+    pub fn checkpoint_unavailable(&self) -> bool {
+        self.config.checkpoint && self.checkpoint_unavailable
+    }
+
+    pub fn set_checkpoint(&mut self, value: bool) -> Result<(), Error> {
+        if value && !CHECKPOINT_SUPPORTED {
+            bail!("Checkpointing isn't supported on this platform");
+        }
+        self.config.checkpoint = value;
+        Ok(())
+    }
+
+    pub fn undo_steps(&self) -> usize {
+        self.config.undo_steps
+    }
+
+    pub fn set_undo_steps(&mut self, undo_steps: usize) {
+        self.config.undo_steps = undo_steps;
+    }
+
+    pub fn promote_borrowed_vars(&self) -> bool {
+        self.config.promote_borrowed_vars
+    }
+
+    pub fn set_promote_borrowed_vars(&mut self, value: bool) {
+        self.config.promote_borrowed_vars = value;
+    }
+
+    /// Arranges for the stored variable `variable_name` to be moved to a leaked heap allocation
+    /// when it's next loaded, so that other variables can persist references to it. From then on,
+    /// the variable is a `&'static` reference to its original value. Returns false if the variable
+    /// can't be promoted, e.g. because it was only just defined, or is already a reference.
+    fn promote_variable(&mut self, variable_name: &str) -> bool {
+        let stored_state = match self.stored_variable_states.get_mut(variable_name) {
+            Some(stored_state) if !stored_state.type_name.starts_with('&') => stored_state,
+            _ => return false,
+        };
+        let variable_state = match self.variable_states.get_mut(variable_name) {
+            Some(variable_state) => variable_state,
+            None => return false,
+        };
+        stored_state.leak_on_load = true;
+        variable_state.type_name = format!("&'static {}", stored_state.type_name);
+        variable_state.is_mut = false;
+        variable_state.is_promoted = true;
+        if self.catches_panics() {
+            // References are Copy, so are safe to store even if the user's code panics.
+            variable_state.is_copy_type = true;
+            variable_state.move_state = VariableMoveState::CopiedIntoCatchUnwind;
+        }
+        true
+    }
+
     pub fn debug_mode(&self) -> bool {
         self.config.debug_mode
     }
@@ -1187,6 +2323,30 @@ impl ContextState {
         &self.config.toolchain
     }
 
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.config.timeout = timeout;
+    }
+
+    pub fn timeout(&self) -> Option<Duration> {
+        self.config.timeout
+    }
+
+    pub fn set_memory_limit_mb(&mut self, memory_limit_mb: Option<u64>) {
+        self.config.resource_limits.memory_limit_mb = memory_limit_mb;
+    }
+
+    pub fn memory_limit_mb(&self) -> Option<u64> {
+        self.config.resource_limits.memory_limit_mb
+    }
+
+    pub fn set_cpu_limit_secs(&mut self, cpu_limit_secs: Option<u64>) {
+        self.config.resource_limits.cpu_limit_secs = cpu_limit_secs;
+    }
+
+    pub fn cpu_limit_secs(&self) -> Option<u64> {
+        self.config.resource_limits.cpu_limit_secs
+    }
+
     /// Adds a crate dependency with the specified name and configuration.
     pub fn add_dep(&mut self, dep: &str, dep_config: &str) -> Result<(), Error> {
         // Avoid repeating dep validation once we're already added it.
@@ -1297,6 +2457,7 @@ impl ContextState {
             || (self.items_by_name != new_state.items_by_name
                 && !new_state.items_by_name.is_empty())
             || (self.config.sccache != new_state.config.sccache)
+            || new_state.variable_query.is_some()
     }
 
     pub(crate) fn format_cargo_deps(&self) -> String {
@@ -1307,8 +2468,96 @@ impl ContextState {
             .join("")
     }
 
+    /// Returns the code of each named item, together with everything else that can affect what
+    /// types defined by those items mean.
+    fn item_definitions(&self) -> ItemDefinitions {
+        let items = self
+            .items_by_name
+            .iter()
+            .map(|(name, item)| (name.clone(), item.code_string()))
+            .collect();
+        let mut crates: HashMap<String, String> = self
+            .external_deps
+            .iter()
+            .map(|(name, krate)| (name.clone(), krate.config.clone()))
+            .collect();
+        for (name, stmt) in &self.extern_crate_stmts {
+            crates.entry(name.clone()).or_default().push_str(stmt);
+        }
+        let mut context = vec![
+            self.attributes_code().code_string(),
+            self.config.toolchain.clone(),
+        ];
+        // Glob imports can bring names into scope, so can change what the names in a type refer to.
+        context.extend(
+            self.unnamed_items
+                .iter()
+                .map(CodeBlock::code_string)
+                .filter(|code| {
+                    let code = code.trim_start();
+                    code.starts_with("use ") || code.starts_with("pub use ")
+                }),
+        );
+        context.sort();
+        ItemDefinitions::new(items, crates, context.join("\n"))
+    }
+
+    /// Works out which stored variables have a type that was redefined since they were stored and
+    /// how to migrate them to the new definition.
+    fn update_variable_migrations(&mut self) {
+        let previous = &self.stored_item_definitions;
+        let current = self.item_definitions();
+        self.variable_migrations = self
+            .stored_variable_states
+            .iter()
+            .filter_map(|(var_name, var_state)| {
+                let type_name = var_state.type_name.trim();
+                let previous_type_key = previous.type_key(type_name)?;
+                if current.type_key(type_name).as_ref() == Some(&previous_type_key) {
+                    return None;
+                }
+                Some((
+                    var_name.clone(),
+                    migration::migration(type_name, previous, &current)?,
+                ))
+            })
+            .collect();
+    }
+
+    /// Returns a note for each variable that was converted to the new definition of its type,
+    /// sorted by variable name.
+    fn variable_migration_notes(&self) -> Vec<String> {
+        let mut var_names: Vec<&String> = self.variable_migrations.keys().collect();
+        var_names.sort();
+        var_names
+            .into_iter()
+            .filter_map(|var_name| {
+                let var_state = self.stored_variable_states.get(var_name)?;
+                let migration = &self.variable_migrations[var_name];
+                let mut note = format!(
+                    "Variable `{}` was converted to the new definition of `{}`",
+                    var_name,
+                    var_state.type_name.trim()
+                );
+                if !migration.defaulted_fields.is_empty() {
+                    note.push_str(&format!(
+                        ", with default values for: {}",
+                        migration.defaulted_fields.join(", ")
+                    ));
+                }
+                Some(note)
+            })
+            .collect()
+    }
+
+    /// Whether code should be run such that if it panics, variables that it doesn't use are kept.
+    /// As well as when asked to, we do this when checkpointing is on but unavailable.
+    fn catches_panics(&self) -> bool {
+        self.config.preserve_vars_on_panic || self.checkpoint_unavailable()
+    }
+
     fn compilation_mode(&self) -> CompilationMode {
-        if self.config.preserve_vars_on_panic {
+        if self.catches_panics() {
             CompilationMode::RunAndCatchPanics
         } else {
             CompilationMode::NoCatch
@@ -1359,7 +2608,9 @@ impl ContextState {
         let mut code = CodeBlock::new()
             .generated("#![allow(unused_imports, unused_mut, dead_code)]")
             .add_all(self.attributes_code())
-            .add_all(self.items_code());
+            .add_all(self.get_imports())
+            .generated(format!("use {}::*;", ITEMS_CRATE_NAME))
+            .add_all(self.macro_rules_code());
         let has_user_code = !user_code.is_empty();
         if has_user_code {
             code = code.add_all(self.wrap_user_code(user_code, compilation_mode));
@@ -1376,6 +2627,155 @@ impl ContextState {
         code
     }
 
+    /// Returns the contents of a `main.rs` that reproduces this session as a standalone binary.
+    /// Items are emitted at the top level and the statements of each executed cell are run in
+    /// order from `main`. Final expressions are printed using the current output format.
+    pub(crate) fn export_main_code(&self) -> CodeBlock {
+        self.export_code(None, "main", |_cell, expression| {
+            format!(
+                "println!(\"{}\", &({}));",
+                self.config.output_format, expression
+            )
+        })
+    }
+
+    /// Returns the contents of an integration test that replays this session. Final expressions
+    /// that produced text output when they were executed are checked with `assert_eq!` against
+    /// that output. Note, values displayed via `evcxr_display` will only match if their output is
+    /// the same as what the current output format produces.
+    pub(crate) fn export_test_code(&self) -> CodeBlock {
+        self.export_code(Some("#[test]"), "session", |cell, expression| {
+            if let Some(output) = &cell.text_output {
+                format!(
+                    "assert_eq!(format!(\"{}\", &({})), {:?});",
+                    self.config.output_format, expression, output
+                )
+            } else {
+                format!("let _ = &({});", expression)
+            }
+        })
+    }
+
+    /// Returns the items from this session followed by a function that runs each executed cell
+    /// in order. `final_expression_code` produces the code for the final expression of a cell.
+    fn export_code(
+        &self,
+        fn_attribute: Option<&str>,
+        fn_name: &str,
+        final_expression_code: impl Fn(&ExecutedCell, &str) -> String,
+    ) -> CodeBlock {
+        let mut code = CodeBlock::new()
+            .add_all(self.attributes_code())
+            .add_all(self.items_code());
+        if let Some(fn_attribute) = fn_attribute {
+            code = code.generated(fn_attribute);
+        }
+        code = code.generated(if self.allow_question_mark {
+            format!(
+                "fn {}() -> Result<(), Box<dyn std::error::Error>> {{",
+                fn_name
+            )
+        } else {
+            format!("fn {}() {{", fn_name)
+        });
+        if self.async_mode {
+            code = code.generated(format!(
+                "{}(async {{",
+                self.config.async_runtime.block_on_code(false)
+            ));
+        }
+        for (index, cell) in self.cell_history.iter().enumerate() {
+            code = code.generated(format!("// Cell {}", index + 1));
+            for statement in &cell.statements {
+                code = code.other_user_code(statement.clone());
+            }
+            if let Some(expression) = &cell.final_expression {
+                code = code.other_user_code(final_expression_code(cell, expression.trim_end()));
+            }
+        }
+        if self.async_mode {
+            if self.allow_question_mark {
+                // The value of block_on becomes the return value of our function.
+                code = code
+                    .generated("Ok::<(), Box<dyn std::error::Error>>(())")
+                    .generated("})");
+            } else {
+                code = code.generated("});");
+            }
+        } else if self.allow_question_mark {
+            code = code.generated("Ok(())");
+        }
+        code.generated("}")
+    }
+
+    /// Adds the cell that was just executed to our history, together with its text output.
+    fn record_pending_cell(&mut self, outputs: &EvalOutputs) {
+        if let Some(mut cell) = self.pending_cell.take() {
+            cell.text_output = outputs.get("text/plain").map(str::to_owned);
+            self.cell_history.push(cell);
+        }
+    }
+
+    /// Returns all items defined so far, for the items crates. Items are made public so that
+    /// they're accessible from later items crates and from the crate that runs the user's code.
+    fn items_crate_code(&self) -> ItemsCode {
+        let mut prelude = CodeBlock::new()
+            .generated("#![allow(unused_imports, unused_mut, dead_code)]")
+            .add_all(self.attributes_code())
+            .add_all(self.get_imports());
+        let mut macros = HashMap::new();
+        let mut items = Vec::new();
+        // Named items are sorted, so that an items crate that gets written again, e.g. when we
+        // retry compilation, gets written the same.
+        let mut names: Vec<&String> = self.items_by_name.keys().collect();
+        names.sort_unstable();
+        for name in names {
+            let item = &self.items_by_name[name];
+            let code = item.code_string();
+            if item::is_macro_rules(&code) {
+                // Macros need to be defined before they're used, so they go in the prelude.
+                prelude = prelude.add_all(item.clone());
+                macros.insert(name.clone(), code);
+            } else {
+                items.push((Some(name.clone()), item::make_public(item)));
+            }
+        }
+        for item in &self.unnamed_items {
+            items.push((None, item::make_public(item)));
+        }
+        ItemsCode {
+            prelude,
+            macros,
+            items,
+            deps: self
+                .external_deps
+                .iter()
+                .map(|(name, krate)| (name.clone(), format!("{} = {}", krate.name, krate.config)))
+                .collect(),
+            environment: format!(
+                "{}\n{}\n{}",
+                self.attributes_code().code_string(),
+                self.opt_level(),
+                self.config.toolchain
+            ),
+        }
+    }
+
+    /// Returns any `macro_rules!` definitions. Macros by example can't be used by name from another
+    /// crate without being exported, so these are included in both the items crate and the crate
+    /// containing the user's code.
+    fn macro_rules_code(&self) -> CodeBlock {
+        let mut code = CodeBlock::new();
+        for item in self.items_by_name.values() {
+            for segment in &item.segments {
+                if item::is_macro_rules(&segment.code) {
+                    code = code.with_segment(segment.clone());
+                }
+            }
+        }
+        code
+    }
+
     fn items_code(&self) -> CodeBlock {
         let mut code = CodeBlock::new().add_all(self.get_imports());
         for item in self.items_by_name.values().chain(self.unnamed_items.iter()) {
@@ -1409,7 +2809,8 @@ impl ContextState {
             if for_analysis {
                 ""
             } else {
-                "println!(\"{}\", evcxr_internal_runtime::USER_ERROR_OCCURRED);"
+                "evcxr_internal_runtime::send_control_message(\
+                 &[evcxr_internal_runtime::USER_ERROR_OCCURRED.as_bytes()]);"
             }
         ))
     }
@@ -1421,16 +2822,40 @@ impl ContextState {
     ) -> CodeBlock {
         let needs_variable_store = !self.variable_states.is_empty()
             || !self.stored_variable_states.is_empty()
+            || self.restore_snapshot.is_some()
+            || self.variable_query.is_some()
             || self.async_mode
             || self.allow_question_mark;
-        let mut code = CodeBlock::new();
+        // Always included, since it's how user code sends control messages, e.g. to say that it
+        // panicked.
+        let mut code = CodeBlock::new()
+            .generated("mod evcxr_internal_runtime {")
+            .generated(include_str!("evcxr_internal_runtime.rs"))
+            .generated("}");
         if self.allow_question_mark {
             code = code.add_all(self.error_trait_code(false));
         }
+        let definitions = self.item_definitions();
+        code = code.add_all(self.previous_types_code());
         if needs_variable_store {
             code = code
-                .generated("mod evcxr_internal_runtime {")
-                .generated(include_str!("evcxr_internal_runtime.rs"))
+                .generated("#[no_mangle]")
+                .generated(format!(
+                    "pub extern \"C\" fn {}(",
+                    runtime::DROP_VARIABLE_FN_NAME
+                ))
+                .generated("  evcxr_variable_store: *mut evcxr_internal_runtime::VariableStore,")
+                .generated("  name: *const u8, name_len: usize) {")
+                .generated(stringify!(
+                    let name = unsafe {
+                        std::str::from_utf8_unchecked(std::slice::from_raw_parts(name, name_len))
+                    };
+                    let evcxr_variable_store = unsafe { &mut *evcxr_variable_store };
+                    // The variable's Drop implementation is user code, which might panic.
+                    let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                        evcxr_variable_store.drop_variable(name)
+                    }));
+                ))
                 .generated("}");
         }
         code = code.generated("#[no_mangle]").generated(format!(
@@ -1447,23 +2872,29 @@ impl ContextState {
                 )
                 .generated("}")
                 .generated("let evcxr_variable_store = unsafe {&mut *evcxr_variable_store};")
-                .add_all(self.check_variable_statements())
+                .add_all(self.restore_snapshot_statements())
+                .add_all(self.check_variable_statements(&definitions))
+                .add_all(self.variable_query_statements())
+                .add_all(self.snapshot_variable_statements())
                 .add_all(self.load_variable_statements());
             user_code = user_code
-                .add_all(self.store_variable_statements(&VariableMoveState::MovedIntoCatchUnwind))
-                .add_all(self.store_variable_statements(&VariableMoveState::CopiedIntoCatchUnwind));
+                .add_all(self.store_variable_statements(
+                    &definitions,
+                    &VariableMoveState::MovedIntoCatchUnwind,
+                ))
+                .add_all(self.store_variable_statements(
+                    &definitions,
+                    &VariableMoveState::CopiedIntoCatchUnwind,
+                ));
         } else {
             code = code.generated("evcxr_variable_store: *mut u8) -> *mut u8 {");
         }
         if self.async_mode {
             user_code = CodeBlock::new()
-                .generated(stringify!(evcxr_variable_store
-                    .lazy_arc("evcxr_tokio_runtime", || std::sync::Mutex::new(
-                        tokio::runtime::Runtime::new().unwrap()
-                    ))
-                    .lock()
-                    .unwrap()))
-                .generated(".block_on(async {")
+                .generated(format!(
+                    "{}(async {{",
+                    self.config.async_runtime.block_on_code(true)
+                ))
                 .add_all(user_code);
             if self.allow_question_mark {
                 user_code = CodeBlock::new()
@@ -1496,29 +2927,35 @@ impl ContextState {
                     .generated("  Ok(inner_store) => evcxr_variable_store.merge(inner_store),")
                     .generated("  Err(_) => {")
                     .add_all(
-                        self.store_variable_statements(&VariableMoveState::CopiedIntoCatchUnwind),
+                        self.store_variable_statements(&definitions, &VariableMoveState::CopiedIntoCatchUnwind),
                     )
-                    .generated(format!("    println!(\"{}\");", PANIC_NOTIFICATION))
+                    .generated(PANIC_NOTIFICATION_CODE)
                     .generated("}}");
             } else {
                 code = code
                     .generated("if std::panic::catch_unwind(||{")
                     .add_all(user_code)
                     .generated("}).is_err() {")
-                    .generated(format!("    println!(\"{}\");", PANIC_NOTIFICATION))
+                    .generated(PANIC_NOTIFICATION_CODE)
                     .generated("}");
             }
         } else {
             code = code.add_all(user_code);
         }
         if needs_variable_store {
-            code = code.add_all(self.store_variable_statements(&VariableMoveState::Available));
+            code = code.add_all(
+                self.store_variable_statements(&definitions, &VariableMoveState::Available),
+            );
         }
         code = code.generated("evcxr_variable_store");
         code.generated("}")
     }
 
-    fn store_variable_statements(&self, move_state: &VariableMoveState) -> CodeBlock {
+    fn store_variable_statements(
+        &self,
+        definitions: &ItemDefinitions,
+        move_state: &VariableMoveState,
+    ) -> CodeBlock {
         let mut statements = CodeBlock::new();
         for (var_name, var_state) in &self.variable_states {
             if var_state.move_state == *move_state {
@@ -1527,8 +2964,11 @@ impl ContextState {
                     format!(
                         // Note, we use stringify instead of quoting ourselves since it results in
                         // better errors if the user forgets to close a double-quote in their code.
-                        "evcxr_variable_store.put_variable::<{}>(stringify!({}), {});",
-                        var_state.type_name, var_name, var_name
+                        "evcxr_variable_store.put_variable::<{}>(stringify!({}), {}, {:?});",
+                        var_state.type_name,
+                        var_name,
+                        var_name,
+                        definitions.type_key(&var_state.type_name)
                     ),
                 );
                 if var_state.is_copy_type {
@@ -1542,26 +2982,125 @@ impl ContextState {
         statements
     }
 
-    fn check_variable_statements(&self) -> CodeBlock {
+    /// Returns code for the module containing previous definitions of structs that variables are
+    /// being migrated from.
+    fn previous_types_code(&self) -> CodeBlock {
+        let mut definitions: Vec<&str> = self
+            .variable_migrations
+            .values()
+            .map(|migration| migration.previous_definition.as_str())
+            .collect();
+        if definitions.is_empty() {
+            return CodeBlock::new();
+        }
+        definitions.sort_unstable();
+        definitions.dedup();
+        CodeBlock::new().generated(format!(
+            "mod {} {{ use super::*; {} }}",
+            PREVIOUS_TYPES_MODULE,
+            definitions.join("\n")
+        ))
+    }
+
+    fn check_variable_statements(&self, definitions: &ItemDefinitions) -> CodeBlock {
         let mut statements = CodeBlock::new().generated("{let mut vars_ok = true;");
         for (var_name, var_state) in &self.stored_variable_states {
+            let type_key = definitions.type_key(&var_state.type_name);
+            if let Some(migration) = self.variable_migrations.get(var_name) {
+                statements = statements.generated(format!(
+                    "vars_ok &= evcxr_variable_store.migrate_variable::<{}::{}, {}>(\
+                     stringify!({}), {:?}, {:?}, {});",
+                    PREVIOUS_TYPES_MODULE,
+                    var_state.type_name.trim(),
+                    var_state.type_name,
+                    var_name,
+                    migration.previous_type_key,
+                    type_key,
+                    migration.conversion
+                ));
+            }
             statements = statements.generated(format!(
-                "vars_ok &= evcxr_variable_store.check_variable::<{}>(stringify!({}));",
-                var_state.type_name, var_name
+                "vars_ok &= evcxr_variable_store.check_variable::<{}>(stringify!({}), {:?});",
+                var_state.type_name, var_name, type_key
             ));
         }
         statements.generated("if !vars_ok {return evcxr_variable_store;}}")
     }
 
+    fn restore_snapshot_statements(&self) -> CodeBlock {
+        let mut statements = CodeBlock::new();
+        if let Some(id) = self.restore_snapshot {
+            statements = statements.generated(format!(
+                "if !evcxr_variable_store.restore_snapshots({}) {{return evcxr_variable_store;}}",
+                id
+            ));
+        }
+        statements
+    }
+
+    fn variable_query_statements(&self) -> CodeBlock {
+        let mut statements = CodeBlock::new();
+        if let Some(query) = &self.variable_query {
+            let variables: Vec<(&str, &str)> = self
+                .stored_variable_states
+                .iter()
+                .map(|(name, state)| (name.as_str(), state.type_name.as_str()))
+                .collect();
+            statements = statements.generated(match query {
+                VariableQuery::SaveValues => session::save_values_code(&variables),
+                VariableQuery::Inspect => inspect::inspect_code(&variables),
+            });
+        }
+        statements
+    }
+
+    /// Returns code to take copies of the values of variables that the user's code might change,
+    /// for those variables whose types implement Clone.
+    fn snapshot_variable_statements(&self) -> CodeBlock {
+        let statements = CodeBlock::new().generated(format!(
+            "evcxr_variable_store.begin_snapshot({}, {});",
+            self.build_num, self.config.undo_steps
+        ));
+        if self.config.undo_steps == 0 {
+            return statements;
+        }
+        let mut statements = statements
+            .generated("{use evcxr_internal_runtime::SnapshotClone as _;")
+            .generated("use evcxr_internal_runtime::SnapshotNoClone as _;");
+        for var_name in &self.snapshot_variables {
+            if let Some(var_state) = self.stored_variable_states.get(var_name) {
+                statements = statements
+                    .generated(format!(
+                        "let evcxr_snapshot = (&evcxr_variable_store.snapshot_source::<{}>(\
+                         stringify!({}))).evcxr_snapshot();",
+                        var_state.type_name, var_name
+                    ))
+                    .generated(format!(
+                        "evcxr_variable_store.record_snapshot(stringify!({}), evcxr_snapshot);",
+                        var_name
+                    ));
+            }
+        }
+        statements.generated("}")
+    }
+
     // Returns code to load values from the variable store back into their variables.
     fn load_variable_statements(&self) -> CodeBlock {
         let mut statements = CodeBlock::new();
         for (var_name, var_state) in &self.stored_variable_states {
             let mutability = if var_state.is_mut { "mut " } else { "" };
-            statements.load_variable(format!(
-                "let {}{} = evcxr_variable_store.take_variable::<{}>(stringify!({}));",
-                mutability, var_name, var_state.type_name, var_name
-            ));
+            if var_state.leak_on_load {
+                statements.load_variable(format!(
+                    "let {}{}: &'static {} = Box::leak(Box::new(\
+                     evcxr_variable_store.take_variable::<{}>(stringify!({}))));",
+                    mutability, var_name, var_state.type_name, var_state.type_name, var_name
+                ));
+            } else {
+                statements.load_variable(format!(
+                    "let {}{} = evcxr_variable_store.take_variable::<{}>(stringify!({}));",
+                    mutability, var_name, var_state.type_name, var_name
+                ));
+            }
         }
         statements
     }
@@ -1595,7 +3134,7 @@ impl ContextState {
     /// code. Things like use-statements will be removed from the returned code,
     /// as they will have been stored in `self`.
     fn apply(&mut self, user_code: CodeBlock, nodes: &[SyntaxNode]) -> Result<CodeBlock, Error> {
-        if self.config.preserve_vars_on_panic {
+        if self.catches_panics() {
             // Any pre-existing, non-copy variables are marked as available, so that we'll take their
             // values from outside of the catch_unwind block. If they remain this way, then this
             // effectively means that they're not being used.
@@ -1613,6 +3152,7 @@ impl ContextState {
         }
 
         let mut code_out = CodeBlock::new();
+        let mut executed_cell = ExecutedCell::default();
         let mut previous_item_name = None;
         let num_statements = user_code.segments.len();
         for (statement_index, segment) in user_code.segments.into_iter().enumerate() {
@@ -1625,6 +3165,7 @@ impl ContextState {
             if let Some(let_stmt) = ast::LetStmt::cast(node.clone()) {
                 if let Some(pat) = let_stmt.pat() {
                     self.record_new_locals(pat, let_stmt.ty(), &segment, node.text_range());
+                    executed_cell.statements.push(segment.code.clone());
                     code_out = code_out.with_segment(segment);
                 }
             } else if ast::Attr::can_cast(node.kind()) {
@@ -1632,25 +3173,43 @@ impl ContextState {
                     node.text().to_string(),
                     CodeBlock::new().with_segment(segment),
                 );
+            } else if let Some(macro_call) = item::item_defining_macro_call(node) {
+                // Macros like `lazy_static!` and `thread_local!` get parsed as statements, but
+                // they define items that need to persist, so we store them with the other items.
+                let mut segment = segment;
+                let code = segment.code.trim_end();
+                if code.ends_with("};") {
+                    // A semicolon following a macro invoked with braces isn't permitted at the
+                    // top level of a crate.
+                    let semicolon = code.len() - 1;
+                    segment.code.remove(semicolon);
+                }
+                let item_block = CodeBlock::new().with_segment(segment);
+                if let Some(item_name) = item::macro_call_item_name(&macro_call) {
+                    self.items_by_name.insert(item_name, item_block);
+                } else {
+                    self.unnamed_items.push(item_block);
+                }
             } else if ast::Expr::can_cast(node.kind()) {
                 if statement_index == num_statements - 1 {
+                    executed_cell.final_expression = Some(segment.code.clone());
                     if self.config.display_final_expression {
                         code_out = code_out.code_with_fallback(
                             // First we try calling .evcxr_display().
                             CodeBlock::new()
-                                .generated("(")
+                                .generated("evcxr_internal_runtime::start_result(&(")
                                 .with_segment(segment.clone())
-                                .generated(").evcxr_display();")
+                                .generated(")).evcxr_display();")
                                 .code_string(),
                             // If that fails, we try debug format.
                             CodeBlock::new()
-                                .generated(SEND_TEXT_PLAIN_DEF)
                                 .generated(&format!(
-                                    "evcxr_send_text_plain(&format!(\"{}\",&(\n",
+                                    "evcxr_internal_runtime::send_content(\"text/plain\", \
+                                     &format!(\"{}\",evcxr_internal_runtime::start_result(&(\n",
                                     self.config.output_format
                                 ))
                                 .with_segment(segment)
-                                .generated(")));"),
+                                .generated("))));"),
                         );
                     } else {
                         code_out = code_out
@@ -1663,6 +3222,7 @@ impl ContextState {
                     // so don't try to print it. Yes, this is possible. For
                     // example `for x in y {}` is an expression. See the test
                     // non_semi_statements.
+                    executed_cell.statements.push(segment.code.clone());
                     code_out = code_out.with_segment(segment);
                 }
             } else if let Some(item) = ast::Item::cast(node.clone()) {
@@ -1756,9 +3316,15 @@ impl ContextState {
                     }
                 }
             } else {
+                executed_cell.statements.push(segment.code.clone());
                 code_out = code_out.with_segment(segment);
             }
         }
+        self.pending_cell = if executed_cell.is_empty() {
+            None
+        } else {
+            Some(executed_cell)
+        };
         Ok(code_out)
     }
 
@@ -1806,13 +3372,13 @@ impl ContextState {
         segment: &Segment,
         let_stmt_range: TextRange,
     ) {
-        // Default new variables to some type, say String. Assuming it isn't a
-        // String, we'll get a compilation error when we try to move the
-        // variable into our variable store, then we'll see what type the error
-        // message says and fix it up. Hacky huh? If the user gave an explicit
-        // type, we'll use that for all variables in that assignment (probably
-        // only correct if it's a single variable). This gives the user a way to
-        // force the type if rustc is giving us a bad suggestion.
+        // Default new variables to some type, say String. Rust analyzer will normally tell us the
+        // actual type. If it can't, then assuming it isn't a String, we'll get a compilation error
+        // when we try to move the variable into our variable store, then we'll see what type the
+        // error message says and fix it up. Hacky huh? If the user gave an explicit type, we'll use
+        // that for all variables in that assignment (probably only correct if it's a single
+        // variable). This gives the user a way to force the type if rustc is giving us a bad
+        // suggestion.
         let type_name = match opt_ty {
             Some(ty) if type_is_fully_specified(&ty) => format!("{}", AstNode::syntax(&ty).text()),
             _ => "String".to_owned(),
@@ -1826,9 +3392,11 @@ impl ContextState {
                     // All new locals will initially be defined only inside our catch_unwind
                     // block.
                     move_state: VariableMoveState::MovedIntoCatchUnwind,
-                    // If we're preserving copy types, then assume this variable
-                    // is copy until we find out it's not.
-                    is_copy_type: self.config.preserve_vars_on_panic,
+                    // Rust analyzer tells us whether the type is Copy. If it can't, treating it
+                    // as not Copy is always safe.
+                    is_copy_type: false,
+                    leak_on_load: false,
+                    is_promoted: false,
                     definition_span: segment.sequence.map(|segment_index| {
                         let range = name.syntax().text_range() - let_stmt_range.start();
                         UserCodeSpan {
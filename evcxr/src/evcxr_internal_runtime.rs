@@ -14,42 +14,439 @@
 
 // This file is both a module of evcxr and is included via include_str! then
 // built as a crate itself. The latter is the primary use-case. It's included as
-// a submodule only so that constants can be shared.
+// a submodule only so that constants and the encoding of control messages can
+// be shared.
 
-pub const VARIABLE_CHANGED_TYPE: &str = "EVCXR_VARIABLE_CHANGED_TYPE:";
-pub const USER_ERROR_OCCURRED: &str = "EVCXR_ERROR_OCCURRED";
+/// The environment variable through which a runtime process finds the channel over which it
+/// exchanges control messages with evcxr. Evcxr sets it to the channel's handle, which on unix is a
+/// file descriptor and on Windows is a socket handle. Once the runtime process has claimed the
+/// channel, the handle is preceded by the ID of the process and a colon. Processes started by user
+/// code inherit the variable, but not the channel, so they can tell from their process ID that
+/// it's not for them.
+pub const CONTROL_CHANNEL_VAR: &str = "EVCXR_CONTROL_CHANNEL";
+
+// Kinds of control messages sent by user code. Each is the first field of the message.
+
+/// Followed by the name of a variable that was lost because its type changed.
+pub const VARIABLE_CHANGED_TYPE: &str = "VARIABLE_CHANGED_TYPE";
+/// A question mark operator in user code returned an error.
+pub const USER_ERROR_OCCURRED: &str = "USER_ERROR_OCCURRED";
+/// User code panicked.
+pub const PANIC_OCCURRED: &str = "PANIC_OCCURRED";
+/// Followed by pairs of mime type and content of that type. Together, these are alternative
+/// representations of a single thing to be displayed.
+pub const CONTENT: &str = "CONTENT";
+/// Followed by an ID, then pairs of mime type and content, as for `CONTENT`. The ID can be used to
+/// replace the content later.
+pub const DISPLAY: &str = "DISPLAY";
+/// Followed by the ID of an earlier `DISPLAY`, then pairs of mime type and content that replace
+/// its content.
+pub const UPDATE_DISPLAY: &str = "UPDATE_DISPLAY";
+/// The final expression of the code being run has been evaluated. Content sent after this is its
+/// value, rather than something that was displayed while the code was running.
+pub const RESULT_STARTED: &str = "RESULT_STARTED";
+/// Followed by a prompt and "1" if the input is a password, otherwise "0". Evcxr replies with an
+/// `INPUT_RESPONSE` message.
+pub const INPUT_REQUEST: &str = "INPUT_REQUEST";
+/// Followed by the input that the user provided.
+pub const INPUT_RESPONSE: &str = "INPUT_RESPONSE";
+
+/// The mime type with which answers to variable queries are sent to the parent process. These are
+/// sent as content, rather than written to a file, since the parent may not share our filesystem.
+pub const VARIABLE_QUERY_MIME_TYPE: &str = "application/x-evcxr-variable-query";
+
+#[cfg(unix)]
+pub type ControlChannel = std::os::unix::net::UnixStream;
+#[cfg(windows)]
+pub type ControlChannel = std::net::TcpStream;
+
+/// Returns the channel over which this process exchanges control messages with evcxr, or None if
+/// we're not running in a runtime process that has claimed its channel. The channel belongs to the
+/// runtime process, so mustn't be closed.
+pub fn control_channel() -> Option<std::mem::ManuallyDrop<ControlChannel>> {
+    let value = std::env::var(CONTROL_CHANNEL_VAR).ok()?;
+    let (pid, handle) = value.split_once(':')?;
+    if pid.parse() != Ok(std::process::id()) {
+        return None;
+    }
+    let handle = handle.parse().ok()?;
+    #[cfg(unix)]
+    let channel = unsafe { std::os::unix::io::FromRawFd::from_raw_fd(handle) };
+    #[cfg(windows)]
+    let channel = unsafe { std::os::windows::io::FromRawSocket::from_raw_socket(handle) };
+    Some(std::mem::ManuallyDrop::new(channel))
+}
+
+/// Encodes a control message made up of `fields`, the first of which identifies the kind of
+/// message. A message is its length followed by its fields. Each field is its length followed by
+/// its contents. Lengths are in bytes, encoded as little-endian u32s.
+pub fn encode_message(fields: &[&[u8]]) -> Vec<u8> {
+    let mut body = Vec::new();
+    for field in fields {
+        body.extend_from_slice(&(field.len() as u32).to_le_bytes());
+        body.extend_from_slice(field);
+    }
+    let mut message = (body.len() as u32).to_le_bytes().to_vec();
+    message.append(&mut body);
+    message
+}
+
+/// Decodes the control message at the start of `bytes`. Returns its fields and its encoded length,
+/// or None if `bytes` doesn't yet contain the whole message.
+pub fn decode_message(bytes: &[u8]) -> Option<(Vec<Vec<u8>>, usize)> {
+    let body_len = read_len(bytes)?;
+    let body = bytes.get(4..4 + body_len)?;
+    Some((decode_fields(body), 4 + body_len))
+}
+
+/// The maximum length in bytes of the body of a message that `read_message` will accept. Messages
+/// can contain whole shared objects, so this is generous, but it stops a corrupt length from making
+/// us allocate up to 4 GiB.
+pub const MAX_MESSAGE_LEN: usize = 1 << 30;
+
+/// Reads a control message from `reader`, returning its fields. Returns None if `reader` is closed
+/// before a message starts.
+pub fn read_message(reader: &mut impl std::io::Read) -> std::io::Result<Option<Vec<Vec<u8>>>> {
+    let mut len = [0; 4];
+    // Read the first byte separately, so that we can tell whether we reached the end of the stream
+    // before the message started, which is expected, or during it, which isn't.
+    if reader.read(&mut len[..1])? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut len[1..])?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_MESSAGE_LEN {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "Control message of {} bytes is longer than the maximum of {} bytes",
+                len, MAX_MESSAGE_LEN
+            ),
+        ));
+    }
+    let mut body = vec![0; len];
+    reader.read_exact(&mut body)?;
+    Ok(Some(decode_fields(&body)))
+}
+
+fn read_len(bytes: &[u8]) -> Option<usize> {
+    let mut len = [0; 4];
+    len.copy_from_slice(bytes.get(..4)?);
+    Some(u32::from_le_bytes(len) as usize)
+}
+
+fn decode_fields(mut body: &[u8]) -> Vec<Vec<u8>> {
+    let mut fields = Vec::new();
+    while let Some(len) = read_len(body) {
+        let field = match body.get(4..4 + len) {
+            Some(field) => field,
+            None => break,
+        };
+        fields.push(field.to_vec());
+        body = &body[4 + len..];
+    }
+    fields
+}
+
+/// Sends a control message made up of `fields` to evcxr. Does nothing if we're not running in a
+/// runtime process. If the message can't be sent, evcxr has gone away, in which case the runtime
+/// process will exit once it next reads from the channel, so we just report the error.
+pub fn send_control_message(fields: &[&[u8]]) {
+    use std::io::Write;
+    let mut channel = match control_channel() {
+        Some(channel) => channel,
+        None => return,
+    };
+    // Holding the lock on stdout stops messages sent from different threads from being interleaved,
+    // and flushing it means that output from before the message is seen before the message.
+    let stdout = std::io::stdout();
+    let mut stdout = stdout.lock();
+    let _ = stdout.flush();
+    let _ = std::io::stderr().flush();
+    if let Err(error) = channel.write_all(&encode_message(fields)) {
+        eprintln!("Failed to send control message to parent: {:?}", error);
+    }
+}
+
+/// Sends `content`, of type `mime_type`, to be displayed.
+pub fn send_content(mime_type: &str, content: &str) {
+    send_control_message(&[CONTENT.as_bytes(), mime_type.as_bytes(), content.as_bytes()]);
+}
+
+/// Lets evcxr know that `value`, the value of the final expression of the code being run, has been
+/// evaluated, then returns it. This way, content that's sent while evaluating the expression isn't
+/// mistaken for its value.
+pub fn start_result<T: ?Sized>(value: &T) -> &T {
+    send_control_message(&[RESULT_STARTED.as_bytes()]);
+    value
+}
+
+/// Sends the answer to a variable query to the parent process.
+pub fn send_query_result(result: &str) {
+    send_content(VARIABLE_QUERY_MIME_TYPE, result);
+}
 
 pub struct VariableStore {
     variables: std::collections::HashMap<String, Box<dyn std::any::Any + 'static>>,
+    /// Keys that identify the definitions of the types of variables. Types that the user defined
+    /// get a new `TypeId` each time their items are rebuilt, but if the key is the same, then so is
+    /// the layout. Types whose layout we can't be sure of don't have a key.
+    type_keys: std::collections::HashMap<std::any::TypeId, String>,
+    /// Values of variables from before each of the most recent executions, oldest first. Used to
+    /// implement undo.
+    snapshots: Vec<Snapshot>,
+}
+
+struct Snapshot {
+    /// The build number of the execution that this snapshot was taken before.
+    id: i32,
+    /// The values of variables that the execution used. `None` if we couldn't take a copy of the
+    /// variable.
+    values: std::collections::HashMap<String, Option<Box<dyn std::any::Any + 'static>>>,
+}
+
+/// A reference to the value of a variable that we'd like to snapshot. Which trait provides
+/// `evcxr_snapshot` depends on whether `T` implements Clone.
+pub struct SnapshotSource<'a, T>(Option<&'a T>);
+
+pub trait SnapshotClone {
+    fn evcxr_snapshot(&self) -> Option<Box<dyn std::any::Any + 'static>>;
+}
+
+impl<'a, T: Clone + 'static> SnapshotClone for SnapshotSource<'a, T> {
+    fn evcxr_snapshot(&self) -> Option<Box<dyn std::any::Any + 'static>> {
+        self.0
+            .map(|value| Box::new(value.clone()) as Box<dyn std::any::Any + 'static>)
+    }
+}
+
+pub trait SnapshotNoClone {
+    fn evcxr_snapshot(&self) -> Option<Box<dyn std::any::Any + 'static>>;
+}
+
+impl<'a, T> SnapshotNoClone for &SnapshotSource<'a, T> {
+    fn evcxr_snapshot(&self) -> Option<Box<dyn std::any::Any + 'static>> {
+        None
+    }
+}
+
+/// A reference to the value of a variable that's being inspected. Which traits provide
+/// `evcxr_preview` and `evcxr_is_copy` depends on whether `T` implements Debug and Copy.
+pub struct InspectSource<'a, T>(pub &'a T);
+
+pub trait InspectDebug {
+    fn evcxr_preview(&self, max_len: usize) -> Option<String>;
+}
+
+impl<'a, T: std::fmt::Debug> InspectDebug for InspectSource<'a, T> {
+    fn evcxr_preview(&self, max_len: usize) -> Option<String> {
+        let mut writer = TruncatingWriter {
+            out: String::new(),
+            max_len,
+            truncated: false,
+        };
+        let _ = std::fmt::Write::write_fmt(&mut writer, format_args!("{:#?}", self.0));
+        if writer.truncated {
+            writer.out.push_str("...");
+        }
+        Some(writer.out)
+    }
+}
+
+pub trait InspectNoDebug {
+    fn evcxr_preview(&self, max_len: usize) -> Option<String>;
+}
+
+impl<'a, T> InspectNoDebug for &InspectSource<'a, T> {
+    fn evcxr_preview(&self, _max_len: usize) -> Option<String> {
+        None
+    }
+}
+
+pub trait InspectCopy {
+    fn evcxr_is_copy(&self) -> bool;
+}
+
+impl<'a, T: Copy> InspectCopy for InspectSource<'a, T> {
+    fn evcxr_is_copy(&self) -> bool {
+        true
+    }
+}
+
+pub trait InspectNoCopy {
+    fn evcxr_is_copy(&self) -> bool;
+}
+
+impl<'a, T> InspectNoCopy for &InspectSource<'a, T> {
+    fn evcxr_is_copy(&self) -> bool {
+        false
+    }
+}
+
+/// Stops formatting once `max_len` bytes have been written, so that previewing a large value
+/// doesn't require formatting all of it.
+struct TruncatingWriter {
+    out: String,
+    max_len: usize,
+    truncated: bool,
+}
+
+impl std::fmt::Write for TruncatingWriter {
+    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+        let remaining = self.max_len - self.out.len();
+        if s.len() <= remaining {
+            self.out.push_str(s);
+            return Ok(());
+        }
+        let mut end = remaining;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.out.push_str(&s[..end]);
+        self.truncated = true;
+        Err(std::fmt::Error)
+    }
+}
+
+/// What we've found out about variables while inspecting them. Each variable is written as a
+/// header line containing its name, its size, whether it's Copy and the length of its preview (or
+/// "-" if it has none), then the preview itself followed by a newline.
+#[derive(Default)]
+pub struct Inspection {
+    out: String,
+}
+
+impl Inspection {
+    pub fn add(&mut self, name: &str, size: usize, is_copy: bool, preview: Option<String>) {
+        let preview_len = preview
+            .as_ref()
+            .map_or_else(|| "-".to_owned(), |preview| preview.len().to_string());
+        self.out.push_str(&format!(
+            "{} {} {} {}\n",
+            name, size, is_copy as u8, preview_len
+        ));
+        if let Some(preview) = preview {
+            self.out.push_str(&preview);
+            self.out.push('\n');
+        }
+    }
+
+    pub fn send(&self) {
+        send_query_result(&self.out);
+    }
 }
 
 impl VariableStore {
     pub fn new() -> VariableStore {
         VariableStore {
             variables: std::collections::HashMap::new(),
+            type_keys: std::collections::HashMap::new(),
+            snapshots: Vec::new(),
         }
     }
 
     pub fn assert_copy_type<T: Copy>(&self, _: T) {}
 
-    pub fn put_variable<T: 'static>(&mut self, name: &str, value: T) {
+    pub fn put_variable<T: 'static>(&mut self, name: &str, value: T, type_key: Option<&str>) {
+        self.register_type::<T>(type_key);
         self.variables.insert(name.to_owned(), Box::new(value));
     }
 
-    pub fn check_variable<T: 'static>(&mut self, name: &str) -> bool {
+    fn register_type<T: 'static>(&mut self, type_key: Option<&str>) {
+        if let Some(type_key) = type_key {
+            self.type_keys
+                .entry(std::any::TypeId::of::<T>())
+                .or_insert_with(|| type_key.to_owned());
+        }
+    }
+
+    pub fn variable_ref<T: 'static>(&self, name: &str) -> Option<&T> {
+        self.variables.get(name).and_then(|v| v.downcast_ref::<T>())
+    }
+
+    pub fn inspect_source<T: 'static>(&self, name: &str) -> Option<InspectSource<'_, T>> {
+        self.variable_ref(name).map(InspectSource)
+    }
+
+    pub fn check_variable<T: 'static>(&mut self, name: &str, type_key: Option<&str>) -> bool {
+        self.register_type::<T>(type_key);
         if let Some(v) = self.variables.get(name) {
             if v.downcast_ref::<T>().is_none() {
+                let same_key = type_key
+                    .filter(|type_key| key_of_type(&self.type_keys, &**v) == Some(*type_key));
+                if let Some(type_key) = same_key {
+                    // The type was rebuilt, but its definition didn't change.
+                    self.retype_variable::<T>(name, type_key);
+                    return true;
+                }
                 eprintln!(
                     "The type of the variable {} was redefined, so was lost.",
                     name
                 );
-                println!("{}{}", VARIABLE_CHANGED_TYPE, name);
+                send_control_message(&[VARIABLE_CHANGED_TYPE.as_bytes(), name.as_bytes()]);
                 return false;
             }
         }
         true
     }
 
+    /// Changes the type of the value of `name`, and of any snapshots of it, to `T`, which has the
+    /// key `type_key`. Only values whose types have the same key are changed.
+    fn retype_variable<T: 'static>(&mut self, name: &str, type_key: &str) {
+        let type_keys = &self.type_keys;
+        let retype = |value: Box<dyn std::any::Any + 'static>| {
+            if key_of_type(type_keys, &*value) == Some(type_key) {
+                // Safe because types with the same key have the same layout.
+                unsafe { Box::from_raw(Box::into_raw(value) as *mut T) }
+            } else {
+                value
+            }
+        };
+        if let Some(value) = self.variables.remove(name) {
+            self.variables.insert(name.to_owned(), retype(value));
+        }
+        for snapshot in &mut self.snapshots {
+            if let Some(Some(value)) = snapshot.values.remove(name) {
+                snapshot.values.insert(name.to_owned(), Some(retype(value)));
+            }
+        }
+    }
+
+    /// Converts the value of `name` to `T`, which has the key `type_key`, if the value is of the
+    /// type `Previous`, a copy of the previous definition of `T` that has the key
+    /// `previous_type_key`. Returns false if the conversion failed and the variable was lost.
+    pub fn migrate_variable<Previous: 'static, T: 'static>(
+        &mut self,
+        name: &str,
+        previous_type_key: &str,
+        type_key: Option<&str>,
+        convert: impl FnOnce(Previous) -> Option<T>,
+    ) -> bool {
+        self.register_type::<T>(type_key);
+        match self.variables.get(name) {
+            Some(value) if key_of_type(&self.type_keys, &**value) == Some(previous_type_key) => {}
+            _ => return true,
+        }
+        let value = self.variables.remove(name).unwrap();
+        // Safe because types with the same key have the same layout.
+        let previous = unsafe { *Box::from_raw(Box::into_raw(value) as *mut Previous) };
+        // Snapshots of the previous value can't be restored.
+        for snapshot in &mut self.snapshots {
+            snapshot.values.remove(name);
+        }
+        if let Some(value) = convert(previous) {
+            self.variables.insert(name.to_owned(), Box::new(value));
+            return true;
+        }
+        eprintln!(
+            "The type of the variable {} was redefined and its value couldn't be converted, so \
+             was lost.",
+            name
+        );
+        send_control_message(&[VARIABLE_CHANGED_TYPE.as_bytes(), name.as_bytes()]);
+        false
+    }
+
     pub fn take_variable<T: 'static>(&mut self, name: &str) -> T {
         match self.variables.remove(name) {
             Some(v) => {
@@ -81,8 +478,127 @@ impl VariableStore {
         }
     }
 
+    /// Starts a new snapshot for the execution with build number `id`. Any snapshots from that
+    /// execution or later are discarded, since they were from executions that didn't succeed. If
+    /// `max_snapshots` is zero, all snapshots are discarded and no new one is started.
+    pub fn begin_snapshot(&mut self, id: i32, max_snapshots: usize) {
+        self.snapshots.retain(|snapshot| snapshot.id < id);
+        if max_snapshots == 0 {
+            self.snapshots.clear();
+            return;
+        }
+        if self.snapshots.len() >= max_snapshots {
+            let excess = self.snapshots.len() + 1 - max_snapshots;
+            self.snapshots.drain(..excess);
+        }
+        self.snapshots.push(Snapshot {
+            id,
+            values: std::collections::HashMap::new(),
+        });
+    }
+
+    pub fn snapshot_source<T: 'static>(&self, name: &str) -> SnapshotSource<'_, T> {
+        SnapshotSource(
+            self.variables
+                .get(name)
+                .and_then(|value| value.downcast_ref()),
+        )
+    }
+
+    pub fn record_snapshot(&mut self, name: &str, value: Option<Box<dyn std::any::Any + 'static>>) {
+        if let Some(snapshot) = self.snapshots.last_mut() {
+            snapshot.values.insert(name.to_owned(), value);
+        }
+    }
+
+    /// Restores variables to their values from before the execution with build number `id`, by
+    /// applying snapshots from that execution onwards, newest first. Returns false if some
+    /// variables couldn't be restored and were lost.
+    pub fn restore_snapshots(&mut self, id: i32) -> bool {
+        let mut lost = std::collections::HashSet::new();
+        while self.snapshots.last().map(|s| s.id >= id).unwrap_or(false) {
+            let snapshot = self.snapshots.pop().unwrap();
+            for (name, value) in snapshot.values {
+                if let Some(value) = value {
+                    lost.remove(&name);
+                    self.variables.insert(name, value);
+                } else if self.variables.remove(&name).is_some() {
+                    lost.insert(name);
+                }
+            }
+        }
+        for name in &lost {
+            eprintln!(
+                "The variable {} doesn't implement Clone, so couldn't be restored and was lost.",
+                name
+            );
+            send_control_message(&[VARIABLE_CHANGED_TYPE.as_bytes(), name.as_bytes()]);
+        }
+        lost.is_empty()
+    }
+
+    pub fn drop_variable(&mut self, name: &str) {
+        self.variables.remove(name);
+        for snapshot in &mut self.snapshots {
+            snapshot.values.remove(name);
+        }
+    }
+
     pub fn merge(&mut self, mut other: VariableStore) {
         self.variables.extend(other.variables.drain());
+        self.type_keys.extend(other.type_keys.drain());
+    }
+}
+
+/// Returns the key of the type of `value`, if we know it.
+fn key_of_type<'a>(
+    type_keys: &'a std::collections::HashMap<std::any::TypeId, String>,
+    value: &(dyn std::any::Any + 'static),
+) -> Option<&'a str> {
+    type_keys.get(&value.type_id()).map(String::as_str)
+}
+
+/// A field of a value that's being migrated to a new definition of its type, which we'd like to
+/// convert to a `T`. Which trait provides `evcxr_migrate` depends on whether `F` implements
+/// `Into<T>` and, for fields that weren't there before, whether `T` implements `Default`.
+pub struct MigrateField<F, T>(std::cell::Cell<Option<F>>, std::marker::PhantomData<T>);
+
+impl<F, T> MigrateField<F, T> {
+    pub fn new(value: F) -> Self {
+        MigrateField(std::cell::Cell::new(Some(value)), std::marker::PhantomData)
+    }
+}
+
+/// Stands in for a field that the previous definition of a type didn't have.
+pub struct MissingField;
+
+pub trait MigrateInto<T> {
+    fn evcxr_migrate(&self) -> Option<T>;
+}
+
+impl<F: Into<T>, T> MigrateInto<T> for &MigrateField<F, T> {
+    fn evcxr_migrate(&self) -> Option<T> {
+        self.0.take().map(Into::into)
+    }
+}
+
+pub trait MigrateDefault<T> {
+    fn evcxr_migrate(&self) -> Option<T>;
+}
+
+impl<T: Default> MigrateDefault<T> for &MigrateField<MissingField, T> {
+    fn evcxr_migrate(&self) -> Option<T> {
+        Some(T::default())
+    }
+}
+
+pub trait MigrateNone<T> {
+    fn evcxr_migrate(&self) -> Option<T>;
+}
+
+impl<F, T> MigrateNone<T> for MigrateField<F, T> {
+    fn evcxr_migrate(&self) -> Option<T> {
+        None
     }
 }
 
@@ -0,0 +1,106 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use crate::eval_context::ContextState;
+use crate::module::write_file;
+use std::path::Path;
+
+/// Writes `state` as a binary crate in `dir`. The directory may already exist, but it mustn't
+/// already contain a crate.
+pub(crate) fn export_crate(state: &ContextState, dir: &Path) -> Result<(), Error> {
+    if dir.join("Cargo.toml").exists() {
+        bail!("{:?} already contains a Cargo.toml", dir);
+    }
+    write_file(dir, "Cargo.toml", &cargo_toml_contents(state, dir))?;
+    write_file(
+        &dir.join("src"),
+        "main.rs",
+        &state.export_main_code().code_string(),
+    )?;
+    Ok(())
+}
+
+/// Writes `state` as an integration test file at `path`. Each final expression that produced text
+/// output is checked against what was output during the session.
+pub(crate) fn export_test(state: &ContextState, path: &Path) -> Result<(), Error> {
+    if path.exists() {
+        bail!("{:?} already exists", path);
+    }
+    let basename = if let Some(basename) = path.file_name() {
+        basename.to_string_lossy()
+    } else {
+        bail!("{:?} is not a file name", path);
+    };
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let mut contents = String::new();
+    let deps = state.format_cargo_deps();
+    if !deps.is_empty() {
+        contents.push_str("// This test requires the following dev-dependencies:\n");
+        for line in deps.lines() {
+            contents.push_str("// ");
+            contents.push_str(line);
+            contents.push('\n');
+        }
+    }
+    contents.push_str(&state.export_test_code().code_string());
+    write_file(dir, &basename, &contents)
+}
+
+fn cargo_toml_contents(state: &ContextState, dir: &Path) -> String {
+    format!(
+        r#"[package]
+name = "{}"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+{}"#,
+        package_name(dir),
+        state.format_cargo_deps()
+    )
+}
+
+/// Derives a package name from the name of the directory that we're exporting to.
+fn package_name(dir: &Path) -> String {
+    let name: String = dir
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default()
+        .chars()
+        .map(|ch| if ch.is_alphanumeric() { ch } else { '_' })
+        .collect();
+    if name.is_empty() || name.starts_with(|ch: char| ch.is_numeric()) {
+        format!("evcxr_export{}", name)
+    } else {
+        name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::package_name;
+    use std::path::Path;
+
+    #[test]
+    fn test_package_name() {
+        assert_eq!(package_name(Path::new("/tmp/my-project")), "my_project");
+        assert_eq!(
+            package_name(Path::new("/tmp/2d_plot")),
+            "evcxr_export2d_plot"
+        );
+        assert_eq!(package_name(Path::new("/")), "evcxr_export");
+    }
+}
@@ -0,0 +1,146 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::errors::bail;
+use crate::errors::Error;
+
+/// The maximum length of a preview of a variable's value. Longer previews are truncated.
+const PREVIEW_MAX_LEN: usize = 10_000;
+
+/// What we know about a variable and its current value.
+#[derive(Debug, PartialEq, Eq)]
+pub struct VariableInspection {
+    pub name: String,
+    pub type_name: String,
+    pub is_mut: bool,
+    pub is_copy: bool,
+    /// Whether the variable was promoted to a `&'static` reference because other variables borrow
+    /// from it, so can no longer be mutated.
+    pub is_promoted: bool,
+    /// As reported by `std::mem::size_of_val`.
+    pub size: usize,
+    /// The value formatted with `{:#?}`, if the type implements Debug.
+    pub preview: Option<String>,
+}
+
+/// Returns code that inspects the values of `variables` (pairs of name and type) and sends what it
+/// finds to the parent process. The values are read directly from the variable store, so this needs
+/// to run before variables are loaded.
+pub(crate) fn inspect_code(variables: &[(&str, &str)]) -> String {
+    let mut code = String::from(
+        "{use evcxr_internal_runtime::{InspectDebug as _, InspectNoDebug as _};\n\
+         use evcxr_internal_runtime::{InspectCopy as _, InspectNoCopy as _};\n\
+         let mut evcxr_inspection = evcxr_internal_runtime::Inspection::default();\n",
+    );
+    for (name, type_name) in variables {
+        code.push_str(&format!(
+            "if let Some(evcxr_source) = evcxr_variable_store.inspect_source::<{type_name}>({name:?}) {{
+                evcxr_inspection.add(
+                    {name:?},
+                    std::mem::size_of_val(evcxr_source.0),
+                    (&evcxr_source).evcxr_is_copy(),
+                    (&evcxr_source).evcxr_preview({max_len}),
+                );
+            }}\n",
+            name = name,
+            type_name = type_name,
+            max_len = PREVIEW_MAX_LEN
+        ));
+    }
+    code.push_str("evcxr_inspection.send();}\n");
+    code
+}
+
+/// Parses what was sent by `Inspection::send`. `variable_info` supplies the type, mutability and
+/// whether it was promoted of each variable.
+pub(crate) fn parse_inspection<'a>(
+    mut output: &str,
+    variable_info: impl Fn(&str) -> Option<(&'a str, bool, bool)>,
+) -> Result<Vec<VariableInspection>, Error> {
+    let mut inspections = Vec::new();
+    while let Some(header_end) = output.find('\n') {
+        let header: Vec<&str> = output[..header_end].split(' ').collect();
+        output = &output[header_end + 1..];
+        let (name, size, is_copy, preview_len) = match header[..] {
+            [name, size, is_copy, preview_len] => (name, size, is_copy, preview_len),
+            _ => bail!("Invalid inspection header: {:?}", header),
+        };
+        let preview = if preview_len == "-" {
+            None
+        } else {
+            let preview_len: usize = match preview_len.parse() {
+                Ok(len) if len <= output.len() && output.is_char_boundary(len) => len,
+                _ => bail!("Invalid inspection preview length: {}", preview_len),
+            };
+            let preview = &output[..preview_len];
+            output = &output[preview_len..];
+            Some(preview.to_owned())
+        };
+        output = output.strip_prefix('\n').unwrap_or(output);
+        if let Some((type_name, is_mut, is_promoted)) = variable_info(name) {
+            inspections.push(VariableInspection {
+                name: name.to_owned(),
+                type_name: type_name.to_owned(),
+                is_mut,
+                is_copy: is_copy == "1",
+                is_promoted,
+                size: size.parse().unwrap_or(0),
+                preview,
+            });
+        }
+    }
+    inspections.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(inspections)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_inspection;
+    use super::VariableInspection;
+
+    #[test]
+    fn parse() {
+        let output = "v 24 0 17\n[\n    1,\n    2,\n]\nf 8 1 -\nx 8 1 2\n42\n";
+        let inspections = parse_inspection(output, |name| match name {
+            "v" => Some(("Vec<i32>", true, false)),
+            "x" => Some(("&'static i32", false, true)),
+            _ => None,
+        })
+        .unwrap();
+        assert_eq!(
+            inspections,
+            vec![
+                VariableInspection {
+                    name: "v".to_owned(),
+                    type_name: "Vec<i32>".to_owned(),
+                    is_mut: true,
+                    is_copy: false,
+                    is_promoted: false,
+                    size: 24,
+                    preview: Some("[\n    1,\n    2,\n]".to_owned()),
+                },
+                VariableInspection {
+                    name: "x".to_owned(),
+                    type_name: "&'static i32".to_owned(),
+                    is_mut: false,
+                    is_copy: true,
+                    is_promoted: true,
+                    size: 8,
+                    preview: Some("42".to_owned()),
+                },
+            ]
+        );
+        assert!(parse_inspection("x 4 1 100\n42\n", |_| Some(("i32", false, false))).is_err());
+    }
+}
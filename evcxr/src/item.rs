@@ -12,7 +12,20 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::code_block::count_columns;
+use crate::code_block::CodeBlock;
+use crate::code_block::CodeKind;
+use crate::code_block::Segment;
+use crate::code_block::UserCodeMetadata;
+use once_cell::sync::OnceCell;
 use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SyntaxElement;
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxNode;
+use ra_ap_syntax::TextRange;
+use ra_ap_syntax::T;
+use regex::Regex;
 
 /// Returns the name of an item if it has one.
 pub(crate) fn item_name(item: &ast::Item) -> Option<String> {
@@ -36,3 +49,370 @@ fn item_ident(item: &ast::Item) -> Option<ast::Name> {
         _ => None,
     }
 }
+
+/// If `code` is an impl block, returns the type that it's for, together with the trait being
+/// implemented if there is one.
+pub(crate) fn impl_target(code: &str) -> Option<String> {
+    match ast::SourceFile::parse(code).tree().items().next()? {
+        ast::Item::Impl(impl_item) => {
+            let self_ty = impl_item.self_ty()?.syntax().to_string();
+            Some(match impl_item.trait_() {
+                Some(trait_ty) => format!("{} for {}", trait_ty.syntax(), self_ty),
+                None => self_ty,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Returns a copy of `code` in which items are made `pub`, so that they can be used from another
+/// crate. Fields of structs and unions, associated items of inherent impls and statics declared
+/// via item-level macro invocations such as `thread_local!` are also made `pub`. Rather than
+/// editing the code in place, segments are split around the inserted visibility so that errors in
+/// the user's code can still be mapped back to where the user wrote it.
+pub(crate) fn make_public(code: &CodeBlock) -> CodeBlock {
+    let mut result = CodeBlock::new();
+    for segment in &code.segments {
+        let edits = visibility_edits(&segment.code);
+        if edits.is_empty() {
+            result = result.with_segment(segment.clone());
+            continue;
+        }
+        let mut piece_start = 0;
+        for (range, replacement) in edits {
+            let start = usize::from(range.start());
+            if start > piece_start {
+                result = result.with(
+                    piece_kind(segment, piece_start),
+                    &segment.code[piece_start..start],
+                );
+            }
+            result = result.generated(replacement);
+            piece_start = usize::from(range.end());
+        }
+        if piece_start < segment.code.len() {
+            result = result.with(
+                piece_kind(segment, piece_start),
+                &segment.code[piece_start..],
+            );
+        }
+    }
+    result
+}
+
+/// Returns whether `code` is a `macro_rules!` definition.
+pub(crate) fn is_macro_rules(code: &str) -> bool {
+    let source_file = ast::SourceFile::parse(code).tree();
+    matches!(
+        ast::HasModuleItem::items(&source_file).next(),
+        Some(ast::Item::MacroRules(_))
+    )
+}
+
+/// Returns everything in `code` that looks like an identifier, including keywords and words within
+/// comments and strings. This is good enough for working out which items some code might use.
+pub(crate) fn identifiers(code: &str) -> impl Iterator<Item = &str> {
+    static IDENTIFIER: OnceCell<Regex> = OnceCell::new();
+    IDENTIFIER
+        .get_or_init(|| Regex::new("[A-Za-z_][A-Za-z0-9_]*").unwrap())
+        .find_iter(code)
+        .map(|m| m.as_str())
+}
+
+/// Returns the macro call if `node` is a statement that invokes a macro which appears to define
+/// items, e.g. `lazy_static! { static ref X: ... }` or `thread_local!`. We can't know what a macro
+/// expands to, so we go by whether its input starts with something like `static` or `struct`.
+pub(crate) fn item_defining_macro_call(node: &SyntaxNode) -> Option<ast::MacroCall> {
+    let macro_call = if let Some(stmt) = ast::ExprStmt::cast(node.clone()) {
+        match stmt.expr()? {
+            ast::Expr::MacroExpr(macro_expr) => macro_expr.macro_call()?,
+            _ => return None,
+        }
+    } else if let Some(macro_expr) = ast::MacroExpr::cast(node.clone()) {
+        macro_expr.macro_call()?
+    } else {
+        ast::MacroCall::cast(node.clone())?
+    };
+    let first_kind = significant_macro_tokens(&macro_call).next()?.kind();
+    if ITEM_KEYWORDS.contains(&first_kind) {
+        Some(macro_call)
+    } else {
+        None
+    }
+}
+
+/// Returns the name of the first item defined by a macro call for which `item_defining_macro_call`
+/// returned something. Returns `None` if the name can't easily be determined, e.g. for `impl`.
+pub(crate) fn macro_call_item_name(macro_call: &ast::MacroCall) -> Option<String> {
+    // Skip any visibility, e.g. `pub` or `pub(crate)`.
+    let mut tokens = significant_macro_tokens(macro_call)
+        .skip_while(|token| token.kind() == T![pub] || token.kind() == SyntaxKind::TOKEN_TREE);
+    if !matches!(
+        tokens.next()?.kind(),
+        SyntaxKind::STATIC_KW
+            | SyntaxKind::STRUCT_KW
+            | SyntaxKind::ENUM_KW
+            | SyntaxKind::FN_KW
+            | SyntaxKind::CONST_KW
+            | SyntaxKind::TYPE_KW
+            | SyntaxKind::TRAIT_KW
+            | SyntaxKind::MOD_KW
+    ) {
+        return None;
+    }
+    tokens
+        .find(|token| token.kind() == SyntaxKind::IDENT)
+        .and_then(|token| token.into_token())
+        .map(|token| token.text().to_owned())
+}
+
+const ITEM_KEYWORDS: &[SyntaxKind] = &[
+    T![pub],
+    T![static],
+    T![struct],
+    T![enum],
+    T![fn],
+    T![const],
+    T![type],
+    T![trait],
+    T![impl],
+    T![mod],
+    T![use],
+];
+
+/// Returns the elements directly within the macro call's token tree, excluding the delimiters,
+/// whitespace, comments and attributes.
+fn significant_macro_tokens(macro_call: &ast::MacroCall) -> impl Iterator<Item = SyntaxElement> {
+    let mut previous_was_pound = false;
+    macro_call
+        .token_tree()
+        .into_iter()
+        .flat_map(|token_tree| token_tree.syntax().children_with_tokens().skip(1))
+        .filter(move |element| {
+            let kind = element.kind();
+            if matches!(kind, SyntaxKind::WHITESPACE | SyntaxKind::COMMENT) {
+                return false;
+            }
+            // Skip attributes, which consist of a `#` followed by a token tree.
+            let is_attribute =
+                kind == T![#] || (previous_was_pound && kind == SyntaxKind::TOKEN_TREE);
+            previous_was_pound = kind == T![#];
+            !is_attribute
+                && !matches!(
+                    kind,
+                    SyntaxKind::R_CURLY | SyntaxKind::R_PAREN | SyntaxKind::R_BRACK
+                )
+        })
+}
+
+/// Returns the kind for the part of `segment` that starts at `offset`. If the segment is the
+/// user's original code, then the returned metadata points at where that part starts.
+fn piece_kind(segment: &Segment, offset: usize) -> CodeKind {
+    if let CodeKind::OriginalUserCode(meta) = &segment.kind {
+        let before = &segment.code[..offset];
+        let line_start = before.rfind('\n').map_or(0, |index| index + 1);
+        let mut column_offset = count_columns(&before[line_start..]);
+        if line_start == 0 {
+            column_offset += meta.column_offset;
+        }
+        CodeKind::OriginalUserCode(UserCodeMetadata {
+            start_byte: meta.start_byte + offset,
+            node_index: meta.node_index,
+            start_line: meta.start_line + before.matches('\n').count(),
+            column_offset,
+        })
+    } else {
+        segment.kind.clone()
+    }
+}
+
+/// Returns the edits, sorted by position, needed to make everything in `code` that can be `pub`,
+/// `pub`. Each edit is a range to be replaced, which will be empty for insertions.
+fn visibility_edits(code: &str) -> Vec<(TextRange, &'static str)> {
+    let mut edits = Vec::new();
+    for node in ast::SourceFile::parse(code).tree().syntax().descendants() {
+        if let Some(macro_call) = ast::MacroCall::cast(node.clone()) {
+            if is_item_level(&node) {
+                if let Some(token_tree) = macro_call.token_tree() {
+                    macro_static_edits(&token_tree, &mut edits);
+                }
+            }
+        } else if can_be_pub(&node) {
+            if let Some(visibility) = node.children().find_map(ast::Visibility::cast) {
+                if visibility.syntax().text() != "pub" {
+                    edits.push((visibility.syntax().text_range(), "pub"));
+                }
+            } else if let Some(start) = node
+                .children_with_tokens()
+                .find(|child| {
+                    !matches!(
+                        child.kind(),
+                        SyntaxKind::ATTR | SyntaxKind::COMMENT | SyntaxKind::WHITESPACE
+                    )
+                })
+                .map(|child| child.text_range().start())
+            {
+                edits.push((TextRange::empty(start), "pub "));
+            }
+        }
+    }
+    edits.sort_by_key(|(range, _)| range.start());
+    edits
+}
+
+fn is_item_level(node: &SyntaxNode) -> bool {
+    node.parent().map_or(false, |parent| {
+        ast::SourceFile::can_cast(parent.kind()) || ast::ItemList::can_cast(parent.kind())
+    })
+}
+
+/// Returns whether `node` is something that is permitted to have a visibility.
+fn can_be_pub(node: &SyntaxNode) -> bool {
+    let parent = node.parent();
+    let grandparent = parent.as_ref().and_then(|parent| parent.parent());
+    match node.kind() {
+        SyntaxKind::CONST | SyntaxKind::FN | SyntaxKind::TYPE_ALIAS => {
+            if parent.map_or(false, |parent| ast::AssocItemList::can_cast(parent.kind())) {
+                // Only items in inherent impls can have a visibility. Items in traits and trait
+                // impls can't.
+                grandparent
+                    .and_then(ast::Impl::cast)
+                    .map_or(false, |imp| imp.trait_().is_none())
+            } else {
+                true
+            }
+        }
+        SyntaxKind::ENUM
+        | SyntaxKind::MODULE
+        | SyntaxKind::STATIC
+        | SyntaxKind::STRUCT
+        | SyntaxKind::TRAIT
+        | SyntaxKind::UNION
+        | SyntaxKind::USE => true,
+        // Fields of enum variants are always public and can't have a visibility.
+        SyntaxKind::RECORD_FIELD | SyntaxKind::TUPLE_FIELD => grandparent.map_or(false, |owner| {
+            ast::Struct::can_cast(owner.kind()) || ast::Union::can_cast(owner.kind())
+        }),
+        _ => false,
+    }
+}
+
+/// Adds edits that make statics declared directly within `token_tree` public. This covers macros
+/// like `thread_local!` and `lazy_static!`.
+fn macro_static_edits(token_tree: &ast::TokenTree, edits: &mut Vec<(TextRange, &'static str)>) {
+    let mut previous = Vec::new();
+    for child in token_tree.syntax().children_with_tokens() {
+        let kind = child.kind();
+        if matches!(kind, SyntaxKind::COMMENT | SyntaxKind::WHITESPACE) {
+            continue;
+        }
+        // Check the previous two tokens so that we also skip things like `pub(crate)`, which is
+        // good enough for the macros that we care about.
+        if kind == T![static] && !previous.iter().rev().take(2).any(|k| *k == T![pub]) {
+            edits.push((TextRange::empty(child.text_range().start()), "pub "));
+        }
+        previous.push(kind);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn make_public_normalized(code: &str) -> String {
+        make_public(&CodeBlock::new().other_user_code(code.to_owned()))
+            .code_string()
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    #[test]
+    fn test_make_public() {
+        assert_eq!(
+            make_public_normalized("#[derive(Debug)] struct Foo { a: i32, pub(crate) b: i32 }"),
+            "#[derive(Debug)] pub struct Foo { pub a: i32, pub b: i32 }"
+        );
+        assert_eq!(
+            make_public_normalized("struct Foo(i32, pub String);"),
+            "pub struct Foo(pub i32, pub String);"
+        );
+        assert_eq!(
+            make_public_normalized("enum Foo { A { a: i32 }, B(i32) }"),
+            "pub enum Foo { A { a: i32 }, B(i32) }"
+        );
+        assert_eq!(
+            make_public_normalized("impl Foo { const A: i32 = 1; fn new() -> Self { todo!() } }"),
+            "impl Foo { pub const A: i32 = 1; pub fn new() -> Self { todo!() } }"
+        );
+        assert_eq!(
+            make_public_normalized("impl Bar for Foo { type T = i32; fn bar(&self) {} }"),
+            "impl Bar for Foo { type T = i32; fn bar(&self) {} }"
+        );
+        assert_eq!(
+            make_public_normalized("trait Bar { fn bar(&self); }"),
+            "pub trait Bar { fn bar(&self); }"
+        );
+        assert_eq!(
+            make_public_normalized("mod m { fn f() {} }"),
+            "pub mod m { pub fn f() {} }"
+        );
+        assert_eq!(
+            make_public_normalized("use std::collections::HashMap;"),
+            "pub use std::collections::HashMap;"
+        );
+        assert_eq!(
+            make_public_normalized("thread_local! { static A: u32 = 1; pub static B: u32 = 2; }"),
+            "thread_local! { pub static A: u32 = 1; pub static B: u32 = 2; }"
+        );
+        assert_eq!(
+            make_public_normalized("macro_rules! foo { () => { static A: u32 = 1; } }"),
+            "macro_rules! foo { () => { static A: u32 = 1; } }"
+        );
+    }
+
+    #[test]
+    fn test_make_public_preserves_user_code_origins() {
+        let (user_code, _) = CodeBlock::from_original_user_code("struct Foo {\n    a: i32,\n}");
+        let code = make_public(&user_code);
+        // Lines are: "pub", "struct Foo {", "    ", "pub", "a: i32,", "}".
+        match code.origin_for_line(5) {
+            (CodeKind::OriginalUserCode(meta), 0) => {
+                assert_eq!(meta.start_line, 2);
+                assert_eq!(meta.column_offset, 4);
+            }
+            other => panic!("Unexpected origin: {:?}", other),
+        }
+    }
+
+    fn macro_item_name(code: &str) -> Option<Option<String>> {
+        let statements = crate::statement_splitter::split_into_statements(code);
+        item_defining_macro_call(&statements[0].node)
+            .map(|macro_call| macro_call_item_name(&macro_call))
+    }
+
+    #[test]
+    fn test_item_defining_macro_call() {
+        assert_eq!(
+            macro_item_name("lazy_static! { static ref FOO: u32 = 1; }"),
+            Some(Some("FOO".to_owned()))
+        );
+        assert_eq!(
+            macro_item_name("thread_local!(pub(crate) static BAR: u32 = 1);"),
+            Some(Some("BAR".to_owned()))
+        );
+        assert_eq!(
+            macro_item_name("bitflags! { #[derive(Default)] struct Flags: u32 { const A = 1; } }"),
+            Some(Some("Flags".to_owned()))
+        );
+        assert_eq!(macro_item_name("foo! { impl Foo {} }"), Some(None));
+        assert_eq!(macro_item_name("println!(\"static\");"), None);
+        assert_eq!(macro_item_name("vec![1, 2]"), None);
+    }
+
+    #[test]
+    fn test_is_macro_rules() {
+        assert!(is_macro_rules("macro_rules! foo { () => {} }"));
+        assert!(!is_macro_rules("fn foo() {}"));
+    }
+}
@@ -0,0 +1,520 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splitting the items that the user has defined between a chain of items crates. Each crate is a
+//! dylib that depends on the one before it and re-exports everything from it, so an item in a
+//! later crate shadows any item with the same name in an earlier one. Once the runtime process has
+//! loaded a crate, loading another with the same name just gives the one that's already loaded, so
+//! crates are never changed, only added. When items are defined or redefined, they go into a new
+//! crate, together with any items that use them. Everything else stays where it is, so its statics
+//! and the `TypeId`s of its types are unaffected. Items that can't be shadowed, like impl blocks,
+//! instead get rebuilt along with everything after them, as does everything after an item that was
+//! removed.
+
+use crate::code_block::CodeBlock;
+use crate::item::identifiers;
+use crate::module::ITEMS_CRATE_NAME;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+
+/// The items to be compiled into the items crates.
+pub(crate) struct ItemsCode {
+    /// Code that goes at the start of each crate. e.g. crate attributes, `extern crate` statements
+    /// and `macro_rules!` definitions.
+    pub(crate) prelude: CodeBlock,
+    /// The code of each `macro_rules!` definition in `prelude`, by name.
+    pub(crate) macros: HashMap<String, String>,
+    /// Each item that isn't a `macro_rules!` definition, together with its name if it has one.
+    pub(crate) items: Vec<(Option<String>, CodeBlock)>,
+    /// The dependency line for each external crate, keyed by crate name.
+    pub(crate) deps: BTreeMap<String, String>,
+    /// Anything else that affects how the crates get built. If it changes, they all get rebuilt.
+    pub(crate) environment: String,
+}
+
+/// An items crate that we've built.
+#[derive(Debug)]
+struct Layer {
+    version: u32,
+    /// The code of each item in the crate, with its name if it has one.
+    items: Vec<(Option<String>, String)>,
+    /// The `macro_rules!` definitions in the crate's prelude.
+    macros: HashMap<String, String>,
+    /// The external crates that the crate was built with.
+    deps: BTreeMap<String, String>,
+}
+
+/// The items crates that we've built, oldest first.
+#[derive(Default)]
+pub(crate) struct ItemLayers {
+    layers: Vec<Layer>,
+    /// The `ItemsCode::environment` that `layers` were built with.
+    environment: String,
+    /// The version to give the next crate that we build. Versions aren't reused, since a crate
+    /// that we no longer use may still be loaded.
+    next_version: u32,
+}
+
+/// The changes needed to the items crates in order to build some `ItemsCode`.
+pub(crate) struct LayerPlan {
+    /// How many of the existing crates are kept.
+    keep: usize,
+    /// A crate to be added after the kept crates, if any.
+    new_layer: Option<Layer>,
+    /// The code of the crate to be added.
+    pub(crate) new_code: Option<CodeBlock>,
+    /// The version of each crate after the change, oldest first.
+    pub(crate) versions: Vec<u32>,
+    environment: String,
+}
+
+impl ItemLayers {
+    /// Returns the version of the newest crate, if we've built any.
+    pub(crate) fn top_version(&self) -> Option<u32> {
+        self.layers.last().map(|layer| layer.version)
+    }
+
+    /// Works out what we need to build in order to get `items`. If nothing has changed, the plan
+    /// will be to keep all existing crates and not add any.
+    pub(crate) fn plan(&self, items: &ItemsCode) -> LayerPlan {
+        let mut keep = if items.environment == self.environment {
+            self.layers.len()
+        } else {
+            0
+        };
+        // If an external crate changed, anything built with it needs rebuilding, otherwise we'd end
+        // up using two versions of the crate.
+        if let Some(index) = self.layers.iter().position(|layer| {
+            layer
+                .deps
+                .iter()
+                .any(|(name, dep)| items.deps.get(name) != Some(dep))
+        }) {
+            keep = keep.min(index);
+        }
+        let codes: Vec<String> = items
+            .items
+            .iter()
+            .map(|(_, code)| code.code_string())
+            .collect();
+        let moved = loop {
+            match self.items_to_move(keep, items, &codes) {
+                Ok(moved) => break moved,
+                Err(index) => keep = index,
+            }
+        };
+
+        let mut versions: Vec<u32> = self.layers[..keep]
+            .iter()
+            .map(|layer| layer.version)
+            .collect();
+        if keep > 0 && moved.is_empty() {
+            return LayerPlan {
+                keep,
+                new_layer: None,
+                new_code: None,
+                versions,
+                environment: items.environment.clone(),
+            };
+        }
+        let mut code = items.prelude.clone();
+        if keep > 0 {
+            code = code.generated(format!("pub use {}::*;", ITEMS_CRATE_NAME));
+        }
+        let mut layer_items = Vec::new();
+        for index in moved {
+            let (name, item) = &items.items[index];
+            code = code.add_all(item.clone());
+            layer_items.push((name.clone(), codes[index].clone()));
+        }
+        versions.push(self.next_version);
+        LayerPlan {
+            keep,
+            new_layer: Some(Layer {
+                version: self.next_version,
+                items: layer_items,
+                macros: items.macros.clone(),
+                deps: items.deps.clone(),
+            }),
+            new_code: Some(code),
+            versions,
+            environment: items.environment.clone(),
+        }
+    }
+
+    /// Returns the indexes, in order, of the items that need to go into a new crate if we keep the
+    /// first `keep` crates. If we can't keep that many, returns how many we could keep instead.
+    fn items_to_move(
+        &self,
+        keep: usize,
+        items: &ItemsCode,
+        codes: &[String],
+    ) -> Result<Vec<usize>, usize> {
+        let kept = &self.layers[..keep];
+        let named: HashMap<&str, usize> = items
+            .items
+            .iter()
+            .enumerate()
+            .filter_map(|(index, (name, _))| Some((name.as_deref()?, index)))
+            .collect();
+        let mut new_unnamed: Vec<usize> = items
+            .items
+            .iter()
+            .enumerate()
+            .filter(|(_, (name, _))| name.is_none())
+            .map(|(index, _)| index)
+            .collect();
+        // The crate and code of the definition of each name that later code sees.
+        let mut visible: HashMap<&str, (usize, &str)> = HashMap::new();
+        for (layer_index, layer) in kept.iter().enumerate() {
+            for (name, code) in &layer.items {
+                match name {
+                    // If an item was removed, we need to rebuild from its first definition, since
+                    // otherwise an earlier definition would become visible again.
+                    Some(name) if !named.contains_key(name.as_str()) => return Err(layer_index),
+                    Some(name) => {
+                        visible.insert(name, (layer_index, code));
+                    }
+                    // Unnamed items can't be shadowed, so if one was removed, we need to rebuild
+                    // the crate that it's in.
+                    None => match new_unnamed.iter().position(|index| codes[*index] == *code) {
+                        Some(position) => {
+                            new_unnamed.remove(position);
+                        }
+                        None => return Err(layer_index),
+                    },
+                }
+            }
+        }
+
+        let mut moved: HashSet<&str> = named
+            .iter()
+            .filter(|(name, index)| {
+                visible.get(*name).map(|(_, code)| *code) != Some(codes[**index].as_str())
+            })
+            .map(|(name, _)| *name)
+            .collect();
+        // Impl blocks need to be in the same crate as the type or trait that they're for, so any
+        // items that new unnamed items refer to get moved along with them.
+        for index in &new_unnamed {
+            for identifier in identifiers(&codes[*index]) {
+                if let Some((name, _)) = named.get_key_value(identifier) {
+                    moved.insert(name);
+                }
+            }
+        }
+        // Items that use moved items, or macros that changed, need to be rebuilt too. Since moving
+        // those may require moving others, we repeat until nothing more needs moving.
+        loop {
+            let mut more_moved = false;
+            for (layer_index, layer) in kept.iter().enumerate() {
+                for (name, code) in &layer.items {
+                    if let Some(name) = name {
+                        // Skip items that are already moving and definitions that are shadowed.
+                        if moved.contains(name.as_str())
+                            || visible.get(name.as_str()).map(|(index, _)| *index)
+                                != Some(layer_index)
+                        {
+                            continue;
+                        }
+                    }
+                    let needs_rebuild = identifiers(code).any(|identifier| {
+                        moved.contains(identifier)
+                            || layer.macros.get(identifier) != items.macros.get(identifier)
+                    });
+                    if !needs_rebuild {
+                        continue;
+                    }
+                    match name
+                        .as_ref()
+                        .and_then(|name| named.get_key_value(name.as_str()))
+                    {
+                        Some((name, _)) => {
+                            moved.insert(name);
+                            more_moved = true;
+                        }
+                        None => return Err(layer_index),
+                    }
+                }
+            }
+            if !more_moved {
+                break;
+            }
+        }
+        let mut indexes: Vec<usize> = moved
+            .iter()
+            .map(|name| named[name])
+            .chain(new_unnamed)
+            .collect();
+        indexes.sort_unstable();
+        Ok(indexes)
+    }
+
+    /// Updates our record of what's been built, once `plan` has been carried out.
+    pub(crate) fn commit(&mut self, plan: LayerPlan) {
+        self.layers.truncate(plan.keep);
+        if let Some(layer) = plan.new_layer {
+            self.next_version = layer.version + 1;
+            self.layers.push(layer);
+        }
+        self.environment = plan.environment;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn items_code(items: &[(Option<&str>, &str)], macros: &[(&str, &str)]) -> ItemsCode {
+        ItemsCode {
+            prelude: CodeBlock::new(),
+            macros: macros
+                .iter()
+                .map(|(name, code)| (name.to_string(), code.to_string()))
+                .collect(),
+            items: items
+                .iter()
+                .map(|(name, code)| {
+                    (
+                        name.map(str::to_owned),
+                        CodeBlock::new().other_user_code(code.to_string()),
+                    )
+                })
+                .collect(),
+            deps: BTreeMap::new(),
+            environment: String::new(),
+        }
+    }
+
+    /// Plans and commits `items`, returning the number of crates kept and the code of the items
+    /// that went into a new crate.
+    fn build(layers: &mut ItemLayers, items: &ItemsCode) -> (usize, Vec<String>) {
+        let plan = layers.plan(items);
+        let result = (
+            plan.keep,
+            plan.new_layer.as_ref().map_or_else(Vec::new, |layer| {
+                layer.items.iter().map(|(_, code)| code.clone()).collect()
+            }),
+        );
+        layers.commit(plan);
+        result
+    }
+
+    #[test]
+    fn new_items_go_in_new_crate() {
+        let mut layers = ItemLayers::default();
+        assert_eq!(
+            build(&mut layers, &items_code(&[(Some("a"), "fn a() {}")], &[])),
+            (0, vec!["fn a() {}".to_owned()])
+        );
+        let items = items_code(&[(Some("a"), "fn a() {}"), (Some("b"), "fn b() {}")], &[]);
+        assert_eq!(
+            build(&mut layers, &items),
+            (1, vec!["fn b() {}".to_owned()])
+        );
+        assert_eq!(build(&mut layers, &items), (2, vec![]));
+        assert_eq!(layers.top_version(), Some(1));
+    }
+
+    #[test]
+    fn redefined_items_moved_with_items_that_use_them() {
+        let mut layers = ItemLayers::default();
+        build(
+            &mut layers,
+            &items_code(
+                &[
+                    (Some("Foo"), "struct Foo;"),
+                    (Some("Bar"), "struct Bar;"),
+                    (Some("foo"), "fn foo(_: Foo) {}"),
+                    (Some("uses_foo"), "fn uses_foo() { foo(Foo) }"),
+                ],
+                &[],
+            ),
+        );
+        let items = items_code(
+            &[
+                (Some("Foo"), "struct Foo(i32);"),
+                (Some("Bar"), "struct Bar;"),
+                (Some("foo"), "fn foo(_: Foo) {}"),
+                (Some("uses_foo"), "fn uses_foo() { foo(Foo) }"),
+            ],
+            &[],
+        );
+        assert_eq!(
+            build(&mut layers, &items),
+            (
+                1,
+                vec![
+                    "struct Foo(i32);".to_owned(),
+                    "fn foo(_: Foo) {}".to_owned(),
+                    "fn uses_foo() { foo(Foo) }".to_owned()
+                ]
+            )
+        );
+        // The original definitions are shadowed, so redefining `Bar` doesn't move them again.
+        let items = items_code(
+            &[
+                (Some("Foo"), "struct Foo(i32);"),
+                (Some("Bar"), "struct Bar(i32);"),
+                (Some("foo"), "fn foo(_: Foo) {}"),
+                (Some("uses_foo"), "fn uses_foo() { foo(Foo) }"),
+            ],
+            &[],
+        );
+        assert_eq!(
+            build(&mut layers, &items),
+            (2, vec!["struct Bar(i32);".to_owned()])
+        );
+    }
+
+    #[test]
+    fn impls_moved_with_what_they_implement() {
+        let mut layers = ItemLayers::default();
+        build(
+            &mut layers,
+            &items_code(
+                &[
+                    (Some("Foo"), "struct Foo;"),
+                    (Some("Bar"), "struct Bar;"),
+                    (Some("foo"), "fn foo(_: Foo) {}"),
+                ],
+                &[],
+            ),
+        );
+        let items = items_code(
+            &[
+                (Some("Foo"), "struct Foo;"),
+                (Some("Bar"), "struct Bar;"),
+                (Some("foo"), "fn foo(_: Foo) {}"),
+                (None, "impl Foo { fn new() -> Foo { Foo } }"),
+            ],
+            &[],
+        );
+        assert_eq!(
+            build(&mut layers, &items),
+            (
+                1,
+                vec![
+                    "struct Foo;".to_owned(),
+                    "fn foo(_: Foo) {}".to_owned(),
+                    "impl Foo { fn new() -> Foo { Foo } }".to_owned()
+                ]
+            )
+        );
+        // The impl can't be shadowed, so redefining `Foo` rebuilds the crate that it's in.
+        let items = items_code(
+            &[
+                (Some("Foo"), "struct Foo(i32);"),
+                (Some("Bar"), "struct Bar;"),
+                (Some("foo"), "fn foo(_: Foo) {}"),
+                (None, "impl Foo { fn new() -> Foo { Foo } }"),
+            ],
+            &[],
+        );
+        assert_eq!(
+            build(&mut layers, &items),
+            (
+                1,
+                vec![
+                    "struct Foo(i32);".to_owned(),
+                    "fn foo(_: Foo) {}".to_owned(),
+                    "impl Foo { fn new() -> Foo { Foo } }".to_owned()
+                ]
+            )
+        );
+    }
+
+    #[test]
+    fn removed_items_rebuilt_from_first_definition() {
+        let mut layers = ItemLayers::default();
+        build(
+            &mut layers,
+            &items_code(&[(Some("a"), "fn a() {}"), (Some("b"), "fn b() {}")], &[]),
+        );
+        build(
+            &mut layers,
+            &items_code(
+                &[(Some("a"), "fn a() {}"), (Some("b"), "fn b() -> i32 { 1 }")],
+                &[],
+            ),
+        );
+        build(
+            &mut layers,
+            &items_code(
+                &[
+                    (Some("a"), "fn a() {}"),
+                    (Some("b"), "fn b() -> i32 { 1 }"),
+                    (Some("c"), "fn c() {}"),
+                ],
+                &[],
+            ),
+        );
+        assert_eq!(
+            build(
+                &mut layers,
+                &items_code(
+                    &[(Some("a"), "fn a() {}"), (Some("b"), "fn b() -> i32 { 1 }")],
+                    &[]
+                )
+            ),
+            (2, vec![])
+        );
+        assert_eq!(
+            build(&mut layers, &items_code(&[(Some("a"), "fn a() {}")], &[])),
+            (0, vec!["fn a() {}".to_owned()])
+        );
+    }
+
+    #[test]
+    fn changed_macros_environment_and_deps() {
+        let mut layers = ItemLayers::default();
+        let mut items = items_code(
+            &[(Some("a"), "fn a() { m!() }"), (Some("b"), "fn b() {}")],
+            &[("m", "macro_rules! m { () => {} }")],
+        );
+        items
+            .deps
+            .insert("foo".to_owned(), "foo = \"1\"".to_owned());
+        build(&mut layers, &items);
+        items
+            .macros
+            .insert("m".to_owned(), "macro_rules! m { () => { () } }".to_owned());
+        assert_eq!(
+            build(&mut layers, &items),
+            (1, vec!["fn a() { m!() }".to_owned()])
+        );
+        // Adding an external crate doesn't affect anything that was built without it.
+        items
+            .deps
+            .insert("bar".to_owned(), "bar = \"1\"".to_owned());
+        assert_eq!(build(&mut layers, &items), (2, vec![]));
+        items
+            .deps
+            .insert("bar".to_owned(), "bar = \"2\"".to_owned());
+        assert_eq!(build(&mut layers, &items), (2, vec![]));
+        items
+            .deps
+            .insert("foo".to_owned(), "foo = \"2\"".to_owned());
+        assert_eq!(
+            build(&mut layers, &items),
+            (
+                0,
+                vec!["fn a() { m!() }".to_owned(), "fn b() {}".to_owned()]
+            )
+        );
+        items.environment = "#![feature(never_type)]".to_owned();
+        assert_eq!(build(&mut layers, &items).0, 0);
+    }
+}
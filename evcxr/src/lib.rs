@@ -27,21 +27,33 @@ mod crate_config;
 mod eval_context;
 #[allow(dead_code)]
 mod evcxr_internal_runtime;
+mod export;
+mod inspect;
 mod item;
+mod item_layers;
+mod migration;
 mod module;
 mod runtime;
+mod runtime_server;
 mod rust_analyzer;
+mod session;
 mod statement_splitter;
+mod transport;
 mod use_trees;
 
+pub use crate::child_process::InterruptHandle;
+pub use crate::child_process::StdinHandle;
 pub use crate::command_context::CommandContext;
 pub use crate::errors::CompilationError;
 pub use crate::errors::Error;
+pub use crate::eval_context::DisplayBundle;
 pub use crate::eval_context::EvalCallbacks;
 pub use crate::eval_context::EvalContext;
 pub use crate::eval_context::EvalContextOutputs;
 pub use crate::eval_context::EvalOutputs;
+pub use crate::inspect::VariableInspection;
 pub use crate::runtime::runtime_hook;
+pub use crate::runtime_server::run_runtime_server;
 pub use rust_analyzer::Completions;
 
 /// Return the directory that evcxr tools should use for their configuration.
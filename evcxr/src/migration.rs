@@ -0,0 +1,577 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Keeping variables when the items that define their types get rebuilt. Each build of an items
+//! crate has a different name, so when a crate gets rebuilt, the types in it get a new `TypeId`,
+//! even if their definitions didn't change. See `item_layers` for when that happens. Alongside
+//! each type, the variable store records a key that identifies its definition. If the key is the
+//! same, the value can be used as-is. If a struct was redefined, we instead compile a copy of its
+//! previous definition and convert values field by field. Both of these rely on the layout of a
+//! type only depending on its definition, which holds so long as everything is built by the same
+//! compiler. Types defined in ways that we can't see, e.g. by macros, don't get a key, so values of
+//! them are dropped when they get rebuilt.
+
+use crate::item::identifiers;
+use ra_ap_syntax::ast;
+use ra_ap_syntax::AstNode;
+use ra_ap_syntax::SyntaxNode;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::hash::Hasher;
+
+/// The name of the module into which we put previous definitions of structs that are being
+/// migrated.
+pub(crate) const PREVIOUS_TYPES_MODULE: &str = "evcxr_previous_types";
+
+/// Names from the standard library prelude that types can refer to.
+const PRELUDE_NAMES: &[&str] = &[
+    "std",
+    "core",
+    "alloc",
+    "bool",
+    "char",
+    "str",
+    "i8",
+    "i16",
+    "i32",
+    "i64",
+    "i128",
+    "isize",
+    "u8",
+    "u16",
+    "u32",
+    "u64",
+    "u128",
+    "usize",
+    "f32",
+    "f64",
+    "Box",
+    "String",
+    "Vec",
+    "Option",
+    "Result",
+    "Copy",
+    "Send",
+    "Sized",
+    "Sync",
+    "Unpin",
+    "Fn",
+    "FnMut",
+    "FnOnce",
+    "Clone",
+    "PartialEq",
+    "PartialOrd",
+    "Eq",
+    "Ord",
+    "AsRef",
+    "AsMut",
+    "Into",
+    "From",
+    "Default",
+    "Iterator",
+    "Extend",
+    "IntoIterator",
+    "DoubleEndedIterator",
+    "ExactSizeIterator",
+    "ToOwned",
+    "ToString",
+    "Drop",
+];
+
+/// The code of the items that have been defined, by name, together with anything else that affects
+/// what those items mean.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ItemDefinitions {
+    items: HashMap<String, String>,
+    /// How each external crate is obtained, keyed by the name that code refers to it by.
+    crates: HashMap<String, String>,
+    /// Anything else that could affect any item, e.g. crate attributes.
+    context: String,
+}
+
+impl ItemDefinitions {
+    pub(crate) fn new(
+        items: HashMap<String, String>,
+        crates: HashMap<String, String>,
+        context: String,
+    ) -> ItemDefinitions {
+        ItemDefinitions {
+            items,
+            crates,
+            context,
+        }
+    }
+
+    /// Returns the items that `code` refers to, directly or via other items, by name. Names are
+    /// matched without regard to scope, so this may include more than is needed.
+    fn referenced_items(&self, code: &str) -> BTreeMap<&str, &str> {
+        let mut referenced = BTreeMap::new();
+        let mut pending: Vec<&str> = identifiers(code).collect();
+        while let Some(name) = pending.pop() {
+            if let Some((name, item_code)) = self.items.get_key_value(name) {
+                if referenced
+                    .insert(name.as_str(), item_code.as_str())
+                    .is_none()
+                {
+                    pending.extend(identifiers(item_code));
+                }
+            }
+        }
+        referenced
+    }
+
+    /// Returns the external crates that `code` or `items` refer to.
+    fn referenced_crates(&self, code: &str, items: &BTreeMap<&str, &str>) -> BTreeMap<&str, &str> {
+        std::iter::once(code)
+            .chain(items.values().copied())
+            .flat_map(identifiers)
+            .filter_map(|name| {
+                self.crates
+                    .get_key_value(name)
+                    .map(|(name, krate)| (name.as_str(), krate.as_str()))
+            })
+            .collect()
+    }
+
+    /// Returns a key that identifies the definition of `type_name`. Two types with the same key
+    /// have the same layout. Returns `None` if the layout might depend on something other than
+    /// named items, external crates and the standard library.
+    pub(crate) fn type_key(&self, type_name: &str) -> Option<String> {
+        if !self.layout_is_known(type_name) {
+            return None;
+        }
+        let mut hasher = std::collections::hash_map::DefaultHasher::new();
+        self.context.hash(&mut hasher);
+        // Type names are included, since they may refer to things other than our items.
+        type_name.trim().hash(&mut hasher);
+        let items = self.referenced_items(type_name);
+        self.referenced_crates(type_name, &items).hash(&mut hasher);
+        items.hash(&mut hasher);
+        Some(format!("{:016x}", hasher.finish()))
+    }
+
+    /// Returns whether every name that the layout of `type_name` depends on, directly or via our
+    /// items, is one of our named items, an external crate or from the standard library prelude.
+    /// Items that don't have a name, such as those defined by macros, can change without us
+    /// knowing, as can anything that we don't understand.
+    fn layout_is_known(&self, type_name: &str) -> bool {
+        let mut pending = match type_names(type_name) {
+            Some(names) => names,
+            None => return false,
+        };
+        let mut checked = HashSet::new();
+        while let Some(name) = pending.pop() {
+            if !checked.insert(name.clone()) {
+                continue;
+            }
+            if let Some(code) = self.items.get(&name) {
+                match item_layout_names(code, &name) {
+                    Some(names) => pending.extend(names),
+                    None => return false,
+                }
+            } else if !self.crates.contains_key(&name) && !PRELUDE_NAMES.contains(&name.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Returns the names that the type `type_name` refers to. For paths, only the first segment is
+/// included.
+fn type_names(type_name: &str) -> Option<Vec<String>> {
+    let source_file = ast::SourceFile::parse(&format!("type EvcxrType = {};", type_name)).tree();
+    match ast::HasModuleItem::items(&source_file).next()? {
+        ast::Item::TypeAlias(alias) => path_names(alias.ty()?.syntax(), &[]),
+        _ => None,
+    }
+}
+
+/// Returns the names that the layout of the item called `name` in `code` depends on. Returns
+/// `None` if the item isn't a kind that we understand.
+fn item_layout_names(code: &str, name: &str) -> Option<Vec<String>> {
+    let source_file = ast::SourceFile::parse(code).tree();
+    let mut nodes = Vec::new();
+    let mut generic_params = Vec::new();
+    for item in ast::HasModuleItem::items(&source_file) {
+        match item {
+            ast::Item::Struct(strukt) if has_name(&strukt, name) => {
+                nodes.extend(strukt.field_list().map(|fields| fields.syntax().clone()));
+                generic_params = generic_param_names(&strukt);
+            }
+            ast::Item::Enum(enum_) if has_name(&enum_, name) => {
+                nodes.extend(
+                    enum_
+                        .variant_list()
+                        .map(|variants| variants.syntax().clone()),
+                );
+                generic_params = generic_param_names(&enum_);
+            }
+            ast::Item::Union(union) if has_name(&union, name) => {
+                nodes.extend(
+                    union
+                        .record_field_list()
+                        .map(|fields| fields.syntax().clone()),
+                );
+                generic_params = generic_param_names(&union);
+            }
+            ast::Item::TypeAlias(alias) if has_name(&alias, name) => {
+                nodes.push(alias.ty()?.syntax().clone());
+                generic_params = generic_param_names(&alias);
+            }
+            ast::Item::Const(konst) if has_name(&konst, name) => {
+                nodes.push(konst.ty()?.syntax().clone());
+                nodes.push(konst.body()?.syntax().clone());
+            }
+            ast::Item::Trait(trait_) if has_name(&trait_, name) => {}
+            ast::Item::Use(use_item) => {
+                // Only the start of the path matters, since the rest is looked up in whatever
+                // that refers to.
+                let mut path = use_item.use_tree()?.path()?;
+                while let Some(qualifier) = path.qualifier() {
+                    path = qualifier;
+                }
+                return Some(vec![path.segment()?.name_ref()?.text().to_string()]);
+            }
+            _ => continue,
+        }
+        let mut names = Vec::new();
+        for node in &nodes {
+            names.extend(path_names(node, &generic_params)?);
+        }
+        return Some(names);
+    }
+    None
+}
+
+/// Returns the first segment of each path in `node`, other than the names of generic parameters.
+fn path_names(node: &SyntaxNode, generic_params: &[String]) -> Option<Vec<String>> {
+    node.descendants()
+        .filter_map(ast::Path::cast)
+        .filter(|path| path.qualifier().is_none())
+        .map(|path| Some(path.segment()?.name_ref()?.text().to_string()))
+        .filter(|name| {
+            name.as_ref()
+                .map_or(true, |name| !generic_params.contains(name))
+        })
+        .collect()
+}
+
+fn has_name(item: &impl ast::HasName, name: &str) -> bool {
+    item.name().map_or(false, |n| n.text() == name)
+}
+
+fn generic_param_names(item: &impl ast::HasGenericParams) -> Vec<String> {
+    item.generic_param_list()
+        .map(|params| {
+            params
+                .generic_params()
+                .filter_map(|param| match param {
+                    ast::GenericParam::TypeParam(param) => ast::HasName::name(&param),
+                    ast::GenericParam::ConstParam(param) => ast::HasName::name(&param),
+                    ast::GenericParam::LifetimeParam(_) => None,
+                })
+                .map(|name| name.text().to_string())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// How to convert values of a struct from its previous definition to its current one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Migration {
+    /// The key of the previous definition. See `ItemDefinitions::type_key`.
+    pub(crate) previous_type_key: String,
+    /// A copy of the previous definition, to be put in `PREVIOUS_TYPES_MODULE`.
+    pub(crate) previous_definition: String,
+    /// A closure that converts a value of the previous definition to an `Option` of the current
+    /// one. The closure returns `None` if a field couldn't be converted.
+    pub(crate) conversion: String,
+    /// Fields of the current definition that the previous one didn't have. These are given their
+    /// default values.
+    pub(crate) defaulted_fields: Vec<String>,
+}
+
+/// Returns how to migrate values of `type_name` as defined by `previous` to `type_name` as defined
+/// by `current`, if we know how. Only structs with named fields and no generic parameters can be
+/// migrated. Fields of the previous definition mustn't refer to any items that have changed, other
+/// than the struct itself, and its layout must be known. See `ItemDefinitions::type_key`.
+pub(crate) fn migration(
+    type_name: &str,
+    previous: &ItemDefinitions,
+    current: &ItemDefinitions,
+) -> Option<Migration> {
+    let previous_struct = struct_definition(previous.items.get(type_name)?, type_name)?;
+    let current_struct = struct_definition(current.items.get(type_name)?, type_name)?;
+    if previous.context != current.context {
+        return None;
+    }
+    for (_, field_type) in &previous_struct.fields {
+        let items = previous.referenced_items(field_type);
+        for (name, code) in &items {
+            if *name != type_name && current.items.get(*name).map(String::as_str) != Some(*code) {
+                return None;
+            }
+        }
+        for (name, krate) in previous.referenced_crates(field_type, &items) {
+            if current.crates.get(name).map(String::as_str) != Some(krate) {
+                return None;
+            }
+        }
+    }
+
+    let mut previous_definition = String::new();
+    for attribute in &previous_struct.repr_attributes {
+        previous_definition.push_str(attribute);
+        previous_definition.push('\n');
+    }
+    previous_definition.push_str(&format!("pub struct {} {{\n", type_name));
+    for (field, field_type) in &previous_struct.fields {
+        previous_definition.push_str(&format!("    pub {}: {},\n", field, field_type));
+    }
+    previous_definition.push('}');
+
+    let mut conversion = format!(
+        "|evcxr_previous: {}::{}| {{\n\
+         use evcxr_internal_runtime::{{MigrateInto as _, MigrateDefault as _, MigrateNone as _}};\n\
+         let {}::{} {{",
+        PREVIOUS_TYPES_MODULE, type_name, PREVIOUS_TYPES_MODULE, type_name
+    );
+    let is_previous_field = |field: &str| previous_struct.fields.iter().any(|(f, _)| f == field);
+    for (field, _) in &current_struct.fields {
+        if is_previous_field(field) {
+            conversion.push_str(&format!(" {},", field));
+        }
+    }
+    conversion.push_str(&format!(
+        " .. }} = evcxr_previous;\nSome({} {{\n",
+        type_name
+    ));
+    for (field, field_type) in &current_struct.fields {
+        let previous_value = if is_previous_field(field) {
+            field.as_str()
+        } else {
+            "evcxr_internal_runtime::MissingField"
+        };
+        conversion.push_str(&format!(
+            "{}: (&&evcxr_internal_runtime::MigrateField::<_, {}>::new({})).evcxr_migrate()?,\n",
+            field, field_type, previous_value
+        ));
+    }
+    conversion.push_str("})\n}");
+    let defaulted_fields = current_struct
+        .fields
+        .iter()
+        .map(|(field, _)| field)
+        .filter(|field| !is_previous_field(field))
+        .cloned()
+        .collect();
+
+    Some(Migration {
+        previous_type_key: previous.type_key(type_name)?,
+        previous_definition,
+        conversion,
+        defaulted_fields,
+    })
+}
+
+/// The parts of a struct definition that determine its layout.
+struct StructDefinition {
+    /// Any `#[repr(...)]` attributes.
+    repr_attributes: Vec<String>,
+    /// The name and type of each field.
+    fields: Vec<(String, String)>,
+}
+
+/// Returns the definition of the struct called `name` in `code`, if it has named fields and no
+/// generic parameters.
+fn struct_definition(code: &str, name: &str) -> Option<StructDefinition> {
+    let source_file = ast::SourceFile::parse(code).tree();
+    let strukt = ast::HasModuleItem::items(&source_file).find_map(|item| match item {
+        ast::Item::Struct(strukt)
+            if ast::HasName::name(&strukt).map_or(false, |n| n.text() == name) =>
+        {
+            Some(strukt)
+        }
+        _ => None,
+    })?;
+    if ast::HasGenericParams::generic_param_list(&strukt).is_some()
+        || ast::HasGenericParams::where_clause(&strukt).is_some()
+    {
+        return None;
+    }
+    let fields = match strukt.field_list()? {
+        ast::FieldList::RecordFieldList(fields) => fields
+            .fields()
+            .map(|field| {
+                Some((
+                    ast::HasName::name(&field)?.text().to_string(),
+                    field.ty()?.syntax().text().to_string(),
+                ))
+            })
+            .collect::<Option<Vec<_>>>()?,
+        ast::FieldList::TupleFieldList(_) => return None,
+    };
+    let repr_attributes = ast::HasAttrs::attrs(&strukt)
+        .filter(|attr| attr.simple_name().as_deref() == Some("repr"))
+        .map(|attr| attr.syntax().text().to_string())
+        .collect();
+    Some(StructDefinition {
+        repr_attributes,
+        fields,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn definitions(items: &[(&str, &str)]) -> ItemDefinitions {
+        definitions_with_crates(items, &[])
+    }
+
+    fn definitions_with_crates(items: &[(&str, &str)], crates: &[(&str, &str)]) -> ItemDefinitions {
+        let to_map = |pairs: &[(&str, &str)]| {
+            pairs
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect()
+        };
+        ItemDefinitions::new(to_map(items), to_map(crates), String::new())
+    }
+
+    #[test]
+    fn test_type_key() {
+        let a = definitions(&[
+            ("Foo", "struct Foo { bar: Bar }"),
+            ("Bar", "struct Bar(i32);"),
+            ("f", "fn f() {}"),
+        ]);
+        let b = definitions(&[
+            ("Foo", "struct Foo { bar: Bar }"),
+            ("Bar", "struct Bar(i32);"),
+            ("f", "fn f() -> i32 { 42 }"),
+        ]);
+        let c = definitions(&[
+            ("Foo", "struct Foo { bar: Bar }"),
+            ("Bar", "struct Bar(i64);"),
+        ]);
+        assert_eq!(a.type_key("Vec<Foo>"), b.type_key("Vec<Foo>"));
+        assert_ne!(a.type_key("Vec<Foo>"), c.type_key("Vec<Foo>"));
+        assert_ne!(a.type_key("Foo"), a.type_key("Vec<Foo>"));
+    }
+
+    #[test]
+    fn test_type_key_crates() {
+        let a = definitions_with_crates(
+            &[
+                ("Foo", "struct Foo { re: regex::Regex }"),
+                ("Bar", "struct Bar;"),
+            ],
+            &[("regex", "\"1.0\""), ("rand", "\"0.8\"")],
+        );
+        let b = definitions_with_crates(
+            &[
+                ("Foo", "struct Foo { re: regex::Regex }"),
+                ("Bar", "struct Bar;"),
+            ],
+            &[("regex", "\"1.0\""), ("rand", "\"0.7\"")],
+        );
+        let c = definitions_with_crates(
+            &[
+                ("Foo", "struct Foo { re: regex::Regex }"),
+                ("Bar", "struct Bar;"),
+            ],
+            &[("regex", "\"1.1\""), ("rand", "\"0.8\"")],
+        );
+        assert_eq!(a.type_key("Bar"), b.type_key("Bar"));
+        assert_eq!(a.type_key("Foo"), b.type_key("Foo"));
+        assert_ne!(a.type_key("Foo"), c.type_key("Foo"));
+        assert_ne!(
+            a.type_key("rand::rngs::ThreadRng"),
+            b.type_key("rand::rngs::ThreadRng")
+        );
+    }
+
+    #[test]
+    fn test_type_key_unknown_layout() {
+        let a = definitions(&[
+            (
+                "make_struct",
+                "macro_rules! make_struct { () => { struct Foo { x: i32 } } }",
+            ),
+            ("Bar", "struct Bar { foo: Foo }"),
+            ("Wrapper", "struct Wrapper<T> { t: T, len: usize }"),
+            ("HashMap", "use std::collections::HashMap;"),
+            ("f", "fn f() {}"),
+        ]);
+        assert_eq!(a.type_key("Foo"), None);
+        assert_eq!(a.type_key("Bar"), None);
+        assert_eq!(a.type_key("Vec<Foo>"), None);
+        assert_eq!(a.type_key("f"), None);
+        assert!(a.type_key("Wrapper<i32>").is_some());
+        assert!(a
+            .type_key("HashMap<String, Vec<Wrapper<Option<u8>>>>")
+            .is_some());
+    }
+
+    #[test]
+    fn test_migration() {
+        let previous = definitions(&[
+            (
+                "Foo",
+                "#[derive(Debug)]\n#[repr(C)]\nstruct Foo { a: i32, b: Bar }\nimpl Foo {}",
+            ),
+            ("Bar", "struct Bar;"),
+        ]);
+        let current = definitions(&[
+            ("Foo", "struct Foo { a: i64, c: String }"),
+            ("Bar", "struct Bar;"),
+        ]);
+        let migration = migration("Foo", &previous, &current).unwrap();
+        assert_eq!(Some(migration.previous_type_key), previous.type_key("Foo"));
+        assert_eq!(
+            migration.previous_definition,
+            "#[repr(C)]\npub struct Foo {\n    pub a: i32,\n    pub b: Bar,\n}"
+        );
+        assert!(migration.conversion.contains(
+            "a: (&&evcxr_internal_runtime::MigrateField::<_, i64>::new(a)).evcxr_migrate()?,"
+        ));
+        assert!(migration.conversion.contains(
+            "c: (&&evcxr_internal_runtime::MigrateField::<_, String>::new(\
+             evcxr_internal_runtime::MissingField)).evcxr_migrate()?,"
+        ));
+        assert_eq!(migration.defaulted_fields, vec!["c".to_owned()]);
+    }
+
+    #[test]
+    fn test_no_migration() {
+        let previous = definitions(&[
+            ("Foo", "struct Foo { bar: Bar }"),
+            ("Bar", "struct Bar(i32);"),
+            ("Generic", "struct Generic<T> { t: T }"),
+        ]);
+        // Bar changed, so we don't know the previous layout of Foo.
+        let current = definitions(&[
+            ("Foo", "struct Foo { bar: Bar, x: i32 }"),
+            ("Bar", "struct Bar(i64);"),
+            ("Generic", "struct Generic<T> { t: T, x: i32 }"),
+        ]);
+        assert_eq!(migration("Foo", &previous, &current), None);
+        assert_eq!(migration("Generic", &previous, &current), None);
+        assert_eq!(migration("Missing", &previous, &current), None);
+    }
+}
@@ -13,11 +13,15 @@
 // limitations under the License.
 
 use crate::code_block::CodeBlock;
+use crate::code_block::CodeKind;
 use crate::errors::bail;
 use crate::errors::CompilationError;
 use crate::errors::Error;
 use crate::eval_context::Config;
 use crate::eval_context::ContextState;
+use crate::item_layers::ItemLayers;
+use crate::item_layers::ItemsCode;
+use crate::item_layers::LayerPlan;
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use std::fs;
@@ -42,7 +46,7 @@ fn create_dir(dir: &Path) -> Result<(), Error> {
     Ok(())
 }
 
-fn write_file(dir: &Path, basename: &str, contents: &str) -> Result<(), Error> {
+pub(crate) fn write_file(dir: &Path, basename: &str, contents: &str) -> Result<(), Error> {
     create_dir(dir)?;
     let filename = dir.join(basename);
     if let Err(err) = fs::write(&filename, contents) {
@@ -83,33 +87,84 @@ fn rename_or_copy_so_file(src: &Path, dest: &Path) -> Result<(), Error> {
 
 pub(crate) struct Module {
     pub(crate) tmpdir: PathBuf,
+    /// A target directory that other contexts, possibly in other processes, may also be building
+    /// into. If `None`, we build into a directory within `tmpdir`.
+    shared_target_dir: Option<PathBuf>,
+    /// The name of the crate that the code for each evaluation is compiled into.
+    crate_name: String,
     build_num: i32,
+    /// The items crates that we've built.
+    item_layers: ItemLayers,
     target: String,
 }
 
 const CRATE_NAME: &str = "ctx";
 
+/// The name by which the crate for each evaluation refers to the newest of the crates into which
+/// items that the user has defined are compiled, and by which each of those crates refers to the
+/// one before it. See `item_layers`. Items crates are dylibs that only get built when items change.
+/// This avoids recompiling all items every time some code is evaluated. It also means that the
+/// runtime process only loads one copy of each item, so statics, thread locals and type identities
+/// stay the same from one evaluation to the next.
+pub(crate) const ITEMS_CRATE_NAME: &str = "evcxr_items";
+
 impl Module {
-    pub(crate) fn new(tmpdir: PathBuf) -> Result<Module, Error> {
+    pub(crate) fn new(
+        tmpdir: PathBuf,
+        shared_target_dir: Option<PathBuf>,
+    ) -> Result<Module, Error> {
+        // When the target directory is shared, external crates get reused by all contexts that
+        // depend on them with the same configuration. Cargo holds a lock on the target directory
+        // while building, so concurrent builds wait for each other. Our own crate however is a
+        // cdylib, whose filename doesn't include a hash, so we give it a name that is unique to our
+        // tmpdir. The items crate is named after our crate for the same reason.
+        let crate_name = if shared_target_dir.is_some() {
+            use std::hash::Hash;
+            use std::hash::Hasher;
+            let mut hasher = std::collections::hash_map::DefaultHasher::new();
+            tmpdir.hash(&mut hasher);
+            format!("{}_{:016x}", CRATE_NAME, hasher.finish())
+        } else {
+            CRATE_NAME.to_owned()
+        };
+        let shared_target_dir = match shared_target_dir {
+            Some(dir) if dir.is_relative() => Some(std::env::current_dir()?.join(dir)),
+            dir => dir,
+        };
         let module = Module {
             tmpdir,
+            shared_target_dir,
+            crate_name,
             build_num: 0,
+            item_layers: ItemLayers::default(),
             target: get_host_target()?,
         };
         Ok(module)
     }
 
     pub(crate) fn deps_dir(&self) -> PathBuf {
-        self.target_dir().join("debug").join("deps")
+        self.target_dir()
+            .join(&self.target)
+            .join("debug")
+            .join("deps")
     }
 
     fn target_dir(&self) -> PathBuf {
-        self.tmpdir.join("target").join(&self.target)
+        self.shared_target_dir
+            .clone()
+            .unwrap_or_else(|| self.tmpdir.join("target"))
+    }
+
+    /// Returns the target directory to pass to Cargo, which runs from within our crate directory.
+    fn cargo_target_dir(&self) -> &Path {
+        self.shared_target_dir
+            .as_deref()
+            .unwrap_or_else(|| Path::new("target"))
     }
 
     fn so_path(&self) -> PathBuf {
         self.deps_dir()
-            .join(shared_object_name_from_crate_name(CRATE_NAME))
+            .join(shared_object_name_from_crate_name(&self.crate_name))
     }
 
     fn src_dir(&self) -> PathBuf {
@@ -120,41 +175,63 @@ impl Module {
         &self.tmpdir
     }
 
+    fn items_crate_dir(&self, version: u32) -> PathBuf {
+        self.tmpdir.join(ITEMS_CRATE_NAME).join(version.to_string())
+    }
+
+    /// Returns the actual name of the items crate with the specified version.
+    fn items_crate_name(&self, version: u32) -> String {
+        format!("{}{}", self.items_crate_prefix(), version)
+    }
+
+    fn items_crate_prefix(&self) -> String {
+        format!("{}_items_", self.crate_name)
+    }
+
     pub fn last_source(&self) -> Result<String, std::io::Error> {
         std::fs::read_to_string(self.src_dir().join("lib.rs"))
     }
 
-    // Writes Cargo.toml. Should be called before compile.
+    // Writes Cargo.toml for our crate, depending on the newest items crate that we've built, if
+    // any. Should be called before compile, which then updates it if items have changed.
     pub(crate) fn write_cargo_toml(&self, state: &ContextState) -> Result<(), Error> {
         write_file(
             self.crate_dir(),
             "Cargo.toml",
-            &self.get_cargo_toml_contents(state),
+            &self.get_cargo_toml_contents(state, self.item_layers.top_version()),
         )
     }
 
     pub(crate) fn check(
         &mut self,
         code_block: &CodeBlock,
+        state: &ContextState,
         config: &Config,
     ) -> Result<Vec<CompilationError>, Error> {
+        // The code being checked includes all items, so it doesn't need any items crate that we
+        // haven't already built.
+        self.write_cargo_toml(state)?;
         self.write_code(code_block)?;
         let output = config
             .cargo_command("check")
             .arg("--message-format=json")
+            .env("CARGO_TARGET_DIR", self.cargo_target_dir())
             .output();
 
         let cargo_output = match output {
             Ok(out) => out,
             Err(err) => bail!("Error running 'cargo check': {}", err),
         };
-        let (errors, _non_json_error) = errors_from_cargo_output(&cargo_output, code_block);
+        let (errors, _non_json_error) =
+            errors_from_cargo_output(&cargo_output, code_block, &self.items_crate_prefix(), None);
         Ok(errors)
     }
 
     pub(crate) fn compile(
         &mut self,
+        items: &ItemsCode,
         code_block: &CodeBlock,
+        state: &ContextState,
         config: &Config,
     ) -> Result<SoFile, Error> {
         let mut command = config.cargo_command("rustc");
@@ -169,7 +246,7 @@ impl Module {
             .arg("--")
             .arg("-C")
             .arg("prefer-dynamic")
-            .env("CARGO_TARGET_DIR", "target");
+            .env("CARGO_TARGET_DIR", self.cargo_target_dir());
         if config.linker == "lld" {
             command
                 .arg("-C")
@@ -181,18 +258,43 @@ impl Module {
         if config.time_passes {
             command.arg("-Ztime-passes");
         }
+        let plan = self.item_layers.plan(items);
+        self.write_items_code(&plan, state)?;
         self.write_code(code_block)?;
-        let cargo_output = run_cargo(command, code_block)?;
+        let new_items_crate_name = plan
+            .new_code
+            .as_ref()
+            .and_then(|_| plan.versions.last())
+            .map(|version| self.items_crate_name(*version));
+        let new_items = new_items_crate_name.as_deref().zip(plan.new_code.as_ref());
+        let items_crate_prefix = self.items_crate_prefix();
+        let cargo_output = run_cargo(command, code_block, &items_crate_prefix, new_items)?;
         if config.time_passes {
             let output = String::from_utf8_lossy(&cargo_output.stderr);
             eprintln!("{}", output);
         }
+        // We only keep warnings for the code currently being evaluated. Warnings for earlier code
+        // were already reported. Warnings for items crates that we built before are being replayed
+        // by Cargo from its cache, so are ignored. A new items crate may also contain items from
+        // earlier code, whose warnings we filter out below by their origin.
+        let (messages, _non_json_error) =
+            errors_from_cargo_output(&cargo_output, code_block, &items_crate_prefix, new_items);
+        let warnings = messages
+            .into_iter()
+            .filter(|message| {
+                message.level() == "warning"
+                    && message
+                        .code_origins
+                        .iter()
+                        .any(|origin| matches!(origin, CodeKind::OriginalUserCode(_)))
+            })
+            .collect();
         self.build_num += 1;
         let copied_so_file = self
             .deps_dir()
             .join(shared_object_name_from_crate_name(&format!(
-                "code_{}",
-                self.build_num
+                "{}_code_{}",
+                self.crate_name, self.build_num
             )));
         // Every time we compile, the output file is the same. We need to
         // renamed it so that we have a unique filename, otherwise we wouldn't
@@ -200,22 +302,61 @@ impl Module {
         // a loaded dll gets locked, so we couldn't even compile a second time
         // if we didn't load a different file.
         rename_or_copy_so_file(&self.so_path(), &copied_so_file)?;
+        let items_paths = plan
+            .versions
+            .iter()
+            .map(|version| {
+                self.deps_dir().join(shared_object_name_from_crate_name(
+                    &self.items_crate_name(*version),
+                ))
+            })
+            .collect();
+        self.item_layers.commit(plan);
         Ok(SoFile {
             path: copied_so_file,
+            items_paths,
+            warnings,
         })
     }
 
     fn write_code(&self, code_block: &CodeBlock) -> Result<(), Error> {
         write_file(&self.src_dir(), "lib.rs", &code_block.code_string())?;
-        self.maybe_bump_lib_mtime();
+        self.maybe_bump_lib_mtime(&self.src_dir());
         Ok(())
     }
 
+    /// Writes the source and Cargo.toml of any new items crate in `plan`, then our crate's
+    /// Cargo.toml, which depends on the newest items crate. A new items crate may have been written
+    /// already by an earlier attempt at compiling. If so, nothing is written unless something has
+    /// changed, since otherwise Cargo would rebuild the crate.
+    fn write_items_code(&self, plan: &LayerPlan, state: &ContextState) -> Result<(), Error> {
+        let top_version = plan.versions.last().copied();
+        if let (Some(code), Some(version)) = (&plan.new_code, top_version) {
+            let previous_version = plan.versions.len().checked_sub(2).map(|i| plan.versions[i]);
+            let crate_dir = self.items_crate_dir(version);
+            let cargo_toml = self.get_items_cargo_toml_contents(state, version, previous_version);
+            if fs::read_to_string(crate_dir.join("Cargo.toml")).ok() != Some(cargo_toml.clone()) {
+                write_file(&crate_dir, "Cargo.toml", &cargo_toml)?;
+            }
+            let src_dir = crate_dir.join("src");
+            let code = code.code_string();
+            if fs::read_to_string(src_dir.join("lib.rs")).ok() != Some(code.clone()) {
+                write_file(&src_dir, "lib.rs", &code)?;
+                self.maybe_bump_lib_mtime(&src_dir);
+            }
+        }
+        write_file(
+            self.crate_dir(),
+            "Cargo.toml",
+            &self.get_cargo_toml_contents(state, top_version),
+        )
+    }
+
     #[cfg(not(target_os = "macos"))]
-    fn maybe_bump_lib_mtime(&self) {}
+    fn maybe_bump_lib_mtime(&self, _src_dir: &Path) {}
 
     #[cfg(target_os = "macos")]
-    fn maybe_bump_lib_mtime(&self) {
+    fn maybe_bump_lib_mtime(&self, src_dir: &Path) {
         // Some Macs use a filesystem that only has 1 second precision on file modification
         // timestamps. Cargo uses these timestamps to see if it needs to recompile things, otherwise
         // it just reuses the previous output. We set the modification timestamp on our source file
@@ -224,13 +365,18 @@ impl Module {
         // as this mostly affects tests and we don't want inability to set mtime to break things for
         // users.
         let _ = filetime::set_file_mtime(
-            self.src_dir().join("lib.rs"),
+            src_dir.join("lib.rs"),
             filetime::FileTime::from_unix_time(filetime::FileTime::now().unix_seconds() + 10, 0),
         );
     }
 
-    fn get_cargo_toml_contents(&self, state: &ContextState) -> String {
-        let crate_imports = state.format_cargo_deps();
+    fn get_cargo_toml_contents(&self, state: &ContextState, items_version: Option<u32>) -> String {
+        let mut crate_imports = state.format_cargo_deps();
+        if let Some(version) = items_version {
+            crate_imports.push_str(
+                &self.items_crate_dependency(&format!("{}/{}", ITEMS_CRATE_NAME, version), version),
+            );
+        }
         format!(
             r#"
 [package]
@@ -256,16 +402,98 @@ overflow-checks = true
 [dependencies]
 {}
 "#,
-            CRATE_NAME,
+            self.crate_name,
             state.opt_level(),
             crate_imports
         )
     }
+
+    /// Returns the line for a Cargo.toml that depends on the items crate with the specified
+    /// version, which is at `path`.
+    fn items_crate_dependency(&self, path: &str, version: u32) -> String {
+        format!(
+            "{} = {{ path = \"{}\", package = \"{}\" }}\n",
+            ITEMS_CRATE_NAME,
+            path,
+            self.items_crate_name(version)
+        )
+    }
+
+    fn get_items_cargo_toml_contents(
+        &self,
+        state: &ContextState,
+        version: u32,
+        previous_version: Option<u32>,
+    ) -> String {
+        let mut crate_imports = state.format_cargo_deps();
+        if let Some(previous_version) = previous_version {
+            crate_imports.push_str(
+                &self.items_crate_dependency(&format!("../{}", previous_version), previous_version),
+            );
+        }
+        format!(
+            r#"
+[package]
+name = "{}"
+version = "1.0.0"
+edition = "2021"
+
+[lib]
+crate-type = ["dylib"]
+path = "src/lib.rs"
+
+[dependencies]
+{}
+"#,
+            self.items_crate_name(version),
+            crate_imports
+        )
+    }
+}
+
+impl Drop for Module {
+    /// Removes what we built into a shared target directory, since nothing else will. Only outputs
+    /// of our own crate are removed. Those of external crates may be in use by other contexts.
+    fn drop(&mut self) {
+        if self.shared_target_dir.is_none() {
+            return;
+        }
+        let target_dir = self.target_dir();
+        let lib_prefix = format!("lib{}", self.crate_name);
+        for profile_dir in &[
+            target_dir.join(&self.target).join("debug"),
+            target_dir.join("debug"),
+        ] {
+            for subdir in &["deps", ".fingerprint", "incremental"] {
+                let entries = match fs::read_dir(profile_dir.join(subdir)) {
+                    Ok(entries) => entries,
+                    Err(_) => continue,
+                };
+                for entry in entries.flatten() {
+                    let name = entry.file_name();
+                    let name = name.to_string_lossy();
+                    if !name.starts_with(&self.crate_name) && !name.starts_with(&lib_prefix) {
+                        continue;
+                    }
+                    // Errors are ignored. e.g. on Windows, files may still be locked by the
+                    // subprocess.
+                    let path = entry.path();
+                    if path.is_dir() {
+                        let _ = fs::remove_dir_all(path);
+                    } else {
+                        let _ = fs::remove_file(path);
+                    }
+                }
+            }
+        }
+    }
 }
 
 fn run_cargo(
     mut command: std::process::Command,
     code_block: &CodeBlock,
+    items_crate_prefix: &str,
+    new_items: Option<(&str, &CodeBlock)>,
 ) -> Result<std::process::Output, Error> {
     let cargo_output = match command.output() {
         Ok(out) => out,
@@ -274,7 +502,8 @@ fn run_cargo(
     if cargo_output.status.success() {
         Ok(cargo_output)
     } else {
-        let (errors, non_json_error) = errors_from_cargo_output(&cargo_output, code_block);
+        let (errors, non_json_error) =
+            errors_from_cargo_output(&cargo_output, code_block, items_crate_prefix, new_items);
         if errors.is_empty() {
             if let Some(error) = non_json_error {
                 bail!(Error::Message(error));
@@ -292,9 +521,14 @@ fn run_cargo(
     }
 }
 
+/// Returns errors found in the output of Cargo. Items crates have names starting with
+/// `items_crate_prefix`. Errors from the one named in `new_items`, if any, are mapped using its
+/// code. Errors from other items crates are ignored, since they were built before.
 fn errors_from_cargo_output(
     cargo_output: &std::process::Output,
     code_block: &CodeBlock,
+    items_crate_prefix: &str,
+    new_items: Option<(&str, &CodeBlock)>,
 ) -> (Vec<CompilationError>, Option<String>) {
     // Our compiler errors should all be in JSON format, but for errors from
     // Cargo errors, we need to add explicit matching for those errors that we
@@ -312,7 +546,19 @@ fn errors_from_cargo_output(
         .filter_map(|line| {
             json::parse(line)
                 .ok()
-                .and_then(|json| CompilationError::opt_new(json, code_block))
+                .and_then(|json| {
+                    let target_name = json["target"]["name"].as_str().unwrap_or_default();
+                    if !target_name.starts_with(items_crate_prefix) {
+                        CompilationError::opt_new(json, code_block)
+                    } else {
+                        match new_items {
+                            Some((crate_name, items)) if crate_name == target_name => {
+                                CompilationError::opt_new(json, items)
+                            }
+                            _ => None,
+                        }
+                    }
+                })
                 .or_else(|| {
                     if known_non_json_errors.is_match(line) {
                         non_json_error = Some(line.to_owned());
@@ -326,6 +572,9 @@ fn errors_from_cargo_output(
 
 pub(crate) struct SoFile {
     pub(crate) path: PathBuf,
+    /// The items crates, oldest first, which the runtime process loads when it loads `path`.
+    pub(crate) items_paths: Vec<PathBuf>,
+    pub(crate) warnings: Vec<CompilationError>,
 }
 
 fn get_host_target() -> Result<String, Error> {
@@ -14,15 +14,64 @@
 
 use crate::errors::bail;
 use crate::errors::Error;
-use once_cell::sync::OnceCell;
-use regex::Regex;
-use std::io;
+use crate::evcxr_internal_runtime::control_channel;
+use crate::evcxr_internal_runtime::read_message;
+use crate::evcxr_internal_runtime::send_control_message;
+use crate::transport::path_from_bytes;
 use std::marker::PhantomData;
+use std::path::Path;
 use std::rc::Rc;
+use std::sync::atomic::AtomicBool;
+#[cfg(unix)]
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
 use std::{self};
 
 pub(crate) const EVCXR_IS_RUNTIME_VAR: &str = "EVCXR_IS_RUNTIME";
-pub(crate) const EVCXR_EXECUTION_COMPLETE: &str = "EVCXR_EXECUTION_COMPLETE";
+/// Set on Windows, where a runtime process can't inherit its control channel, to the address to
+/// which it should connect to establish one, followed by a token with which to identify itself.
+pub(crate) const EVCXR_CONTROL_ADDRESS_VAR: &str = "EVCXR_CONTROL_ADDRESS";
+
+// Kinds of control messages that we send to and receive from the runtime process, in addition to
+// the ones sent by user code, which are defined in evcxr_internal_runtime.
+
+/// Followed by the path of a shared object and the name of a function in it to run.
+pub(crate) const LOAD_AND_RUN: &str = "LOAD_AND_RUN";
+/// Like `LOAD_AND_RUN`, but takes a checkpoint first. See `Runtime::load_and_run_with_checkpoint`.
+pub(crate) const LOAD_AND_RUN_WITH_CHECKPOINT: &str = "LOAD_AND_RUN_WITH_CHECKPOINT";
+/// Like `LOAD_AND_RUN_WITH_CHECKPOINT`, but we only resume from the checkpoint if the code is
+/// interrupted. If it crashes in some other way, we terminate in the same way, as if no checkpoint
+/// had been taken. Nothing is reported if we can't take a checkpoint.
+pub(crate) const LOAD_AND_RUN_WITH_INTERRUPT_CHECKPOINT: &str =
+    "LOAD_AND_RUN_WITH_INTERRUPT_CHECKPOINT";
+/// Followed by the name of a variable to drop.
+pub(crate) const DROP_VARIABLE: &str = "DROP_VARIABLE";
+/// Asks for `EXECUTION_COMPLETE` to be sent straight away. Since stdout and stderr are flushed
+/// before each message is sent, anything that was written before then, for example by threads
+/// that are still running after earlier code finished, is received before the reply.
+pub(crate) const FLUSH: &str = "FLUSH";
+/// Sent once we've finished handling a message.
+pub(crate) const EXECUTION_COMPLETE: &str = "EXECUTION_COMPLETE";
+/// Sent in place of the output of code that crashed the process that was running it, after we've
+/// resumed from the checkpoint taken before it ran. Followed by a description of the crash and the
+/// number of the signal that caused it, which is empty if it wasn't caused by a signal.
+pub(crate) const CHECKPOINT_RESTORED: &str = "CHECKPOINT_RESTORED";
+/// Sent when we were asked to take a checkpoint before running code, but couldn't, or after
+/// running code, if we won't be able to before running more, e.g. because user code has started
+/// threads.
+pub(crate) const CHECKPOINT_UNAVAILABLE: &str = "CHECKPOINT_UNAVAILABLE";
+/// Written to stderr by a checkpoint once it has taken over. Everything that the process that
+/// crashed wrote to stderr comes before it.
+pub(crate) const STDERR_SYNC: &str = "EVCXR_STDERR_SYNC";
+/// The name of the function, exported by code that uses the variable store, that drops a single
+/// variable from the store.
+pub(crate) const DROP_VARIABLE_FN_NAME: &str = "evcxr_drop_variable";
+
+/// How we describe user code having been stopped by an interrupt.
+pub(crate) const EXECUTION_INTERRUPTED: &str = "Execution interrupted";
+
+/// Whether we're currently running user code. Interrupts received at other times are ignored.
+static USER_CODE_RUNNING: AtomicBool = AtomicBool::new(false);
 
 /// Binaries can call this just after staring. If we detect that we're actually
 /// running as a subprocess, control will not return.
@@ -50,49 +99,182 @@ impl Runtime {
     }
 
     fn run_loop(&mut self) -> ! {
-        use std::io::BufRead;
+        #[cfg(windows)]
+        connect_control_channel();
+        claim_control_channel();
+        let mut control_channel = match control_channel() {
+            Some(control_channel) => control_channel,
+            None => {
+                eprintln!("Runtime process wasn't given a control channel");
+                std::process::exit(99);
+            }
+        };
 
+        #[cfg(unix)]
+        record_cpu_limit();
+        #[cfg(target_os = "linux")]
+        checkpoint::start_supervisor();
         self.install_crash_handlers();
 
-        let stdin = std::io::stdin();
-        #[allow(unknown_lints, clippy::significant_drop_in_scrutinee)]
-        for line in stdin.lock().lines() {
-            if let Err(error) = self.handle_line(&line) {
+        loop {
+            let message = match read_message(&mut *control_channel) {
+                Ok(Some(message)) => message,
+                // Evcxr has finished with us.
+                Ok(None) => std::process::exit(0),
+                Err(error) => {
+                    eprintln!("Failed to read control message: {}", error);
+                    std::process::exit(99);
+                }
+            };
+            if let Err(error) = self.handle_message(&message) {
                 eprintln!(
-                    "While processing instruction `{:?}`, got error: {:?}",
-                    line, error
+                    "While processing control message `{:?}`, got error: {:?}",
+                    message
+                        .iter()
+                        .map(|field| String::from_utf8_lossy(field))
+                        .collect::<Vec<_>>(),
+                    error
                 );
                 std::process::exit(99);
             }
         }
-        std::process::exit(0);
     }
 
-    fn handle_line(&mut self, line: &io::Result<String>) -> Result<(), Error> {
-        let line = line.as_ref()?;
-        static LOAD_AND_RUN: OnceCell<Regex> = OnceCell::new();
-        let load_and_run =
-            LOAD_AND_RUN.get_or_init(|| Regex::new("LOAD_AND_RUN ([^ ]+) ([^ ]+)").unwrap());
-        if let Some(captures) = load_and_run.captures(line) {
-            self.load_and_run(&captures[1], &captures[2])
-        } else {
-            bail!("Unrecognised line: {}", line);
+    fn handle_message(&mut self, message: &[Vec<u8>]) -> Result<(), Error> {
+        let (kind, args) = match message.split_first() {
+            Some((kind, args)) => (std::str::from_utf8(kind).unwrap_or_default(), args),
+            None => bail!("Empty control message"),
+        };
+        match (kind, args) {
+            (LOAD_AND_RUN, [so_path, fn_name]) => {
+                self.load_and_run(&path_from_bytes(so_path), fn_name)
+            }
+            (LOAD_AND_RUN_WITH_CHECKPOINT, [so_path, fn_name]) => {
+                self.load_and_run_with_checkpoint(&path_from_bytes(so_path), fn_name, false)
+            }
+            (LOAD_AND_RUN_WITH_INTERRUPT_CHECKPOINT, [so_path, fn_name]) => {
+                self.load_and_run_with_checkpoint(&path_from_bytes(so_path), fn_name, true)
+            }
+            (DROP_VARIABLE, [variable_name]) => self.drop_variable(variable_name),
+            (FLUSH, []) => {
+                send_control_message(&[EXECUTION_COMPLETE.as_bytes()]);
+                Ok(())
+            }
+            _ => bail!("Unrecognised control message"),
         }
     }
 
-    fn load_and_run(&mut self, so_path: &str, fn_name: &str) -> Result<(), Error> {
+    fn load_and_run(&mut self, so_path: &Path, fn_name: &[u8]) -> Result<(), Error> {
+        self.run_user_fn(so_path, fn_name)?;
+        send_control_message(&[EXECUTION_COMPLETE.as_bytes()]);
+        Ok(())
+    }
+
+    /// Like `load_and_run`, but first forks a copy of this process to act as a checkpoint. If the
+    /// user's code crashes the process running it, the checkpoint takes over, with everything as it
+    /// was before the code ran. If we can't take a checkpoint, we say so and run the code anyway.
+    /// If `interrupts_only` is set, the checkpoint only takes over if the code was interrupted and
+    /// we don't say anything if we can't take one.
+    #[cfg(target_os = "linux")]
+    fn load_and_run_with_checkpoint(
+        &mut self,
+        so_path: &Path,
+        fn_name: &[u8],
+        interrupts_only: bool,
+    ) -> Result<(), Error> {
+        if interrupts_only && checkpoint::unavailable_reason().is_some() {
+            return self.load_and_run(so_path, fn_name);
+        }
+        if let Some(reason) = checkpoint::unavailable_reason() {
+            eprintln!(
+                "Warning: No checkpoint was taken before running this code, since {}. If it \
+                 panics, variables that it doesn't use will be kept, but if it crashes in any \
+                 other way, all variables will be lost.",
+                reason
+            );
+            send_control_message(&[CHECKPOINT_UNAVAILABLE.as_bytes()]);
+            return self.load_and_run(so_path, fn_name);
+        }
+        match checkpoint::fork()? {
+            checkpoint::Fork::Worker(checkpoint) => {
+                self.run_user_fn(so_path, fn_name)?;
+                checkpoint.release();
+                // Let evcxr know in advance, so that it can compile the next code to keep variables
+                // if it panics.
+                if !interrupts_only && checkpoint::unavailable_reason().is_some() {
+                    send_control_message(&[CHECKPOINT_UNAVAILABLE.as_bytes()]);
+                }
+            }
+            checkpoint::Fork::Restored { status } => {
+                if interrupts_only && checkpoint::signal(status) != Some(libc::SIGINT) {
+                    checkpoint::exit_like(status);
+                }
+                eprintln!("{}", STDERR_SYNC);
+                let signal = checkpoint::signal(status)
+                    .map(|signal| signal.to_string())
+                    .unwrap_or_default();
+                send_control_message(&[
+                    CHECKPOINT_RESTORED.as_bytes(),
+                    checkpoint::describe_status(status).as_bytes(),
+                    signal.as_bytes(),
+                ]);
+            }
+        }
+        send_control_message(&[EXECUTION_COMPLETE.as_bytes()]);
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn load_and_run_with_checkpoint(
+        &mut self,
+        so_path: &Path,
+        fn_name: &[u8],
+        _interrupts_only: bool,
+    ) -> Result<(), Error> {
+        self.load_and_run(so_path, fn_name)
+    }
+
+    fn run_user_fn(&mut self, so_path: &Path, fn_name: &[u8]) -> Result<(), Error> {
         use std::os::raw::c_void;
         let shared_object = unsafe { libloading::Library::new(so_path) }?;
+        #[cfg(unix)]
+        restart_cpu_limit();
         unsafe {
-            let user_fn = shared_object
-                .get::<extern "C" fn(*mut c_void) -> *mut c_void>(fn_name.as_bytes())?;
+            let user_fn =
+                shared_object.get::<extern "C" fn(*mut c_void) -> *mut c_void>(fn_name)?;
+            USER_CODE_RUNNING.store(true, Ordering::SeqCst);
             self.variable_store_ptr = user_fn(self.variable_store_ptr);
+            USER_CODE_RUNNING.store(false, Ordering::SeqCst);
         }
-        println!("{}", EVCXR_EXECUTION_COMPLETE);
         self.shared_objects.push(shared_object);
         Ok(())
     }
 
+    fn drop_variable(&mut self, variable_name: &[u8]) -> Result<(), Error> {
+        use std::os::raw::c_void;
+        if !self.variable_store_ptr.is_null() {
+            // Not all code that we load has access to the variable store. Use the most recently
+            // loaded code that does, since its definition of the variable store will be current.
+            for shared_object in self.shared_objects.iter().rev() {
+                let drop_fn = unsafe {
+                    shared_object.get::<extern "C" fn(*mut c_void, *const u8, usize)>(
+                        DROP_VARIABLE_FN_NAME.as_bytes(),
+                    )
+                };
+                if let Ok(drop_fn) = drop_fn {
+                    drop_fn(
+                        self.variable_store_ptr,
+                        variable_name.as_ptr(),
+                        variable_name.len(),
+                    );
+                    break;
+                }
+            }
+        }
+        send_control_message(&[EXECUTION_COMPLETE.as_bytes()]);
+        Ok(())
+    }
+
     #[cfg(all(unix, not(target_os = "freebsd")))]
     pub fn install_crash_handlers(&self) {
         use backtrace::Backtrace;
@@ -111,15 +293,135 @@ impl Runtime {
             std::process::abort();
         }
 
+        // Terminates the process that's running user code, by re-raising the signal with its
+        // default action. We can't unwind the user code from here, since unwinding out of a signal
+        // handler isn't sound, and a panic can't get through the `extern "C"` functions that the
+        // code is called through anyway. Where we can, we take a checkpoint before running the
+        // code, even if not asked to, so that it can take over with all variables intact.
+        // Otherwise evcxr restarts the subprocess.
+        extern "C" fn interrupt_handler(signal: i32) {
+            if !USER_CODE_RUNNING.load(Ordering::SeqCst) {
+                return;
+            }
+            unsafe {
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+        }
+
         signal!(Sig::SEGV, segfault_handler);
         signal!(Sig::ILL, segfault_handler);
         signal!(Sig::BUS, segfault_handler);
+        signal!(Sig::INT, interrupt_handler);
     }
 
     #[cfg(not(all(unix, not(target_os = "freebsd"))))]
     pub fn install_crash_handlers(&self) {}
 }
 
+/// How much CPU time, in seconds, each execution of user code may use. Zero if there's no limit.
+#[cfg(unix)]
+static CPU_LIMIT_SECS: AtomicU64 = AtomicU64::new(0);
+
+/// Records the CPU time limit that we were started with, which is for each execution. See
+/// ResourceLimits::apply.
+#[cfg(unix)]
+fn record_cpu_limit() {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_CPU, &mut limit) } == 0
+        && limit.rlim_cur != libc::RLIM_INFINITY
+    {
+        CPU_LIMIT_SECS.store(limit.rlim_cur as u64, Ordering::SeqCst);
+    }
+}
+
+/// The CPU time limit applies to all the CPU time that the process has used. Moves it so that the
+/// user code that we're about to run gets the full limit.
+#[cfg(unix)]
+fn restart_cpu_limit() {
+    let cpu_limit_secs = CPU_LIMIT_SECS.load(Ordering::SeqCst);
+    if cpu_limit_secs == 0 {
+        return;
+    }
+    unsafe {
+        let mut usage: libc::rusage = std::mem::zeroed();
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if libc::getrusage(libc::RUSAGE_SELF, &mut usage) != 0
+            || libc::getrlimit(libc::RLIMIT_CPU, &mut limit) != 0
+        {
+            return;
+        }
+        // Round up, so that what's been used doesn't count towards the limit.
+        let used_secs = (usage.ru_utime.tv_sec + usage.ru_stime.tv_sec + 1) as u64;
+        limit.rlim_cur = std::cmp::min(
+            used_secs.saturating_add(cpu_limit_secs) as libc::rlim_t,
+            limit.rlim_max,
+        );
+        libc::setrlimit(libc::RLIMIT_CPU, &limit);
+    }
+}
+
+/// Makes the control channel that we were given available to code in this process, but not to
+/// processes that it starts. See `CONTROL_CHANNEL_VAR`. Needs to be called again in processes that
+/// we fork, since they have different process IDs.
+fn claim_control_channel() {
+    use crate::evcxr_internal_runtime::CONTROL_CHANNEL_VAR;
+    let value = match std::env::var(CONTROL_CHANNEL_VAR) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let handle = value
+        .split_once(':')
+        .map_or(value.as_str(), |(_, handle)| handle);
+    // Evcxr cleared close-on-exec so that we'd inherit the channel. Set it again so that programs
+    // that user code runs don't.
+    #[cfg(unix)]
+    if let Ok(fd) = handle.parse() {
+        unsafe {
+            libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+        }
+    }
+    std::env::set_var(
+        CONTROL_CHANNEL_VAR,
+        format!("{}:{}", std::process::id(), handle),
+    );
+}
+
+/// Establishes our control channel on Windows, where we can't inherit it, by connecting to the
+/// address that we were given. Records the channel in the environment, ready to be claimed.
+#[cfg(windows)]
+fn connect_control_channel() {
+    use crate::evcxr_internal_runtime::encode_message;
+    use crate::evcxr_internal_runtime::CONTROL_CHANNEL_VAR;
+    use std::io::Write;
+    use std::os::windows::io::IntoRawSocket;
+    let value = match std::env::var(EVCXR_CONTROL_ADDRESS_VAR) {
+        Ok(value) => value,
+        Err(_) => return,
+    };
+    let (address, token) = value.split_once(' ').unwrap_or((&value, ""));
+    let connected = std::net::TcpStream::connect(address).and_then(|mut stream| {
+        stream.set_nodelay(true)?;
+        stream.write_all(&encode_message(&[token.as_bytes()]))?;
+        Ok(stream)
+    });
+    match connected {
+        Ok(stream) => {
+            std::env::set_var(CONTROL_CHANNEL_VAR, stream.into_raw_socket().to_string());
+        }
+        Err(error) => {
+            eprintln!("Failed to connect control channel: {}", error);
+            std::process::exit(99);
+        }
+    }
+}
+
 impl Drop for Runtime {
     fn drop(&mut self) {
         // We never actually unload libraries. This is to prevent segfault on shutdown due to TLS
@@ -138,3 +440,197 @@ impl Drop for Runtime {
         }
     }
 }
+
+/// Checkpointing of the process that runs user code, using fork. Before running some code, we fork.
+/// The child runs the code while the parent, which is our checkpoint, waits. If the code completes,
+/// the checkpoint exits and the child carries on handling requests. If the child dies, the
+/// checkpoint carries on instead, as if the code had never run.
+///
+/// So that the process that our parent started stays around for as long as we're handling
+/// requests, it doesn't handle requests itself. It just reaps the processes that do. See
+/// `start_supervisor`.
+#[cfg(target_os = "linux")]
+mod checkpoint {
+    use crate::errors::bail;
+    use crate::errors::Error;
+    use std::io;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+
+    /// Whether we're running under a supervisor, which will reap processes that get orphaned when
+    /// their checkpoint exits.
+    static SUPERVISED: AtomicBool = AtomicBool::new(false);
+
+    /// Forks a process to handle requests. The current process stays behind as a supervisor and
+    /// adopts any orphaned descendants, which is what the processes that handle requests become
+    /// once the checkpoint that forked them exits. When there are no descendants left, the
+    /// supervisor exits in the same way as the last of them. Returns in the process that should
+    /// handle requests, which if we fail to set up a supervisor is just the current process.
+    pub(super) fn start_supervisor() {
+        unsafe {
+            if libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) != 0 {
+                return;
+            }
+            match libc::fork() {
+                -1 => return,
+                0 => {
+                    SUPERVISED.store(true, Ordering::SeqCst);
+                    super::claim_control_channel();
+                    return;
+                }
+                _ => {}
+            }
+            // Interrupts are sent to our whole process group. They're for whichever process is
+            // running user code, not us.
+            libc::signal(libc::SIGINT, libc::SIG_IGN);
+            // Let go of our stdin, stdout and control channel, so that our parent sees them close
+            // once the processes that actually use them have all exited.
+            let dev_null = libc::open(b"/dev/null\0".as_ptr() as *const libc::c_char, libc::O_RDWR);
+            if dev_null >= 0 {
+                libc::dup2(dev_null, 0);
+                libc::dup2(dev_null, 1);
+            }
+            if let Some(control_channel) = crate::evcxr_internal_runtime::control_channel() {
+                libc::close(std::os::unix::io::AsRawFd::as_raw_fd(&*control_channel));
+            }
+            let mut last_status = 0;
+            loop {
+                let mut status = 0;
+                if libc::waitpid(-1, &mut status, 0) == -1 {
+                    if io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    // No descendants are left.
+                    break;
+                }
+                last_status = status;
+            }
+            exit_like(last_status);
+        }
+    }
+
+    /// Terminates this process in the same way as the process whose wait status is `status`.
+    pub(super) fn exit_like(status: libc::c_int) -> ! {
+        unsafe {
+            if libc::WIFSIGNALED(status) {
+                let signal = libc::WTERMSIG(status);
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+    }
+
+    /// Returns the signal that terminated the process whose wait status is `status`, if any.
+    pub(super) fn signal(status: libc::c_int) -> Option<libc::c_int> {
+        libc::WIFSIGNALED(status).then(|| libc::WTERMSIG(status))
+    }
+
+    /// Returns why we can't checkpoint right now, if we can't. Only the thread that calls fork
+    /// exists in the child, so if user code has started other threads, the child would likely
+    /// misbehave.
+    pub(super) fn unavailable_reason() -> Option<&'static str> {
+        if !SUPERVISED.load(Ordering::SeqCst) {
+            return Some("the process running your code couldn't be set up for it");
+        }
+        let single_threaded = std::fs::read_dir("/proc/self/task")
+            .map(|tasks| tasks.count() == 1)
+            .unwrap_or(false);
+        if !single_threaded {
+            return Some("your code has started threads, which wouldn't survive being forked");
+        }
+        None
+    }
+
+    pub(super) enum Fork {
+        /// We're the child and should run the code. Call `release` if it completes.
+        Worker(Checkpoint),
+        /// We're the checkpoint and the child died before completing the code. Contains the
+        /// child's wait status.
+        Restored { status: libc::c_int },
+    }
+
+    /// Held by the process that's running code. Lets the checkpoint know when it's no longer needed.
+    pub(super) struct Checkpoint {
+        release_fd: libc::c_int,
+    }
+
+    impl Checkpoint {
+        /// Tells the checkpoint to exit, leaving this process to carry on handling requests.
+        pub(super) fn release(self) {
+            unsafe {
+                libc::write(self.release_fd, [1u8].as_ptr() as *const libc::c_void, 1);
+                libc::close(self.release_fd);
+            }
+        }
+    }
+
+    /// Forks a child to run code. In the parent, doesn't return unless the child dies without
+    /// releasing the checkpoint.
+    pub(super) fn fork() -> Result<Fork, Error> {
+        let mut fds = [0; 2];
+        unsafe {
+            // Close-on-exec, so that processes started by user code don't hold on to our pipe.
+            if libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) != 0 {
+                bail!("Failed to create pipe: {}", io::Error::last_os_error());
+            }
+            let [read_fd, write_fd] = fds;
+            let pid = libc::fork();
+            if pid == -1 {
+                libc::close(read_fd);
+                libc::close(write_fd);
+                bail!("Failed to fork: {}", io::Error::last_os_error());
+            }
+            if pid == 0 {
+                libc::close(read_fd);
+                super::claim_control_channel();
+                return Ok(Fork::Worker(Checkpoint {
+                    release_fd: write_fd,
+                }));
+            }
+            libc::close(write_fd);
+            let mut byte = 0u8;
+            let released = loop {
+                let result = libc::read(read_fd, &mut byte as *mut u8 as *mut libc::c_void, 1);
+                if result == -1 && io::Error::last_os_error().kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                break result == 1;
+            };
+            libc::close(read_fd);
+            if released {
+                // The child has taken over from us. It's important that we don't run any exit
+                // handlers or destructors, since the child shares any resources they'd release.
+                libc::_exit(0);
+            }
+            let mut status = 0;
+            while libc::waitpid(pid, &mut status, 0) == -1 {
+                if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+                    break;
+                }
+            }
+            Ok(Fork::Restored { status })
+        }
+    }
+
+    /// Returns a description of how the process whose wait status is `status` terminated.
+    pub(super) fn describe_status(status: libc::c_int) -> String {
+        if libc::WIFSIGNALED(status) {
+            let signal = libc::WTERMSIG(status);
+            if signal == libc::SIGINT {
+                return super::EXECUTION_INTERRUPTED.to_owned();
+            }
+            let name = unsafe { std::ffi::CStr::from_ptr(libc::strsignal(signal)) };
+            format!(
+                "Subprocess terminated with signal {} ({})",
+                signal,
+                name.to_string_lossy()
+            )
+        } else {
+            format!(
+                "Subprocess exited with status {}",
+                libc::WEXITSTATUS(status)
+            )
+        }
+    }
+}
@@ -0,0 +1,224 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::child_process::ResourceLimits;
+use crate::errors::bail;
+use crate::errors::Error;
+use crate::evcxr_internal_runtime::read_message;
+use crate::transport;
+use crate::transport::Connection;
+use crate::transport::Interrupter;
+use crate::transport::LocalTransport;
+use crate::transport::RuntimeProcess;
+use crate::transport::StdinWriter;
+use crate::transport::Transport;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Listens for connections on `address` and, for each one, starts a runtime process that runs
+/// code on behalf of whoever connected. The runtime process is a subprocess of the current
+/// process, so the current binary must call `runtime_hook` when it starts. Only returns if
+/// listening fails.
+///
+/// There is no authentication. Anyone who can connect can run arbitrary code with the privileges
+/// of this process, so `address` should only be reachable by trusted clients.
+pub fn run_runtime_server(address: &str) -> Result<(), Error> {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => bail!("Failed to listen on {}: {}", address, error),
+    };
+    // Printed so that whoever started us knows which port was picked if `address` had port 0.
+    println!("Listening on {}", listener.local_addr()?);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                std::thread::spawn(move || {
+                    if let Err(error) = serve_connection(stream) {
+                        eprintln!("{}", error);
+                    }
+                });
+            }
+            Err(error) => eprintln!("Failed to accept connection: {}", error),
+        }
+    }
+    Ok(())
+}
+
+fn serve_connection(mut stream: TcpStream) -> Result<(), Error> {
+    stream.set_nodelay(true)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let limits = read_start_message(&mut reader)?;
+    // Files that we're sent are written here. The runtime process runs from this directory, so
+    // that they can be referred to by relative paths.
+    let files_dir = tempfile::tempdir()?;
+    let mut command = Command::new(std::env::current_exe()?);
+    command.current_dir(files_dir.path());
+    add_library_paths(&mut command, files_dir.path());
+    let Connection { output, process } = LocalTransport::new(command)?.start(limits)?;
+    let interrupter = process.interrupter();
+    let stdin_writer = process.stdin_writer();
+    let process = Arc::new(Mutex::new(process));
+    // Input is handled on a separate thread, since we need to close the connection when the
+    // runtime process terminates, even if we're waiting for input at the time.
+    std::thread::spawn({
+        let files_dir = files_dir.path().to_owned();
+        let process = Arc::clone(&process);
+        move || {
+            if let Err(error) =
+                forward_input(reader, &process, &interrupter, &stdin_writer, &files_dir)
+            {
+                eprintln!("{}", error);
+            }
+        }
+    });
+    for output in output {
+        // Ignore errors, since it just means that the client has gone away. We keep reading so
+        // that the runtime process doesn't block writing.
+        let _ = stream.write_all(&transport::encode_output(&output));
+    }
+    let termination = process.lock().unwrap().wait()?;
+    stream.write_all(&termination.encode())?;
+    stream.shutdown(Shutdown::Both)?;
+    Ok(())
+}
+
+fn read_start_message(reader: &mut impl Read) -> Result<ResourceLimits, Error> {
+    let fields = read_message(reader)?.unwrap_or_default();
+    let limits: Vec<u64> = match fields.split_first() {
+        Some((kind, limits)) if kind == transport::START.as_bytes() => limits
+            .iter()
+            .filter_map(|limit| std::str::from_utf8(limit).ok()?.parse().ok())
+            .collect(),
+        _ => Vec::new(),
+    };
+    let non_zero = |value| if value == 0 { None } else { Some(value) };
+    match limits[..] {
+        [memory_limit_mb, cpu_limit_secs] => Ok(ResourceLimits {
+            memory_limit_mb: non_zero(memory_limit_mb),
+            cpu_limit_secs: non_zero(cpu_limit_secs),
+        }),
+        _ => bail!("Invalid start message"),
+    }
+}
+
+/// Handles what the client sends until it closes the connection. Control messages and stdin are
+/// passed through to the runtime process, everything else is an instruction for us.
+fn forward_input(
+    mut reader: impl Read,
+    process: &Mutex<Box<dyn RuntimeProcess>>,
+    interrupter: &Interrupter,
+    stdin_writer: &StdinWriter,
+    files_dir: &Path,
+) -> Result<(), Error> {
+    loop {
+        let fields = match read_message(&mut reader)? {
+            Some(fields) => fields,
+            None => {
+                // Lets the runtime process know that it's time to terminate.
+                process.lock().unwrap().close();
+                return Ok(());
+            }
+        };
+        let (kind, args) = match fields.split_first() {
+            Some((kind, args)) => (kind.as_slice(), args),
+            None => bail!("Received an empty message"),
+        };
+        // Errors writing to the runtime process are ignored, since it may have terminated, in
+        // which case the connection will be closed once we've forwarded all of its output.
+        if kind == transport::CONTROL.as_bytes() {
+            let args: Vec<&[u8]> = args.iter().map(Vec::as_slice).collect();
+            let _ = process.lock().unwrap().send_message(&args);
+        } else if kind == transport::STDIN.as_bytes() {
+            for data in args {
+                let _ = stdin_writer(Some(data));
+            }
+        } else if kind == transport::CLOSE_STDIN.as_bytes() {
+            let _ = stdin_writer(None);
+        } else if kind == transport::INTERRUPT.as_bytes() {
+            if let Err(error) = interrupter() {
+                eprintln!("{}", error);
+            }
+        } else if kind == transport::KILL.as_bytes() {
+            process.lock().unwrap().kill();
+            return Ok(());
+        } else if kind == transport::FILE.as_bytes() {
+            let (name, contents) = match args {
+                [name, contents] => (String::from_utf8_lossy(name), contents),
+                _ => bail!("Invalid file message"),
+            };
+            if name.is_empty() || name.contains(&['/', '\\'][..]) || name.starts_with('.') {
+                bail!("Invalid file name: {}", name);
+            }
+            std::fs::write(files_dir.join(name.as_ref()), contents)?;
+        } else {
+            bail!("Unknown message kind: {}", String::from_utf8_lossy(kind));
+        }
+    }
+}
+
+/// The code that we're sent is dynamically linked against the standard library and the items crate.
+/// It normally finds them via an rpath, but that is relative to where the code was compiled, so we
+/// add the directory where we write files and the library directory of our own Rust toolchain to
+/// the runtime process's search path. The toolchain needs to be the same version of Rust that the
+/// code was compiled with.
+fn add_library_paths(command: &mut Command, files_dir: &Path) {
+    let sysroot = match Command::new("rustc").arg("--print").arg("sysroot").output() {
+        Ok(output) if output.status.success() => Some(std::path::PathBuf::from(
+            String::from_utf8_lossy(&output.stdout).trim(),
+        )),
+        _ => None,
+    };
+    let (var_name, library_subdir) = if cfg!(windows) {
+        ("PATH", "bin")
+    } else if cfg!(target_os = "macos") {
+        ("DYLD_LIBRARY_PATH", "lib")
+    } else {
+        ("LD_LIBRARY_PATH", "lib")
+    };
+    let mut paths = vec![files_dir.to_owned()];
+    paths.extend(sysroot.map(|sysroot| sysroot.join(library_subdir)));
+    if let Some(existing) = std::env::var_os(var_name) {
+        paths.extend(std::env::split_paths(&existing));
+    }
+    if let Ok(value) = std::env::join_paths(paths) {
+        command.env(var_name, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::read_start_message;
+    use crate::evcxr_internal_runtime::MAX_MESSAGE_LEN;
+
+    #[test]
+    fn oversized_message_rejected() {
+        // Only the length is sent, so if we tried to read the body, we'd fail with an unexpected
+        // end of file instead.
+        let len = (MAX_MESSAGE_LEN as u32 + 1).to_le_bytes();
+        let error = read_start_message(&mut &len[..]).unwrap_err();
+        assert!(
+            error.to_string().contains("longer than the maximum"),
+            "{}",
+            error
+        );
+    }
+}
@@ -30,10 +30,14 @@ use ra_ap_project_model::ProjectManifest;
 use ra_ap_project_model::ProjectWorkspace;
 use ra_ap_syntax::ast::AstNode;
 use ra_ap_syntax::ast::{self};
+use ra_ap_syntax::SyntaxKind;
+use ra_ap_syntax::SyntaxNode;
+use ra_ap_syntax::TextSize;
 use ra_ap_vfs as ra_vfs;
 use ra_ap_vfs_notify as vfs_notify;
 use ra_ide::CallableSnippets;
 use std::collections::HashMap;
+use std::collections::HashSet;
 use std::convert::TryFrom;
 use std::path::Path;
 use std::sync::mpsc;
@@ -58,6 +62,28 @@ pub(crate) struct VariableInfo {
     pub(crate) type_name: String,
     /// Whether the variable is declared as mutable.
     pub(crate) is_mutable: bool,
+    /// Whether the variable's type implements Copy.
+    pub(crate) is_copy: bool,
+    /// Names of variables that the initializer of the variable's `let` statement borrows, so that
+    /// the variable's value may contain references to them.
+    pub(crate) borrows: HashSet<String>,
+}
+
+/// How the body of a function makes use of its top-level variables and parameters.
+#[derive(Debug, Default)]
+pub(crate) struct VariableUsage {
+    /// Variables that are referenced anywhere in the function.
+    pub(crate) referenced: HashSet<String>,
+    /// Variables that are moved and not subsequently reassigned, so no longer hold a value at the
+    /// end of the function. This is conservative - a variable that is moved in a way that we don't
+    /// recognise won't be included.
+    pub(crate) moved: HashSet<String>,
+    /// Whether `.await` is used outside of any nested closure or async block.
+    pub(crate) uses_await: bool,
+    /// Whether the `?` operator is used outside of any nested closure or async block.
+    pub(crate) uses_question_mark: bool,
+    /// Names of methods that couldn't be resolved even though the receiver's type is known.
+    pub(crate) unresolved_methods: HashSet<String>,
 }
 
 impl RustAnalyzer {
@@ -147,11 +173,16 @@ impl RustAnalyzer {
                         .unwrap();
                     for statement in body.statements() {
                         if let ast::Stmt::LetStmt(let_stmt) = statement {
+                            let borrows = let_stmt
+                                .initializer()
+                                .map(|initializer| borrowed_variables(&initializer, &sema))
+                                .unwrap_or_default();
                             if let Some(pat) = let_stmt.pat() {
                                 if !add_variable_for_pattern(
                                     &pat,
                                     &sema,
                                     let_stmt.ty(),
+                                    &borrows,
                                     module,
                                     &mut result,
                                 ) {
@@ -167,6 +198,7 @@ impl RustAnalyzer {
                                                 &sub_pat,
                                                 &sema,
                                                 None,
+                                                &borrows,
                                                 module,
                                                 &mut result,
                                             );
@@ -182,6 +214,139 @@ impl RustAnalyzer {
         result
     }
 
+    /// Determines which top-level variables and parameters of the specified function are referenced
+    /// and which are moved, as well as some other properties of the function body that affect how
+    /// we need to compile it.
+    pub(crate) fn variable_usage(&self, function_name: &str) -> VariableUsage {
+        use ra_ap_syntax::ast::HasModuleItem;
+        use ra_ap_syntax::ast::HasName;
+        let mut usage = VariableUsage::default();
+        let sema = ra_ide::Semantics::new(self.analysis_host.raw_database());
+        let source_file = sema.parse(self.source_file_id);
+        let function = source_file.items().find_map(|item| match item {
+            ast::Item::Fn(function)
+                if function
+                    .name()
+                    .map(|n| n.text() == function_name)
+                    .unwrap_or(false) =>
+            {
+                Some(function)
+            }
+            _ => None,
+        });
+        let (function, body) = match function.and_then(|f| f.body().map(|b| (f, b))) {
+            Some(x) => x,
+            None => return usage,
+        };
+        // Map from each top-level local to its name. If a name is shadowed, only the last binding
+        // is included, since that's the one whose value we'll store.
+        let mut locals_by_name = HashMap::new();
+        let params = function
+            .param_list()
+            .into_iter()
+            .flat_map(|list| list.params())
+            .filter_map(|param| param.pat());
+        let let_pats = body.statements().filter_map(|statement| match statement {
+            ast::Stmt::LetStmt(let_stmt) => let_stmt.pat(),
+            _ => None,
+        });
+        for pat in params.chain(let_pats) {
+            for ident_pat in pat.syntax().descendants().filter_map(ast::IdentPat::cast) {
+                if let (Some(name), Some(local)) = (ident_pat.name(), sema.to_def(&ident_pat)) {
+                    locals_by_name.insert(name.text().to_string(), local);
+                }
+            }
+        }
+        let names_by_local: HashMap<ra_hir::Local, String> = locals_by_name
+            .into_iter()
+            .map(|(name, local)| (local, name))
+            .collect();
+
+        let mut last_move: HashMap<String, TextSize> = HashMap::new();
+        let mut last_assignment: HashMap<String, TextSize> = HashMap::new();
+        for node in body.syntax().descendants() {
+            if let Some(path_expr) = ast::PathExpr::cast(node.clone()) {
+                let name = match path_expr.path().and_then(|path| sema.resolve_path(&path)) {
+                    Some(ra_hir::PathResolution::Local(local)) => {
+                        match names_by_local.get(&local) {
+                            Some(name) => name.clone(),
+                            None => continue,
+                        }
+                    }
+                    _ => continue,
+                };
+                if is_variable_store_arg(&node) {
+                    continue;
+                }
+                usage.referenced.insert(name.clone());
+                let offset = node.text_range().start();
+                if is_assignment_target(&node) {
+                    last_assignment.insert(name, offset);
+                } else if is_moved(&path_expr, &sema) {
+                    last_move.insert(name, offset);
+                }
+            } else if let Some(macro_call) = ast::MacroCall::cast(node.clone()) {
+                // Macro arguments aren't expanded in the syntax tree, so we treat any identifier
+                // that matches the name of a variable as a reference to that variable.
+                let idents = macro_call
+                    .token_tree()
+                    .into_iter()
+                    .flat_map(|tree| tree.syntax().descendants_with_tokens())
+                    .filter_map(|element| element.into_token())
+                    .filter(|token| token.kind() == SyntaxKind::IDENT);
+                for ident in idents {
+                    if names_by_local.values().any(|name| name == ident.text()) {
+                        usage.referenced.insert(ident.text().to_owned());
+                        // Whether the variable gets moved depends on where the macro puts it in
+                        // its expansion.
+                        let moved = sema
+                            .descend_into_macros(ident.clone())
+                            .into_iter()
+                            .filter_map(|token| token.parent())
+                            .filter_map(|node| node.ancestors().find_map(ast::PathExpr::cast))
+                            .filter(|path_expr| {
+                                path_expr
+                                    .path()
+                                    .and_then(|path| path.as_single_name_ref())
+                                    .map(|name_ref| name_ref.text() == ident.text())
+                                    .unwrap_or(false)
+                            })
+                            .any(|path_expr| is_moved(&path_expr, &sema));
+                        if moved {
+                            last_move.insert(ident.text().to_owned(), ident.text_range().start());
+                        }
+                    }
+                }
+            } else if ast::AwaitExpr::can_cast(node.kind()) {
+                usage.uses_await |= !is_in_nested_body(&node, body.syntax());
+            } else if ast::TryExpr::can_cast(node.kind()) {
+                usage.uses_question_mark |= !is_in_nested_body(&node, body.syntax());
+            } else if let Some(method_call) = ast::MethodCallExpr::cast(node) {
+                if sema.resolve_method_call(&method_call).is_none() {
+                    let receiver_type_known = method_call
+                        .receiver()
+                        .and_then(|receiver| sema.type_of_expr(&receiver))
+                        .map(|ty| !ty.original().is_unknown())
+                        .unwrap_or(false);
+                    if let (true, Some(name_ref)) = (receiver_type_known, method_call.name_ref()) {
+                        usage.unresolved_methods.insert(name_ref.text().to_string());
+                    }
+                }
+            }
+        }
+        for (name, move_offset) in last_move {
+            // A variable that's assigned after it's moved holds a value again.
+            if last_assignment
+                .get(&name)
+                .map(|assignment_offset| *assignment_offset < move_offset)
+                .unwrap_or(true)
+            {
+                usage.moved.insert(name);
+            }
+        }
+        usage
+    }
+
     fn load_cargo_toml(&mut self, change: &mut ra_ide::Change) -> Result<()> {
         let manifest = ProjectManifest::from_manifest_file(self.cargo_toml_filename())?;
         let config = CargoConfig {
@@ -330,23 +495,26 @@ fn add_variable_for_pattern(
     pat: &ast::Pat,
     sema: &ra_hir::Semantics<ra_ide::RootDatabase>,
     explicit_type: Option<ast::Type>,
+    borrows: &HashSet<String>,
     module: ra_hir::Module,
     result: &mut HashMap<String, VariableInfo>,
 ) -> bool {
     use ra_ap_syntax::ast::HasName;
     if let ast::Pat::IdentPat(ident_pat) = pat {
         if let Some(name) = ident_pat.name() {
-            if let Some(type_name) = get_type_name(
-                explicit_type,
-                sema.type_of_pat(pat).map(|info| info.original()),
-                sema,
-                module,
-            ) {
+            let inferred_type = sema.type_of_pat(pat).map(|info| info.original());
+            let is_copy = inferred_type
+                .as_ref()
+                .map(|ty| ty.is_copy(sema.db))
+                .unwrap_or(false);
+            if let Some(type_name) = get_type_name(explicit_type, inferred_type, sema, module) {
                 result.insert(
                     name.text().to_string(),
                     VariableInfo {
                         type_name,
                         is_mutable: ident_pat.mut_token().is_some(),
+                        is_copy,
+                        borrows: borrows.clone(),
                     },
                 );
                 return true;
@@ -356,6 +524,170 @@ fn add_variable_for_pattern(
     false
 }
 
+/// Returns whether `node` is the argument of a call to `evcxr_variable_store`. Such calls are
+/// generated by us in order to get the types of variables, so shouldn't count as uses.
+fn is_variable_store_arg(node: &SyntaxNode) -> bool {
+    node.parent()
+        .filter(|parent| ast::ArgList::can_cast(parent.kind()))
+        .and_then(|arg_list| arg_list.parent())
+        .and_then(ast::CallExpr::cast)
+        .and_then(|call| call.expr())
+        .map(|callee| callee.syntax().text() == "evcxr_variable_store")
+        .unwrap_or(false)
+}
+
+/// Returns whether `node` is the left hand side of a plain assignment (not a compound assignment).
+fn is_assignment_target(node: &SyntaxNode) -> bool {
+    node.parent()
+        .and_then(ast::BinExpr::cast)
+        .filter(|bin_expr| {
+            bin_expr.op_kind() == Some(ast::BinaryOp::Assignment { op: None })
+                && bin_expr
+                    .lhs()
+                    .map(|lhs| lhs.syntax() == node)
+                    .unwrap_or(false)
+        })
+        .is_some()
+}
+
+/// Returns whether `node` is inside a closure, async block or function that is itself inside
+/// `body`. Such nested bodies have their own scope for things like `?` and `.await`.
+fn is_in_nested_body(node: &SyntaxNode, body: &SyntaxNode) -> bool {
+    node.ancestors()
+        .take_while(|ancestor| ancestor != body)
+        .any(|ancestor| {
+            ast::ClosureExpr::can_cast(ancestor.kind())
+                || ast::Fn::can_cast(ancestor.kind())
+                || ast::BlockExpr::cast(ancestor)
+                    .map(|block| block.async_token().is_some())
+                    .unwrap_or(false)
+        })
+}
+
+/// Returns whether the value of the variable referenced by `path_expr` is moved by the expression
+/// that contains it. Only recognises the common cases, so may return false for something that
+/// actually does move the value.
+fn is_moved(path_expr: &ast::PathExpr, sema: &ra_hir::Semantics<ra_ide::RootDatabase>) -> bool {
+    let is_copy_or_unknown = sema
+        .type_of_expr(&ast::Expr::PathExpr(path_expr.clone()))
+        .map(|ty| ty.original().is_unknown() || ty.original().is_copy(sema.db))
+        .unwrap_or(true);
+    if is_copy_or_unknown {
+        return false;
+    }
+    let mut node = path_expr.syntax().clone();
+    // Parentheses don't affect whether a value gets moved.
+    while let Some(parent) = node
+        .parent()
+        .filter(|parent| ast::ParenExpr::can_cast(parent.kind()))
+    {
+        node = parent;
+    }
+    let in_move_closure = node
+        .ancestors()
+        .filter_map(ast::ClosureExpr::cast)
+        .any(|closure| closure.move_token().is_some());
+    if in_move_closure {
+        return true;
+    }
+    let parent = if let Some(parent) = node.parent() {
+        parent
+    } else {
+        return false;
+    };
+    if let Some(let_stmt) = ast::LetStmt::cast(parent.clone()) {
+        return !matches!(let_stmt.pat(), Some(ast::Pat::WildcardPat(_)));
+    }
+    if let Some(method_call) = ast::MethodCallExpr::cast(parent.clone()) {
+        let is_receiver = method_call
+            .receiver()
+            .map(|receiver| *receiver.syntax() == node)
+            .unwrap_or(false);
+        return is_receiver
+            && sema
+                .resolve_method_call(&method_call)
+                .and_then(|function| function.self_param(sema.db))
+                .map(|self_param| matches!(self_param.access(sema.db), ra_hir::Access::Owned))
+                .unwrap_or(false);
+    }
+    if let Some(bin_expr) = ast::BinExpr::cast(parent.clone()) {
+        return match bin_expr.op_kind() {
+            Some(ast::BinaryOp::Assignment { op: None }) => bin_expr
+                .rhs()
+                .map(|rhs| *rhs.syntax() == node)
+                .unwrap_or(false),
+            Some(ast::BinaryOp::ArithOp(_)) => true,
+            _ => false,
+        };
+    }
+    ast::ArgList::can_cast(parent.kind())
+        || ast::ForExpr::can_cast(parent.kind())
+        || ast::ReturnExpr::can_cast(parent.kind())
+        || ast::BreakExpr::can_cast(parent.kind())
+        || ast::RecordExprField::can_cast(parent.kind())
+        || ast::ArrayExpr::can_cast(parent.kind())
+        || ast::TupleExpr::can_cast(parent.kind())
+}
+
+/// Returns the names of the local variables that `expr` borrows, either by taking a shared reference
+/// to them or to part of them, or by calling a method that takes `&self` on them.
+fn borrowed_variables(
+    expr: &ast::Expr,
+    sema: &ra_hir::Semantics<ra_ide::RootDatabase>,
+) -> HashSet<String> {
+    let mut borrowed = HashSet::new();
+    for path_expr in expr.syntax().descendants().filter_map(ast::PathExpr::cast) {
+        let path = match path_expr.path() {
+            Some(path) => path,
+            None => continue,
+        };
+        if let (Some(ra_hir::PathResolution::Local(_)), Some(name_ref)) =
+            (sema.resolve_path(&path), path.as_single_name_ref())
+        {
+            if is_borrowed(&path_expr, sema) {
+                borrowed.insert(name_ref.text().to_string());
+            }
+        }
+    }
+    borrowed
+}
+
+/// Returns whether the variable referenced by `path_expr` is borrowed by the expression that
+/// contains it. Like `is_moved`, only the common cases are recognised.
+fn is_borrowed(path_expr: &ast::PathExpr, sema: &ra_hir::Semantics<ra_ide::RootDatabase>) -> bool {
+    let mut node = path_expr.syntax().clone();
+    // Borrowing a field or an indexed element of a variable borrows the variable.
+    while let Some(parent) = node.parent().filter(|parent| {
+        ast::ParenExpr::can_cast(parent.kind())
+            || ast::FieldExpr::can_cast(parent.kind())
+            || ast::IndexExpr::cast(parent.clone())
+                .and_then(|index_expr| index_expr.base())
+                .map(|base| *base.syntax() == node)
+                .unwrap_or(false)
+    }) {
+        node = parent;
+    }
+    let parent = if let Some(parent) = node.parent() {
+        parent
+    } else {
+        return false;
+    };
+    if let Some(ref_expr) = ast::RefExpr::cast(parent.clone()) {
+        return ref_expr.mut_token().is_none();
+    }
+    ast::MethodCallExpr::cast(parent)
+        .filter(|method_call| {
+            method_call
+                .receiver()
+                .map(|receiver| *receiver.syntax() == node)
+                .unwrap_or(false)
+        })
+        .and_then(|method_call| sema.resolve_method_call(&method_call))
+        .and_then(|function| function.self_param(sema.db))
+        .map(|self_param| matches!(self_param.access(sema.db), ra_hir::Access::Shared))
+        .unwrap_or(false)
+}
+
 fn get_type_name(
     explicit_type: Option<ast::Type>,
     inferred_type: Option<ra_hir::Type>,
@@ -392,7 +724,6 @@ pub struct Completion {
 /// types, produces invalid code. In particular, fixed sized arrays come out without a size. e.g.
 /// instead of `[i32, 5]`, we get `[i32, _]`.
 pub(crate) fn is_type_valid(type_name: &str) -> bool {
-    use ra_ap_syntax::SyntaxKind;
     let wrapped_source = format!("const _: {} = foo();", type_name);
     let parsed = ast::SourceFile::parse(&wrapped_source);
     if !parsed.errors().is_empty() {
@@ -406,9 +737,41 @@ pub(crate) fn is_type_valid(type_name: &str) -> bool {
     true
 }
 
+/// Returns `type_name` with any elided or anonymous reference lifetimes replaced with `'static`.
+pub(crate) fn with_static_lifetimes(type_name: &str) -> String {
+    const PREFIX: &str = "const _: ";
+    const SUFFIX: &str = " = foo();";
+    let mut wrapped_source = format!("{}{}{}", PREFIX, type_name, SUFFIX);
+    let parsed = ast::SourceFile::parse(&wrapped_source);
+    let mut edits = Vec::new();
+    for node in parsed.syntax_node().descendants() {
+        if let Some(ref_type) = ast::RefType::cast(node.clone()) {
+            if let (None, Some(amp)) = (ref_type.lifetime(), ref_type.amp_token()) {
+                let end = usize::from(amp.text_range().end());
+                edits.push((end..end, "'static "));
+            }
+        } else if let Some(lifetime) = ast::Lifetime::cast(node) {
+            if lifetime.syntax().text() == "'_" {
+                let range = lifetime.syntax().text_range();
+                edits.push((
+                    usize::from(range.start())..usize::from(range.end()),
+                    "'static",
+                ));
+            }
+        }
+    }
+    // Apply edits from last to first so that earlier offsets remain valid.
+    edits.sort_by_key(|(range, _)| std::cmp::Reverse(range.start));
+    for (range, replacement) in edits {
+        wrapped_source.replace_range(range, replacement);
+    }
+    wrapped_source[PREFIX.len()..wrapped_source.len() - SUFFIX.len()].to_owned()
+}
+
 #[cfg(test)]
 mod test {
     use super::is_type_valid;
+    use super::with_static_lifetimes;
     use super::RustAnalyzer;
     use anyhow::Result;
     use tempfile;
@@ -445,6 +808,8 @@ mod test {
                 let (v4, ..) = (42u64, 43, 44);
                 let p1 = Point {x: 1, y: 2};
                 let Point {x, y: y2} = p1;
+                let v5 = &p1.x;
+                let v6 = v4;
             }
             fn foo2() {
                 let v9 = true;
@@ -461,6 +826,11 @@ mod test {
         assert_eq!(var_types["v4"].type_name, "u64");
         assert_eq!(var_types["x"].type_name, "u8");
         assert_eq!(var_types["y2"].type_name, "u8");
+        assert_eq!(
+            var_types["v5"].borrows.iter().collect::<Vec<_>>(),
+            vec!["p1"]
+        );
+        assert!(var_types["v6"].borrows.is_empty());
 
         ra.set_source(
             r#"
@@ -476,6 +846,59 @@ mod test {
         Ok(())
     }
 
+    #[test]
+    fn get_variable_usage() -> Result<()> {
+        let tmpdir = tempfile::tempdir()?;
+        let mut ra = RustAnalyzer::new(tmpdir.path())?;
+        ra.with_sysroot = false;
+        std::fs::write(
+            ra.cargo_toml_filename().to_path_buf(),
+            r#"
+            [package]
+            name = "foo"
+            version = "0.1.0"
+
+            [lib]
+            "#,
+        )?;
+
+        ra.set_source(
+            r#"
+            struct Foo;
+            fn take(_: Foo) {}
+            fn evcxr_variable_store<T>(_: T) {}
+            macro_rules! take_it {
+                ($e:expr) => { take($e) };
+            }
+            fn foo(p1: Foo, p2: Foo, p3: Foo) {
+                let a = Foo;
+                let b = Foo;
+                let c = Foo;
+                let mut d = Foo;
+                take(p1);
+                let _ = &p2;
+                let e = a;
+                take(d);
+                d = Foo;
+                let _f = (b);
+                evcxr_variable_store(c);
+                take_it!(p3);
+            }"#
+            .to_owned(),
+        )?;
+        let usage = ra.variable_usage("foo");
+        let mut moved: Vec<_> = usage.moved.iter().map(String::as_str).collect();
+        moved.sort_unstable();
+        assert_eq!(moved, vec!["a", "b", "p1", "p3"]);
+        let mut referenced: Vec<_> = usage.referenced.iter().map(String::as_str).collect();
+        referenced.sort_unstable();
+        assert_eq!(referenced, vec!["a", "b", "d", "p1", "p2", "p3"]);
+        assert!(!usage.uses_await);
+        assert!(!usage.uses_question_mark);
+
+        Ok(())
+    }
+
     #[test]
     fn test_is_type_valid() {
         assert!(is_type_valid("Vec<String>"));
@@ -484,4 +907,16 @@ mod test {
         assert!(!is_type_valid("Vec<_>"));
         assert!(is_type_valid("Foo<42>"));
     }
+
+    #[test]
+    fn test_with_static_lifetimes() {
+        assert_eq!(with_static_lifetimes("&[i32]"), "&'static [i32]");
+        assert_eq!(with_static_lifetimes("&'a str"), "&'a str");
+        assert_eq!(with_static_lifetimes("Vec<&'_ str>"), "Vec<&'static str>");
+        assert_eq!(
+            with_static_lifetimes("(&mut i32, Option<&str>)"),
+            "(&'static mut i32, Option<&'static str>)"
+        );
+        assert_eq!(with_static_lifetimes("Vec<i32>"), "Vec<i32>");
+    }
 }
@@ -0,0 +1,218 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::errors::bail;
+use crate::errors::Error;
+use json::JsonValue;
+use std::path::Path;
+
+/// Incremented whenever the format of session files changes in an incompatible way.
+const FORMAT_VERSION: u32 = 1;
+
+/// A session as saved to disk. Variable values are stored as JSON produced by serde_json in the
+/// subprocess, so the parent process never needs to know anything about their types.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Session {
+    pub(crate) config: JsonValue,
+    /// Pairs of dependency name and config, as would be passed to `:dep`.
+    pub(crate) deps: Vec<(String, String)>,
+    /// Source code for all the items that were defined.
+    pub(crate) items: String,
+    pub(crate) variables: Vec<SavedVariable>,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct SavedVariable {
+    pub(crate) name: String,
+    pub(crate) type_name: String,
+    pub(crate) is_mut: bool,
+    pub(crate) value: String,
+}
+
+impl Session {
+    pub(crate) fn read(path: &Path) -> Result<Session, Error> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => bail!("Failed to read {:?}: {}", path, error),
+        };
+        Session::from_json(&json::parse(&contents)?)
+    }
+
+    pub(crate) fn write(&self, path: &Path) -> Result<(), Error> {
+        if let Err(error) = std::fs::write(path, self.to_json().pretty(2)) {
+            bail!("Failed to write {:?}: {}", path, error);
+        }
+        Ok(())
+    }
+
+    fn to_json(&self) -> JsonValue {
+        let mut json = JsonValue::new_object();
+        json["version"] = FORMAT_VERSION.into();
+        json["config"] = self.config.clone();
+        json["dependencies"] = JsonValue::new_object();
+        for (name, config) in &self.deps {
+            json["dependencies"][name.as_str()] = config.as_str().into();
+        }
+        json["items"] = self.items.as_str().into();
+        json["variables"] = JsonValue::new_array();
+        for variable in &self.variables {
+            let mut variable_json = JsonValue::new_object();
+            variable_json["name"] = variable.name.as_str().into();
+            variable_json["type"] = variable.type_name.as_str().into();
+            variable_json["mutable"] = variable.is_mut.into();
+            variable_json["value"] = variable.value.as_str().into();
+            // Can't fail, since we just created the array.
+            let _ = json["variables"].push(variable_json);
+        }
+        json
+    }
+
+    fn from_json(json: &JsonValue) -> Result<Session, Error> {
+        if json["version"].as_u32() != Some(FORMAT_VERSION) {
+            bail!(
+                "Unsupported session file version {}. Expected {}",
+                json["version"],
+                FORMAT_VERSION
+            );
+        }
+        let mut deps = Vec::new();
+        for (name, config) in json["dependencies"].entries() {
+            if let Some(config) = config.as_str() {
+                deps.push((name.to_owned(), config.to_owned()));
+            } else {
+                bail!("Invalid config for dependency `{}` in session file", name);
+            }
+        }
+        let mut variables = Vec::new();
+        for variable in json["variables"].members() {
+            match (
+                variable["name"].as_str(),
+                variable["type"].as_str(),
+                variable["value"].as_str(),
+            ) {
+                (Some(name), Some(type_name), Some(value)) => variables.push(SavedVariable {
+                    name: name.to_owned(),
+                    type_name: type_name.to_owned(),
+                    is_mut: variable["mutable"].as_bool().unwrap_or(false),
+                    value: value.to_owned(),
+                }),
+                _ => bail!("Invalid variable in session file: {}", variable),
+            }
+        }
+        Ok(Session {
+            config: json["config"].clone(),
+            deps,
+            items: json["items"].as_str().unwrap_or("").to_owned(),
+            variables,
+        })
+    }
+
+    /// Returns code that, when evaluated, defines the items and variables of this session. If a
+    /// value can't be deserialized, the code returns early with an error, so none of the variables
+    /// get defined.
+    pub(crate) fn restore_code(&self) -> String {
+        let mut code = self.items.clone();
+        for variable in &self.variables {
+            code.push_str(&format!(
+                "let {mutability}{name}: {type_name} = serde_json::from_str({value:?})\
+                 .map_err(|error| format!(\"Failed to restore `{name}`: {{}}\", error))?;\n",
+                mutability = if variable.is_mut { "mut " } else { "" },
+                name = variable.name,
+                type_name = variable.type_name,
+                value = variable.value
+            ));
+        }
+        code
+    }
+}
+
+/// Returns code that sends the values of `variables` (pairs of name and type) to the parent process
+/// as a JSON object mapping variable names to their serialized values. The values are read directly
+/// from the variable store, so this needs to run before variables are loaded. Variables whose
+/// types don't implement both `Serialize` and `DeserializeOwned` are left out.
+pub(crate) fn save_values_code(variables: &[(&str, &str)]) -> String {
+    let mut code = String::from(stringify!(
+        struct EvcxrSessionValue<'a, T>(&'a T);
+        trait EvcxrSerialize {
+            fn evcxr_to_json(&self) -> Option<String>;
+        }
+        impl<'a, T: serde::Serialize + serde::de::DeserializeOwned> EvcxrSerialize
+            for EvcxrSessionValue<'a, T>
+        {
+            fn evcxr_to_json(&self) -> Option<String> {
+                serde_json::to_string(self.0).ok()
+            }
+        }
+        // Only used when the more specific implementation above doesn't apply.
+        trait EvcxrNoSerialize {
+            fn evcxr_to_json(&self) -> Option<String>;
+        }
+        impl<'a, T> EvcxrNoSerialize for &EvcxrSessionValue<'a, T> {
+            fn evcxr_to_json(&self) -> Option<String> {
+                None
+            }
+        }
+        let mut evcxr_values = serde_json::Map::new();
+    ));
+    for (name, type_name) in variables {
+        code.push_str(&format!(
+            "if let Some(evcxr_value) = evcxr_variable_store.variable_ref::<{type_name}>({name:?}) {{
+                if let Some(evcxr_json) = (&EvcxrSessionValue(evcxr_value)).evcxr_to_json() {{
+                    evcxr_values.insert({name:?}.to_owned(), serde_json::Value::String(evcxr_json));
+                }}
+            }}\n",
+            name = name,
+            type_name = type_name
+        ));
+    }
+    code.push_str(
+        "evcxr_internal_runtime::send_query_result(\
+         &serde_json::Value::Object(evcxr_values).to_string());\n",
+    );
+    format!("{{\n{}}}\n", code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SavedVariable;
+    use super::Session;
+
+    #[test]
+    fn json_round_trip() {
+        let mut config = json::JsonValue::new_object();
+        config["opt_level"] = "1".into();
+        let session = Session {
+            config,
+            deps: vec![("regex".to_owned(), "\"1.0\"".to_owned())],
+            items: "struct Foo;\n".to_owned(),
+            variables: vec![SavedVariable {
+                name: "v".to_owned(),
+                type_name: "Vec<String>".to_owned(),
+                is_mut: true,
+                value: r#"["a\"b"]"#.to_owned(),
+            }],
+        };
+        assert_eq!(Session::from_json(&session.to_json()).unwrap(), session);
+        assert_eq!(
+            session.restore_code(),
+            "struct Foo;\nlet mut v: Vec<String> = serde_json::from_str(\"[\\\"a\\\\\\\"b\\\"]\")\
+             .map_err(|error| format!(\"Failed to restore `v`: {}\", error))?;\n"
+        );
+    }
+
+    #[test]
+    fn unsupported_version() {
+        assert!(Session::from_json(&json::parse(r#"{"version": 1000}"#).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,826 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::child_process::ResourceLimits;
+use crate::errors::bail;
+use crate::errors::Error;
+use crate::evcxr_internal_runtime::decode_message;
+use crate::evcxr_internal_runtime::encode_message;
+use crate::evcxr_internal_runtime::read_message;
+use crate::evcxr_internal_runtime::ControlChannel;
+use crate::runtime;
+use std::collections::HashMap;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// Kinds of messages exchanged with a runtime server. These are encoded in the same way as control
+// messages. See `encode_message`.
+
+/// The first message sent to a runtime server. Followed by the memory limit in MB and the CPU time
+/// limit in seconds, with zero meaning unlimited.
+pub(crate) const START: &str = "START";
+/// Followed by the fields of a control message to or from the runtime process.
+pub(crate) const CONTROL: &str = "CONTROL";
+/// Sent to a runtime server, followed by data to write to the runtime process's stdin.
+pub(crate) const STDIN: &str = "STDIN";
+/// Asks a runtime server to close the runtime process's stdin.
+pub(crate) const CLOSE_STDIN: &str = "CLOSE_STDIN";
+/// Sent by a runtime server, followed by a line that the runtime process wrote to stdout.
+pub(crate) const STDOUT: &str = "STDOUT";
+/// Sent by a runtime server, followed by a line that the runtime process wrote to stderr.
+pub(crate) const STDERR: &str = "STDERR";
+/// Sent to a runtime server, followed by the name and contents of a file that the runtime process
+/// will need.
+pub(crate) const FILE: &str = "FILE";
+/// Asks a runtime server to interrupt whatever its runtime process is running.
+pub(crate) const INTERRUPT: &str = "INTERRUPT";
+/// Asks a runtime server to kill its runtime process.
+pub(crate) const KILL: &str = "KILL";
+/// Sent by a runtime server once its runtime process has terminated. Followed by the number of the
+/// signal that terminated it, if any, then a description of how it terminated.
+pub(crate) const TERMINATED: &str = "TERMINATED";
+
+/// Stops whatever user code is running in a runtime process by terminating the process running it.
+/// If a checkpoint was taken before the code ran, the runtime process carries on from there.
+pub(crate) type Interrupter = Box<dyn Fn() -> Result<(), Error> + Send + Sync>;
+
+/// Writes to the stdin of a runtime process, or closes it if given `None`. Can be used from any
+/// thread.
+pub(crate) type StdinWriter = Box<dyn Fn(Option<&[u8]>) -> std::io::Result<()> + Send + Sync>;
+
+/// A means of starting processes that run the code that we compile and of communicating with
+/// them.
+pub(crate) trait Transport: Send {
+    /// Starts a new runtime process, applying `limits` to it.
+    fn start(&mut self, limits: ResourceLimits) -> Result<Connection, Error>;
+}
+
+/// A runtime process that we've started, together with what it outputs.
+pub(crate) struct Connection {
+    /// Everything that the runtime process outputs. Disconnected once the runtime process has
+    /// terminated.
+    pub(crate) output: crossbeam_channel::Receiver<RuntimeOutput>,
+    pub(crate) process: Box<dyn RuntimeProcess>,
+}
+
+pub(crate) enum RuntimeOutput {
+    Stdout(String),
+    Stderr(String),
+    /// A control message. Anything written to stdout or stderr before the message was sent is
+    /// received before it, at least on unix.
+    Message(Message),
+}
+
+/// A message made up of fields, the first of which identifies the kind of message.
+#[derive(Debug)]
+pub(crate) struct Message {
+    fields: Vec<Vec<u8>>,
+}
+
+impl Message {
+    fn new(fields: Vec<Vec<u8>>) -> Message {
+        Message { fields }
+    }
+
+    pub(crate) fn kind(&self) -> &str {
+        self.fields
+            .first()
+            .and_then(|kind| std::str::from_utf8(kind).ok())
+            .unwrap_or_default()
+    }
+
+    /// Returns the fields that follow the kind.
+    pub(crate) fn args(&self) -> &[Vec<u8>] {
+        self.fields.get(1..).unwrap_or_default()
+    }
+
+    /// Returns the field at `index` in `args` as a string, or an empty string if there isn't one.
+    pub(crate) fn arg(&self, index: usize) -> String {
+        self.args()
+            .get(index)
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .unwrap_or_default()
+    }
+}
+
+pub(crate) trait RuntimeProcess: Send {
+    /// Returns something that can interrupt the process from another thread.
+    fn interrupter(&self) -> Interrupter;
+
+    /// Makes the file at `path` available to the process. Returns the path by which the process
+    /// can access it.
+    fn provide_file(&mut self, path: &Path) -> Result<PathBuf, Error>;
+
+    /// Sends a control message made up of `fields` to the process.
+    fn send_message(&mut self, fields: &[&[u8]]) -> std::io::Result<()>;
+
+    /// Returns something that can write to the process's stdin from another thread.
+    fn stdin_writer(&self) -> StdinWriter;
+
+    /// Lets the process know that we won't be sending it anything more, which causes it to exit
+    /// once it's finished what it's doing.
+    fn close(&mut self);
+
+    /// Kills the process, together with any processes that it forked, if it's still running.
+    fn kill(&mut self);
+
+    /// Waits for the process to terminate.
+    fn wait(&mut self) -> Result<Termination, Error>;
+}
+
+/// How a runtime process terminated.
+pub(crate) struct Termination {
+    /// e.g. "exit status: 1".
+    pub(crate) description: String,
+    /// The signal that terminated the process, if it was terminated by a signal.
+    pub(crate) signal: Option<i32>,
+}
+
+impl Termination {
+    fn from_exit_status(exit_status: std::process::ExitStatus) -> Termination {
+        #[cfg(unix)]
+        let signal = std::os::unix::process::ExitStatusExt::signal(&exit_status);
+        #[cfg(not(unix))]
+        let signal = None;
+        Termination {
+            description: exit_status.to_string(),
+            signal,
+        }
+    }
+
+    /// Encodes this termination as the `TERMINATED` message that a runtime server sends.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let signal = self
+            .signal
+            .map(|signal| signal.to_string())
+            .unwrap_or_default();
+        encode_message(&[
+            TERMINATED.as_bytes(),
+            signal.as_bytes(),
+            self.description.as_bytes(),
+        ])
+    }
+
+    fn decode(message: &Message) -> Termination {
+        Termination {
+            description: message.arg(1),
+            signal: message.arg(0).parse().ok(),
+        }
+    }
+}
+
+/// Converts a path into bytes that can be sent in a control message. On unix, paths needn't be
+/// valid UTF-8, so we send their bytes as is.
+#[cfg(unix)]
+pub(crate) fn path_to_bytes(path: &Path) -> Vec<u8> {
+    std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).to_vec()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_to_bytes(path: &Path) -> Vec<u8> {
+    path.to_string_lossy().into_owned().into_bytes()
+}
+
+/// The inverse of `path_to_bytes`.
+#[cfg(unix)]
+pub(crate) fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(<std::ffi::OsStr as std::os::unix::ffi::OsStrExt>::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+pub(crate) fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Sends SIGINT to the process group led by `process_id`. If a runtime process is running user
+/// code, SIGINT terminates it. See Runtime::install_crash_handlers. We signal the whole process
+/// group, since when it checkpoints, the code is run by a process that it forks.
+#[cfg(all(unix, not(target_os = "freebsd")))]
+fn interrupt_process_group(process_id: u32) -> Result<(), Error> {
+    if unsafe { libc::kill(-(process_id as libc::pid_t), libc::SIGINT) } != 0 {
+        bail!(
+            "Failed to interrupt subprocess: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(not(all(unix, not(target_os = "freebsd"))))]
+fn interrupt_process_group(_process_id: u32) -> Result<(), Error> {
+    bail!("Interrupting execution isn't supported on this platform");
+}
+
+/// Kills the process group led by `process_id`, which includes any processes forked to run user
+/// code after taking a checkpoint.
+fn kill_process_group(process_id: u32) {
+    #[cfg(unix)]
+    unsafe {
+        libc::kill(-(process_id as libc::pid_t), libc::SIGKILL);
+    }
+    #[cfg(not(unix))]
+    let _ = process_id;
+}
+
+/// What we need to give a local runtime process when we start it. These are read after fork,
+/// where it isn't safe to acquire a lock, so we use atomics. For limits, zero means unlimited.
+#[derive(Default)]
+struct SharedStartParams {
+    memory_limit_mb: AtomicU64,
+    cpu_limit_secs: AtomicU64,
+    /// The runtime process's end of its control channel.
+    #[cfg(unix)]
+    control_fd: std::sync::atomic::AtomicI32,
+}
+
+impl SharedStartParams {
+    fn limits(&self) -> ResourceLimits {
+        let non_zero = |value| if value == 0 { None } else { Some(value) };
+        ResourceLimits {
+            memory_limit_mb: non_zero(self.memory_limit_mb.load(Ordering::SeqCst)),
+            cpu_limit_secs: non_zero(self.cpu_limit_secs.load(Ordering::SeqCst)),
+        }
+    }
+
+    fn set_limits(&self, limits: ResourceLimits) {
+        self.memory_limit_mb
+            .store(limits.memory_limit_mb.unwrap_or(0), Ordering::SeqCst);
+        self.cpu_limit_secs
+            .store(limits.cpu_limit_secs.unwrap_or(0), Ordering::SeqCst);
+    }
+}
+
+/// Runs code in subprocesses of the current process. Control messages are exchanged over a
+/// dedicated channel, leaving stdin, stdout and stderr to the user's code.
+pub(crate) struct LocalTransport {
+    command: std::process::Command,
+    params: Arc<SharedStartParams>,
+}
+
+impl LocalTransport {
+    pub(crate) fn new(mut command: std::process::Command) -> Result<LocalTransport, Error> {
+        // Avoid a fork bomb. We could call runtime_hook here but then all the work that we did up
+        // to this point would be wasted. Also, it's possible that we could already have started
+        // threads, which could get messy.
+        if std::env::var(runtime::EVCXR_IS_RUNTIME_VAR).is_ok() {
+            bail!("Our current binary doesn't call runtime_hook()");
+        }
+        let params = Arc::new(SharedStartParams::default());
+        command
+            .env(runtime::EVCXR_IS_RUNTIME_VAR, "1")
+            .env("RUST_BACKTRACE", "1")
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::piped());
+        #[cfg(unix)]
+        {
+            use std::os::unix::process::CommandExt;
+            let params = Arc::clone(&params);
+            unsafe {
+                command.pre_exec(move || {
+                    // Put the subprocess in its own process group so that if the user presses
+                    // Ctrl-C in a terminal, it's up to us whether it gets interrupted.
+                    libc::setpgid(0, 0);
+                    // Let the subprocess inherit its end of the control channel. It sets
+                    // close-on-exec again once it has claimed it.
+                    let control_fd = params.control_fd.load(Ordering::SeqCst);
+                    if libc::fcntl(control_fd, libc::F_SETFD, 0) != 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    params.limits().apply()
+                });
+            }
+        }
+        Ok(LocalTransport { command, params })
+    }
+
+    fn spawn(&mut self) -> Result<std::process::Child, Error> {
+        match self.command.spawn() {
+            Ok(child) => Ok(child),
+            Err(error) => bail!("Failed to run '{:?}': {:?}", self.command, error),
+        }
+    }
+
+    #[cfg(unix)]
+    fn spawn_with_control_channel(
+        &mut self,
+    ) -> Result<(std::process::Child, ControlChannel), Error> {
+        use std::os::unix::io::AsRawFd;
+        let (control, child_control) = ControlChannel::pair()?;
+        let child_control_fd = child_control.as_raw_fd();
+        self.params
+            .control_fd
+            .store(child_control_fd, Ordering::SeqCst);
+        self.command.env(
+            crate::evcxr_internal_runtime::CONTROL_CHANNEL_VAR,
+            child_control_fd.to_string(),
+        );
+        let child = self.spawn()?;
+        // The subprocess has its own copy now. We need to close ours so that we see the channel
+        // close when the subprocess terminates.
+        drop(child_control);
+        Ok((child, control))
+    }
+
+    /// The standard library has no way to pass a socket to a subprocess on Windows, so instead we
+    /// listen on a loopback address for the subprocess to connect to us. The subprocess identifies
+    /// itself with a token that only it has been given.
+    #[cfg(windows)]
+    fn spawn_with_control_channel(
+        &mut self,
+    ) -> Result<(std::process::Child, ControlChannel), Error> {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+        let token = random_token();
+        self.command.env(
+            runtime::EVCXR_CONTROL_ADDRESS_VAR,
+            format!("{} {}", listener.local_addr()?, token),
+        );
+        let mut child = self.spawn()?;
+        listener.set_nonblocking(true)?;
+        loop {
+            match listener.accept() {
+                Ok((mut stream, _)) => {
+                    stream.set_nonblocking(false)?;
+                    stream.set_read_timeout(Some(std::time::Duration::from_secs(10)))?;
+                    if let Ok(Some(fields)) = read_message(&mut stream) {
+                        if fields == [token.as_bytes()] {
+                            stream.set_read_timeout(None)?;
+                            stream.set_nodelay(true)?;
+                            return Ok((child, stream));
+                        }
+                    }
+                }
+                Err(error) if error.kind() == std::io::ErrorKind::WouldBlock => {
+                    if let Some(status) = child.try_wait()? {
+                        bail!(
+                            "Subprocess terminated before connecting its control channel: {}",
+                            status
+                        );
+                    }
+                    std::thread::sleep(std::time::Duration::from_millis(10));
+                }
+                Err(error) => bail!("Failed to accept control channel: {}", error),
+            }
+        }
+    }
+}
+
+/// Returns a string that other processes can't guess.
+#[cfg(windows)]
+fn random_token() -> String {
+    use std::hash::BuildHasher;
+    use std::hash::Hasher;
+    // Each RandomState is seeded with random keys.
+    (0..2)
+        .map(|_| {
+            format!(
+                "{:016x}",
+                std::collections::hash_map::RandomState::new()
+                    .build_hasher()
+                    .finish()
+            )
+        })
+        .collect()
+}
+
+impl Transport for LocalTransport {
+    fn start(&mut self, limits: ResourceLimits) -> Result<Connection, Error> {
+        self.params.set_limits(limits);
+        let (mut child, control) = self.spawn_with_control_channel()?;
+        let (sender, output) = crossbeam_channel::unbounded();
+        let control_reader = control.try_clone()?;
+        let stdout = child.stdout.take().unwrap();
+        let stderr = child.stderr.take().unwrap();
+        std::thread::spawn(move || forward_output(control_reader, stdout, stderr, sender));
+        Ok(Connection {
+            output,
+            process: Box::new(LocalProcess {
+                stdin: Arc::new(Mutex::new(child.stdin.take())),
+                child,
+                control,
+            }),
+        })
+    }
+}
+
+/// One of the streams that a runtime process writes to.
+struct OutputStream<R> {
+    reader: R,
+    /// What we've read, but not yet sent, since it doesn't yet make up a whole line or message.
+    pending: Vec<u8>,
+    open: bool,
+}
+
+impl<R: Read> OutputStream<R> {
+    fn new(reader: R) -> OutputStream<R> {
+        OutputStream {
+            reader,
+            pending: Vec::new(),
+            open: true,
+        }
+    }
+
+    /// Reads some of what's available, blocking if nothing is.
+    fn read(&mut self) {
+        let mut buffer = [0; 8192];
+        match self.reader.read(&mut buffer) {
+            Ok(0) => self.open = false,
+            Ok(len) => self.pending.extend_from_slice(&buffer[..len]),
+            Err(error) if error.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => self.open = false,
+        }
+    }
+
+    /// Reads exactly what's available right now, without blocking.
+    #[cfg(unix)]
+    fn read_available(&mut self)
+    where
+        R: std::os::unix::io::AsRawFd,
+    {
+        let mut available: libc::c_int = 0;
+        if !self.open
+            || unsafe { libc::ioctl(self.reader.as_raw_fd(), libc::FIONREAD, &mut available) } != 0
+        {
+            return;
+        }
+        let start = self.pending.len();
+        self.pending.resize(start + available as usize, 0);
+        if self.reader.read_exact(&mut self.pending[start..]).is_err() {
+            self.pending.truncate(start);
+            self.open = false;
+        }
+    }
+
+    /// Sends each whole line that we've read. If `flush` is set, or there's nothing more to read,
+    /// also sends any partial line.
+    fn send_lines(
+        &mut self,
+        to_output: fn(String) -> RuntimeOutput,
+        sender: &crossbeam_channel::Sender<RuntimeOutput>,
+        flush: bool,
+    ) {
+        while let Some(end) = self.pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = self.pending.drain(..=end).collect();
+            let _ = sender.send(to_output(line_to_string(&line[..end])));
+        }
+        if (flush || !self.open) && !self.pending.is_empty() {
+            let line = std::mem::take(&mut self.pending);
+            let _ = sender.send(to_output(line_to_string(&line)));
+        }
+    }
+
+    fn next_message(&mut self) -> Option<Message> {
+        let (fields, len) = decode_message(&self.pending)?;
+        self.pending.drain(..len);
+        Some(Message::new(fields))
+    }
+}
+
+fn line_to_string(line: &[u8]) -> String {
+    String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line)).into_owned()
+}
+
+/// Reads what a runtime process writes to its control channel, stdout and stderr, sending it to
+/// `sender`. Returns once all three have been closed. Everything is read on one thread, so that
+/// before we send each control message, we can make sure that we've sent everything that the
+/// runtime process wrote to stdout and stderr before it. Among other things, this means that once
+/// we've received the message saying that some code has finished running, we've received all of
+/// its output.
+#[cfg(unix)]
+fn forward_output(
+    control: impl Read + std::os::unix::io::AsRawFd,
+    stdout: impl Read + std::os::unix::io::AsRawFd,
+    stderr: impl Read + std::os::unix::io::AsRawFd,
+    sender: crossbeam_channel::Sender<RuntimeOutput>,
+) {
+    let mut control = OutputStream::new(control);
+    let mut stdout = OutputStream::new(stdout);
+    let mut stderr = OutputStream::new(stderr);
+    while control.open || stdout.open || stderr.open {
+        let fd = |open: bool, fd: libc::c_int| if open { fd } else { -1 };
+        let ready = poll_readable([
+            fd(control.open, control.reader.as_raw_fd()),
+            fd(stdout.open, stdout.reader.as_raw_fd()),
+            fd(stderr.open, stderr.reader.as_raw_fd()),
+        ]);
+        if ready[1] {
+            stdout.read();
+            stdout.send_lines(RuntimeOutput::Stdout, &sender, false);
+        }
+        if ready[2] {
+            stderr.read();
+            stderr.send_lines(RuntimeOutput::Stderr, &sender, false);
+        }
+        if ready[0] {
+            control.read();
+            while let Some(message) = control.next_message() {
+                // Whatever was written before the message was sent is available to read now.
+                stdout.read_available();
+                stdout.send_lines(RuntimeOutput::Stdout, &sender, true);
+                stderr.read_available();
+                stderr.send_lines(RuntimeOutput::Stderr, &sender, true);
+                let _ = sender.send(RuntimeOutput::Message(message));
+            }
+        }
+    }
+}
+
+/// Waits until at least one of `fds` can be read without blocking, or has been closed. Returns
+/// which ones can. Negative file descriptors are ignored.
+#[cfg(unix)]
+fn poll_readable(fds: [libc::c_int; 3]) -> [bool; 3] {
+    let mut poll_fds = fds.map(|fd| libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    });
+    while unsafe { libc::poll(poll_fds.as_mut_ptr(), poll_fds.len() as libc::nfds_t, -1) } < 0 {
+        if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted {
+            // Shouldn't happen. Reading whatever's open will at least tell us if it's closed.
+            return fds.map(|fd| fd >= 0);
+        }
+    }
+    poll_fds.map(|poll_fd| poll_fd.revents != 0)
+}
+
+/// Without a portable way to tell whether we can read without blocking, we read each stream on its
+/// own thread. This means that, unlike on unix, control messages may be received before output
+/// that was written before them.
+#[cfg(not(unix))]
+fn forward_output(
+    control: impl Read,
+    stdout: impl Read + Send + 'static,
+    stderr: impl Read + Send + 'static,
+    sender: crossbeam_channel::Sender<RuntimeOutput>,
+) {
+    fn forward_lines(
+        stream: impl Read + Send + 'static,
+        to_output: fn(String) -> RuntimeOutput,
+        sender: crossbeam_channel::Sender<RuntimeOutput>,
+    ) {
+        let mut stream = OutputStream::new(stream);
+        std::thread::spawn(move || {
+            while stream.open {
+                stream.read();
+                stream.send_lines(to_output, &sender, false);
+            }
+        });
+    }
+    forward_lines(stdout, RuntimeOutput::Stdout, sender.clone());
+    forward_lines(stderr, RuntimeOutput::Stderr, sender.clone());
+    let mut control = OutputStream::new(control);
+    while control.open {
+        control.read();
+        while let Some(message) = control.next_message() {
+            let _ = sender.send(RuntimeOutput::Message(message));
+        }
+    }
+}
+
+struct LocalProcess {
+    child: std::process::Child,
+    control: ControlChannel,
+    /// Only `None` once we've closed it.
+    stdin: Arc<Mutex<Option<std::process::ChildStdin>>>,
+}
+
+impl RuntimeProcess for LocalProcess {
+    fn interrupter(&self) -> Interrupter {
+        let process_id = self.child.id();
+        Box::new(move || interrupt_process_group(process_id))
+    }
+
+    fn provide_file(&mut self, path: &Path) -> Result<PathBuf, Error> {
+        Ok(path.to_owned())
+    }
+
+    fn send_message(&mut self, fields: &[&[u8]]) -> std::io::Result<()> {
+        self.control.write_all(&encode_message(fields))
+    }
+
+    fn stdin_writer(&self) -> StdinWriter {
+        let stdin = Arc::clone(&self.stdin);
+        Box::new(move |data| {
+            let mut stdin = stdin.lock().unwrap();
+            match (&mut *stdin, data) {
+                (Some(stdin), Some(data)) => {
+                    stdin.write_all(data)?;
+                    stdin.flush()
+                }
+                (Some(_), None) => {
+                    *stdin = None;
+                    Ok(())
+                }
+                (None, _) => Err(std::io::ErrorKind::BrokenPipe.into()),
+            }
+        })
+    }
+
+    fn close(&mut self) {
+        // The thread that reads from the control channel has its own handle to it, so dropping
+        // ours wouldn't close it.
+        let _ = self.control.shutdown(Shutdown::Write);
+        *self.stdin.lock().unwrap() = None;
+    }
+
+    fn kill(&mut self) {
+        // Once the process has been waited for, its ID may be reused, so we mustn't signal it.
+        if let Ok(None) = self.child.try_wait() {
+            kill_process_group(self.child.id());
+        }
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+
+    fn wait(&mut self) -> Result<Termination, Error> {
+        Ok(Termination::from_exit_status(self.child.wait()?))
+    }
+}
+
+/// Encodes `output` as the message that a runtime server sends for it.
+pub(crate) fn encode_output(output: &RuntimeOutput) -> Vec<u8> {
+    match output {
+        RuntimeOutput::Stdout(line) => encode_message(&[STDOUT.as_bytes(), line.as_bytes()]),
+        RuntimeOutput::Stderr(line) => encode_message(&[STDERR.as_bytes(), line.as_bytes()]),
+        RuntimeOutput::Message(message) => {
+            let mut fields = vec![CONTROL.as_bytes()];
+            fields.extend(message.fields.iter().map(Vec::as_slice));
+            encode_message(&fields)
+        }
+    }
+}
+
+/// Runs code in processes started by a runtime server (see `run_runtime_server`) that we connect
+/// to over TCP. Each runtime process gets its own connection, over which everything is sent as
+/// messages. This includes files that the runtime process needs, such as the code that we compile.
+pub(crate) struct TcpTransport {
+    address: String,
+}
+
+impl TcpTransport {
+    pub(crate) fn new(address: &str) -> TcpTransport {
+        TcpTransport {
+            address: address.to_owned(),
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn start(&mut self, limits: ResourceLimits) -> Result<Connection, Error> {
+        let stream = match TcpStream::connect(&self.address) {
+            Ok(stream) => stream,
+            Err(error) => bail!(
+                "Failed to connect to runtime server at {}: {}",
+                self.address,
+                error
+            ),
+        };
+        stream.set_nodelay(true)?;
+        let mut reader = std::io::BufReader::new(stream.try_clone()?);
+        let stream = Arc::new(Mutex::new(stream));
+        write_message(
+            &stream,
+            &[
+                START.as_bytes(),
+                limits.memory_limit_mb.unwrap_or(0).to_string().as_bytes(),
+                limits.cpu_limit_secs.unwrap_or(0).to_string().as_bytes(),
+            ],
+        )?;
+
+        let (sender, output) = crossbeam_channel::unbounded();
+        let termination = Arc::new(Mutex::new(None));
+        let reader_thread = std::thread::spawn({
+            let termination = Arc::clone(&termination);
+            move || {
+                while let Ok(Some(fields)) = read_message(&mut reader) {
+                    let message = Message::new(fields);
+                    let output = match message.kind() {
+                        STDOUT => RuntimeOutput::Stdout(message.arg(0)),
+                        STDERR => RuntimeOutput::Stderr(message.arg(0)),
+                        CONTROL => RuntimeOutput::Message(Message::new(message.args().to_vec())),
+                        TERMINATED => {
+                            *termination.lock().unwrap() = Some(Termination::decode(&message));
+                            continue;
+                        }
+                        _ => continue,
+                    };
+                    let _ = sender.send(output);
+                }
+            }
+        });
+        Ok(Connection {
+            output,
+            process: Box::new(RemoteProcess {
+                stream,
+                termination,
+                reader_thread: Some(reader_thread),
+                sent_files: HashMap::new(),
+            }),
+        })
+    }
+}
+
+/// Writes a message made up of `fields` to `stream` with a single write, so that it can't be
+/// interleaved with messages written from other threads.
+fn write_message(stream: &Mutex<TcpStream>, fields: &[&[u8]]) -> std::io::Result<()> {
+    stream.lock().unwrap().write_all(&encode_message(fields))
+}
+
+struct RemoteProcess {
+    stream: Arc<Mutex<TcpStream>>,
+    /// Set once the runtime server tells us how the runtime process terminated.
+    termination: Arc<Mutex<Option<Termination>>>,
+    /// Reads from the connection until the runtime server closes it. Only `None` once we've
+    /// waited for it.
+    reader_thread: Option<std::thread::JoinHandle<()>>,
+    /// The modification times of files that we've sent, so that we don't send them again unless
+    /// they change. The items crate, in particular, is provided for each execution.
+    sent_files: HashMap<PathBuf, SystemTime>,
+}
+
+impl RuntimeProcess for RemoteProcess {
+    fn interrupter(&self) -> Interrupter {
+        let stream = Arc::clone(&self.stream);
+        Box::new(move || {
+            if let Err(error) = write_message(&stream, &[INTERRUPT.as_bytes()]) {
+                bail!("Failed to interrupt runtime process: {}", error);
+            }
+            Ok(())
+        })
+    }
+
+    fn provide_file(&mut self, path: &Path) -> Result<PathBuf, Error> {
+        let name = match path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => bail!("Can't send {:?} to runtime server", path),
+        };
+        let modified = std::fs::metadata(path)?.modified()?;
+        if self.sent_files.get(path) != Some(&modified) {
+            let contents = std::fs::read(path)?;
+            write_message(&self.stream, &[FILE.as_bytes(), name.as_bytes(), &contents])?;
+            self.sent_files.insert(path.to_owned(), modified);
+        }
+        // The runtime server runs the runtime process in the directory where it writes files.
+        Ok(Path::new(".").join(name))
+    }
+
+    fn send_message(&mut self, fields: &[&[u8]]) -> std::io::Result<()> {
+        let mut message = vec![CONTROL.as_bytes()];
+        message.extend_from_slice(fields);
+        write_message(&self.stream, &message)
+    }
+
+    fn stdin_writer(&self) -> StdinWriter {
+        let stream = Arc::clone(&self.stream);
+        Box::new(move |data| match data {
+            Some(data) => write_message(&stream, &[STDIN.as_bytes(), data]),
+            None => write_message(&stream, &[CLOSE_STDIN.as_bytes()]),
+        })
+    }
+
+    fn close(&mut self) {
+        // The runtime server closes the runtime process's control channel when we stop writing.
+        // Other references to the stream keep it open, so we need to explicitly shut it down.
+        let _ = self.stream.lock().unwrap().shutdown(Shutdown::Write);
+    }
+
+    fn kill(&mut self) {
+        let _ = write_message(&self.stream, &[KILL.as_bytes()]);
+        let _ = self.wait();
+    }
+
+    fn wait(&mut self) -> Result<Termination, Error> {
+        if let Some(reader_thread) = self.reader_thread.take() {
+            let _ = reader_thread.join();
+        }
+        Ok(self
+            .termination
+            .lock()
+            .unwrap()
+            .take()
+            .unwrap_or_else(|| Termination {
+                description: "connection to runtime server closed".to_owned(),
+                signal: None,
+            }))
+    }
+}
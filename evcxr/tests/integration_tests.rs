@@ -14,9 +14,12 @@
 
 use evcxr::CommandContext;
 use evcxr::Error;
+use evcxr::EvalCallbacks;
 use evcxr::EvalContext;
 use evcxr::EvalContextOutputs;
+use evcxr::EvalOutputs;
 use once_cell::sync::OnceCell;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::io;
@@ -143,6 +146,14 @@ fn variable_names_and_types(ctx: &CommandContext) -> Vec<(&str, &str)> {
     var_names
 }
 
+fn phase_names(outputs: &EvalOutputs) -> Vec<&str> {
+    outputs
+        .phases
+        .iter()
+        .map(|phase| phase.name.as_str())
+        .collect()
+}
+
 fn variable_names(ctx: &CommandContext) -> Vec<&str> {
     let mut var_names = ctx
         .variables_and_types()
@@ -235,6 +246,168 @@ fn printing() {
     assert_eq!(outputs.stderr.recv(), Ok("Another stderr line".to_owned()));
 }
 
+// Output that looks like the messages that the runtime process used to send on stdout shouldn't
+// confuse us.
+#[test]
+fn print_protocol_like_lines() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval!(e,
+        println!("EVCXR_EXECUTION_COMPLETE");
+        println!("EVCXR_PANIC_NOTIFICATION");
+        println!("Still running");
+    );
+    assert_eq!(
+        outputs.stdout.recv(),
+        Ok("EVCXR_EXECUTION_COMPLETE".to_owned())
+    );
+    assert_eq!(
+        outputs.stdout.recv(),
+        Ok("EVCXR_PANIC_NOTIFICATION".to_owned())
+    );
+    assert_eq!(outputs.stdout.recv(), Ok("Still running".to_owned()));
+    eval!(e, let x = 42;);
+    assert_eq!(eval_and_unwrap(&mut e, "x"), text_plain("42"));
+}
+
+#[test]
+fn flush_output_from_background_thread() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    // The thread waits for input, so that it prints once the cell has finished. Then it writes to
+    // stderr, which gets passed on straight away, so that we know it has printed.
+    eval!(e,
+        std::thread::spawn(|| {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).unwrap();
+            println!("Printed after the cell finished");
+            eprintln!("Done printing");
+        });
+    );
+    e.stdin_handle().write(b"go\n").unwrap();
+    while outputs.stderr.recv().unwrap() != "Done printing" {}
+    // Stdout is only passed on when we next hear from the subprocess.
+    assert!(outputs.stdout.try_recv().is_err());
+    e.flush_output().unwrap();
+    assert_eq!(
+        outputs.stdout.try_recv(),
+        Ok("Printed after the cell finished".to_owned())
+    );
+}
+
+#[test]
+fn write_to_stdin_of_user_code() {
+    let mut e = new_context();
+    e.stdin_handle().write(b"hello\n").unwrap();
+    assert_eq!(
+        eval!(e, {
+            let mut line = String::new();
+            std::io::stdin().read_line(&mut line).unwrap();
+            line
+        }),
+        text_plain("\"hello\\n\"")
+    );
+}
+
+#[test]
+fn close_stdin_of_user_code() {
+    // Closing stdin lasts until the subprocess restarts, so we don't use a shared context.
+    let (mut e, _) = new_command_context_and_outputs();
+    let stdin = e.stdin_handle();
+    stdin.write(b"hello\n").unwrap();
+    stdin.close().unwrap();
+    assert_eq!(
+        eval!(e, {
+            let mut input = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut input).unwrap();
+            input
+        }),
+        text_plain("\"hello\\n\"")
+    );
+}
+
+#[test]
+fn display_bundles_in_order() {
+    let mut e = new_context();
+    let displayed = RefCell::new(Vec::new());
+    let mut callbacks = EvalCallbacks {
+        display_handler: &|bundle| displayed.borrow_mut().push(bundle.clone()),
+        ..EvalCallbacks::default()
+    };
+    let outputs = e
+        .execute_with_callbacks(
+            r#"
+            pub struct Image(u32);
+            impl Image {
+                pub fn evcxr_display(&self) {
+                    println!("EVCXR_BEGIN_CONTENT image/png\n{}\nEVCXR_END_CONTENT", self.0);
+                }
+            }
+            for i in 1..=2 {
+                Image(i).evcxr_display();
+            }
+            Image(3)
+            "#,
+            &mut callbacks,
+        )
+        .unwrap();
+    let images: Vec<_> = outputs
+        .display_bundles
+        .iter()
+        .map(|bundle| bundle.get("image/png"))
+        .collect();
+    assert_eq!(images, vec![Some("1"), Some("2")]);
+    assert_eq!(*displayed.borrow(), outputs.display_bundles);
+    // The final expression is the result, not something that was displayed along the way.
+    assert_eq!(outputs.get("image/png"), Some("3"));
+}
+
+#[test]
+fn display_handle_updates() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        &format!(
+            ":dep evcxr_runtime = {{ path = {:?} }}",
+            std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+                .join("../evcxr_runtime")
+                .to_string_lossy()
+        ),
+    );
+    let outputs = e
+        .execute(
+            r#"
+            let progress = evcxr_runtime::display("text/plain", "0%");
+            progress.update("text/plain", "100%");
+            "#,
+        )
+        .unwrap();
+    let bundles = &outputs.display_bundles;
+    assert_eq!(bundles.len(), 2);
+    assert_eq!(bundles[0].get("text/plain"), Some("0%"));
+    assert!(!bundles[0].is_update);
+    assert_eq!(bundles[1].get("text/plain"), Some("100%"));
+    assert!(bundles[1].is_update);
+    assert!(bundles[0].display_id.is_some());
+    assert_eq!(bundles[0].display_id, bundles[1].display_id);
+}
+
+// Programs run by user code shouldn't get the control channel.
+#[test]
+#[cfg(target_os = "linux")]
+fn control_channel_not_inherited() {
+    let mut e = new_context();
+    assert_eq!(
+        eval!(e, {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg("[ -e /proc/self/fd/${EVCXR_CONTROL_CHANNEL#*:} ] && echo open || echo closed")
+                .output()
+                .unwrap();
+            String::from_utf8(output.stdout).unwrap()
+        }),
+        text_plain("\"closed\\n\"")
+    );
+}
+
 #[test]
 fn rc_refcell_etc() {
     let mut e = new_context();
@@ -361,6 +534,329 @@ fn moved_value() {
     assert_eq!(variable_names_and_types(&e), vec![]);
 }
 
+#[test]
+fn moves_determined_without_recompiling() {
+    let mut e = new_context();
+    eval!(e, let a = "foo".to_owned(); let b = "bar".to_owned(); let c = 1;);
+    let outputs = e.execute("let d = a; b.len() + c").unwrap();
+    assert_eq!(phase_names(&outputs), vec!["Final compile", "Execution"]);
+    assert_eq!(outputs.content_by_mime_type, text_plain("4"));
+    assert_eq!(variable_names(&e), vec!["b", "c", "d"]);
+}
+
+// Each cell should only need a single cargo invocation. Phases are only recorded for successful
+// compilation or for fixing things after compilation errors, so a cell that needed to be compiled
+// again would have more phases.
+#[test]
+fn normal_cells_compiled_once() {
+    let mut e = new_context();
+    e.execute(":preserve_vars_on_panic 1").unwrap();
+    e.execute(":promote_borrowed_vars 1").unwrap();
+    for code in [
+        "let v = [42; 5]; let n = 1; let s = String::from(\"foo\");",
+        "let total: i32 = v.iter().sum(); let t = s;",
+        "let items = vec![t]; for item in items.clone() { drop(item); }",
+        "let first = &items[0];",
+        "let x: u64 = \"5\".parse()?; x + n as u64 + first.len() as u64",
+    ] {
+        let outputs = e.execute(code).unwrap();
+        assert_eq!(
+            phase_names(&outputs),
+            vec!["Final compile", "Execution"],
+            "{}",
+            code
+        );
+    }
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![
+            ("first", "&'static String"),
+            ("items", "&'static Vec<String>"),
+            ("n", "i32"),
+            ("total", "i32"),
+            ("v", "[i32; 5]"),
+            ("x", "u64"),
+        ]
+    );
+}
+
+#[test]
+fn undo() {
+    let mut e = new_context();
+    eval!(e, let mut v = vec![1, 2, 3]; let x = 42;);
+    // Undo is off by default.
+    assert!(e.execute(":undo").is_err());
+    eval_and_unwrap(&mut e, ":undo_steps 2");
+    eval!(e, v.push(4); let x = "redefined"; struct Foo;);
+    assert!(defined_item_names(&e).contains(&"Foo"));
+    e.execute(":undo").unwrap();
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![("v", "Vec<i32>"), ("x", "i32")]
+    );
+    assert!(!defined_item_names(&e).contains(&"Foo"));
+    assert_eq!(eval!(e, (v.len(), x)), text_plain("(3, 42)"));
+    assert!(e.execute(":undo 100").is_err());
+    // Only the configured number of executions can be undone.
+    eval!(e, v.push(4););
+    eval!(e, v.push(5););
+    eval!(e, v.push(6););
+    assert!(e.execute(":undo 3").is_err());
+    e.execute(":undo 2").unwrap();
+    assert_eq!(eval!(e, v.len()), text_plain("4"));
+}
+
+#[test]
+fn forget_and_remove() {
+    let mut e = new_context();
+    eval!(e, struct Foo(i32); fn bar() -> i32 { 1 } let foo = Foo(2); let x = 3;);
+    assert!(e.execute(":remove_item Foo").is_err());
+    e.execute(":forget foo").unwrap();
+    assert_eq!(variable_names_and_types(&e), vec![("x", "i32")]);
+    e.execute(":remove_item Foo").unwrap();
+    e.execute(":remove_item bar").unwrap();
+    assert!(!defined_item_names(&e).contains(&"Foo"));
+    assert!(!defined_item_names(&e).contains(&"bar"));
+    assert!(e.execute(":forget foo").is_err());
+    assert!(e.execute(":undep regex").is_err());
+    assert_eq!(eval!(e, x), text_plain("3"));
+}
+
+#[test]
+fn remove_item_with_dependents() {
+    let mut e = new_context();
+    eval!(e, struct Foo(i32););
+    eval!(e, impl Foo { fn get(&self) -> i32 { self.0 } });
+    eval!(e, impl std::fmt::Display for Foo {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result { write!(f, "{}", self.0) }
+    });
+    eval!(
+        e,
+        fn make_foo() -> Foo {
+            Foo(1)
+        }
+    );
+    assert_eq!(eval!(e, make_foo().get()), text_plain("1"));
+    // Items that use `Foo` would fail to compile without it.
+    let error = e.execute(":remove_item Foo").unwrap_err().to_string();
+    assert!(error.contains("`make_foo`"), "{}", error);
+    e.execute(":remove_item make_foo").unwrap();
+    // Impls of `Foo` are removed with it, so later code still compiles.
+    e.execute(":remove_item Foo").unwrap();
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+    // `Foo` can then be defined again, with different impls.
+    eval!(e, struct Foo;);
+    eval!(e, impl Foo { fn get(&self) -> i32 { 2 } });
+    assert_eq!(eval!(e, Foo.get()), text_plain("2"));
+}
+
+#[test]
+fn undep_in_use() {
+    let mut e = new_context();
+    let krate = TmpCrate::new("undep_crate", "pub fn forty_two() -> i32 { 42 }").unwrap();
+    eval_and_unwrap(&mut e, &krate.dep_command(""));
+    eval_and_unwrap(&mut e, "fn answer() -> i32 { undep_crate::forty_two() }");
+    let error = e.execute(":undep undep_crate").unwrap_err().to_string();
+    assert!(error.contains("`answer`"), "{}", error);
+    e.execute(":remove_item answer").unwrap();
+    e.execute(":undep undep_crate").unwrap();
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
+
+#[test]
+fn inspect_variables() {
+    let mut e = new_context();
+    eval!(e, struct NoDebug; let mut v = vec![1u8, 2]; let x = 42i32; let n = NoDebug;);
+    let outputs = e.execute(":vars").unwrap().content_by_mime_type;
+    assert_eq!(
+        outputs["text/plain"],
+        "n: NoDebug (0 bytes)\n\
+         v: Vec<u8> (24 bytes, mut) = [\n    1,\n    2,\n]\n\
+         x: i32 (4 bytes, Copy) = 42\n"
+    );
+    assert!(outputs["text/html"].contains("<details open><summary>[</summary>"));
+    // Inspecting shouldn't disturb the variables.
+    assert_eq!(eval!(e, v.len() as i32 + x), text_plain("44"));
+}
+
+#[test]
+fn variables_version() {
+    let mut e = new_context();
+    eval!(e, let mut v = vec![1]; let x = 1;);
+    let version = e.variables_version();
+    // Nothing here uses or defines variables.
+    e.execute("println!(\"hello\");").unwrap();
+    e.execute(":vars").unwrap();
+    e.inspect_variables().unwrap();
+    eval!(
+        e,
+        fn double(n: i32) -> i32 {
+            n * 2
+        }
+    );
+    assert_eq!(e.variables_version(), version);
+
+    eval!(e, v.push(2););
+    let version_after_use = e.variables_version();
+    assert_ne!(version_after_use, version);
+    eval!(e, let y = double(x););
+    let version_after_define = e.variables_version();
+    assert_ne!(version_after_define, version_after_use);
+    e.execute(":forget y").unwrap();
+    assert_ne!(e.variables_version(), version_after_define);
+}
+
+#[test]
+fn save_and_load_session() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("session.json");
+    let mut e = new_context();
+    e.execute(":async_runtime async-std").unwrap();
+    eval!(e, struct Foo;);
+    eval!(
+        e,
+        fn double(x: i32) -> i32 {
+            x * 2
+        }
+    );
+    eval!(e, let mut names = vec!["a".to_owned(), "b".to_owned()]; let x = 21; let foo = Foo;);
+    // `Foo` doesn't implement Serialize, so `foo` can't be saved.
+    assert_eq!(
+        eval_and_unwrap(&mut e, &format!(":save_session {}", path.display())),
+        text_plain(&format!(
+            "Saved session to {}. Skipped variables that don't implement \
+             Serialize + DeserializeOwned: foo",
+            path.display()
+        ))
+    );
+    let mut e = new_context();
+    e.execute(&format!(":load_session {}", path.display()))
+        .unwrap();
+    assert!(defined_item_names(&e).contains(&"Foo"));
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![("names", "Vec<String>"), ("x", "i32")]
+    );
+    assert_eq!(eval!(e, double(x)), text_plain("42"));
+    eval!(e, names.push("c".to_owned()););
+    assert_eq!(eval!(e, names.join(",")), text_plain("\"a,b,c\""));
+    assert_eq!(
+        e.execute(":async_runtime").unwrap().content_by_mime_type,
+        text_plain("Async runtime: async-std\n")
+    );
+    assert!(e.execute(":load_session /does/not/exist.json").is_err());
+
+    // A value that no longer deserializes is reported as an error rather than a panic.
+    let contents = std::fs::read_to_string(&path).unwrap();
+    std::fs::write(&path, contents.replace("\"21\"", "\"\\\"oops\\\"\"")).unwrap();
+    let mut e = new_context();
+    let error = e
+        .execute(&format!(":load_session {}", path.display()))
+        .unwrap_err()
+        .to_string();
+    assert!(error.contains("Failed to restore variables"), "{}", error);
+    assert!(defined_item_names(&e).contains(&"Foo"));
+}
+
+#[test]
+fn shared_target_dir() {
+    let target_dir = tempfile::tempdir().unwrap();
+    let threads: Vec<_> = (0..2)
+        .map(|i| {
+            let target_dir = target_dir.path().to_owned();
+            std::thread::spawn(move || {
+                let (eval_context, outputs) =
+                    EvalContext::new_for_testing_with_shared_target_dir(&target_dir);
+                send_output(outputs.stderr, io::stderr());
+                let mut e = CommandContext::with_eval_context(eval_context);
+                eval_and_unwrap(&mut e, &format!("fn id() -> i32 {{ {} }}", i));
+                eval_and_unwrap(&mut e, "let x = id() * 10;");
+                eval_and_unwrap(&mut e, "x + id()")
+            })
+        })
+        .collect();
+    for (i, thread) in threads.into_iter().enumerate() {
+        assert_eq!(thread.join().unwrap(), text_plain(&(i * 11).to_string()));
+    }
+}
+
+#[test]
+fn promote_borrowed_variables() {
+    let mut e = new_context();
+    e.execute(":promote_borrowed_vars 1").unwrap();
+    eval!(e, let all = vec![10, 20, 30, 40, 50];);
+    eval!(e, let some = &all[2..4];);
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![("all", "&'static Vec<i32>"), ("some", "&'static [i32]")]
+    );
+    assert_eq!(
+        eval!(e, some.iter().sum::<i32>() + all.len() as i32),
+        text_plain("75")
+    );
+    let promoted: Vec<_> = e
+        .inspect_variables()
+        .unwrap()
+        .into_iter()
+        .map(|variable| (variable.name, variable.is_promoted))
+        .collect();
+    assert_eq!(
+        promoted,
+        vec![("all".to_owned(), true), ("some".to_owned(), false)]
+    );
+    assert!(
+        e.execute(":vars").unwrap().content_by_mime_type["text/plain"]
+            .starts_with("all: &'static Vec<i32> (8 bytes, Copy, promoted) = [")
+    );
+}
+
+#[test]
+fn async_runtime_selection() {
+    let mut e = new_context();
+    assert_eq!(
+        e.execute(":async_runtime").unwrap().content_by_mime_type,
+        text_plain("Async runtime: tokio\n")
+    );
+    assert_eq!(
+        e.execute(":async_runtime smol")
+            .unwrap()
+            .content_by_mime_type,
+        text_plain("Async runtime: smol\n")
+    );
+    assert!(e.execute(":async_runtime tokio-0.2").is_err());
+    assert_eq!(
+        e.execute(":async_runtime").unwrap().content_by_mime_type,
+        text_plain("Async runtime: smol\n")
+    );
+}
+
+#[test]
+fn async_runtimes_run_awaited_code() {
+    // Each of these only works when run by the selected runtime.
+    let runtimes_and_code = [
+        (
+            "tokio",
+            "tokio::time::sleep(std::time::Duration::from_millis(1)).await;\n\
+             tokio::task::spawn(async { 40 }).await.unwrap() + 2",
+        ),
+        (
+            "async-std",
+            "async_std::task::spawn(async { 40 }).await + 2",
+        ),
+        ("smol", "smol::unblock(|| 40).await + 2"),
+    ];
+    for (runtime, code) in runtimes_and_code {
+        let mut e = new_context();
+        eval_and_unwrap(&mut e, &format!(":async_runtime {}", runtime));
+        assert_eq!(
+            eval_and_unwrap(&mut e, code),
+            text_plain("42"),
+            "{}",
+            runtime
+        );
+    }
+}
+
 struct TmpCrate {
     name: String,
     tempdir: tempfile::TempDir,
@@ -401,6 +897,43 @@ impl TmpCrate {
     }
 }
 
+#[test]
+fn macros_from_external_crates() {
+    let (mut e, _) = new_command_context_and_outputs();
+    let krate = TmpCrate::new(
+        "macro_crate",
+        r#"
+        #[macro_export]
+        macro_rules! double {
+            ($e:expr) => { $e * 2 };
+        }
+        #[macro_export]
+        macro_rules! triple {
+            ($e:expr) => { $e * 3 };
+        }
+        "#,
+    )
+    .unwrap();
+    eval_and_unwrap(&mut e, &krate.dep_command(""));
+    eval_and_unwrap(&mut e, "use macro_crate::double;");
+    assert_eq!(eval_and_unwrap(&mut e, "double!(21)"), text_plain("42"));
+    eval_and_unwrap(&mut e, "#[macro_use] extern crate macro_crate;");
+    eval_and_unwrap(
+        &mut e,
+        "fn nine_times(x: u32) -> u32 { triple!(triple!(x)) }",
+    );
+    assert_eq!(eval_and_unwrap(&mut e, "nine_times(2)"), text_plain("18"));
+    // Macro invocations that define items should persist like other items.
+    eval_and_unwrap(
+        &mut e,
+        "thread_local! { static COUNTER: std::cell::Cell<u32> = std::cell::Cell::new(40); };",
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, "COUNTER.with(|c| c.get()) + triple!(1) - 1"),
+        text_plain("42")
+    );
+}
+
 #[test]
 fn crate_deps() {
     let (mut e, _) = new_command_context_and_outputs();
@@ -554,13 +1087,18 @@ fn redefine_type_with_existing_var() {
         struct Foo { x: i32, y: i32 }
         let f3 = Foo {x: 42, y: 43};
     );
-    // `f1` and `f2` should have been dropped because the type Foo was
-    // redefined.
-    assert_eq!(variable_names_and_types(&e), vec![("f3", "Foo")]);
+    // `f1` and `f2` should have been migrated to the new definition of Foo,
+    // with the new field getting its default value.
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![("f1", "Foo"), ("f2", "Foo"), ("f3", "Foo")]
+    );
     // Make sure that we actually evaluated the above by checking that f3 is
     // accessible.
     eval!(e,
         assert_eq!(f3.x, 42);
+        assert_eq!((f1.x, f1.y), (42, 0));
+        assert_eq!((f2.x, f2.y), (42, 0));
     );
 }
 
@@ -601,6 +1139,40 @@ fn abort_and_restart() {
     assert_eq!(e.defined_item_names().next(), None);
 }
 
+#[cfg(target_os = "linux")]
+#[test]
+fn crash_resumes_from_checkpoint() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":checkpoint 1");
+    eval!(
+        e,
+        pub fn foo() -> i32 {
+            42
+        }
+    );
+    eval!(e, let mut a = vec![1, 2, 3]; let b = 10;);
+    let result = e.execute(stringify!(a.push(4); std::process::abort();));
+    if let Err(Error::Message(message)) = result {
+        assert!(message.starts_with("Subprocess terminated with signal 6"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    let result = e.execute(stringify!(let c = a; std::process::exit(1);));
+    if let Err(Error::Message(message)) = result {
+        assert!(message.starts_with("Subprocess exited with status 1"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    assert_eq!(
+        variable_names_and_types(&e),
+        vec![("a", "Vec<i32>"), ("b", "i32")]
+    );
+    assert_eq!(
+        eval!(e, format!("{:?} {} {}", a, b, foo())),
+        text_plain("\"[1, 2, 3] 10 42\"")
+    );
+}
+
 #[test]
 fn variable_assignment_compile_fail_then_use_statement() {
     let mut e = new_context();
@@ -1017,3 +1589,581 @@ let s2 = "さび  äää"; let s2: String = 42; fn foo() -> i32 {
     // Dropped variables shouldn't report errors.
     assert_no_errors(&mut ctx, "let s1 = String::new(); std::mem::drop(s1);");
 }
+
+#[test]
+fn warnings_reported_on_success() {
+    let mut e = new_context();
+    let outputs = e
+        .execute("fn f() -> Result<(), ()> { Ok(()) }\nf();")
+        .unwrap();
+    let warning = outputs
+        .warnings
+        .iter()
+        .find(|warning| warning.message().contains("unused `Result`"))
+        .expect("Missing warning for unused Result");
+    assert_eq!(warning.level(), "warning");
+    assert_eq!(
+        warning
+            .primary_spanned_message()
+            .unwrap()
+            .span
+            .as_ref()
+            .unwrap()
+            .start_line,
+        2
+    );
+    // Code from previous evaluations shouldn't produce warnings again.
+    assert!(e.execute("let x = 1;").unwrap().warnings.is_empty());
+
+    eval_and_unwrap(&mut e, ":warnings 0");
+    assert!(e.execute("f();").unwrap().warnings.is_empty());
+    eval_and_unwrap(&mut e, ":warnings 1");
+    // Without an argument, the setting is toggled.
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":warnings"),
+        text_plain("Show warnings: false")
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":warnings"),
+        text_plain("Show warnings: true")
+    );
+    assert!(e.execute(":warnings yes").is_err());
+}
+
+#[test]
+fn item_warnings_only_reported_once() {
+    let mut e = new_context();
+    let unused_variable_warnings = |outputs: &evcxr::EvalOutputs| {
+        outputs
+            .warnings
+            .iter()
+            .filter(|warning| warning.message().contains("unused variable"))
+            .count()
+    };
+    let outputs = e.execute("fn foo() { let x = 1; }").unwrap();
+    assert_eq!(unused_variable_warnings(&outputs), 1);
+    // Neither code that doesn't change the items, nor code that does, should report the warning
+    // for `foo` again.
+    let outputs = e.execute("let y = 2;").unwrap();
+    assert_eq!(unused_variable_warnings(&outputs), 0);
+    let outputs = e.execute("fn bar() {}").unwrap();
+    assert_eq!(unused_variable_warnings(&outputs), 0);
+    let outputs = e.execute("fn baz() { let z = 3; }").unwrap();
+    assert_eq!(unused_variable_warnings(&outputs), 1);
+}
+
+#[test]
+fn items_compiled_into_separate_crate() {
+    let mut e = new_context();
+    // Items don't need to be public for later code to use them, even though they're compiled into
+    // a separate crate.
+    eval_and_unwrap(
+        &mut e,
+        r#"
+struct Counter {
+    count: u32,
+}
+impl Counter {
+    fn increment(&mut self) {
+        self.count += 1;
+    }
+}
+let mut c = Counter { count: 0 };
+c.increment();
+"#,
+    );
+    let compile_dir = eval_and_unwrap(&mut e, ":last_compile_dir")["text/plain"].clone();
+    let items_dir = std::path::Path::new(compile_dir.trim_matches('"')).join("evcxr_items");
+    let items_sources = || {
+        let mut sources: Vec<_> = std::fs::read_dir(&items_dir)
+            .unwrap()
+            .map(|entry| {
+                let source = entry.unwrap().path().join("src").join("lib.rs");
+                let modified = std::fs::metadata(&source).unwrap().modified().unwrap();
+                (source, modified)
+            })
+            .collect();
+        sources.sort();
+        sources
+    };
+    let sources = items_sources();
+    eval_and_unwrap(&mut e, "c.increment();");
+    assert_eq!(eval!(e, c.count), text_plain("2"));
+    // Evaluating code that doesn't define any items shouldn't touch the items crates.
+    assert_eq!(items_sources(), sources);
+}
+
+#[test]
+fn statics_persist_until_item_redefined() {
+    let mut e = new_context();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+use std::sync::atomic::{AtomicUsize, Ordering};
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+thread_local! { static LOCAL: std::cell::Cell<u32> = std::cell::Cell::new(0); }
+struct Marker;
+"#,
+    );
+    let type_id = eval!(e, format!("{:?}", std::any::TypeId::of::<Marker>()));
+    eval_and_unwrap(
+        &mut e,
+        "COUNTER.fetch_add(1, Ordering::SeqCst); LOCAL.with(|l| l.set(l.get() + 1));",
+    );
+    assert_eq!(eval!(e, COUNTER.load(Ordering::SeqCst)), text_plain("1"));
+    assert_eq!(eval!(e, LOCAL.with(|l| l.get())), text_plain("1"));
+    assert_eq!(
+        eval!(e, format!("{:?}", std::any::TypeId::of::<Marker>())),
+        type_id
+    );
+    // Defining other items doesn't affect existing ones.
+    eval_and_unwrap(&mut e, "fn unrelated() -> Marker { Marker }");
+    assert_eq!(eval!(e, COUNTER.load(Ordering::SeqCst)), text_plain("1"));
+    assert_eq!(eval!(e, LOCAL.with(|l| l.get())), text_plain("1"));
+    assert_eq!(
+        eval!(e, format!("{:?}", std::any::TypeId::of::<Marker>())),
+        type_id
+    );
+    // Redefining an item only starts that item again.
+    eval_and_unwrap(
+        &mut e,
+        "static COUNTER: AtomicUsize = AtomicUsize::new(10);",
+    );
+    assert_eq!(eval!(e, COUNTER.load(Ordering::SeqCst)), text_plain("10"));
+    assert_eq!(eval!(e, LOCAL.with(|l| l.get())), text_plain("1"));
+}
+
+#[test]
+fn variables_kept_when_other_items_defined() {
+    let mut e = new_context();
+    eval!(e,
+        struct Counter { count: u32 }
+        impl Counter { fn increment(&mut self) { self.count += 1; } }
+        let mut counter = Counter { count: 1 };
+        let type_id = std::any::TypeId::of::<Counter>();
+    );
+    eval!(e, fn unrelated() {});
+    eval!(e, counter.increment());
+    assert_eq!(eval!(e, counter.count), text_plain("2"));
+    assert_eq!(
+        eval!(e, type_id == std::any::TypeId::of::<Counter>()),
+        text_plain("true")
+    );
+}
+
+#[test]
+fn redefined_struct_variables_migrated() {
+    let mut e = new_context();
+    eval!(e,
+        struct Point { x: i32, y: i32 }
+        let p = Point { x: 1, y: 2 };
+        let points = vec![Point { x: 3, y: 4 }];
+    );
+    // Defining an unrelated item keeps variables of types that didn't change.
+    eval!(e, fn unrelated() {});
+    assert_eq!(eval!(e, p.x + points[0].y), text_plain("5"));
+    // Fields are converted and new fields get their default value. Variables whose type only
+    // contains the redefined struct are lost.
+    eval!(
+        e,
+        struct Point {
+            x: i64,
+            y: i32,
+            label: String,
+        }
+    );
+    let outputs = e
+        .execute(stringify!(format!("{} {} {:?}", p.x, p.y, p.label)))
+        .unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("1 2 \"\""));
+    assert_eq!(
+        outputs.notes,
+        vec![
+            "Variable `p` was converted to the new definition of `Point`, with default values for: \
+             label"
+        ]
+    );
+    assert_eq!(variable_names_and_types(&e), vec![("p", "Point")]);
+    assert!(e.execute("p.x").unwrap().notes.is_empty());
+}
+
+#[test]
+fn struct_field_changing_type_drops_variable() {
+    let mut e = new_context();
+    eval!(e,
+        struct Foo { a: u32 }
+        let foo = Foo { a: 1 };
+        let other = 5;
+    );
+    // `f32` has the same size as `u32`, but there's no `Into` conversion between them, so the
+    // variable is dropped rather than having its bytes reinterpreted.
+    eval!(
+        e,
+        struct Foo {
+            a: f32,
+        }
+    );
+    let outputs = e.execute("other").unwrap();
+    assert_eq!(outputs.content_by_mime_type, text_plain("5"));
+    assert!(outputs.notes.is_empty());
+    assert_eq!(variable_names_and_types(&e), vec![("other", "i32")]);
+}
+
+#[test]
+fn macro_defined_struct_variables_not_migrated() {
+    let mut e = new_context();
+    eval!(e,
+        macro_rules! make_struct {
+            () => { struct Foo { x: i32 } }
+        }
+        make_struct!();
+        let f = Foo { x: 42 };
+    );
+    eval!(e, fn unrelated() {});
+    assert_eq!(eval!(e, f.x), text_plain("42"));
+    // We can't tell what a macro defines, so once the struct gets rebuilt, we don't know whether
+    // the previous value still fits it.
+    eval!(
+        e,
+        macro_rules! make_struct {
+            () => {
+                struct Foo {
+                    x: String,
+                    y: String,
+                }
+            };
+        }
+    );
+    assert_eq!(variable_names_and_types(&e), vec![]);
+    eval!(e,
+        let f = Foo { x: "a".to_owned(), y: "b".to_owned() };
+    );
+    assert_eq!(eval!(e, f.x + &f.y), text_plain("\"ab\""));
+}
+
+#[test]
+fn export_crate() {
+    let mut e = new_context();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        pub fn add(a: i32, b: i32) -> i32 {
+            a + b
+        }
+        let mut total = add(40, 1);"#,
+    );
+    eval_and_unwrap(&mut e, "total += 1;");
+    // Cells that fail to compile shouldn't end up in the exported crate.
+    assert!(e.execute("total += not_defined;").is_err());
+    assert_eq!(eval_and_unwrap(&mut e, "total"), text_plain("42"));
+    let tempdir = tempfile::tempdir().unwrap();
+    let crate_dir = tempdir.path().join("exported");
+    eval_and_unwrap(
+        &mut e,
+        &format!(":export_crate {}", crate_dir.to_string_lossy()),
+    );
+    let cargo_toml = std::fs::read_to_string(crate_dir.join("Cargo.toml")).unwrap();
+    assert!(cargo_toml.contains("name = \"exported\""));
+    let main_rs = std::fs::read_to_string(crate_dir.join("src").join("main.rs")).unwrap();
+    assert!(main_rs.contains("pub fn add(a: i32, b: i32)"));
+    assert!(main_rs.contains("fn main() {"));
+    assert!(!main_rs.contains("not_defined"));
+    let first = main_rs.find("let mut total = add(40, 1);").unwrap();
+    let second = main_rs.find("total += 1;").unwrap();
+    let third = main_rs.find("println!(\"{:?}\", &(total));").unwrap();
+    assert!(first < second && second < third);
+
+    // Exporting over the top of an existing crate should fail.
+    assert!(e
+        .execute(&format!(":export_crate {}", crate_dir.to_string_lossy()))
+        .is_err());
+}
+
+#[test]
+fn export_test() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        :preserve_vars_on_panic 1
+        let mut values = vec![1, 2];"#,
+    );
+    assert_eq!(
+        eval_and_unwrap(&mut e, "values.push(3); values"),
+        text_plain("[1, 2, 3]")
+    );
+    // Cells that panic shouldn't end up in the exported test.
+    eval_and_unwrap(&mut e, r#"panic!("Intentional panic");"#);
+    let tempdir = tempfile::tempdir().unwrap();
+    let test_file = tempdir.path().join("tests").join("session.rs");
+    eval_and_unwrap(
+        &mut e,
+        &format!(":export_test {}", test_file.to_string_lossy()),
+    );
+    let test_rs = std::fs::read_to_string(&test_file).unwrap();
+    assert!(test_rs.contains("#[test]\nfn session() {"));
+    assert!(test_rs.contains("let mut values = vec![1, 2];"));
+    assert!(test_rs.contains(r#"assert_eq!(format!("{:?}", &(values)), "[1, 2, 3]");"#));
+    assert!(!test_rs.contains("Intentional panic"));
+
+    assert!(e
+        .execute(&format!(":export_test {}", test_file.to_string_lossy()))
+        .is_err());
+}
+
+/// Executes `code`, which is expected to run until interrupted, interrupting it repeatedly until
+/// execution finishes, since we don't know exactly when the code will start running.
+#[cfg(all(unix, not(target_os = "freebsd")))]
+fn execute_and_interrupt(e: &mut CommandContext, code: &str) -> Result<evcxr::EvalOutputs, Error> {
+    use std::sync::atomic::AtomicBool;
+    use std::sync::atomic::Ordering;
+    use std::sync::Arc;
+
+    let done = Arc::new(AtomicBool::new(false));
+    let interrupter = std::thread::spawn({
+        let interrupt_handle = e.interrupt_handle();
+        let done = Arc::clone(&done);
+        move || {
+            while !done.load(Ordering::SeqCst) {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+                interrupt_handle.interrupt().unwrap();
+            }
+        }
+    });
+    let result = e.execute(code);
+    done.store(true, Ordering::SeqCst);
+    interrupter.join().unwrap();
+    result
+}
+
+/// Even without `:checkpoint 1`, a checkpoint is taken so that interrupting doesn't lose variables.
+#[cfg(target_os = "linux")]
+#[test]
+fn interrupt_execution() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, "let a = 42;");
+    let result = execute_and_interrupt(
+        &mut e,
+        "loop { std::thread::sleep(std::time::Duration::from_millis(10)); }",
+    );
+    if let Err(Error::Message(message)) = result {
+        assert!(message.starts_with("Execution interrupted. Resumed from a checkpoint"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    assert_eq!(variable_names_and_types(&e), vec![("a", "i32")]);
+    assert_eq!(eval!(e, a), text_plain("42"));
+}
+
+/// Once threads have been started, or on platforms other than Linux, no checkpoint can be taken, so
+/// the subprocess is restarted and variables are lost.
+#[cfg(all(unix, not(target_os = "freebsd")))]
+#[test]
+fn interrupt_execution_without_checkpoint() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        "std::thread::spawn(|| loop { std::thread::sleep(std::time::Duration::from_secs(1)); });",
+    );
+    eval_and_unwrap(&mut e, "let a = 42;");
+    let result = execute_and_interrupt(
+        &mut e,
+        "loop { std::thread::sleep(std::time::Duration::from_millis(10)); }",
+    );
+    if let Err(Error::SubprocessTerminated(message)) = result {
+        assert!(message.ends_with("Execution interrupted"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    assert_eq!(variable_names_and_types(&e), vec![]);
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
+
+/// With checkpointing, we resume from the checkpoint taken before the code ran, so even variables
+/// that the interrupted code used are as they were before it ran.
+#[cfg(target_os = "linux")]
+#[test]
+fn interrupt_execution_with_checkpoint() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(
+        &mut e,
+        r#"
+        :checkpoint 1
+        let mut a = vec![1, 2, 3];
+        let b = 42;"#,
+    );
+    let result = execute_and_interrupt(
+        &mut e,
+        "a.push(4); loop { std::thread::sleep(std::time::Duration::from_millis(10)); }",
+    );
+    if let Err(Error::Message(message)) = result {
+        assert!(message.starts_with("Execution interrupted. Resumed from a checkpoint"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    assert_eq!(
+        eval_and_unwrap(&mut e, "format!(\"{:?}, {}\", a, b)"),
+        text_plain("\"[1, 2, 3], 42\"")
+    );
+}
+
+#[test]
+fn execution_timeout() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":timeout 1");
+    let result = e.execute("std::thread::sleep(std::time::Duration::from_secs(60));");
+    if let Err(Error::SubprocessTerminated(message)) = result {
+        assert_eq!(message, "Subprocess killed after 1s timeout");
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    // The subprocess should have been restarted, so we can keep going.
+    assert_eq!(eval!(e, 40 + 2), text_plain("42"));
+}
+
+#[cfg(unix)]
+#[test]
+fn memory_limit() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":memory_limit 2048");
+    let result = e.execute("let v: Vec<u8> = Vec::with_capacity(4 * 1024 * 1024 * 1024);");
+    if let Err(Error::SubprocessTerminated(message)) = result {
+        assert!(message.ends_with("Subprocess exceeded 2GB memory limit"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn cpu_limit() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":cpu_limit 2");
+    // The limit is for each execution, so using most of it several times is fine.
+    let busy = "let start = std::time::Instant::now(); \
+                while start.elapsed() < std::time::Duration::from_millis(1500) {}";
+    for _ in 0..3 {
+        eval_and_unwrap(&mut e, busy);
+    }
+    let result = e.execute("loop {}");
+    if let Err(Error::SubprocessTerminated(message)) = result {
+        assert!(message.ends_with("Subprocess exceeded 2s CPU time limit"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+}
+
+/// When code that exceeds a resource limit was checkpointed, we resume from the checkpoint, but
+/// still report which limit was exceeded.
+#[cfg(target_os = "linux")]
+#[test]
+fn limits_with_checkpoint() {
+    let (mut e, _) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":checkpoint 1");
+    eval_and_unwrap(&mut e, ":memory_limit 2048");
+    eval_and_unwrap(&mut e, ":cpu_limit 2");
+    eval_and_unwrap(&mut e, "let a = 42;");
+    let result = e.execute("let v: Vec<u8> = Vec::with_capacity(4 * 1024 * 1024 * 1024);");
+    if let Err(Error::Message(message)) = result {
+        assert!(
+            message.starts_with("Subprocess exceeded 2GB memory limit. Resumed from a checkpoint")
+        );
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    let result = e.execute("loop {}");
+    if let Err(Error::Message(message)) = result {
+        assert!(
+            message.starts_with("Subprocess exceeded 2s CPU time limit. Resumed from a checkpoint")
+        );
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    // Other crashes aren't mistaken for exceeding a limit.
+    let result = e.execute("std::process::abort();");
+    if let Err(Error::Message(message)) = result {
+        assert!(message.starts_with("Subprocess terminated with signal 6"));
+    } else {
+        panic!("Unexpected result: {:?}", result);
+    }
+    assert_eq!(eval!(e, a), text_plain("42"));
+}
+
+/// Forked processes only have the thread that forked, so once user code has started threads, we
+/// can't checkpoint. Each time that happens, we say so, and variables are instead preserved on
+/// panic.
+#[cfg(target_os = "linux")]
+#[test]
+fn checkpoint_unavailable_with_threads() {
+    let (mut e, outputs) = new_command_context_and_outputs();
+    eval_and_unwrap(&mut e, ":checkpoint 1");
+    eval_and_unwrap(
+        &mut e,
+        "std::thread::spawn(|| loop { std::thread::sleep(std::time::Duration::from_secs(1)); });",
+    );
+    eval_and_unwrap(&mut e, "let a = vec![1, 2, 3];");
+    assert_eq!(
+        eval_and_unwrap(&mut e, ":checkpoint"),
+        text_plain("Checkpoint: true (unavailable, so only preserving variables on panic)")
+    );
+    // The warning is written to stderr, which we might not have received all of yet.
+    let warning = loop {
+        let line = outputs
+            .stderr
+            .recv_timeout(std::time::Duration::from_secs(10))
+            .unwrap();
+        if line.contains("No checkpoint was taken") {
+            break line;
+        }
+    };
+    assert!(
+        warning.contains("since your code has started threads"),
+        "{}",
+        warning
+    );
+    eval!(e, panic!("Intentional panic"););
+    assert_eq!(eval!(e, a.len()), text_plain("3"));
+}
+
+#[test]
+fn runtime_server() {
+    use std::io::BufRead;
+    let server_path = std::env::current_exe()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .parent()
+        .unwrap()
+        .join("evcxr_runtime_server");
+    let mut server = std::process::Command::new(server_path)
+        .arg("127.0.0.1:0")
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut listening = String::new();
+    io::BufReader::new(server.stdout.take().unwrap())
+        .read_line(&mut listening)
+        .unwrap();
+    let address = listening.trim().strip_prefix("Listening on ").unwrap();
+    let (eval_context, outputs) = EvalContext::with_runtime_server(address).unwrap();
+    send_output(outputs.stderr, io::stderr());
+    let mut e = CommandContext::with_eval_context(eval_context);
+    eval_and_unwrap(&mut e, ":offline 1");
+    eval!(
+        e,
+        fn double(x: i32) -> i32 {
+            x * 2
+        }
+    );
+    eval!(e, let x = double(20););
+    assert_eq!(eval!(e, x + 2), text_plain("42"));
+    let inspections = e.inspect_variables().unwrap();
+    assert_eq!(inspections.len(), 1);
+    assert_eq!(inspections[0].preview.as_deref(), Some("40"));
+    // Make sure that the runtime process gets restarted after it terminates.
+    assert!(e.execute("std::process::exit(1);").is_err());
+    assert_eq!(eval!(e, double(4)), text_plain("8"));
+    drop(e);
+    let _ = server.kill();
+    let _ = server.wait();
+}
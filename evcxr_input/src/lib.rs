@@ -9,6 +9,10 @@ pub fn get_password(prompt: &str) -> String {
 }
 
 fn get_input(prompt: &str, is_password: bool) -> Option<String> {
+    if let Some(input) = request_input(prompt, is_password) {
+        return input;
+    }
+    // We're running under an older version of Evcxr, which doesn't give us a control channel.
     if is_password {
         println!("{}:{}", GET_CMD_PASSWORD, prompt);
     } else {
@@ -19,6 +23,25 @@ fn get_input(prompt: &str, is_password: bool) -> Option<String> {
     Some(line.trim().to_owned())
 }
 
+/// Requests input via the channel over which Evcxr exchanges control messages with the process
+/// running user code. Returns None if there's no control channel, otherwise the user's input, or
+/// None if it couldn't be obtained.
+fn request_input(prompt: &str, is_password: bool) -> Option<Option<String>> {
+    use evcxr_runtime::control;
+    let is_password = if is_password { "1" } else { "0" };
+    let response = control::request(&[
+        control::INPUT_REQUEST.as_bytes(),
+        prompt.as_bytes(),
+        is_password.as_bytes(),
+    ])?;
+    Some(match response.as_deref() {
+        Ok([kind, input]) if kind == control::INPUT_RESPONSE.as_bytes() => {
+            Some(String::from_utf8_lossy(input).into_owned())
+        }
+        _ => None,
+    })
+}
+
 // The following constants are here so that they can be shared between this crate and Evcxr. They're
 // not really intended to be used.
 
@@ -20,12 +20,12 @@ use anyhow::Result;
 use colored::*;
 use crossbeam_channel::Select;
 use evcxr::CommandContext;
+use evcxr::InterruptHandle;
 use json::JsonValue;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::thread;
-use std::time;
 use std::time::Duration;
 
 // Note, to avoid potential deadlocks, each thread should lock at most one mutex at a time.
@@ -36,6 +36,12 @@ pub(crate) struct Server {
     latest_execution_request: Arc<Mutex<Option<JupyterMessage>>>,
     shutdown_requested_receiver: Arc<Mutex<crossbeam_channel::Receiver<()>>>,
     shutdown_requested_sender: Arc<Mutex<crossbeam_channel::Sender<()>>>,
+    /// Open `evcxr-variables` comms. These get sent the variables after executions that may have
+    /// changed them.
+    variables_comms: Arc<Mutex<VariablesComms>>,
+    /// Asks the output pass-through thread to send all output that it has received, then to reply
+    /// on the supplied sender.
+    flush_output_sender: crossbeam_channel::Sender<crossbeam_channel::Sender<()>>,
 }
 
 impl Server {
@@ -68,12 +74,15 @@ impl Server {
         let (shutdown_requested_sender, shutdown_requested_receiver) =
             crossbeam_channel::unbounded();
 
+        let (flush_output_sender, flush_output_receiver) = crossbeam_channel::unbounded();
         let server = Server {
             iopub,
             latest_execution_request: Arc::new(Mutex::new(None)),
             stdin: Arc::new(Mutex::new(stdin_socket)),
             shutdown_requested_receiver: Arc::new(Mutex::new(shutdown_requested_receiver)),
             shutdown_requested_sender: Arc::new(Mutex::new(shutdown_requested_sender)),
+            variables_comms: Arc::new(Mutex::new(VariablesComms::default())),
+            flush_output_sender,
         };
 
         let (execution_sender, execution_receiver) = crossbeam_channel::unbounded();
@@ -81,9 +90,12 @@ impl Server {
             crossbeam_channel::unbounded();
 
         thread::spawn(move || Self::handle_hb(&heartbeat));
-        server.start_thread(move |server: Server| server.handle_control(control_socket));
         let (mut context, outputs) = CommandContext::new()?;
         context.execute(":load_config")?;
+        let interrupt_handle = context.interrupt_handle();
+        server.start_thread(move |server: Server| {
+            server.handle_control(control_socket, &interrupt_handle)
+        });
         let context = Arc::new(Mutex::new(context));
         server.start_thread({
             let context = Arc::clone(&context);
@@ -103,10 +115,10 @@ impl Server {
                 &execution_response_sender,
             )
         });
-        server.clone().start_output_pass_through_thread(vec![
-            ("stdout", outputs.stdout),
-            ("stderr", outputs.stderr),
-        ]);
+        server.clone().start_output_pass_through_thread(
+            vec![("stdout", outputs.stdout), ("stderr", outputs.stderr)],
+            flush_output_receiver,
+        );
         Ok(server)
     }
 
@@ -157,6 +169,14 @@ impl Server {
         loop {
             let message = receiver.recv()?;
 
+            // Output from threads that were still running after earlier code finished should be
+            // shown under the request that ran that code, so make sure it's been sent before we
+            // switch to the new request. Once we've switched, output from those threads can't be
+            // told apart from output of the new request.
+            if let Err(error) = context.lock().unwrap().flush_output() {
+                eprintln!("Failed to flush output: {}", error);
+            }
+            self.flush_output();
             // If we want this clone to be cheaper, we probably only need the header, not the
             // whole message.
             *self.latest_execution_request.lock().unwrap() = Some(message.clone());
@@ -174,38 +194,50 @@ impl Server {
                     self.request_input(&message, prompt, is_password)
                         .unwrap_or_default()
                 },
+                display_handler: &|bundle| {
+                    // Anything printed before the content should be shown before it.
+                    self.flush_output();
+                    let mut content = object! {
+                        "data" => mime_bundle_json(&bundle.content_by_mime_type),
+                        "metadata" => object!(),
+                    };
+                    if let Some(display_id) = &bundle.display_id {
+                        content["transient"] = object! {
+                            "display_id" => display_id.as_str(),
+                        };
+                    }
+                    let message_type = if bundle.is_update {
+                        "update_display_data"
+                    } else {
+                        "display_data"
+                    };
+                    let result = message
+                        .new_message(message_type)
+                        .with_content(content)
+                        .send(&self.iopub.lock().unwrap());
+                    if let Err(error) = result {
+                        eprintln!("Failed to send display data: {:?}", error);
+                    }
+                },
             };
 
-            #[allow(unknown_lints, clippy::significant_drop_in_scrutinee)]
-            match context
+            let result = context
                 .lock()
                 .unwrap()
-                .execute_with_callbacks(src, &mut callbacks)
-            {
+                .execute_with_callbacks(src, &mut callbacks);
+            // By the time execution finishes, everything that the code printed has been received,
+            // but not necessarily sent.
+            self.flush_output();
+            match result {
                 Ok(output) => {
+                    self.emit_warnings(&output.warnings, &message)?;
+                    self.emit_notes(&output.notes, &message)?;
                     if !output.is_empty() {
-                        // Increase the odds that stdout will have been finished being sent. A
-                        // less hacky alternative would be to add a print statement, then block
-                        // waiting for it.
-                        thread::sleep(time::Duration::from_millis(1));
-                        let mut data = HashMap::new();
-                        // At the time of writing the json crate appears to have a generic From
-                        // implementation for a Vec<T> where T implements Into<JsonValue>. It also
-                        // has conversion from HashMap<String, JsonValue>, but it doesn't have
-                        // conversion from HashMap<String, T>. Perhaps send a PR? For now, we
-                        // convert the values manually.
-                        for (k, v) in output.content_by_mime_type {
-                            if k.contains("json") {
-                                data.insert(k, json::parse(&v).unwrap_or_else(|_| json::from(v)));
-                            } else {
-                                data.insert(k, json::from(v));
-                            }
-                        }
                         message
                             .new_message("execute_result")
                             .with_content(object! {
                                 "execution_count" => execution_count,
-                                "data" => data,
+                                "data" => mime_bundle_json(&output.content_by_mime_type),
                                 "metadata" => object!(),
                             })
                             .send(&self.iopub.lock().unwrap())?;
@@ -243,6 +275,43 @@ impl Server {
                     }))?;
                 }
             };
+            self.send_variables_to_comms(&message, &context);
+        }
+    }
+
+    /// Sends the current variables to each open `evcxr-variables` comm that hasn't already been
+    /// sent them. Inspecting variables requires compiling code, so this does nothing if there
+    /// aren't any such comms. Failures are only logged, since they shouldn't stop us handling
+    /// further execution requests.
+    fn send_variables_to_comms(
+        &self,
+        parent_message: &JupyterMessage,
+        context: &Mutex<CommandContext>,
+    ) {
+        if !self.variables_comms.lock().unwrap().any_open() {
+            return;
+        }
+        let version = context.lock().unwrap().variables_version();
+        let comm_ids = self
+            .variables_comms
+            .lock()
+            .unwrap()
+            .needing_refresh(version);
+        if comm_ids.is_empty() {
+            return;
+        }
+        let data = variables_json(&mut context.lock().unwrap());
+        for comm_id in comm_ids {
+            let result = parent_message
+                .new_message("comm_msg")
+                .with_content(object! {
+                    "comm_id" => comm_id.as_str(),
+                    "data" => data.clone(),
+                })
+                .send(&self.iopub.lock().unwrap());
+            if let Err(error) = result {
+                eprintln!("Failed to send variables to comm {}: {:?}", comm_id, error);
+            }
         }
     }
 
@@ -322,7 +391,31 @@ impl Server {
             execution_channel.send(message)?;
             execution_reply_receiver.recv()?.send(connection)?;
         } else if message.message_type() == "comm_open" {
-            comm_open(message, context, Arc::clone(&self.iopub))?;
+            comm_open(
+                message,
+                context,
+                Arc::clone(&self.iopub),
+                &self.variables_comms,
+            )?;
+        } else if message.message_type() == "comm_close" {
+            self.variables_comms
+                .lock()
+                .unwrap()
+                .close(message.comm_id());
+        } else if message.message_type() == "comm_msg"
+            && self
+                .variables_comms
+                .lock()
+                .unwrap()
+                .is_open(message.comm_id())
+        {
+            // Any message from the front end on a variables comm is a request for the variables.
+            send_variables(
+                message,
+                Arc::clone(context),
+                Arc::clone(&self.iopub),
+                Arc::clone(&self.variables_comms),
+            );
         } else if message.message_type() == "comm_msg"
             || message.message_type() == "comm_info_request"
         {
@@ -349,16 +442,20 @@ impl Server {
         Ok(())
     }
 
-    fn handle_control(self, connection: Connection) -> Result<()> {
+    fn handle_control(
+        self,
+        connection: Connection,
+        interrupt_handle: &InterruptHandle,
+    ) -> Result<()> {
         loop {
             let message = JupyterMessage::read(&connection)?;
             match message.message_type() {
                 "shutdown_request" => self.signal_shutdown(),
                 "interrupt_request" => {
+                    if let Err(error) = interrupt_handle.interrupt() {
+                        eprintln!("{}", error);
+                    }
                     message.new_reply().send(&connection)?;
-                    eprintln!(
-                        "Rust doesn't support interrupting execution. Perhaps restart kernel?"
-                    );
                 }
                 _ => {
                     eprintln!(
@@ -373,14 +470,27 @@ impl Server {
     fn start_output_pass_through_thread(
         self,
         channels: Vec<(&'static str, crossbeam_channel::Receiver<String>)>,
+        flush_requests: crossbeam_channel::Receiver<crossbeam_channel::Sender<()>>,
     ) {
         thread::spawn(move || {
             let mut select = Select::new();
             for (_, channel) in &channels {
                 select.recv(channel);
             }
+            let flush_index = select.recv(&flush_requests);
             loop {
                 let index = select.ready();
+                if index == flush_index {
+                    if let Ok(reply_sender) = flush_requests.try_recv() {
+                        for (output_name, channel) in &channels {
+                            while let Ok(line) = channel.try_recv() {
+                                self.pass_output_line(output_name, line);
+                            }
+                        }
+                        let _ = reply_sender.send(());
+                    }
+                    continue;
+                }
                 let (output_name, channel) = &channels[index];
                 // Read from the channel that has output until it has been idle
                 // for 1ms before we return to checking other channels. This
@@ -396,6 +506,21 @@ impl Server {
         });
     }
 
+    /// Waits until all output that's been received has been sent. Lines are sent by a separate
+    /// thread, so without this, they could end up after things we send afterwards, or attributed
+    /// to a later execution request.
+    fn flush_output(&self) {
+        let (reply_sender, reply_receiver) = crossbeam_channel::bounded(1);
+        if self.flush_output_sender.send(reply_sender).is_ok() {
+            let _ = reply_receiver.recv();
+        }
+    }
+
+    /// Sends a line of output as part of the latest execution request, which is where all output
+    /// goes, regardless of which request started the code that wrote it. Output isn't tagged with
+    /// the execution that produced it, since threads started by earlier executions write to the
+    /// same stdout and stderr as the current one, so the runtime can't tell them apart. Flushing
+    /// output before each new request at least keeps the output of finished executions together.
     fn pass_output_line(&self, output_name: &'static str, line: String) {
         let mut message = None;
         if let Some(exec_request) = &*self.latest_execution_request.lock().unwrap() {
@@ -420,32 +545,7 @@ impl Server {
                 for error in errors {
                     let message = format!("{}", error.message().bright_red());
                     if error.is_from_user_code() {
-                        let mut traceback = Vec::new();
-                        for spanned_message in error.spanned_messages() {
-                            for line in &spanned_message.lines {
-                                traceback.push(line.clone());
-                            }
-                            if let Some(span) = &spanned_message.span {
-                                let mut carrots = String::new();
-                                for _ in 1..span.start_column {
-                                    carrots.push(' ');
-                                }
-                                for _ in span.start_column..span.end_column {
-                                    carrots.push('^');
-                                }
-                                traceback.push(format!(
-                                    "{} {}",
-                                    carrots.bright_red(),
-                                    spanned_message.label.bright_blue()
-                                ));
-                            } else {
-                                traceback.push(spanned_message.label.clone());
-                            }
-                        }
-                        traceback.push(error.message());
-                        for help in error.help() {
-                            traceback.push(format!("{}: {}", "help".bold(), help));
-                        }
+                        let traceback = user_code_traceback(error);
                         parent_message
                             .new_message("error")
                             .with_content(object! {
@@ -482,12 +582,80 @@ impl Server {
         }
         Ok(())
     }
+
+    /// Sends compiler warnings to stderr. Unlike errors, warnings don't cause the cell to fail.
+    fn emit_warnings(
+        &self,
+        warnings: &[evcxr::CompilationError],
+        parent_message: &JupyterMessage,
+    ) -> Result<()> {
+        for warning in warnings {
+            parent_message
+                .new_message("stream")
+                .with_content(object! {
+                    "name" => "stderr",
+                    "text" => format!("{}\n", user_code_traceback(warning).join("\n")),
+                })
+                .send(&self.iopub.lock().unwrap())?;
+        }
+        Ok(())
+    }
+
+    fn emit_notes(&self, notes: &[String], parent_message: &JupyterMessage) -> Result<()> {
+        for note in notes {
+            parent_message
+                .new_message("stream")
+                .with_content(object! {
+                    "name" => "stderr",
+                    "text" => format!("{}\n", note),
+                })
+                .send(&self.iopub.lock().unwrap())?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns lines showing where in the user's code `error` occurred, followed by the message.
+fn user_code_traceback(error: &evcxr::CompilationError) -> Vec<String> {
+    let mut traceback = Vec::new();
+    for spanned_message in error.spanned_messages() {
+        for line in &spanned_message.lines {
+            traceback.push(line.clone());
+        }
+        if let Some(span) = &spanned_message.span {
+            let mut carrots = String::new();
+            for _ in 1..span.start_column {
+                carrots.push(' ');
+            }
+            for _ in span.start_column..span.end_column {
+                carrots.push('^');
+            }
+            let carrots = if error.level() == "warning" {
+                carrots.bright_yellow()
+            } else {
+                carrots.bright_red()
+            };
+            traceback.push(format!(
+                "{} {}",
+                carrots,
+                spanned_message.label.bright_blue()
+            ));
+        } else {
+            traceback.push(spanned_message.label.clone());
+        }
+    }
+    traceback.push(error.message());
+    for help in error.help() {
+        traceback.push(format!("{}: {}", "help".bold(), help));
+    }
+    traceback
 }
 
 fn comm_open(
     message: JupyterMessage,
     context: &Arc<Mutex<CommandContext>>,
     iopub: Arc<Mutex<Connection>>,
+    variables_comms: &Arc<Mutex<VariablesComms>>,
 ) -> Result<()> {
     if message.target_name() == "evcxr-cargo-check" {
         let context = Arc::clone(context);
@@ -511,12 +679,155 @@ fn comm_open(
                 .unwrap();
         });
         Ok(())
+    } else if message.target_name() == "evcxr-variables" {
+        variables_comms.lock().unwrap().open(message.comm_id());
+        // Send the variables that we've already got.
+        send_variables(
+            message,
+            Arc::clone(context),
+            iopub,
+            Arc::clone(variables_comms),
+        );
+        Ok(())
     } else {
         // Unrecognised comm target, just close the comm.
         message.comm_close_message().send(&iopub.lock().unwrap())
     }
 }
 
+/// Sends the current variables to the comm that `message` was sent on. This is done on a separate
+/// thread, since we may need to wait for an execution that's in progress to finish.
+fn send_variables(
+    message: JupyterMessage,
+    context: Arc<Mutex<CommandContext>>,
+    iopub: Arc<Mutex<Connection>>,
+    variables_comms: Arc<Mutex<VariablesComms>>,
+) {
+    std::thread::spawn(move || {
+        let (version, data) = {
+            let mut context = context.lock().unwrap();
+            (context.variables_version(), variables_json(&mut context))
+        };
+        variables_comms
+            .lock()
+            .unwrap()
+            .sent(message.comm_id(), version);
+        let response_content = object! {
+            "comm_id" => message.comm_id(),
+            "data" => data,
+        };
+        let result = message
+            .new_message("comm_msg")
+            .without_parent_header()
+            .with_content(response_content)
+            .send(&iopub.lock().unwrap());
+        if let Err(error) = result {
+            eprintln!(
+                "Failed to send variables to comm {}: {:?}",
+                message.comm_id(),
+                error
+            );
+        }
+    });
+}
+
+/// The open `evcxr-variables` comms, together with the version of the variables (see
+/// `CommandContext::variables_version`) that each was last sent, if any.
+#[derive(Default)]
+struct VariablesComms {
+    sent_versions: HashMap<String, Option<u64>>,
+}
+
+impl VariablesComms {
+    fn open(&mut self, comm_id: &str) {
+        self.sent_versions.insert(comm_id.to_owned(), None);
+    }
+
+    fn close(&mut self, comm_id: &str) {
+        self.sent_versions.remove(comm_id);
+    }
+
+    fn is_open(&self, comm_id: &str) -> bool {
+        self.sent_versions.contains_key(comm_id)
+    }
+
+    fn any_open(&self) -> bool {
+        !self.sent_versions.is_empty()
+    }
+
+    /// Records that `comm_id` has been sent `version` of the variables.
+    fn sent(&mut self, comm_id: &str, version: u64) {
+        if let Some(sent_version) = self.sent_versions.get_mut(comm_id) {
+            *sent_version = Some(version);
+        }
+    }
+
+    /// Returns the IDs of the comms that haven't been sent `version` of the variables, sorted, and
+    /// records that they have been.
+    fn needing_refresh(&mut self, version: u64) -> Vec<String> {
+        let mut comm_ids = Vec::new();
+        for (comm_id, sent_version) in &mut self.sent_versions {
+            if *sent_version != Some(version) {
+                *sent_version = Some(version);
+                comm_ids.push(comm_id.clone());
+            }
+        }
+        comm_ids.sort();
+        comm_ids
+    }
+}
+
+/// Converts content keyed by mime type into the `data` of a Jupyter message. JSON content is
+/// embedded as JSON if it parses.
+fn mime_bundle_json(content_by_mime_type: &HashMap<String, String>) -> HashMap<String, JsonValue> {
+    // At the time of writing the json crate appears to have a generic From implementation for a
+    // Vec<T> where T implements Into<JsonValue>. It also has conversion from HashMap<String,
+    // JsonValue>, but it doesn't have conversion from HashMap<String, T>. Perhaps send a PR? For
+    // now, we convert the values manually.
+    content_by_mime_type
+        .iter()
+        .map(|(k, v)| {
+            let value = if k.contains("json") {
+                json::parse(v).unwrap_or_else(|_| json::from(v.as_str()))
+            } else {
+                json::from(v.as_str())
+            };
+            (k.clone(), value)
+        })
+        .collect()
+}
+
+/// Returns the variables in the form used by the JupyterLab variable inspector. If inspecting the
+/// variables fails, the error is reported in place of the variables.
+fn variables_json(context: &mut CommandContext) -> JsonValue {
+    let inspections = match context.inspect_variables() {
+        Ok(inspections) => inspections,
+        Err(error) => {
+            return object! {
+                "variables" => array![],
+                "error" => error.to_string(),
+            }
+        }
+    };
+    let variables: Vec<JsonValue> = inspections
+        .into_iter()
+        .map(|variable| {
+            object! {
+                "varName" => variable.name,
+                "varType" => variable.type_name,
+                "varSize" => format!("{} bytes", variable.size),
+                "varShape" => "",
+                "varContent" => variable.preview.unwrap_or_default(),
+                "isMatrix" => false,
+                "isWidget" => false,
+            }
+        })
+        .collect();
+    object! {
+        "variables" => variables,
+    }
+}
+
 fn cargo_check(code: &str, context: &Mutex<CommandContext>) -> JsonValue {
     let problems = context.lock().unwrap().check(code).unwrap_or_default();
     let problems_json: Vec<JsonValue> = problems
@@ -658,4 +969,36 @@ mod tests {
         assert_eq!(byte_offset_to_grapheme_offset(src, 6).unwrap(), 2);
         assert_eq!(byte_offset_to_grapheme_offset(src, 7).unwrap(), 3);
     }
+
+    #[test]
+    fn variables_comms_refreshed_when_variables_change() {
+        let mut comms = VariablesComms::default();
+        assert!(!comms.any_open());
+        assert!(comms.needing_refresh(0).is_empty());
+
+        // A newly opened comm gets sent the variables straight away, so doesn't need them again
+        // until they change.
+        comms.open("a");
+        assert!(comms.is_open("a"));
+        comms.sent("a", 0);
+        assert!(comms.needing_refresh(0).is_empty());
+        assert_eq!(comms.needing_refresh(1), vec!["a".to_owned()]);
+        assert!(comms.needing_refresh(1).is_empty());
+
+        // A comm whose initial variables haven't been sent yet needs them after an execution.
+        comms.open("b");
+        assert_eq!(comms.needing_refresh(1), vec!["b".to_owned()]);
+        assert_eq!(
+            comms.needing_refresh(2),
+            vec!["a".to_owned(), "b".to_owned()]
+        );
+
+        comms.close("a");
+        assert!(!comms.is_open("a"));
+        comms.sent("a", 3);
+        assert_eq!(comms.needing_refresh(3), vec!["b".to_owned()]);
+        comms.close("b");
+        assert!(!comms.any_open());
+        assert!(comms.needing_refresh(4).is_empty());
+    }
 }
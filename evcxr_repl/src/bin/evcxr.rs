@@ -16,8 +16,10 @@ use colored::*;
 use evcxr::CommandContext;
 use evcxr::CompilationError;
 use evcxr::Error;
+use evcxr_repl::read_input;
 use evcxr_repl::BgInitMutex;
 use evcxr_repl::EvcxrRustylineHelper;
+use evcxr_repl::StdinForwarder;
 use rustyline::error::ReadlineError;
 use rustyline::At;
 use rustyline::Cmd;
@@ -38,6 +40,8 @@ const PROMPT: &str = ">> ";
 struct Repl {
     command_context: Arc<BgInitMutex<CommandContext>>,
     ide_mode: bool,
+    use_readline: bool,
+    stdin_forwarder: StdinForwarder,
 }
 
 fn send_output<T: io::Write + Send + 'static>(
@@ -60,12 +64,25 @@ fn send_output<T: io::Write + Send + 'static>(
 }
 
 impl Repl {
-    fn new(ide_mode: bool, opt: String) -> Repl {
+    fn new(ide_mode: bool, use_readline: bool, opt: String) -> Repl {
         let initialize = move || -> Result<CommandContext, Error> {
             let (mut command_context, outputs) = CommandContext::new()?;
 
             send_output(outputs.stdout, io::stdout(), None);
             send_output(outputs.stderr, io::stderr(), Some(Color::BrightRed));
+            // Ctrl-C while at the prompt is handled by rustyline. While code is running, we use it
+            // to interrupt that code.
+            let interrupt_handle = command_context.interrupt_handle();
+            if let Err(error) = ctrlc::set_handler(move || {
+                if let Err(error) = interrupt_handle.interrupt() {
+                    eprintln!("{}", error);
+                }
+            }) {
+                eprintln!(
+                    "Failed to set up Ctrl-C handling. Running code can't be interrupted: {}",
+                    error
+                );
+            }
             command_context.execute(":load_config --quiet")?;
             if !opt.is_empty() {
                 // Ignore failure
@@ -83,12 +100,38 @@ impl Repl {
         Repl {
             command_context,
             ide_mode,
+            use_readline,
+            stdin_forwarder: StdinForwarder::new(),
         }
     }
     fn execute(&mut self, to_run: &str) {
-        let execution_result = self.command_context.lock().execute(to_run);
+        let use_readline = self.use_readline;
+        let stdin_forwarder = &self.stdin_forwarder;
+        let mut callbacks = evcxr::EvalCallbacks {
+            input_reader: &|prompt, is_password| {
+                stdin_forwarder.paused(|| read_input(prompt, is_password, use_readline))
+            },
+            display_handler: &|bundle| {
+                if let Some(text) = bundle.get("text/plain") {
+                    println!("{}", text);
+                }
+            },
+        };
+        let execution_result = {
+            let mut command_context = self.command_context.lock();
+            // While code runs, what's typed goes to its stdin.
+            stdin_forwarder.forward_to(Some(command_context.stdin_handle()));
+            let result = command_context.execute_with_callbacks(to_run, &mut callbacks);
+            stdin_forwarder.forward_to(None);
+            result
+        };
         let success = match execution_result {
-            Ok(output) => {
+            Ok(mut output) => {
+                let warnings = std::mem::take(&mut output.warnings);
+                self.display_errors(to_run, warnings);
+                for note in &output.notes {
+                    eprintln!("{}", note.yellow());
+                }
                 if let Some(text) = output.get("text/plain") {
                     println!("{}", text);
                 }
@@ -123,6 +166,13 @@ impl Repl {
     fn display_errors(&mut self, source: &str, errors: Vec<CompilationError>) {
         let mut last_span_lines: &Vec<String> = &vec![];
         for error in &errors {
+            let highlight = |text: &str| {
+                if error.level() == "warning" {
+                    text.bright_yellow()
+                } else {
+                    text.bright_red()
+                }
+            };
             if error.is_from_user_code() {
                 for spanned_message in error.spanned_messages() {
                     if let Some(span) = &spanned_message.span {
@@ -156,7 +206,7 @@ impl Repl {
                         // them above.
                         let span_diff = end_column - start_column;
                         let carrots = "^".repeat(span_diff);
-                        print!("{}", carrots.bright_red());
+                        print!("{}", highlight(&carrots));
                         println!(" {}", spanned_message.label.bright_blue());
                     } else {
                         // Our error originates from both user-code and generated
@@ -164,7 +214,7 @@ impl Repl {
                         println!("{}", spanned_message.label.bright_blue());
                     }
                 }
-                println!("{}", error.message().bright_red());
+                println!("{}", highlight(&error.message()));
                 for help in error.help() {
                     println!("{} {}", "help:".bold(), help);
                 }
@@ -256,7 +306,11 @@ fn main() {
             println!("Prelude will be loaded from {}", prelude.display());
         }
     }
-    let mut repl = Repl::new(options.ide_mode, options.opt.clone());
+    let mut repl = Repl::new(
+        options.ide_mode,
+        !options.disable_readline,
+        options.opt.clone(),
+    );
     let mut config_builder = match options.edit_mode {
         EditMode::Vi => {
             rustyline::Config::builder()
@@ -0,0 +1,282 @@
+// Copyright 2022 The Evcxr Authors.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     https://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use evcxr::StdinHandle;
+use rustyline::config::Configurer;
+use rustyline::highlight::Highlighter;
+use rustyline::Editor;
+use std::borrow::Cow;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+/// Reads a line of input on behalf of user code that asked for it, e.g. via `evcxr_input`. If
+/// `is_password` is set, what's typed is masked. Returns an empty string if reading fails.
+pub fn read_input(prompt: &str, is_password: bool, use_readline: bool) -> String {
+    if !use_readline {
+        print!("{}", prompt);
+        let _ = std::io::stdout().flush();
+        let mut line = String::new();
+        let _ = std::io::stdin().read_line(&mut line);
+        return line.trim_end_matches(&['\r', '\n'][..]).to_owned();
+    }
+    let mut editor = Editor::<MaskingHelper>::new();
+    editor.set_helper(Some(MaskingHelper {
+        masking: is_password,
+    }));
+    editor.set_auto_add_history(false);
+    if is_password {
+        // Masking is done by highlighting, which would otherwise be skipped if colors are off.
+        editor.set_color_mode(rustyline::ColorMode::Forced);
+    }
+    editor.readline(prompt).unwrap_or_default()
+}
+
+/// Replaces what's typed with asterisks if `masking` is set.
+struct MaskingHelper {
+    masking: bool,
+}
+
+impl Highlighter for MaskingHelper {
+    fn highlight<'l>(&self, line: &'l str, _pos: usize) -> Cow<'l, str> {
+        if self.masking {
+            Cow::Owned("*".repeat(line.chars().count()))
+        } else {
+            Cow::Borrowed(line)
+        }
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize) -> bool {
+        self.masking
+    }
+}
+
+impl rustyline::completion::Completer for MaskingHelper {
+    type Candidate = String;
+}
+
+impl rustyline::hint::Hinter for MaskingHelper {
+    type Hint = String;
+}
+
+impl rustyline::validate::Validator for MaskingHelper {}
+
+impl rustyline::Helper for MaskingHelper {}
+
+/// Forwards what's typed at the terminal to the stdin of user code. This is only done while code
+/// is running, so that we don't take input that was meant for the REPL itself. Does nothing if our
+/// stdin isn't a terminal, since then it's most likely commands for the REPL.
+pub struct StdinForwarder {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    /// Where to forward input to. `None` when no code is running.
+    target: Mutex<Option<StdinHandle>>,
+    /// Notified when `target` is set.
+    target_set: Condvar,
+    /// Written to when `target` is cleared, to wake the forwarding thread if it's waiting for
+    /// input.
+    #[cfg(unix)]
+    wake: Option<WakePipe>,
+}
+
+impl StdinForwarder {
+    pub fn new() -> StdinForwarder {
+        #[cfg(unix)]
+        let wake = if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
+            WakePipe::new()
+        } else {
+            None
+        };
+        let shared = Arc::new(Shared {
+            target: Mutex::new(None),
+            target_set: Condvar::new(),
+            #[cfg(unix)]
+            wake,
+        });
+        #[cfg(unix)]
+        if shared.wake.is_some() {
+            let shared = Arc::clone(&shared);
+            std::thread::spawn(move || forward_stdin(&shared));
+        }
+        StdinForwarder { shared }
+    }
+
+    /// Starts or, if `target` is `None`, stops forwarding input.
+    pub fn forward_to(&self, target: Option<StdinHandle>) {
+        let forwarding = target.is_some();
+        *self.shared.target.lock().unwrap() = target;
+        if forwarding {
+            self.shared.target_set.notify_all();
+        } else {
+            #[cfg(unix)]
+            if let Some(wake) = &self.shared.wake {
+                wake.wake();
+            }
+        }
+    }
+
+    /// Runs `f` without forwarding input while it runs, so that it can read from stdin itself.
+    pub fn paused<T>(&self, f: impl FnOnce() -> T) -> T {
+        // Holding the lock stops the forwarding thread from reading.
+        let _target = self.shared.target.lock().unwrap();
+        f()
+    }
+}
+
+impl Default for StdinForwarder {
+    fn default() -> Self {
+        StdinForwarder::new()
+    }
+}
+
+#[cfg(unix)]
+fn forward_stdin(shared: &Shared) {
+    let wake = match &shared.wake {
+        Some(wake) => wake,
+        None => return,
+    };
+    let mut buffer = [0u8; 4096];
+    loop {
+        {
+            let mut target = shared.target.lock().unwrap();
+            while target.is_none() {
+                target = shared.target_set.wait(target).unwrap();
+            }
+        }
+        // Wait until there's input, or until we're told to stop forwarding. We don't hold the
+        // lock while waiting, so that forwarding can be stopped or paused.
+        let mut poll_fds = [
+            libc::pollfd {
+                fd: libc::STDIN_FILENO,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: wake.read_fd,
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        if unsafe { libc::poll(poll_fds.as_mut_ptr(), 2, -1) } <= 0 {
+            continue;
+        }
+        if poll_fds[1].revents != 0 {
+            wake.clear();
+        }
+        if poll_fds[0].revents == 0 {
+            continue;
+        }
+        let target = shared.target.lock().unwrap();
+        let handle = match &*target {
+            Some(handle) => handle,
+            // The input is for the REPL.
+            None => continue,
+        };
+        // If input was read while forwarding was paused, there may be none left, in which case
+        // reading would block.
+        if !stdin_ready() {
+            continue;
+        }
+        // The terminal is in line mode while code is running, so this will generally be a whole
+        // line. We bypass std's buffered stdin, since anything it buffered wouldn't be seen by
+        // poll or by the REPL.
+        let len = unsafe {
+            libc::read(
+                libc::STDIN_FILENO,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        };
+        if len < 0 {
+            if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                continue;
+            }
+            return;
+        }
+        let result = if len == 0 {
+            // End of file, e.g. because Ctrl-D was pressed.
+            handle.close()
+        } else {
+            handle.write(&buffer[..len as usize])
+        };
+        if let Err(error) = result {
+            eprintln!("{}", error);
+        }
+        // Once the terminal has gone away, there won't be any more input.
+        if poll_fds[0].revents & libc::POLLHUP != 0 {
+            return;
+        }
+    }
+}
+
+/// Returns whether there's input waiting to be read from stdin, or it's at end of file.
+#[cfg(unix)]
+fn stdin_ready() -> bool {
+    let mut poll_fd = libc::pollfd {
+        fd: libc::STDIN_FILENO,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut poll_fd, 1, 0) == 1 }
+}
+
+/// A pipe used to wake a thread that's waiting in `poll`.
+#[cfg(unix)]
+struct WakePipe {
+    read_fd: libc::c_int,
+    write_fd: libc::c_int,
+}
+
+#[cfg(unix)]
+impl WakePipe {
+    fn new() -> Option<WakePipe> {
+        let mut fds = [0; 2];
+        unsafe {
+            if libc::pipe(fds.as_mut_ptr()) != 0 {
+                return None;
+            }
+            for fd in fds {
+                libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC);
+                libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK);
+            }
+        }
+        Some(WakePipe {
+            read_fd: fds[0],
+            write_fd: fds[1],
+        })
+    }
+
+    fn wake(&self) {
+        // If the pipe is full, there's already a wake-up pending.
+        unsafe {
+            libc::write(self.write_fd, [0u8].as_ptr() as *const libc::c_void, 1);
+        }
+    }
+
+    /// Discards pending wake-ups.
+    fn clear(&self) {
+        let mut buffer = [0u8; 64];
+        while unsafe {
+            libc::read(
+                self.read_fd,
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+            )
+        } > 0
+        {}
+    }
+}
@@ -13,9 +13,12 @@
 // limitations under the License.
 
 mod bginit;
+mod input;
 mod repl;
 mod scan;
 
 pub use bginit::BgInitMutex;
 pub use bginit::BgInitMutexGuard;
+pub use input::read_input;
+pub use input::StdinForwarder;
 pub use repl::EvcxrRustylineHelper;
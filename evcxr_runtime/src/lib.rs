@@ -45,11 +45,7 @@ impl ContentMimeType {
     ///     .text("<span style=\"color: red\">>Hello world</span>");
     /// ```
     pub fn text<S: AsRef<str>>(self, text: S) {
-        println!(
-            "EVCXR_BEGIN_CONTENT {}\n{}\nEVCXR_END_CONTENT",
-            self.mime_type,
-            text.as_ref()
-        );
+        send_content(control::CONTENT, None, &self.mime_type, text.as_ref());
     }
 
     /// Emits the supplied content, which should be of the mime type already
@@ -65,8 +61,221 @@ impl ContentMimeType {
     }
 }
 
+/// A display whose content can be replaced after it has been shown, for example
+/// to show progress.
+pub struct DisplayHandle {
+    id: String,
+}
+
+/// Displays the supplied content, which should be of the specified mime type,
+/// returning a handle through which it can later be replaced.
+/// ```
+/// let progress = evcxr_runtime::display("text/plain", "0%");
+/// progress.update("text/plain", "100%");
+/// ```
+pub fn display<M: AsRef<str>, S: AsRef<str>>(mime_type: M, text: S) -> DisplayHandle {
+    let handle = DisplayHandle {
+        id: new_display_id(),
+    };
+    send_content(
+        control::DISPLAY,
+        Some(&handle.id),
+        mime_type.as_ref(),
+        text.as_ref(),
+    );
+    handle
+}
+
+impl DisplayHandle {
+    /// Replaces the displayed content with the supplied content, which should
+    /// be of the specified mime type.
+    pub fn update<M: AsRef<str>, S: AsRef<str>>(&self, mime_type: M, text: S) {
+        send_content(
+            control::UPDATE_DISPLAY,
+            Some(&self.id),
+            mime_type.as_ref(),
+            text.as_ref(),
+        );
+    }
+
+    /// Returns the ID that identifies this display to the front end.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// Returns an ID that won't be reused, even by other processes.
+fn new_display_id() -> String {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{SystemTime, UNIX_EPOCH};
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default();
+    format!(
+        "evcxr-{:x}-{:x}-{:x}",
+        std::process::id(),
+        nanos,
+        NEXT_ID.fetch_add(1, Ordering::SeqCst)
+    )
+}
+
+/// Sends content as a message of the specified kind, preceded by `id` if
+/// there is one.
+fn send_content(kind: &str, id: Option<&str>, mime_type: &str, text: &str) {
+    let mut fields = vec![kind.as_bytes()];
+    fields.extend(id.map(str::as_bytes));
+    fields.push(mime_type.as_bytes());
+    fields.push(text.as_bytes());
+    match control::send(&fields) {
+        Some(Ok(())) => return,
+        Some(Err(error)) => eprintln!("Failed to send content to Evcxr: {}", error),
+        None => {}
+    }
+    // We're running under an older version of Evcxr, which doesn't give us a
+    // control channel, not under Evcxr at all, or the channel failed. Updates
+    // can't be done in place, so the new content is just shown again.
+    println!(
+        "EVCXR_BEGIN_CONTENT {}\n{}\nEVCXR_END_CONTENT",
+        mime_type, text
+    );
+}
+
+/// Exchanges messages with Evcxr over the channel that it gives the process running user code. The
+/// encoding matches that in Evcxr's `evcxr_internal_runtime`. This is shared with other crates
+/// that work with Evcxr, such as `evcxr_input`, and isn't intended to be used otherwise.
+#[doc(hidden)]
+pub mod control {
+    use std::io;
+    use std::io::Read;
+    use std::io::Write;
+
+    /// See `CONTROL_CHANNEL_VAR` in Evcxr's `evcxr_internal_runtime`.
+    const CONTROL_CHANNEL_VAR: &str = "EVCXR_CONTROL_CHANNEL";
+    pub const CONTENT: &str = "CONTENT";
+    pub const DISPLAY: &str = "DISPLAY";
+    pub const UPDATE_DISPLAY: &str = "UPDATE_DISPLAY";
+    pub const INPUT_REQUEST: &str = "INPUT_REQUEST";
+    pub const INPUT_RESPONSE: &str = "INPUT_RESPONSE";
+
+    #[cfg(unix)]
+    type ControlChannel = std::os::unix::net::UnixStream;
+    #[cfg(not(unix))]
+    type ControlChannel = std::net::TcpStream;
+
+    /// Sends a message made up of `fields`. Returns None if there's no control
+    /// channel to send it to.
+    pub fn send(fields: &[&[u8]]) -> Option<io::Result<()>> {
+        with_channel(|channel| channel.write_all(&encode_message(fields)))
+    }
+
+    /// Sends a message made up of `fields`, then returns the fields of the
+    /// reply. Returns None if there's no control channel to send it to.
+    pub fn request(fields: &[&[u8]]) -> Option<io::Result<Vec<Vec<u8>>>> {
+        with_channel(|channel| {
+            channel.write_all(&encode_message(fields))?;
+            read_message(channel)
+        })
+    }
+
+    /// Runs `f` with the control channel, if this process has one. So that
+    /// anything printed beforehand is seen first, stdout and stderr are flushed
+    /// first. Holding the lock on stdout also stops messages from different
+    /// threads from being interleaved.
+    #[cfg(any(unix, windows))]
+    fn with_channel<T, F>(f: F) -> Option<io::Result<T>>
+    where
+        F: FnOnce(&mut ControlChannel) -> io::Result<T>,
+    {
+        let value = std::env::var(CONTROL_CHANNEL_VAR).ok()?;
+        let (pid, handle) = value.split_once(':')?;
+        // Processes started by code running under Evcxr inherit the variable,
+        // but not the channel.
+        if pid.parse() != Ok(std::process::id()) {
+            return None;
+        }
+        let handle = handle.parse().ok()?;
+        // The channel belongs to the process, so we mustn't close it.
+        #[cfg(unix)]
+        let channel: ControlChannel = unsafe { std::os::unix::io::FromRawFd::from_raw_fd(handle) };
+        #[cfg(windows)]
+        let channel: ControlChannel =
+            unsafe { std::os::windows::io::FromRawSocket::from_raw_socket(handle) };
+        let mut channel = std::mem::ManuallyDrop::new(channel);
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        let _ = stdout.flush();
+        let _ = io::stderr().flush();
+        Some(f(&mut channel))
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    fn with_channel<T, F>(_f: F) -> Option<io::Result<T>>
+    where
+        F: FnOnce(&mut ControlChannel) -> io::Result<T>,
+    {
+        None
+    }
+
+    /// Encodes a message made up of `fields`. A message is its length followed
+    /// by its fields. Each field is its length followed by its contents.
+    /// Lengths are in bytes, encoded as little-endian u32s.
+    pub fn encode_message(fields: &[&[u8]]) -> Vec<u8> {
+        let mut body = Vec::new();
+        for field in fields {
+            body.extend_from_slice(&(field.len() as u32).to_le_bytes());
+            body.extend_from_slice(field);
+        }
+        let mut message = (body.len() as u32).to_le_bytes().to_vec();
+        message.append(&mut body);
+        message
+    }
+
+    /// The maximum length in bytes of the body of a message that `read_message` will accept.
+    pub const MAX_MESSAGE_LEN: usize = 1 << 30;
+
+    /// Reads a message from `reader`, returning its fields.
+    pub fn read_message<R: Read>(reader: &mut R) -> io::Result<Vec<Vec<u8>>> {
+        let len = read_len(reader)?;
+        if len > MAX_MESSAGE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Control message of {} bytes is longer than the maximum of {} bytes",
+                    len, MAX_MESSAGE_LEN
+                ),
+            ));
+        }
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+        let mut body = &body[..];
+        let mut fields = Vec::new();
+        while !body.is_empty() {
+            let len = read_len(&mut body)?;
+            if len > body.len() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Control message field is longer than the message",
+                ));
+            }
+            fields.push(body[..len].to_vec());
+            body = &body[len..];
+        }
+        Ok(fields)
+    }
+
+    fn read_len<R: Read>(reader: &mut R) -> io::Result<usize> {
+        let mut len = [0; 4];
+        reader.read_exact(&mut len)?;
+        Ok(u32::from_le_bytes(len) as usize)
+    }
+}
+
 #[cfg(test)]
 mod tests {
+    use super::control;
+    use super::display;
     use super::mime_type;
 
     #[test]
@@ -78,4 +287,20 @@ mod tests {
     fn test_mime_type_accept_string() {
         mime_type("text/plain".to_owned()).text("Hello world");
     }
+
+    #[test]
+    fn test_read_message_rejects_oversized_length() {
+        // Only the length is there, so if we tried to read the body, we'd get UnexpectedEof.
+        let len = (control::MAX_MESSAGE_LEN as u32 + 1).to_le_bytes();
+        let error = control::read_message(&mut &len[..]).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_display_ids_are_unique() {
+        let first = display("text/plain", "Hello");
+        let second = display("text/plain", "world");
+        assert_ne!(first.id(), second.id());
+        second.update("text/plain", "everyone");
+    }
 }